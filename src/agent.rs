@@ -1,201 +1,534 @@
-use crate::snmp::{self, SnmpMessage, SnmpValue, Varbind};
+use crate::agentx::AgentxMaster;
+#[cfg(feature = "tokio")]
+use crate::async_agent::AsyncMibHandler;
+use crate::client::Target;
+use crate::community::{Community, Role};
+#[cfg(feature = "if-mib")]
+use crate::if_mib::{self, IfMib};
+use crate::mib::{self, MibStore, ObjectType};
+use crate::snmp::{self, ErrorStatus, PduType, SnmpMessage, SnmpValue, Varbind};
+use crate::system::{self, SystemGroup, SystemState};
+use crate::table::Table;
+use crate::transport::{self, Transport, TransportAddr, UdpTransport};
 use anyhow::{Context, Result};
 use bytes::BytesMut;
-use std::collections::HashMap;
-use std::net::{SocketAddr, UdpSocket};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::IpAddr;
+use std::ops::Bound;
+use std::pin::pin;
 use std::sync::{Arc, RwLock};
+use std::task::{Context as TaskContext, Poll, Waker};
 use std::thread;
 use std::time::Duration;
-type MibDB = HashMap<Vec<u32>, SnmpValue>;
 
-pub struct SnmpAgent {
-    socket: UdpSocket,
-    communities: Vec<String>,
-    mib: Arc<RwLock<MibDB>>,
-}
+/// Serves a subtree of the MIB whose values are computed on request, e.g.
+/// a live uptime or counters kept by the application.
+///
+/// A handler registered at a prefix answers for every OID under it,
+/// shadowing values registered with [`SnmpAgent::register_oid`]. See
+/// [`SnmpAgent::register_scalar`] for a single computed value.
+pub trait MibHandler: Send + Sync {
+    /// Returns the value of `oid`, or `None` if there is no such instance.
+    fn get(&self, oid: &[u32]) -> Option<SnmpValue>;
 
-impl SnmpAgent {
-    pub fn new(addr: &str, communities: Vec<String>) -> Result<Self> {
-        let socket = UdpSocket::bind(addr).context("Failed to bind UDP socket")?;
-        socket
-            .set_read_timeout(Some(Duration::from_secs(5)))
-            .context("Failed to set socket timeout")?;
-
-        Ok(Self {
-            socket,
-            communities,
-            mib: Arc::new(RwLock::new(HashMap::new())),
-        })
+    /// Returns the first varbind of the handler's subtree that follows
+    /// `oid` in lexicographical order. `oid` may lie before the subtree.
+    fn get_next(&self, oid: &[u32]) -> Option<Varbind>;
+
+    /// Checks a value before any varbind of the SetRequest is stored; the
+    /// default accepts everything and leaves errors to [`MibHandler::set`].
+    fn test_set(&self, oid: &[u32], value: &SnmpValue) -> Result<(), ErrorStatus> {
+        let _ = (oid, value);
+        Ok(())
     }
 
-    pub fn register_oid(&self, oid: Vec<u32>, value: SnmpValue) -> Result<()> {
-        self.mib.write().unwrap().insert(oid, value);
+    /// Stores a value; the default rejects every write with notWritable.
+    fn set(&self, oid: &[u32], value: &SnmpValue) -> Result<(), ErrorStatus> {
+        let _ = (oid, value);
+        Err(ErrorStatus::NotWritable)
+    }
+
+    /// Checks all varbinds of a SetRequest that fall in the handler's
+    /// subtree before any of them is stored, failing with the error and
+    /// the index of the varbind that caused it. The default calls
+    /// [`MibHandler::test_set`] for each in turn.
+    fn test_set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        for (i, varbind) in varbinds.iter().enumerate() {
+            self.test_set(&varbind.oid, &varbind.value)
+                .map_err(|status| (status, i))?;
+        }
         Ok(())
     }
 
-    // Process an SNMP message
-    fn process_message(&self, data: &[u8], src_addr: SocketAddr) -> Result<()> {
-        // Decode the message
-        let message = match snmp::decode_snmp_message(data) {
-            Ok(msg) => msg,
-            Err(e) => {
-                println!("Error decoding message: {}", e);
-                return Ok(());
+    /// Stores all varbinds of a SetRequest that fall in the handler's
+    /// subtree, failing with the error and the index of the varbind that
+    /// caused it. The default calls [`MibHandler::set`] for each in turn;
+    /// handlers that need to see related varbinds together, such as the
+    /// columns of a new table row, override it.
+    ///
+    /// On failure, the varbinds before the one blamed are taken to be
+    /// those stored, and only they are passed to
+    /// [`MibHandler::undo_set_all`], so an override must store them in
+    /// order.
+    fn set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        for (i, varbind) in varbinds.iter().enumerate() {
+            self.set(&varbind.oid, &varbind.value)
+                .map_err(|status| (status, i))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the values `varbinds` are about to replace, for
+    /// [`MibHandler::undo_set_all`]. The default calls [`MibHandler::get`]
+    /// for each; a handler serving cached values reads them afresh.
+    fn previous_values(&self, varbinds: &[Varbind]) -> Vec<Option<SnmpValue>> {
+        varbinds
+            .iter()
+            .map(|varbind| self.get(&varbind.oid))
+            .collect()
+    }
+
+    /// Reverts varbinds stored by a SetRequest that failed later on: all
+    /// of the batch, or those before the one that failed if
+    /// [`MibHandler::set_all`] itself did. `previous` holds what
+    /// [`MibHandler::previous_values`] returned for them before the
+    /// request. The default stores the old values back, in reverse order,
+    /// and fails with undoFailed if an object did not exist.
+    fn undo_set_all(
+        &self,
+        varbinds: &[Varbind],
+        previous: &[Option<SnmpValue>],
+    ) -> Result<(), ErrorStatus> {
+        let mut result = Ok(());
+        for (varbind, value) in varbinds.iter().zip(previous).rev() {
+            let restored = match value {
+                Some(value) => self.set(&varbind.oid, value),
+                None => Err(ErrorStatus::UndoFailed),
+            };
+            if restored.is_err() {
+                result = Err(ErrorStatus::UndoFailed);
             }
-        };
+        }
+        result
+    }
+}
 
-        // Check community string
-        let community_str = String::from_utf8_lossy(&message.community);
-        if !self.communities.iter().any(|c| c == community_str.as_ref()) {
-            println!("Invalid community string: {}", community_str);
-            return Ok(());
+type HandlerList = Vec<(Vec<u32>, Handler)>;
+
+// The indexes of the SetRequest varbinds going to one handler, or to the
+// static values
+type SetGroup = (Option<Handler>, Vec<usize>);
+
+// A handler serving a subtree, computing its values either right away or,
+// for the tokio agent, asynchronously
+#[derive(Clone)]
+pub(crate) enum Handler {
+    Sync(Arc<dyn MibHandler>),
+    #[cfg(feature = "tokio")]
+    Async(Arc<dyn AsyncMibHandler>),
+}
+
+impl Handler {
+    fn same(&self, other: &Handler) -> bool {
+        match (self, other) {
+            (Handler::Sync(a), Handler::Sync(b)) => Arc::ptr_eq(a, b),
+            #[cfg(feature = "tokio")]
+            (Handler::Async(a), Handler::Async(b)) => Arc::ptr_eq(a, b),
+            #[cfg(feature = "tokio")]
+            _ => false,
         }
-        // Process PDU based on type
-        match message.pdu.pdu_type {
-            crate::snmp::PduType::GET_REQUEST => {
-                self.handle_get_request(&message, src_addr)?;
-            }
-            crate::snmp::PduType::GET_NEXT_REQUEST => {
-                self.handle_get_next_request(&message, src_addr)?;
-            }
-            crate::snmp::PduType::SET_REQUEST => {
-                self.handle_set_request(&message, src_addr)?;
-            }
-            _ => {
-                println!("Unsupported PDU type");
-            }
+    }
+
+    async fn get(&self, oid: &[u32]) -> Option<SnmpValue> {
+        match self {
+            Handler::Sync(handler) => handler.get(oid),
+            #[cfg(feature = "tokio")]
+            Handler::Async(handler) => handler.get(oid).await,
         }
+    }
 
-        Ok(())
+    async fn get_next(&self, oid: &[u32]) -> Option<Varbind> {
+        match self {
+            Handler::Sync(handler) => handler.get_next(oid),
+            #[cfg(feature = "tokio")]
+            Handler::Async(handler) => handler.get_next(oid).await,
+        }
     }
 
-    // Handle a GetRequest
-    fn handle_get_request(&self, request: &SnmpMessage, src_addr: SocketAddr) -> Result<()> {
-        let mib = self.mib.read().unwrap();
-        let mut response_varbinds = Vec::new();
-        let mut error_status = 0;
-        let mut error_index = 0;
-
-        // Process each varbind in the request
-        for (i, varbind) in request.pdu.varbinds.iter().enumerate() {
-            if let Some(value) = mib.get(&varbind.oid) {
-                // OID found, add to response
-                response_varbinds.push(Varbind {
-                    oid: varbind.oid.clone(),
-                    value: value.clone(),
-                });
-            } else {
-                // OID not found, set error
-                error_status = 2; // noSuchName
-                error_index = (i + 1) as i32;
-
-                // Add the original varbind with NULL value
-                response_varbinds.push(Varbind {
-                    oid: varbind.oid.clone(),
-                    value: SnmpValue::Null,
-                });
-            }
-        }
-
-        // Build and send response
-        let mut response_buf = BytesMut::new();
-        snmp::build_response_message(
-            request,
-            response_varbinds,
-            error_status,
-            error_index,
-            &mut response_buf,
-        );
+    async fn test_set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        match self {
+            Handler::Sync(handler) => handler.test_set_all(varbinds),
+            #[cfg(feature = "tokio")]
+            Handler::Async(handler) => handler.test_set_all(varbinds).await,
+        }
+    }
 
-        self.socket
-            .send_to(&response_buf, src_addr)
-            .context("Failed to send SNMP response")?;
+    async fn set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        match self {
+            Handler::Sync(handler) => handler.set_all(varbinds),
+            #[cfg(feature = "tokio")]
+            Handler::Async(handler) => handler.set_all(varbinds).await,
+        }
+    }
 
-        Ok(())
+    async fn previous_values(&self, varbinds: &[Varbind]) -> Vec<Option<SnmpValue>> {
+        match self {
+            Handler::Sync(handler) => handler.previous_values(varbinds),
+            #[cfg(feature = "tokio")]
+            Handler::Async(handler) => handler.previous_values(varbinds).await,
+        }
     }
 
-    // Handle a GetNextRequest
-    fn handle_get_next_request(&self, request: &SnmpMessage, src_addr: SocketAddr) -> Result<()> {
-        let mib = self.mib.read().unwrap();
-        let mut response_varbinds = Vec::new();
-        let mut error_status = 0;
-        let mut error_index = 0;
-
-        // Process each varbind in the request
-        for (i, varbind) in request.pdu.varbinds.iter().enumerate() {
-            // Find the next OID in lexicographical order
-            let next_oid = mib
-                .keys()
-                .filter(|k| k > &&varbind.oid)
-                .min_by(|a, b| a.cmp(b));
-
-            if let Some(next_oid) = next_oid {
-                // Next OID found, add to response
-                if let Some(value) = mib.get(next_oid) {
-                    response_varbinds.push(Varbind {
-                        oid: next_oid.clone(),
-                        value: value.clone(),
-                    });
-                }
-            } else {
-                // No next OID, set error
-                error_status = 2; // noSuchName
-                error_index = (i + 1) as i32;
+    async fn undo_set_all(
+        &self,
+        varbinds: &[Varbind],
+        previous: &[Option<SnmpValue>],
+    ) -> Result<(), ErrorStatus> {
+        match self {
+            Handler::Sync(handler) => handler.undo_set_all(varbinds, previous),
+            #[cfg(feature = "tokio")]
+            Handler::Async(handler) => handler.undo_set_all(varbinds, previous).await,
+        }
+    }
+}
 
-                // Add the original varbind with NULL value
-                response_varbinds.push(Varbind {
-                    oid: varbind.oid.clone(),
-                    value: SnmpValue::Null,
-                });
-            }
+/// The transport-independent part of an agent: the MIB, the accepted
+/// communities and the message size limit.
+///
+/// [`AgentCore::handle_datagram`] maps a received request to the response
+/// to send back, so an agent can run over any transport or event loop.
+/// Clones share the MIB and the AgentX master.
+#[derive(Clone)]
+pub struct AgentCore {
+    communities: Vec<Community>,
+    mib: Arc<Mib>,
+    max_message_size: usize,
+    // Shared so that objects registered before the master is attached,
+    // such as sysORTable, find it at request time
+    agentx: Arc<RwLock<Option<AgentxMaster>>>,
+    system: Arc<SystemState>,
+}
+
+impl AgentCore {
+    /// Creates an agent core accepting `communities` with read-write
+    /// access to the whole MIB; see [`AgentCore::with_community`] for
+    /// narrower ones.
+    pub fn new(communities: Vec<String>) -> Self {
+        Self {
+            communities: communities.into_iter().map(Community::from).collect(),
+            mib: Arc::new(Mib::default()),
+            max_message_size: snmp::DEFAULT_MAX_MESSAGE_SIZE,
+            agentx: Arc::new(RwLock::new(None)),
+            system: Arc::new(SystemState::new()),
         }
+    }
 
-        // Build and send response
-        let mut response_buf = BytesMut::new();
-        snmp::build_response_message(
-            request,
-            response_varbinds,
-            error_status,
-            error_index,
-            &mut response_buf,
-        );
+    /// Sets the largest message the agent will accept or send.
+    ///
+    /// Requests over the limit are dropped; responses over it are answered
+    /// with tooBig, or truncated for GetBulk.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
 
-        self.socket
-            .send_to(&response_buf, src_addr)
-            .context("Failed to send SNMP response")?;
+    /// Serves the subtrees registered with `master` through its subagents,
+    /// alongside the agent's own OIDs.
+    pub fn with_agentx(self, master: AgentxMaster) -> Self {
+        *self.agentx.write().unwrap() = Some(master);
+        self
+    }
+
+    /// Accepts `community` as well, with the role, source network and
+    /// view it carries. When several match a request, the first one added
+    /// applies.
+    pub fn with_community(mut self, community: Community) -> Self {
+        self.communities.push(community);
+        self
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
 
+    pub fn register_oid(&self, oid: Vec<u32>, value: SnmpValue) -> Result<()> {
+        self.mib.values.write().unwrap().insert(oid, value);
         Ok(())
     }
 
-    // Handle a SetRequest
-    fn handle_set_request(&self, request: &SnmpMessage, src_addr: SocketAddr) -> Result<()> {
-        let mut mib = self.mib.write().unwrap();
-        let mut response_varbinds = Vec::new();
-        let mut error_status = 0;
-        let mut error_index = 0;
+    /// Registers an OID along with its MAX-ACCESS, SYNTAX and status,
+    /// which requests for it must then respect. Without a definition, an
+    /// OID is read-write with the type of its current value.
+    pub fn register_object(
+        &self,
+        oid: Vec<u32>,
+        value: SnmpValue,
+        object: ObjectType,
+    ) -> Result<()> {
+        mib::check_value(&oid, &value, &object)?;
+        self.mib
+            .values
+            .write()
+            .unwrap()
+            .insert_object(oid, value, object);
+        Ok(())
+    }
 
-        // Process each varbind in the request
-        for (i, varbind) in request.pdu.varbinds.iter().enumerate() {
-            // Update the MIB
-            mib.insert(varbind.oid.clone(), varbind.value.clone());
+    /// Hands the subtree under `prefix` to `handler`, which computes its
+    /// values on request. When prefixes nest, the longest one matching an
+    /// OID wins.
+    pub fn register_handler(&self, prefix: Vec<u32>, handler: Arc<dyn MibHandler>) -> Result<()> {
+        self.add_handler(prefix, Handler::Sync(handler))
+    }
+
+    pub(crate) fn add_handler(&self, prefix: Vec<u32>, handler: Handler) -> Result<()> {
+        let mut handlers = self.mib.handlers.write().unwrap();
+        handlers.retain(|(p, _)| *p != prefix);
+        handlers.push((prefix, handler));
+        Ok(())
+    }
+
+    /// Serves the single OID `oid` with the value `value` returns at the
+    /// time of each request.
+    pub fn register_scalar(
+        &self,
+        oid: Vec<u32>,
+        value: impl Fn() -> SnmpValue + Send + Sync + 'static,
+    ) -> Result<()> {
+        self.register_handler(oid.clone(), Arc::new(Scalar { oid, value }))
+    }
+
+    /// Serves `table` under its OID; see [`Table`].
+    pub fn register_table(&self, table: Table) -> Result<()> {
+        self.register_handler(table.oid().to_vec(), Arc::new(table))
+    }
+
+    /// Serves the SNMPv2-MIB system group described by `system`. When the
+    /// agent is an AgentX master, sysORTable also lists the capabilities
+    /// its subagents announce.
+    pub fn register_system(&self, system: SystemGroup) -> Result<()> {
+        system::register(self, &self.system, system, self.agentx.clone())
+    }
+
+    /// Adds the entry `id` to sysORTable, e.g. the MODULE-COMPLIANCE a
+    /// newly loaded MIB module implements, updating sysORLastChange.
+    pub fn register_capabilities(&self, id: Vec<u32>, description: &str) {
+        self.system.add_capabilities(id, description);
+    }
+
+    /// Serves IF-MIB's ifNumber, ifTable and ifXTable from the interfaces
+    /// `if_mib` reads, and lists IF-MIB in sysORTable.
+    #[cfg(feature = "if-mib")]
+    pub fn register_if_mib(&self, if_mib: IfMib) -> Result<()> {
+        if_mib::register(self, if_mib)
+    }
+
+    /// Registers many OIDs at once, taking the MIB lock a single time.
+    pub fn register_oids(&self, entries: impl IntoIterator<Item = (Vec<u32>, SnmpValue)>) {
+        self.mib.values.write().unwrap().extend(entries);
+    }
+
+    /// Removes every OID under `prefix`, returning how many there were.
+    pub fn unregister_subtree(&self, prefix: &[u32]) -> usize {
+        self.mib.values.write().unwrap().remove_subtree(prefix)
+    }
+
+    /// Processes one request and returns the response datagram, or `None`
+    /// if the request is to be dropped.
+    ///
+    /// The sender is unknown, so communities limited to a source network
+    /// never apply; see [`AgentCore::handle_datagram_from`].
+    pub fn handle_datagram(&self, data: &[u8]) -> Option<BytesMut> {
+        self.handle(data, None, None)
+    }
 
-            // Add to response
-            response_varbinds.push(varbind.clone());
+    /// Like [`AgentCore::handle_datagram`], for a request whose sender the
+    /// transport has authenticated as `security_name`.
+    ///
+    /// The security name then takes the place of the community: the
+    /// request is accepted if it is one of the agent's communities, and
+    /// the community in the message is ignored.
+    pub fn handle_secure_datagram(
+        &self,
+        data: &[u8],
+        security_name: Option<&str>,
+    ) -> Option<BytesMut> {
+        self.handle(data, None, security_name)
+    }
+
+    /// Like [`AgentCore::handle_secure_datagram`], for a request received
+    /// from `source`, which communities limited to a network are checked
+    /// against.
+    pub fn handle_datagram_from(
+        &self,
+        data: &[u8],
+        source: &TransportAddr,
+        security_name: Option<&str>,
+    ) -> Option<BytesMut> {
+        self.handle(data, source.ip(), security_name)
+    }
+
+    fn handle(
+        &self,
+        data: &[u8],
+        source: Option<IpAddr>,
+        security_name: Option<&str>,
+    ) -> Option<BytesMut> {
+        run_ready(self.process(data, source, security_name))
+    }
+
+    // Process a request; the future only suspends on asynchronous handlers
+    pub(crate) async fn process(
+        &self,
+        data: &[u8],
+        source: Option<IpAddr>,
+        security_name: Option<&str>,
+    ) -> Option<BytesMut> {
+        if data.len() > self.max_message_size {
+            println!("Dropping message: exceeds {} bytes", self.max_message_size);
+            return None;
         }
 
-        // Build and send response
-        let mut response_buf = BytesMut::new();
-        snmp::build_response_message(
-            request,
-            response_varbinds,
-            error_status,
-            error_index,
-            &mut response_buf,
-        );
+        let request = Request {
+            communities: &self.communities,
+            data,
+            source,
+            security_name,
+            max_message_size: self.max_message_size,
+        };
+        let agentx = self.agentx.read().unwrap().clone();
+        match &agentx {
+            Some(master) => process_datagram(&master.mib(&self.mib), request).await,
+            None => process_datagram(self.mib.as_ref(), request).await,
+        }
+    }
+
+    // Whether requests may be forwarded to AgentX subagents, which block
+    // until a subagent answers
+    #[cfg(feature = "tokio")]
+    pub(crate) fn is_agentx_master(&self) -> bool {
+        self.agentx.read().unwrap().is_some()
+    }
+}
+
+/// SNMP agent serving an [`AgentCore`] over a blocking [`Transport`].
+pub struct SnmpAgent {
+    transport: Box<dyn Transport>,
+    core: AgentCore,
+}
+
+impl SnmpAgent {
+    /// Creates an agent listening on UDP.
+    pub fn new(addr: &str, communities: Vec<String>) -> Result<Self> {
+        let transport = UdpTransport::bind(addr).context("Failed to bind UDP socket")?;
+        Ok(Self::with_transport(Box::new(transport), communities))
+    }
+
+    /// Creates an agent listening on a target string such as
+    /// `tcp:0.0.0.0:161` or `unix:/var/run/snmp.sock`, see [`Target`].
+    pub fn listen(target: &str, communities: Vec<String>) -> Result<Self> {
+        let target: Target = target.parse().context("Invalid listen address")?;
+        let transport = transport::listen(&target)
+            .with_context(|| format!("Failed to listen on {}", target))?;
+        Ok(Self::with_transport(transport, communities))
+    }
+
+    pub fn with_transport(transport: Box<dyn Transport>, communities: Vec<String>) -> Self {
+        Self {
+            transport,
+            core: AgentCore::new(communities),
+        }
+    }
+
+    /// See [`AgentCore::with_max_message_size`].
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.core = self.core.with_max_message_size(max_message_size);
+        self
+    }
+
+    /// See [`AgentCore::with_agentx`].
+    pub fn with_agentx(mut self, master: AgentxMaster) -> Self {
+        self.core = self.core.with_agentx(master);
+        self
+    }
+
+    /// See [`AgentCore::with_community`].
+    pub fn with_community(mut self, community: Community) -> Self {
+        self.core = self.core.with_community(community);
+        self
+    }
+
+    pub fn register_oid(&self, oid: Vec<u32>, value: SnmpValue) -> Result<()> {
+        self.core.register_oid(oid, value)
+    }
 
-        self.socket
-            .send_to(&response_buf, src_addr)
-            .context("Failed to send SNMP response")?;
+    /// See [`AgentCore::register_object`].
+    pub fn register_object(
+        &self,
+        oid: Vec<u32>,
+        value: SnmpValue,
+        object: ObjectType,
+    ) -> Result<()> {
+        self.core.register_object(oid, value, object)
+    }
+
+    /// See [`AgentCore::register_handler`].
+    pub fn register_handler(&self, prefix: Vec<u32>, handler: Arc<dyn MibHandler>) -> Result<()> {
+        self.core.register_handler(prefix, handler)
+    }
+
+    /// See [`AgentCore::register_scalar`].
+    pub fn register_scalar(
+        &self,
+        oid: Vec<u32>,
+        value: impl Fn() -> SnmpValue + Send + Sync + 'static,
+    ) -> Result<()> {
+        self.core.register_scalar(oid, value)
+    }
+
+    /// See [`AgentCore::register_table`].
+    pub fn register_table(&self, table: Table) -> Result<()> {
+        self.core.register_table(table)
+    }
+
+    /// See [`AgentCore::register_system`].
+    pub fn register_system(&self, system: SystemGroup) -> Result<()> {
+        self.core.register_system(system)
+    }
+
+    /// See [`AgentCore::register_capabilities`].
+    pub fn register_capabilities(&self, id: Vec<u32>, description: &str) {
+        self.core.register_capabilities(id, description)
+    }
+
+    /// See [`AgentCore::register_if_mib`].
+    #[cfg(feature = "if-mib")]
+    pub fn register_if_mib(&self, if_mib: IfMib) -> Result<()> {
+        self.core.register_if_mib(if_mib)
+    }
+
+    /// See [`AgentCore::register_oids`].
+    pub fn register_oids(&self, entries: impl IntoIterator<Item = (Vec<u32>, SnmpValue)>) {
+        self.core.register_oids(entries)
+    }
+
+    /// See [`AgentCore::unregister_subtree`].
+    pub fn unregister_subtree(&self, prefix: &[u32]) -> usize {
+        self.core.unregister_subtree(prefix)
+    }
+
+    // Process an SNMP message
+    fn process_message(&self, data: &[u8], src_addr: &TransportAddr) -> Result<()> {
+        let security_name = self.transport.security_name(src_addr);
+        if let Some(response_buf) =
+            self.core
+                .handle_datagram_from(data, src_addr, security_name.as_deref())
+        {
+            self.transport
+                .send_to(&response_buf, src_addr)
+                .context("Failed to send SNMP response")?;
+        }
 
         Ok(())
     }
@@ -204,21 +537,31 @@ impl SnmpAgent {
     pub fn run(&self) -> Result<()> {
         println!(
             "SNMP agent running on {}",
-            self.socket
+            self.transport
                 .local_addr()
                 .context("Failed to get local address")?
         );
 
-        let mut buf = [0u8; 4096];
+        // One spare byte so that an oversized datagram shows up as too long
+        // instead of being silently truncated
+        let mut buf = vec![0u8; self.core.max_message_size() + 1];
 
         loop {
-            match self.socket.recv_from(&mut buf) {
+            match self
+                .transport
+                .recv_from(&mut buf, Some(Duration::from_secs(5)))
+            {
                 Ok((size, src_addr)) => {
-                    if let Err(e) = self.process_message(&buf[..size], src_addr) {
+                    if let Err(e) = self.process_message(&buf[..size], &src_addr) {
                         println!("Error processing message: {}", e);
                     }
                 }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
                     // Timeout, continue
                     continue;
                 }
@@ -237,3 +580,1348 @@ impl SnmpAgent {
         thread::spawn(move || self.run())
     }
 }
+
+// The lookups request processing needs from a MIB. They are async so the
+// tokio agent can back them with async handlers; the synchronous agent's
+// implementation never suspends.
+//
+// An error fails the whole request, e.g. genErr when an AgentX subagent
+// does not answer.
+pub(crate) trait MibAccess {
+    // The value of `oid`, or noSuchObject/noSuchInstance when it is absent
+    async fn get(&self, oid: &[u32]) -> Result<SnmpValue, ErrorStatus>;
+
+    // The first varbind after `oid` in lexicographical order
+    async fn get_next(&self, oid: &[u32]) -> Result<Option<Varbind>, ErrorStatus>;
+
+    // Up to `count` consecutive varbinds after `oid` for a GetBulk
+    // repetition column; none means the end of the MIB. The default
+    // fetches one at a time.
+    async fn get_bulk(&self, oid: &[u32], count: usize) -> Result<Vec<Varbind>, ErrorStatus> {
+        let _ = count;
+        Ok(self.get_next(oid).await?.into_iter().collect())
+    }
+
+    async fn set(&self, varbind: &Varbind) -> Result<(), ErrorStatus>;
+
+    // Check the varbinds of a SetRequest without storing any of them,
+    // failing with the error and the index of the varbind that caused it.
+    // The default leaves every check to `set_all`.
+    async fn test_set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        let _ = varbinds;
+        Ok(())
+    }
+
+    // Apply the varbinds of a SetRequest, failing with the error and the
+    // index of the varbind that caused it. The default sets them in order.
+    async fn set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        for (i, varbind) in varbinds.iter().enumerate() {
+            self.set(varbind).await.map_err(|status| (status, i))?;
+        }
+        Ok(())
+    }
+}
+
+impl MibAccess for RwLock<MibStore> {
+    async fn get(&self, oid: &[u32]) -> Result<SnmpValue, ErrorStatus> {
+        let mib = self.read().unwrap();
+        Ok(match mib.get(oid) {
+            Some(value) if mib.is_readable(oid) => value.clone(),
+            Some(_) => SnmpValue::NoSuchObject,
+            None => missing_value(&mib, oid),
+        })
+    }
+
+    async fn get_next(&self, oid: &[u32]) -> Result<Option<Varbind>, ErrorStatus> {
+        Ok(self.read().unwrap().next(oid))
+    }
+
+    async fn get_bulk(&self, oid: &[u32], count: usize) -> Result<Vec<Varbind>, ErrorStatus> {
+        let mib = self.read().unwrap();
+        Ok(mib
+            .range((Bound::Excluded(oid), Bound::Unbounded))
+            .filter(|(oid, _)| mib.is_readable(oid))
+            .take(count)
+            .map(|(oid, value)| Varbind {
+                oid: oid.to_vec(),
+                value: value.clone(),
+            })
+            .collect())
+    }
+
+    async fn set(&self, varbind: &Varbind) -> Result<(), ErrorStatus> {
+        self.set_all(std::slice::from_ref(varbind))
+            .await
+            .map_err(|(status, _)| status)
+    }
+
+    async fn test_set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        let mib = self.read().unwrap();
+        for (i, varbind) in varbinds.iter().enumerate() {
+            mib::check_set(&mib, varbind).map_err(|status| (status, i))?;
+        }
+        Ok(())
+    }
+
+    async fn set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        // Check and store under one lock so no request sees half of it
+        let mut mib = self.write().unwrap();
+        for (i, varbind) in varbinds.iter().enumerate() {
+            mib::check_set(&mib, varbind).map_err(|status| (status, i))?;
+        }
+        for varbind in varbinds {
+            mib.insert(varbind.oid.clone(), varbind.value.clone());
+        }
+        Ok(())
+    }
+}
+
+// The agent's MIB: static values and the handlers serving subtrees
+#[derive(Default)]
+pub(crate) struct Mib {
+    values: RwLock<MibStore>,
+    handlers: RwLock<HandlerList>,
+}
+
+impl Mib {
+    // The handler with the longest prefix covering `oid`
+    fn handler_for(&self, oid: &[u32]) -> Option<Handler> {
+        self.handlers
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(prefix, _)| oid.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, handler)| handler.clone())
+    }
+
+    // Group the varbinds of a SetRequest by the handler serving them, so
+    // that each handler sees all of its varbinds at once
+    fn set_groups(&self, varbinds: &[Varbind]) -> Vec<SetGroup> {
+        let mut groups: Vec<SetGroup> = Vec::new();
+        for (i, varbind) in varbinds.iter().enumerate() {
+            let handler = self.handler_for(&varbind.oid);
+            let group = groups.iter_mut().find(|(h, _)| match (h, &handler) {
+                (Some(a), Some(b)) => a.same(b),
+                (None, None) => true,
+                _ => false,
+            });
+            match group {
+                Some((_, indices)) => indices.push(i),
+                None => groups.push((handler, vec![i])),
+            }
+        }
+        groups
+    }
+}
+
+impl MibAccess for Mib {
+    async fn get(&self, oid: &[u32]) -> Result<SnmpValue, ErrorStatus> {
+        if let Some(handler) = self.handler_for(oid) {
+            return Ok(handler.get(oid).await.unwrap_or(SnmpValue::NoSuchInstance));
+        }
+        MibAccess::get(&self.values, oid).await
+    }
+
+    async fn get_next(&self, oid: &[u32]) -> Result<Option<Varbind>, ErrorStatus> {
+        // Every handler whose subtree may hold a successor of `oid`
+        let handlers = self.handlers.read().unwrap().clone();
+        let prefixes: Vec<&[u32]> = handlers
+            .iter()
+            .map(|(prefix, _)| prefix.as_slice())
+            .collect();
+        let mut next = mib::next_unshadowed(&self.values.read().unwrap(), &prefixes, oid);
+
+        for (prefix, handler) in &handlers {
+            if !(oid.starts_with(prefix) || prefix.as_slice() > oid) {
+                continue;
+            }
+            let mut from = oid.to_vec();
+            while let Some(found) = handler.get_next(&from).await {
+                // Ignore answers that leave the subtree or go backwards
+                if !found.oid.starts_with(prefix) || found.oid <= from {
+                    break;
+                }
+                // Skip over parts of the subtree claimed by a nested handler
+                if self
+                    .handler_for(&found.oid)
+                    .is_some_and(|owner| !owner.same(handler))
+                {
+                    from = found.oid;
+                    continue;
+                }
+                if next.as_ref().is_none_or(|n| found.oid < n.oid) {
+                    next = Some(found);
+                }
+                break;
+            }
+        }
+        Ok(next)
+    }
+
+    async fn get_bulk(&self, oid: &[u32], count: usize) -> Result<Vec<Varbind>, ErrorStatus> {
+        if self.handlers.read().unwrap().is_empty() {
+            return self.values.get_bulk(oid, count).await;
+        }
+        let mut varbinds: Vec<Varbind> = Vec::new();
+        while varbinds.len() < count {
+            let from = varbinds.last().map_or(oid, |last| last.oid.as_slice());
+            match self.get_next(from).await? {
+                Some(varbind) => varbinds.push(varbind),
+                None => break,
+            }
+        }
+        Ok(varbinds)
+    }
+
+    async fn set(&self, varbind: &Varbind) -> Result<(), ErrorStatus> {
+        self.set_all(std::slice::from_ref(varbind))
+            .await
+            .map_err(|(status, _)| status)
+    }
+
+    async fn test_set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        for (handler, indices) in self.set_groups(varbinds) {
+            let batch: Vec<Varbind> = indices.iter().map(|&i| varbinds[i].clone()).collect();
+            let result = match handler {
+                Some(handler) => handler.test_set_all(&batch).await,
+                None => self.values.test_set_all(&batch).await,
+            };
+            result.map_err(|(status, i)| (status, indices[i]))?;
+        }
+        Ok(())
+    }
+
+    // The set phases of RFC 3416 section 4.2.5: check every varbind, then
+    // store them all, undoing the stored ones if a handler fails
+    async fn set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        self.test_set_all(varbinds).await?;
+
+        // Static values go last so they never need undoing: stored under
+        // one lock, either all of them change or none do
+        let mut groups = self.set_groups(varbinds);
+        groups.sort_by_key(|(handler, _)| handler.is_none());
+
+        let mut committed: Vec<Committed> = Vec::new();
+        for (handler, indices) in groups {
+            let mut batch: Vec<Varbind> = indices.iter().map(|&i| varbinds[i].clone()).collect();
+            let Some(handler) = handler else {
+                if let Err((status, i)) = self.values.set_all(&batch).await {
+                    return Err((undo(&committed).await.unwrap_or(status), indices[i]));
+                }
+                continue;
+            };
+
+            let mut previous = handler.previous_values(&batch).await;
+            let result = handler.set_all(&batch).await;
+            // Only the varbinds before the one that failed were stored
+            if let Err((_, i)) = result {
+                batch.truncate(i);
+                previous.truncate(i);
+            }
+            if !batch.is_empty() {
+                committed.push((handler, batch, previous));
+            }
+            if let Err((status, i)) = result {
+                // A failure before anything was stored leaves nothing
+                // changed, so it is reported as it is
+                return Err((undo(&committed).await.unwrap_or(status), indices[i]));
+            }
+        }
+        Ok(())
+    }
+}
+
+// A handler that has stored part of a SetRequest, with the varbinds and
+// the values they replaced
+type Committed = (Handler, Vec<Varbind>, Vec<Option<SnmpValue>>);
+
+// Revert the handlers in `committed`, latest first, returning the status
+// to report: commitFailed, or undoFailed if a handler could not revert
+async fn undo(committed: &[Committed]) -> Option<ErrorStatus> {
+    if committed.is_empty() {
+        return None;
+    }
+    let mut status = ErrorStatus::CommitFailed;
+    for (handler, batch, previous) in committed.iter().rev() {
+        if handler.undo_set_all(batch, previous).await.is_err() {
+            status = ErrorStatus::UndoFailed;
+        }
+    }
+    Some(status)
+}
+
+// A handler serving a single OID from a closure
+struct Scalar<F> {
+    oid: Vec<u32>,
+    value: F,
+}
+
+impl<F: Fn() -> SnmpValue + Send + Sync> MibHandler for Scalar<F> {
+    fn get(&self, oid: &[u32]) -> Option<SnmpValue> {
+        (oid == self.oid).then(|| (self.value)())
+    }
+
+    fn get_next(&self, oid: &[u32]) -> Option<Varbind> {
+        (oid < self.oid.as_slice()).then(|| Varbind {
+            oid: self.oid.clone(),
+            value: (self.value)(),
+        })
+    }
+
+    fn test_set(&self, oid: &[u32], value: &SnmpValue) -> Result<(), ErrorStatus> {
+        let _ = (oid, value);
+        Err(ErrorStatus::NotWritable)
+    }
+}
+
+// Drive a future that is known to complete without waiting, as all
+// futures of the synchronous agent do
+fn run_ready<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    match future
+        .as_mut()
+        .poll(&mut TaskContext::from_waker(Waker::noop()))
+    {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("synchronous MIB access never suspends"),
+    }
+}
+
+// A received datagram, with what decides whether it is accepted
+pub(crate) struct Request<'a> {
+    pub(crate) communities: &'a [Community],
+    pub(crate) data: &'a [u8],
+    pub(crate) source: Option<IpAddr>,
+    pub(crate) security_name: Option<&'a str>,
+    pub(crate) max_message_size: usize,
+}
+
+// Turn a received datagram into the response datagram, if any
+pub(crate) async fn process_datagram<M: MibAccess>(
+    mib: &M,
+    request: Request<'_>,
+) -> Option<BytesMut> {
+    // Decode the message
+    let message = match snmp::decode_snmp_message(request.data) {
+        Ok(msg) => msg,
+        Err(e) => {
+            println!("Error decoding message: {}", e);
+            return None;
+        }
+    };
+
+    // Check community string, or the security name established by the transport
+    let community_str = String::from_utf8_lossy(&message.community);
+    let name = request.security_name.unwrap_or(&community_str);
+    let Some(community) = request
+        .communities
+        .iter()
+        .find(|c| c.matches(name, request.source))
+    else {
+        println!("Invalid community string: {}", name);
+        return None;
+    };
+    let mib = &Scoped { mib, community };
+    let max_message_size = request.max_message_size;
+
+    // Process PDU based on type
+    let (response_varbinds, error_status, error_index) = match message.pdu.pdu_type {
+        PduType::GET_REQUEST => handle_get_request(mib, &message).await,
+        PduType::GET_NEXT_REQUEST => handle_get_next_request(mib, &message).await,
+        PduType::SET_REQUEST => handle_set_request(mib, &message).await,
+        PduType::GET_BULK_REQUEST if message.version != snmp::SNMP_VERSION_1 as i32 => {
+            handle_get_bulk_request(mib, &message, max_message_size).await
+        }
+        _ => {
+            println!("Unsupported PDU type");
+            return None;
+        }
+    };
+
+    build_response(
+        &message,
+        response_varbinds,
+        error_status,
+        error_index,
+        max_message_size,
+    )
+}
+
+// The MIB as a request's community sees it: only the community's view,
+// and read-only unless the community may write
+struct Scoped<'a, M> {
+    mib: &'a M,
+    community: &'a Community,
+}
+
+impl<M> Scoped<'_, M> {
+    fn in_view(&self, oid: &[u32]) -> bool {
+        self.community
+            .view
+            .as_ref()
+            .is_none_or(|view| oid.starts_with(view))
+    }
+
+    // RFC 3416 section 4.2.5: noAccess for a varbind the community may
+    // not write, being read-only or outside its view
+    fn check_writable(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        if self.community.role == Role::ReadOnly && !varbinds.is_empty() {
+            return Err((ErrorStatus::NoAccess, 0));
+        }
+        match varbinds.iter().position(|v| !self.in_view(&v.oid)) {
+            Some(i) => Err((ErrorStatus::NoAccess, i)),
+            None => Ok(()),
+        }
+    }
+
+    // Where a walk from `oid` continues inside the view, `None` if the
+    // view lies entirely before it
+    fn walk_from<'o>(&'o self, oid: &'o [u32]) -> Option<&'o [u32]> {
+        match &self.community.view {
+            Some(view) if oid < view.as_slice() => Some(view),
+            Some(_) if !self.in_view(oid) => None,
+            _ => Some(oid),
+        }
+    }
+
+    // The view's root, if it is an instance itself, for a walk from `oid`
+    // that starts before the view, which `walk_from` skips past.
+    // A root the MIB fails to read is left out like a missing one
+    async fn view_root(&self, oid: &[u32]) -> Option<Varbind>
+    where
+        M: MibAccess,
+    {
+        let view = self
+            .community
+            .view
+            .as_ref()
+            .filter(|view| oid < view.as_slice())?;
+        match self.mib.get(view).await {
+            Ok(SnmpValue::NoSuchObject | SnmpValue::NoSuchInstance | SnmpValue::EndOfMibView)
+            | Err(_) => None,
+            Ok(value) => Some(Varbind {
+                oid: view.clone(),
+                value,
+            }),
+        }
+    }
+}
+
+impl<M: MibAccess> MibAccess for Scoped<'_, M> {
+    async fn get(&self, oid: &[u32]) -> Result<SnmpValue, ErrorStatus> {
+        if !self.in_view(oid) {
+            return Ok(SnmpValue::NoSuchObject);
+        }
+        self.mib.get(oid).await
+    }
+
+    async fn get_next(&self, oid: &[u32]) -> Result<Option<Varbind>, ErrorStatus> {
+        if let Some(root) = self.view_root(oid).await {
+            return Ok(Some(root));
+        }
+        let Some(from) = self.walk_from(oid) else {
+            return Ok(None);
+        };
+        Ok(self
+            .mib
+            .get_next(from)
+            .await?
+            .filter(|next| self.in_view(&next.oid)))
+    }
+
+    async fn get_bulk(&self, oid: &[u32], count: usize) -> Result<Vec<Varbind>, ErrorStatus> {
+        let root = match self.view_root(oid).await.filter(|_| count > 0) {
+            Some(root) if count == 1 => return Ok(vec![root]),
+            root => root,
+        };
+        let Some(from) = self.walk_from(oid) else {
+            return Ok(Vec::new());
+        };
+        let count = count - usize::from(root.is_some());
+        let mut varbinds = self.mib.get_bulk(from, count).await?;
+        varbinds.truncate(varbinds.partition_point(|next| self.in_view(&next.oid)));
+        varbinds.splice(0..0, root);
+        Ok(varbinds)
+    }
+
+    async fn set(&self, varbind: &Varbind) -> Result<(), ErrorStatus> {
+        self.set_all(std::slice::from_ref(varbind))
+            .await
+            .map_err(|(status, _)| status)
+    }
+
+    async fn test_set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        self.check_writable(varbinds)?;
+        self.mib.test_set_all(varbinds).await
+    }
+
+    async fn set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        self.check_writable(varbinds)?;
+        self.mib.set_all(varbinds).await
+    }
+}
+
+// Handle a GetRequest
+async fn handle_get_request<M: MibAccess>(
+    mib: &M,
+    request: &SnmpMessage,
+) -> (Vec<Varbind>, ErrorStatus, i32) {
+    let is_v1 = request.version == snmp::SNMP_VERSION_1 as i32;
+    let mut response_varbinds = Vec::new();
+
+    // Process each varbind in the request
+    for (i, varbind) in request.pdu.varbinds.iter().enumerate() {
+        let value = match mib.get(&varbind.oid).await {
+            Ok(value) => value,
+            Err(status) => return (Vec::new(), status, (i + 1) as i32),
+        };
+        let missing = matches!(value, SnmpValue::NoSuchObject | SnmpValue::NoSuchInstance);
+
+        // SNMPv1 cannot carry a Counter64, it is reported as not found
+        // (RFC 3584 section 4.2.2.1)
+        if is_v1 && (missing || matches!(value, SnmpValue::Counter64(_))) {
+            // OID not found, the whole request fails
+            return (Vec::new(), ErrorStatus::NoSuchName, (i + 1) as i32);
+        }
+
+        // SNMPv2c reports a missing OID per varbind with an exception value
+        response_varbinds.push(Varbind {
+            oid: varbind.oid.clone(),
+            value,
+        });
+    }
+
+    (response_varbinds, ErrorStatus::NoError, 0)
+}
+
+// Handle a GetNextRequest
+async fn handle_get_next_request<M: MibAccess>(
+    mib: &M,
+    request: &SnmpMessage,
+) -> (Vec<Varbind>, ErrorStatus, i32) {
+    let is_v1 = request.version == snmp::SNMP_VERSION_1 as i32;
+    let mut response_varbinds = Vec::new();
+
+    // Process each varbind in the request
+    for (i, varbind) in request.pdu.varbinds.iter().enumerate() {
+        // SNMPv1 skips Counter64 objects (RFC 3584 section 4.2.2.1)
+        let mut from = varbind.oid.clone();
+        let next = loop {
+            match mib.get_next(&from).await {
+                Ok(Some(next)) if is_v1 && matches!(next.value, SnmpValue::Counter64(_)) => {
+                    from = next.oid
+                }
+                Ok(next) => break next,
+                Err(status) => return (Vec::new(), status, (i + 1) as i32),
+            }
+        };
+        if let Some(next) = next {
+            // Next OID found, add to response
+            response_varbinds.push(next);
+        } else if is_v1 {
+            // No next OID, the whole request fails
+            return (Vec::new(), ErrorStatus::NoSuchName, (i + 1) as i32);
+        } else {
+            response_varbinds.push(Varbind {
+                oid: varbind.oid.clone(),
+                value: SnmpValue::EndOfMibView,
+            });
+        }
+    }
+
+    (response_varbinds, ErrorStatus::NoError, 0)
+}
+
+// The smallest encoded varbind: a one sub-identifier OID with a NULL value
+const MIN_VARBIND_LEN: usize = 7;
+
+// Handle a GetBulkRequest (SNMPv2c only, RFC 3416 section 4.2.3)
+async fn handle_get_bulk_request<M: MibAccess>(
+    mib: &M,
+    request: &SnmpMessage,
+    max_message_size: usize,
+) -> (Vec<Varbind>, ErrorStatus, i32) {
+    let varbinds = &request.pdu.varbinds;
+    let non_repeaters = (request.pdu.non_repeaters.max(0) as usize).min(varbinds.len());
+    let repeaters = varbinds.len() - non_repeaters;
+    // No more repetitions than fit the response, each adds a varbind per
+    // repeater
+    let max_repetitions = (request.pdu.max_repetitions.max(0) as usize)
+        .min(max_message_size / (MIN_VARBIND_LEN * repeaters.max(1)));
+    let mut response_varbinds = Vec::new();
+
+    for (i, varbind) in varbinds[..non_repeaters].iter().enumerate() {
+        match next_or_end(mib, &varbind.oid).await {
+            Ok(next) => response_varbinds.push(next),
+            Err(status) => return (Vec::new(), status, (i + 1) as i32),
+        }
+    }
+
+    // Stop generating repetitions once the varbinds no longer fit,
+    // build_response trims what the message header adds
+    let mut size: usize = response_varbinds
+        .iter()
+        .map(snmp::encoded_varbind_len)
+        .sum();
+    let mut last: Vec<Vec<u32>> = varbinds[non_repeaters..]
+        .iter()
+        .map(|v| v.oid.clone())
+        .collect();
+    // Varbinds fetched ahead for each repeater, as a MIB may return a whole
+    // column at once
+    let mut columns: Vec<VecDeque<Varbind>> = vec![VecDeque::new(); last.len()];
+
+    for repetition in 0..max_repetitions {
+        if last.is_empty() {
+            break;
+        }
+
+        let mut all_ended = true;
+        for (i, (oid, column)) in last.iter_mut().zip(columns.iter_mut()).enumerate() {
+            if column.is_empty() {
+                // Only as many as the space left may hold
+                let count = (max_repetitions - repetition)
+                    .min(max_message_size.saturating_sub(size) / (MIN_VARBIND_LEN * repeaters));
+                match mib.get_bulk(oid, count.max(1)).await {
+                    Ok(fetched) => column.extend(fetched),
+                    Err(status) => return (Vec::new(), status, (non_repeaters + i + 1) as i32),
+                }
+            }
+            let next = column.pop_front().unwrap_or_else(|| Varbind {
+                oid: oid.clone(),
+                value: SnmpValue::EndOfMibView,
+            });
+            if !matches!(next.value, SnmpValue::EndOfMibView) {
+                all_ended = false;
+            }
+            size += snmp::encoded_varbind_len(&next);
+            if size > max_message_size {
+                return (response_varbinds, ErrorStatus::NoError, 0);
+            }
+            *oid = next.oid.clone();
+            response_varbinds.push(next);
+        }
+
+        if all_ended {
+            break;
+        }
+    }
+
+    (response_varbinds, ErrorStatus::NoError, 0)
+}
+
+// Handle a SetRequest
+async fn handle_set_request<M: MibAccess>(
+    mib: &M,
+    request: &SnmpMessage,
+) -> (Vec<Varbind>, ErrorStatus, i32) {
+    // Update the MIB
+    if let Err((status, i)) = mib.set_all(&request.pdu.varbinds).await {
+        return (Vec::new(), status, (i + 1) as i32);
+    }
+
+    (request.pdu.varbinds.clone(), ErrorStatus::NoError, 0)
+}
+
+async fn next_or_end<M: MibAccess>(mib: &M, oid: &[u32]) -> Result<Varbind, ErrorStatus> {
+    Ok(mib.get_next(oid).await?.unwrap_or_else(|| Varbind {
+        oid: oid.to_vec(),
+        value: SnmpValue::EndOfMibView,
+    }))
+}
+
+// Build the response to a request.
+//
+// As required by RFC 1157 and RFC 3416, an error response carries the
+// request varbinds unchanged instead of the partially processed ones.
+fn build_response(
+    request: &SnmpMessage,
+    response_varbinds: Vec<Varbind>,
+    error_status: ErrorStatus,
+    error_index: i32,
+    max_message_size: usize,
+) -> Option<BytesMut> {
+    let mut response_varbinds = if error_status == ErrorStatus::NoError {
+        response_varbinds
+    } else {
+        request.pdu.varbinds.clone()
+    };
+
+    let mut response_buf = BytesMut::new();
+    snmp::build_response_message(
+        request,
+        response_varbinds.clone(),
+        error_status,
+        error_index,
+        &mut response_buf,
+    );
+
+    // A GetBulk response is cut down to the repetitions that fit, the
+    // non-repeaters must always be present
+    if let PduType::GET_BULK_REQUEST = request.pdu.pdu_type {
+        let keep = request.pdu.non_repeaters.max(0) as usize;
+        while response_buf.len() > max_message_size && response_varbinds.len() > keep {
+            let mut excess = response_buf.len() - max_message_size;
+            while excess > 0 && response_varbinds.len() > keep {
+                let removed = response_varbinds.pop().unwrap();
+                excess = excess.saturating_sub(snmp::encoded_varbind_len(&removed));
+            }
+
+            response_buf.clear();
+            snmp::build_response_message(
+                request,
+                response_varbinds.clone(),
+                error_status,
+                error_index,
+                &mut response_buf,
+            );
+        }
+    }
+
+    if response_buf.len() > max_message_size {
+        // SNMPv1 answers tooBig with the request varbinds, SNMPv2c with
+        // an empty varbind list
+        let too_big_varbinds = if request.version == snmp::SNMP_VERSION_1 as i32 {
+            request.pdu.varbinds.clone()
+        } else {
+            Vec::new()
+        };
+
+        response_buf.clear();
+        snmp::build_response_message(
+            request,
+            too_big_varbinds,
+            ErrorStatus::TooBig,
+            0,
+            &mut response_buf,
+        );
+
+        if response_buf.len() > max_message_size {
+            println!("Dropping response: tooBig response does not fit");
+            return None;
+        }
+    }
+
+    Some(response_buf)
+}
+
+// Pick the SNMPv2 exception for an OID that is not in the MIB: noSuchInstance
+// when the object itself exists (some sibling instance is registered),
+// noSuchObject otherwise.
+fn missing_value(mib: &MibStore, oid: &[u32]) -> SnmpValue {
+    let object = &oid[..oid.len().saturating_sub(1)];
+    if !object.is_empty() && mib.subtree(object).any(|(k, _)| k.len() == oid.len()) {
+        SnmpValue::NoSuchInstance
+    } else {
+        SnmpValue::NoSuchObject
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client;
+    use crate::mib::{Access, Syntax, ValueType};
+    use crate::snmp::SnmpPdu;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // An endless column, counting the lookups made
+    #[derive(Default)]
+    struct Endless {
+        lookups: AtomicUsize,
+    }
+
+    impl MibHandler for Endless {
+        fn get(&self, _oid: &[u32]) -> Option<SnmpValue> {
+            None
+        }
+
+        fn get_next(&self, oid: &[u32]) -> Option<Varbind> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            let index = match oid {
+                [1, 3, 6, 1, 4, 1, 99, index, ..] => index + 1,
+                _ => 1,
+            };
+            Some(Varbind {
+                oid: vec![1, 3, 6, 1, 4, 1, 99, index],
+                value: SnmpValue::Integer(0),
+            })
+        }
+    }
+
+    // Stores any value but -1, except at the OID ending in `refused`, and
+    // records what it stores and undoes
+    #[derive(Default)]
+    struct Recorder {
+        refused: u32,
+        stored: Mutex<Vec<Vec<u32>>>,
+        undone: Mutex<Vec<Vec<u32>>>,
+    }
+
+    impl MibHandler for Recorder {
+        fn get(&self, _oid: &[u32]) -> Option<SnmpValue> {
+            Some(SnmpValue::Integer(0))
+        }
+
+        fn get_next(&self, _oid: &[u32]) -> Option<Varbind> {
+            None
+        }
+
+        fn test_set(&self, _oid: &[u32], value: &SnmpValue) -> Result<(), ErrorStatus> {
+            match value {
+                SnmpValue::Integer(-1) => Err(ErrorStatus::WrongValue),
+                _ => Ok(()),
+            }
+        }
+
+        fn set(&self, oid: &[u32], _value: &SnmpValue) -> Result<(), ErrorStatus> {
+            if oid.last() == Some(&self.refused) {
+                return Err(ErrorStatus::ResourceUnavailable);
+            }
+            self.stored.lock().unwrap().push(oid.to_vec());
+            Ok(())
+        }
+
+        fn undo_set_all(
+            &self,
+            varbinds: &[Varbind],
+            previous: &[Option<SnmpValue>],
+        ) -> Result<(), ErrorStatus> {
+            assert_eq!(varbinds.len(), previous.len());
+            let mut undone = self.undone.lock().unwrap();
+            undone.extend(varbinds.iter().map(|varbind| varbind.oid.clone()));
+            Ok(())
+        }
+    }
+
+    const FIRST: [u32; 7] = [1, 3, 6, 1, 4, 1, 97];
+    const SECOND: [u32; 7] = [1, 3, 6, 1, 4, 1, 98];
+
+    // Recorders at FIRST and SECOND, the second refusing to store
+    // SECOND.3, and a static value at 1.3.6.1.4.1.99.0
+    fn recorders() -> (AgentCore, Arc<Recorder>, Arc<Recorder>) {
+        let core = AgentCore::new(vec!["public".to_string()]);
+        let first = Arc::new(Recorder::default());
+        let second = Arc::new(Recorder {
+            refused: 3,
+            ..Default::default()
+        });
+        core.register_handler(FIRST.to_vec(), first.clone())
+            .unwrap();
+        core.register_handler(SECOND.to_vec(), second.clone())
+            .unwrap();
+        core.register_oid(vec![1, 3, 6, 1, 4, 1, 99, 0], SnmpValue::Integer(5))
+            .unwrap();
+        (core, first, second)
+    }
+
+    fn request(community: &str, pdu_type: PduType, varbinds: &[Varbind]) -> Vec<u8> {
+        client::encode_request(
+            snmp::SNMP_VERSION_2C,
+            community,
+            1,
+            pdu_type,
+            varbinds,
+            None,
+        )
+        .to_vec()
+    }
+
+    fn set_request(varbinds: &[(&[u32], SnmpValue)]) -> Vec<u8> {
+        let varbinds: Vec<Varbind> = varbinds
+            .iter()
+            .map(|(oid, value)| Varbind {
+                oid: oid.to_vec(),
+                value: value.clone(),
+            })
+            .collect();
+        request("public", PduType::SET_REQUEST, &varbinds)
+    }
+
+    fn cell(prefix: &[u32], index: u32) -> Vec<u32> {
+        [prefix, &[index]].concat()
+    }
+
+    fn bulk_request(max_repetitions: i32, oids: &[&[u32]]) -> Vec<u8> {
+        let mut varbinds = BytesMut::new();
+        snmp::build_varbind_list(oids, &mut varbinds);
+        let mut pdu = BytesMut::new();
+        snmp::build_bulk_pdu(1, 0, max_repetitions, &varbinds, &mut pdu);
+        message(&pdu)
+    }
+
+    fn message(pdu: &[u8]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        snmp::build_snmp_msg(snmp::SNMP_VERSION_2C, "public", pdu, &mut buf);
+        buf.to_vec()
+    }
+
+    fn respond(core: &AgentCore, request: &[u8]) -> SnmpPdu {
+        let response = core.handle_datagram(request).unwrap();
+        snmp::decode_snmp_message(&response).unwrap().pdu
+    }
+
+    #[test]
+    fn answers_with_the_request_id_and_drops_what_it_cannot_answer() {
+        let core = AgentCore::new(vec!["public".to_string()]).with_max_message_size(484);
+        core.register_oid(
+            vec![1, 3, 6, 1, 2, 1, 1, 5, 0],
+            SnmpValue::OctetString(b"a".to_vec()),
+        )
+        .unwrap();
+        let get = |community, request_id| {
+            client::encode_request(
+                snmp::SNMP_VERSION_2C,
+                community,
+                request_id,
+                PduType::GET_REQUEST,
+                &client::null_varbinds(&[&[1, 3, 6, 1, 2, 1, 1, 5, 0]]),
+                None,
+            )
+        };
+
+        let pdu = respond(&core, &get("public", 0x1234_5678));
+        assert_eq!(pdu.request_id, 0x1234_5678);
+        assert_eq!(pdu.error_status, ErrorStatus::NoError);
+        let pdu = respond(&core, &get("public", -7));
+        assert_eq!(pdu.request_id, -7);
+
+        // Unknown community, garbage, and a message larger than the agent accepts
+        assert!(core.handle_datagram(&get("private", 1)).is_none());
+        assert!(core.handle_datagram(&[0x30, 0x03, 0x02, 0x01]).is_none());
+        assert!(core.handle_datagram(&[0; 485]).is_none());
+    }
+
+    #[test]
+    fn get_bulk_stops_at_message_size() {
+        let core = AgentCore::new(vec!["public".to_string()]).with_max_message_size(1500);
+        let handler = Arc::new(Endless::default());
+        core.register_handler(vec![1, 3, 6, 1, 4, 1, 99], handler.clone())
+            .unwrap();
+
+        let response = respond(&core, &bulk_request(i32::MAX, &[&[1, 3, 6, 1, 4, 1, 99]]));
+        assert_eq!(response.error_status, ErrorStatus::NoError);
+        assert!(!response.varbinds.is_empty());
+        let lookups = handler.lookups.load(Ordering::Relaxed);
+        assert!(lookups <= 1500 / MIN_VARBIND_LEN, "{} lookups", lookups);
+    }
+
+    #[test]
+    fn v1_does_not_see_counter64_objects() {
+        let core = AgentCore::new(vec!["public".to_string()]);
+        for (oid, value) in [
+            (vec![1, 3, 6, 1, 4, 1, 99, 1, 1], SnmpValue::Integer(1)),
+            (
+                vec![1, 3, 6, 1, 4, 1, 99, 2, 1],
+                SnmpValue::Counter64(1 << 40),
+            ),
+            (vec![1, 3, 6, 1, 4, 1, 99, 2, 2], SnmpValue::Counter64(2)),
+            (vec![1, 3, 6, 1, 4, 1, 99, 3, 1], SnmpValue::Integer(3)),
+        ] {
+            core.register_oid(oid, value).unwrap();
+        }
+        let v1_request = |pdu_type, oid: &[u32]| {
+            client::encode_request(
+                snmp::SNMP_VERSION_1,
+                "public",
+                1,
+                pdu_type,
+                &client::null_varbinds(&[oid]),
+                None,
+            )
+        };
+
+        // GetNext walks past the Counter64 column
+        let response = respond(
+            &core,
+            &v1_request(PduType::GET_NEXT_REQUEST, &[1, 3, 6, 1, 4, 1, 99, 1, 1]),
+        );
+        assert_eq!(response.error_status, ErrorStatus::NoError);
+        assert_eq!(response.varbinds[0].oid, [1, 3, 6, 1, 4, 1, 99, 3, 1]);
+        let response = respond(
+            &core,
+            &v1_request(PduType::GET_NEXT_REQUEST, &[1, 3, 6, 1, 4, 1, 99, 3, 1]),
+        );
+        assert_eq!(response.error_status, ErrorStatus::NoSuchName);
+
+        let response = respond(
+            &core,
+            &v1_request(PduType::GET_REQUEST, &[1, 3, 6, 1, 4, 1, 99, 2, 1]),
+        );
+        assert_eq!(response.error_status, ErrorStatus::NoSuchName);
+        assert_eq!(response.error_index, 1);
+
+        // SNMPv2c sees the column
+        let response = respond(
+            &core,
+            &request(
+                "public",
+                PduType::GET_NEXT_REQUEST,
+                &client::null_varbinds(&[&[1, 3, 6, 1, 4, 1, 99, 1, 1]]),
+            ),
+        );
+        assert!(matches!(
+            response.varbinds[0].value,
+            SnmpValue::Counter64(v) if v == 1 << 40
+        ));
+    }
+
+    #[test]
+    fn handler_shadows_static_values() {
+        let core = AgentCore::new(vec!["public".to_string()]);
+        core.register_oids([
+            (vec![1, 3, 6, 1, 4, 1, 98, 0], SnmpValue::Integer(1)),
+            (vec![1, 3, 6, 1, 4, 1, 99, 1], SnmpValue::Integer(2)),
+            (vec![1, 3, 6, 1, 4, 1, 100, 0], SnmpValue::Integer(3)),
+        ]);
+        core.register_handler(vec![1, 3, 6, 1, 4, 1, 99], Arc::new(Endless::default()))
+            .unwrap();
+
+        let next = |oid: &[u32]| run_ready(core.mib.get_next(oid)).unwrap().unwrap();
+        let first = next(&[1, 3, 6, 1, 4, 1, 98, 0]);
+        assert_eq!(first.oid, [1, 3, 6, 1, 4, 1, 99, 1]);
+        assert!(matches!(first.value, SnmpValue::Integer(0)));
+        assert!(matches!(
+            run_ready(core.mib.get(&[1, 3, 6, 1, 4, 1, 99, 1])),
+            Ok(SnmpValue::NoSuchInstance)
+        ));
+    }
+
+    #[test]
+    fn failed_set_undoes_only_what_was_stored() {
+        let (core, first, second) = recorders();
+
+        let response = respond(
+            &core,
+            &set_request(&[
+                (&cell(&FIRST, 1), SnmpValue::Integer(1)),
+                (&cell(&SECOND, 1), SnmpValue::Integer(1)),
+                (&cell(&SECOND, 2), SnmpValue::Integer(1)),
+                (&cell(&SECOND, 3), SnmpValue::Integer(1)),
+                (&cell(&SECOND, 4), SnmpValue::Integer(1)),
+                (&[1, 3, 6, 1, 4, 1, 99, 0], SnmpValue::Integer(6)),
+            ]),
+        );
+        assert_eq!(response.error_status, ErrorStatus::CommitFailed);
+        assert_eq!(response.error_index, 4);
+
+        assert_eq!(*first.undone.lock().unwrap(), [cell(&FIRST, 1)]);
+        assert_eq!(
+            *second.stored.lock().unwrap(),
+            [cell(&SECOND, 1), cell(&SECOND, 2)]
+        );
+        assert_eq!(
+            *second.undone.lock().unwrap(),
+            [cell(&SECOND, 1), cell(&SECOND, 2)]
+        );
+        assert!(matches!(
+            run_ready(core.mib.get(&[1, 3, 6, 1, 4, 1, 99, 0])),
+            Ok(SnmpValue::Integer(5))
+        ));
+    }
+
+    #[test]
+    fn failure_before_anything_is_stored_is_reported_as_it_is() {
+        let (core, first, second) = recorders();
+
+        let response = respond(
+            &core,
+            &set_request(&[
+                (&cell(&SECOND, 3), SnmpValue::Integer(1)),
+                (&cell(&FIRST, 1), SnmpValue::Integer(1)),
+            ]),
+        );
+        assert_eq!(response.error_status, ErrorStatus::ResourceUnavailable);
+        assert_eq!(response.error_index, 1);
+        assert!(first.stored.lock().unwrap().is_empty());
+        assert!(first.undone.lock().unwrap().is_empty());
+        assert!(second.undone.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn set_is_tested_before_anything_is_stored() {
+        let (core, first, second) = recorders();
+
+        let response = respond(
+            &core,
+            &set_request(&[
+                (&cell(&FIRST, 1), SnmpValue::Integer(1)),
+                (&[1, 3, 6, 1, 4, 1, 99, 0], SnmpValue::Integer(6)),
+                (&cell(&SECOND, 1), SnmpValue::Integer(-1)),
+            ]),
+        );
+        assert_eq!(response.error_status, ErrorStatus::WrongValue);
+        assert_eq!(response.error_index, 3);
+        assert!(first.stored.lock().unwrap().is_empty());
+        assert!(second.stored.lock().unwrap().is_empty());
+        assert!(first.undone.lock().unwrap().is_empty());
+        assert!(matches!(
+            run_ready(core.mib.get(&[1, 3, 6, 1, 4, 1, 99, 0])),
+            Ok(SnmpValue::Integer(5))
+        ));
+    }
+
+    const OBJECTS: [u32; 7] = [1, 3, 6, 1, 4, 1, 90];
+
+    fn object(id: u32) -> Vec<u32> {
+        [&OBJECTS[..], &[id, 0]].concat()
+    }
+
+    // A name of up to 8 octets, an up(1)/down(2) status and a percentage,
+    // all read-write, a read-only and a not-accessible object, and one
+    // registered without a definition
+    fn objects() -> AgentCore {
+        let core = AgentCore::new(vec!["public".to_string()]);
+        let objects = [
+            (
+                SnmpValue::OctetString(b"edge".to_vec()),
+                ObjectType::new(
+                    Syntax::new(ValueType::OctetString).with_size(0, 8),
+                    Access::ReadWrite,
+                ),
+            ),
+            (
+                SnmpValue::Integer(1),
+                ObjectType::new(
+                    Syntax::new(ValueType::Integer)
+                        .with_enum(1, "up")
+                        .with_enum(2, "down"),
+                    Access::ReadWrite,
+                ),
+            ),
+            (
+                SnmpValue::Integer(50),
+                ObjectType::new(
+                    Syntax::new(ValueType::Integer).with_range(0, 100),
+                    Access::ReadWrite,
+                ),
+            ),
+            (
+                SnmpValue::Integer(4),
+                ObjectType::new(ValueType::Integer, Access::ReadOnly),
+            ),
+            (
+                SnmpValue::Integer(5),
+                ObjectType::new(ValueType::Integer, Access::NotAccessible),
+            ),
+        ];
+        for (id, (value, object_type)) in (1..).zip(objects) {
+            core.register_object(object(id), value, object_type)
+                .unwrap();
+        }
+        core.register_oid(object(6), SnmpValue::Integer(6)).unwrap();
+        core
+    }
+
+    #[test]
+    fn set_enforces_access_and_syntax() {
+        let core = objects();
+        let name = |len| SnmpValue::OctetString(vec![b'a'; len]);
+
+        for (id, value, status) in [
+            (7, SnmpValue::Integer(1), ErrorStatus::NoCreation),
+            (4, SnmpValue::Integer(1), ErrorStatus::NotWritable),
+            (5, SnmpValue::Integer(1), ErrorStatus::NoAccess),
+            (1, SnmpValue::Integer(1), ErrorStatus::WrongType),
+            (6, name(1), ErrorStatus::WrongType),
+            (1, name(9), ErrorStatus::WrongLength),
+            (2, SnmpValue::Integer(3), ErrorStatus::WrongValue),
+            (3, SnmpValue::Integer(101), ErrorStatus::WrongValue),
+            (3, SnmpValue::Integer(-1), ErrorStatus::WrongValue),
+        ] {
+            // The valid varbind before it is not stored either
+            let response = respond(
+                &core,
+                &set_request(&[(&object(2), SnmpValue::Integer(2)), (&object(id), value)]),
+            );
+            assert_eq!(response.error_status, status, "object {}", id);
+            assert_eq!(response.error_index, 2);
+            assert!(matches!(
+                run_ready(core.mib.get(&object(2))),
+                Ok(SnmpValue::Integer(1))
+            ));
+        }
+
+        let response = respond(
+            &core,
+            &set_request(&[
+                (&object(1), name(8)),
+                (&object(2), SnmpValue::Integer(2)),
+                (&object(3), SnmpValue::Integer(100)),
+                (&object(6), SnmpValue::Integer(-6)),
+            ]),
+        );
+        assert_eq!(response.error_status, ErrorStatus::NoError);
+        assert!(matches!(
+            run_ready(core.mib.get(&object(1))),
+            Ok(SnmpValue::OctetString(name)) if name.len() == 8
+        ));
+        assert!(matches!(
+            run_ready(core.mib.get(&object(3))),
+            Ok(SnmpValue::Integer(100))
+        ));
+    }
+
+    #[test]
+    fn registered_value_must_fit_its_syntax() {
+        let core = objects();
+        let percentage = ObjectType::new(
+            Syntax::new(ValueType::Integer).with_range(0, 100),
+            Access::ReadWrite,
+        );
+        assert!(
+            core.register_object(object(8), SnmpValue::Integer(101), percentage.clone())
+                .is_err()
+        );
+        assert!(
+            core.register_object(object(8), SnmpValue::Gauge32(1), percentage)
+                .is_err()
+        );
+        assert!(matches!(
+            run_ready(core.mib.get(&object(8))),
+            Ok(SnmpValue::NoSuchObject)
+        ));
+    }
+
+    // system is open to "sys" and sysName to "name", both read-only;
+    // everything to "public"
+    fn scoped_agent() -> AgentCore {
+        let core = AgentCore::new(vec!["public".to_string()])
+            .with_community(Community::read_only("sys").with_view(vec![1, 3, 6, 1, 2, 1, 1]))
+            .with_community(
+                Community::read_only("name").with_view(vec![1, 3, 6, 1, 2, 1, 1, 5, 0]),
+            );
+        core.register_oids([
+            (
+                vec![1, 3, 6, 1, 2, 1, 1, 5, 0],
+                SnmpValue::OctetString(b"edge".to_vec()),
+            ),
+            (
+                vec![1, 3, 6, 1, 2, 1, 1, 6, 0],
+                SnmpValue::OctetString(b"rack 4".to_vec()),
+            ),
+            (vec![1, 3, 6, 1, 2, 1, 2, 1, 0], SnmpValue::Integer(3)),
+            (vec![1, 3, 6, 1, 4, 1, 1, 0], SnmpValue::Integer(1)),
+        ]);
+        core
+    }
+
+    fn oids(response: &SnmpPdu) -> Vec<&[u32]> {
+        response.varbinds.iter().map(|v| v.oid.as_slice()).collect()
+    }
+
+    #[test]
+    fn read_only_community_cannot_set() {
+        let core = scoped_agent();
+        let name = SnmpValue::OctetString(b"core".to_vec());
+        let varbinds = [Varbind {
+            oid: vec![1, 3, 6, 1, 2, 1, 1, 5, 0],
+            value: name,
+        }];
+
+        let response = respond(&core, &request("sys", PduType::SET_REQUEST, &varbinds));
+        assert_eq!(response.error_status, ErrorStatus::NoAccess);
+        assert_eq!(response.error_index, 1);
+        assert!(matches!(
+            run_ready(core.mib.get(&[1, 3, 6, 1, 2, 1, 1, 5, 0])),
+            Ok(SnmpValue::OctetString(name)) if name == b"edge"
+        ));
+
+        let response = respond(&core, &request("public", PduType::SET_REQUEST, &varbinds));
+        assert_eq!(response.error_status, ErrorStatus::NoError);
+    }
+
+    #[test]
+    fn view_hides_the_rest_of_the_mib() {
+        let core = scoped_agent();
+        let next = |community, oid: &[u32]| {
+            let varbinds = client::null_varbinds(&[oid]);
+            respond(
+                &core,
+                &request(community, PduType::GET_NEXT_REQUEST, &varbinds),
+            )
+        };
+
+        // A walk from the start enters the view and ends at its end
+        let response = next("sys", &[1, 3]);
+        assert_eq!(oids(&response), [&[1, 3, 6, 1, 2, 1, 1, 5, 0][..]]);
+        let response = next("sys", &[1, 3, 6, 1, 2, 1, 1, 6, 0]);
+        assert!(matches!(
+            response.varbinds[0].value,
+            SnmpValue::EndOfMibView
+        ));
+        let response = next("sys", &[1, 3, 6, 1, 2, 1, 2]);
+        assert!(matches!(
+            response.varbinds[0].value,
+            SnmpValue::EndOfMibView
+        ));
+        let response = next("public", &[1, 3, 6, 1, 2, 1, 1, 6, 0]);
+        assert_eq!(oids(&response), [&[1, 3, 6, 1, 2, 1, 2, 1, 0][..]]);
+
+        // A view that is a single instance still shows it
+        let response = next("name", &[1, 3]);
+        assert_eq!(oids(&response), [&[1, 3, 6, 1, 2, 1, 1, 5, 0][..]]);
+        let response = next("name", &[1, 3, 6, 1, 2, 1, 1, 5]);
+        assert_eq!(oids(&response), [&[1, 3, 6, 1, 2, 1, 1, 5, 0][..]]);
+        let response = next("name", &[1, 3, 6, 1, 2, 1, 1, 5, 0]);
+        assert!(matches!(
+            response.varbinds[0].value,
+            SnmpValue::EndOfMibView
+        ));
+
+        let varbinds =
+            client::null_varbinds(&[&[1, 3, 6, 1, 2, 1, 2, 1, 0], &[1, 3, 6, 1, 2, 1, 1, 5, 0]]);
+        let response = respond(&core, &request("sys", PduType::GET_REQUEST, &varbinds));
+        assert!(matches!(
+            response.varbinds[0].value,
+            SnmpValue::NoSuchObject
+        ));
+        assert!(matches!(
+            response.varbinds[1].value,
+            SnmpValue::OctetString(_)
+        ));
+
+        let bulk = client::encode_request(
+            snmp::SNMP_VERSION_2C,
+            "sys",
+            1,
+            PduType::GET_BULK_REQUEST,
+            &client::null_varbinds(&[&[1, 3, 6, 1, 2]]),
+            Some((0, 10)),
+        );
+        let response = respond(&core, &bulk);
+        assert_eq!(
+            oids(&response)[..2],
+            [
+                &[1, 3, 6, 1, 2, 1, 1, 5, 0][..],
+                &[1, 3, 6, 1, 2, 1, 1, 6, 0]
+            ]
+        );
+        assert!(
+            response.varbinds[2..]
+                .iter()
+                .all(|v| matches!(v.value, SnmpValue::EndOfMibView))
+        );
+
+        for max_repetitions in [1, 10] {
+            let bulk = client::encode_request(
+                snmp::SNMP_VERSION_2C,
+                "name",
+                1,
+                PduType::GET_BULK_REQUEST,
+                &client::null_varbinds(&[&[1, 3, 6, 1, 2]]),
+                Some((0, max_repetitions)),
+            );
+            let response = respond(&core, &bulk);
+            assert_eq!(oids(&response)[0], [1, 3, 6, 1, 2, 1, 1, 5, 0]);
+            assert!(response.varbinds.len() <= max_repetitions as usize);
+            assert!(
+                response.varbinds[1..]
+                    .iter()
+                    .all(|v| matches!(v.value, SnmpValue::EndOfMibView))
+            );
+        }
+    }
+}
@@ -0,0 +1,1390 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use super::Address;
+use super::pdu::{self, Header, Payload, Pdu, SearchRange};
+use crate::agent::{Mib, MibAccess};
+use crate::mib;
+use crate::snmp::{ErrorStatus, SnmpValue, Varbind};
+
+/// Receives the notifications subagents send through the master.
+pub type NotificationHandler = Arc<dyn Fn(&[Varbind]) + Send + Sync>;
+
+/// AgentX master agent (RFC 2741).
+///
+/// Subagents connect over TCP or a Unix socket, open sessions and
+/// register subtrees. A request for an OID inside a registered subtree is
+/// forwarded to the session that registered it; when registrations
+/// overlap, the most specific subtree wins, then the lowest priority
+/// value. Everything else is answered from the agent's own MIB.
+///
+/// Attach the master to an agent with [`crate::agent::SnmpAgent::with_agentx`].
+#[derive(Clone)]
+pub struct AgentxMaster {
+    state: Arc<MasterState>,
+    timeout: Duration,
+}
+
+struct MasterState {
+    registry: Mutex<Registry>,
+    // Session, connection, packet and transaction IDs
+    next_id: AtomicU32,
+    started: Instant,
+    notification_handler: RwLock<Option<NotificationHandler>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    sessions: HashMap<u32, Session>,
+    registrations: Vec<Registration>,
+    agent_caps: Vec<AgentCaps>,
+    // When capabilities were last added or removed
+    caps_changed: Option<Instant>,
+}
+
+struct Session {
+    connection: Arc<Connection>,
+    timeout: u8,
+}
+
+// A registered subtree. A range registration stands for one region per
+// value from the subtree's sub-identifier at `range_subid` up to
+// `upper_bound`, without listing them.
+struct Registration {
+    session_id: u32,
+    subtree: Vec<u32>,
+    priority: u8,
+    range: Option<(u8, u32)>,
+    timeout: u8,
+}
+
+struct AgentCaps {
+    session_id: u32,
+    id: Vec<u32>,
+    description: Vec<u8>,
+    added: Instant,
+}
+
+// A subagent connection; its reader thread handles administrative PDUs
+// and hands responses to the requests waiting for them
+struct Connection {
+    id: u32,
+    writer: Mutex<Box<dyn Write + Send>>,
+    pending: Mutex<HashMap<u32, Sender<Payload>>>,
+}
+
+impl Connection {
+    fn send(&self, pdu: &Pdu) -> bool {
+        self.writer.lock().unwrap().write_all(&pdu.encode()).is_ok()
+    }
+}
+
+// A subagent's answer to a request
+struct Reply {
+    error: u16,
+    index: u16,
+    varbinds: Vec<Varbind>,
+}
+
+// Who answers for the OIDs from some position up to `end`
+enum Step {
+    Subagent {
+        session_id: u32,
+        timeout: u8,
+        end: Option<Vec<u32>>,
+    },
+    Local {
+        end: Option<Vec<u32>>,
+    },
+}
+
+impl AgentxMaster {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(MasterState {
+                registry: Mutex::new(Registry::default()),
+                next_id: AtomicU32::new(1),
+                started: Instant::now(),
+                notification_handler: RwLock::new(None),
+            }),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Sets how long to wait for a subagent that registered no timeout of
+    /// its own; the request fails with genErr when it runs out.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Passes notifications sent by subagents to `handler`.
+    pub fn with_notification_handler(
+        self,
+        handler: impl Fn(&[Varbind]) + Send + Sync + 'static,
+    ) -> Self {
+        *self.state.notification_handler.write().unwrap() = Some(Arc::new(handler));
+        self
+    }
+
+    /// Accepts subagent connections on `address`, either `tcp:host[:port]`
+    /// or a Unix socket path with an optional `unix:` prefix, as in
+    /// net-snmp's agentXSocket. May be called for several addresses.
+    pub fn listen(&self, address: &str) -> Result<()> {
+        let state = self.state.clone();
+        match Address::parse(address) {
+            Address::Tcp(addr) => {
+                let listener = TcpListener::bind(&addr)
+                    .with_context(|| format!("Failed to listen for subagents on {}", addr))?;
+                thread::spawn(move || {
+                    for stream in listener.incoming().flatten() {
+                        if let Ok(reader) = stream.try_clone() {
+                            state.accept(Box::new(reader), Box::new(stream));
+                        }
+                    }
+                });
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                // A socket left behind by an earlier master would make bind fail
+                if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+                    let _ = std::fs::remove_file(&path);
+                }
+                let listener = UnixListener::bind(&path)
+                    .with_context(|| format!("Failed to listen for subagents on {}", path))?;
+                thread::spawn(move || {
+                    for stream in listener.incoming().flatten() {
+                        if let Ok(reader) = stream.try_clone() {
+                            state.accept(Box::new(reader), Box::new(stream));
+                        }
+                    }
+                });
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => anyhow::bail!("Unix domain sockets are not available"),
+        }
+        Ok(())
+    }
+
+    /// The capabilities subagents announced with AddAgentCaps, as
+    /// (sysORID, sysORDescr) pairs.
+    pub fn agent_capabilities(&self) -> Vec<(Vec<u32>, String)> {
+        self.state
+            .registry
+            .lock()
+            .unwrap()
+            .agent_caps
+            .iter()
+            .map(|caps| {
+                (
+                    caps.id.clone(),
+                    String::from_utf8_lossy(&caps.description).into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    // The capabilities with the time each was added, for sysORTable
+    pub(crate) fn capabilities_added(&self) -> Vec<(Vec<u32>, String, Instant)> {
+        self.state
+            .registry
+            .lock()
+            .unwrap()
+            .agent_caps
+            .iter()
+            .map(|caps| {
+                (
+                    caps.id.clone(),
+                    String::from_utf8_lossy(&caps.description).into_owned(),
+                    caps.added,
+                )
+            })
+            .collect()
+    }
+
+    // When capabilities were last added or removed, for sysORLastChange
+    pub(crate) fn capabilities_changed(&self) -> Option<Instant> {
+        self.state.registry.lock().unwrap().caps_changed
+    }
+
+    pub(crate) fn mib<'a>(&'a self, local: &'a Mib) -> MasterMib<'a> {
+        MasterMib {
+            master: self,
+            local,
+        }
+    }
+
+    // Send a request to a session and wait for the response
+    fn request(
+        &self,
+        session_id: u32,
+        transaction_id: u32,
+        payload: Payload,
+        timeout: u8,
+    ) -> Result<Reply, ErrorStatus> {
+        let (connection, session_timeout) = {
+            let registry = self.state.registry.lock().unwrap();
+            let session = registry
+                .sessions
+                .get(&session_id)
+                .ok_or(ErrorStatus::GenErr)?;
+            (session.connection.clone(), session.timeout)
+        };
+        // A registration's timeout overrides the session's, which
+        // overrides the master's
+        let timeout = [timeout, session_timeout]
+            .into_iter()
+            .find(|&t| t != 0)
+            .map_or(self.timeout, |t| Duration::from_secs(t as u64));
+
+        let packet_id = self.state.next_id();
+        let (tx, rx) = mpsc::channel();
+        connection.pending.lock().unwrap().insert(packet_id, tx);
+        let sent = connection.send(&Pdu::new(session_id, transaction_id, packet_id, payload));
+        let response = if sent {
+            rx.recv_timeout(timeout).ok()
+        } else {
+            None
+        };
+        connection.pending.lock().unwrap().remove(&packet_id);
+
+        match response {
+            Some(Payload::Response {
+                error,
+                index,
+                varbinds,
+                ..
+            }) => Ok(Reply {
+                error,
+                index,
+                varbinds,
+            }),
+            _ => {
+                println!("AgentX session {} did not answer", session_id);
+                Err(ErrorStatus::GenErr)
+            }
+        }
+    }
+
+    // Send a PDU that gets no response
+    fn send(&self, session_id: u32, transaction_id: u32, payload: Payload) {
+        let connection = self
+            .state
+            .registry
+            .lock()
+            .unwrap()
+            .sessions
+            .get(&session_id)
+            .map(|s| s.connection.clone());
+        if let Some(connection) = connection {
+            let packet_id = self.state.next_id();
+            connection.send(&Pdu::new(session_id, transaction_id, packet_id, payload));
+        }
+    }
+
+    // Run the TestSet and CommitSet phases (RFC 2741 section 7.2.1.2) with
+    // the sessions owning some of `varbinds`, undoing the commits and
+    // cleaning up if one fails. On success the caller ends the transaction
+    // with `undo_subagents` or `cleanup`.
+    fn set_subagents(
+        &self,
+        transaction_id: u32,
+        varbinds: &[Varbind],
+        sessions: &[(u32, u8, Vec<usize>)],
+    ) -> Result<(), (ErrorStatus, usize)> {
+        for (n, (session_id, timeout, indices)) in sessions.iter().enumerate() {
+            let batch = indices.iter().map(|&i| varbinds[i].clone()).collect();
+            let result = self.request(
+                *session_id,
+                transaction_id,
+                Payload::TestSet(batch),
+                *timeout,
+            );
+            if let Some((status, index)) = failure(result, indices) {
+                self.cleanup(transaction_id, &sessions[..=n]);
+                return Err((status, index));
+            }
+        }
+
+        for (n, (session_id, timeout, indices)) in sessions.iter().enumerate() {
+            let result = self.request(*session_id, transaction_id, Payload::CommitSet, *timeout);
+            if let Some((_, index)) = failure(result, indices) {
+                // Roll back every session that has committed, the failing one included
+                let status = self.undo_subagents(transaction_id, &sessions[..=n]);
+                self.cleanup(transaction_id, sessions);
+                return Err((status, index));
+            }
+        }
+        Ok(())
+    }
+
+    // Send UndoSet to `sessions`, returning the status to report:
+    // commitFailed, or undoFailed if a session could not undo
+    fn undo_subagents(
+        &self,
+        transaction_id: u32,
+        sessions: &[(u32, u8, Vec<usize>)],
+    ) -> ErrorStatus {
+        let mut status = ErrorStatus::CommitFailed;
+        for (session_id, timeout, indices) in sessions {
+            let result = self.request(*session_id, transaction_id, Payload::UndoSet, *timeout);
+            if failure(result, indices).is_some() {
+                status = ErrorStatus::UndoFailed;
+            }
+        }
+        status
+    }
+
+    fn cleanup(&self, transaction_id: u32, sessions: &[(u32, u8, Vec<usize>)]) {
+        for (session_id, _, _) in sessions {
+            self.send(*session_id, transaction_id, Payload::CleanupSet);
+        }
+    }
+}
+
+impl Default for AgentxMaster {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The error a set phase ended with, if any, and the index of the request
+// varbind it refers to
+fn failure(result: Result<Reply, ErrorStatus>, indices: &[usize]) -> Option<(ErrorStatus, usize)> {
+    match result {
+        Ok(reply) if reply.error == 0 => None,
+        Ok(reply) => {
+            let index = (reply.index as usize)
+                .checked_sub(1)
+                .and_then(|i| indices.get(i))
+                .unwrap_or(&indices[0]);
+            Some((status(reply.error), *index))
+        }
+        Err(status) => Some((status, indices[0])),
+    }
+}
+
+// AgentX errors beyond the SNMP error-status codes become genErr
+fn status(error: u16) -> ErrorStatus {
+    ErrorStatus::from_code(error as i32).unwrap_or(ErrorStatus::GenErr)
+}
+
+impl MasterState {
+    fn next_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // sysUpTime for responses, in hundredths of a second
+    fn uptime(&self) -> u32 {
+        (self.started.elapsed().as_millis() / 10) as u32
+    }
+
+    fn accept(self: &Arc<Self>, reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>) {
+        let connection = Arc::new(Connection {
+            id: self.next_id(),
+            writer: Mutex::new(writer),
+            pending: Mutex::new(HashMap::new()),
+        });
+        let state = self.clone();
+        thread::spawn(move || {
+            state.serve(reader, &connection);
+            state.disconnect(&connection);
+        });
+    }
+
+    fn serve(&self, mut reader: Box<dyn Read + Send>, connection: &Arc<Connection>) {
+        while let Ok(Some(frame)) = pdu::read_frame(&mut reader) {
+            let Ok(header) = pdu::decode_header(&frame) else {
+                return;
+            };
+            let response = match Pdu::decode(&frame) {
+                Ok(request) => self.handle(connection, request),
+                Err(e) => {
+                    println!("Malformed AgentX PDU: {}", e);
+                    Some(self.respond(&header, pdu::ERROR_PARSE_ERROR))
+                }
+            };
+            if let Some(response) = response
+                && !connection.send(&response)
+            {
+                return;
+            }
+        }
+    }
+
+    fn respond(&self, request: &Header, error: u16) -> Pdu {
+        Pdu::response(request, self.uptime(), error, 0, Vec::new())
+    }
+
+    // Handle a PDU from a subagent, returning the response to send
+    fn handle(&self, connection: &Arc<Connection>, request: Pdu) -> Option<Pdu> {
+        let header = request.header();
+        let session_id = request.session_id;
+
+        let payload = match request.payload {
+            Payload::Response { .. } => {
+                let waiter = connection
+                    .pending
+                    .lock()
+                    .unwrap()
+                    .remove(&request.packet_id);
+                if let Some(waiter) = waiter {
+                    let _ = waiter.send(request.payload);
+                }
+                return None;
+            }
+            Payload::Open {
+                timeout,
+                description,
+                ..
+            } => {
+                let session_id = self.next_id();
+                self.registry.lock().unwrap().sessions.insert(
+                    session_id,
+                    Session {
+                        connection: connection.clone(),
+                        timeout,
+                    },
+                );
+                println!(
+                    "AgentX session {} opened: {}",
+                    session_id,
+                    String::from_utf8_lossy(&description)
+                );
+                let header = Header {
+                    session_id,
+                    ..header
+                };
+                return Some(self.respond(&header, 0));
+            }
+            payload => payload,
+        };
+
+        // Everything else has to name a session opened on this connection
+        let mut registry = self.registry.lock().unwrap();
+        if registry
+            .sessions
+            .get(&session_id)
+            .is_none_or(|s| s.connection.id != connection.id)
+        {
+            return Some(self.respond(&header, pdu::ERROR_NOT_OPEN));
+        }
+        // The agent serves a single context
+        if request.context.is_some() {
+            return Some(self.respond(&header, pdu::ERROR_UNSUPPORTED_CONTEXT));
+        }
+
+        let error = match payload {
+            Payload::Close { .. } => {
+                registry.close(session_id);
+                0
+            }
+            Payload::Register {
+                timeout,
+                priority,
+                subtree,
+                range,
+            } => registry.register(Registration {
+                session_id,
+                subtree,
+                priority,
+                range,
+                timeout,
+            }),
+            Payload::Unregister {
+                priority,
+                subtree,
+                range,
+            } => {
+                let count = registry.registrations.len();
+                registry.registrations.retain(|r| {
+                    !(r.session_id == session_id
+                        && r.subtree == subtree
+                        && r.priority == priority
+                        && r.range == range)
+                });
+                if registry.registrations.len() == count {
+                    pdu::ERROR_UNKNOWN_REGISTRATION
+                } else {
+                    0
+                }
+            }
+            Payload::Ping => 0,
+            Payload::Notify(varbinds) => {
+                drop(registry);
+                let handler = self.notification_handler.read().unwrap().clone();
+                match handler {
+                    Some(handler) => handler(&varbinds),
+                    None => println!(
+                        "Notification from AgentX session {}: {:?}",
+                        session_id, varbinds
+                    ),
+                }
+                0
+            }
+            Payload::AddAgentCaps { id, description } => {
+                registry.agent_caps.push(AgentCaps {
+                    session_id,
+                    id,
+                    description,
+                    added: Instant::now(),
+                });
+                registry.caps_changed = Some(Instant::now());
+                0
+            }
+            Payload::RemoveAgentCaps { id } => {
+                let count = registry.agent_caps.len();
+                registry
+                    .agent_caps
+                    .retain(|caps| !(caps.session_id == session_id && caps.id == id));
+                if registry.agent_caps.len() == count {
+                    pdu::ERROR_UNKNOWN_AGENT_CAPS
+                } else {
+                    registry.caps_changed = Some(Instant::now());
+                    0
+                }
+            }
+            // Index allocation is not supported, and the remaining PDUs
+            // are only sent by masters
+            _ => pdu::ERROR_PROCESSING_ERROR,
+        };
+        Some(self.respond(&header, error))
+    }
+
+    // Drop the sessions of a closed connection along with what they registered
+    fn disconnect(&self, connection: &Connection) {
+        let mut registry = self.registry.lock().unwrap();
+        let closed: Vec<u32> = registry
+            .sessions
+            .iter()
+            .filter(|(_, s)| s.connection.id == connection.id)
+            .map(|(&id, _)| id)
+            .collect();
+        for session_id in closed {
+            registry.close(session_id);
+        }
+        connection.pending.lock().unwrap().clear();
+    }
+}
+
+impl Registry {
+    fn close(&mut self, session_id: u32) {
+        self.sessions.remove(&session_id);
+        self.registrations.retain(|r| r.session_id != session_id);
+        let count = self.agent_caps.len();
+        self.agent_caps.retain(|caps| caps.session_id != session_id);
+        if self.agent_caps.len() != count {
+            self.caps_changed = Some(Instant::now());
+        }
+        println!("AgentX session {} closed", session_id);
+    }
+
+    fn register(&mut self, registration: Registration) -> u16 {
+        if registration.range_position().is_none() {
+            return pdu::ERROR_PARSE_ERROR;
+        }
+        // The same subtree may only be registered again with another priority
+        let duplicate = self
+            .registrations
+            .iter()
+            .any(|r| r.priority == registration.priority && r.overlaps(&registration));
+        if duplicate {
+            return pdu::ERROR_DUPLICATE_REGISTRATION;
+        }
+        self.registrations.push(registration);
+        0
+    }
+
+    // The registration responsible for `oid` and the region it matched
+    fn owner<'a>(&self, oid: &'a [u32]) -> Option<(&Registration, &'a [u32])> {
+        self.registrations
+            .iter()
+            .filter_map(|r| r.region_of(oid).map(|region| (r, region)))
+            .min_by_key(|(r, region)| (Reverse(region.len()), r.priority))
+    }
+
+    // Where the next registered region after `from` starts
+    fn next_start(&self, from: &[u32]) -> Option<Vec<u32>> {
+        self.registrations
+            .iter()
+            .filter_map(|r| r.next_region(from))
+            .min()
+    }
+
+    fn step(&self, from: &[u32]) -> Step {
+        match self.owner(from) {
+            Some((registration, region)) => {
+                // A more specific registration inside the region takes over
+                // where it starts
+                let region_end = mib::subtree_end(region);
+                let end = self
+                    .next_start(from)
+                    .filter(|start| region_end.as_ref().is_none_or(|end| start < end))
+                    .or(region_end);
+                Step::Subagent {
+                    session_id: registration.session_id,
+                    timeout: registration.timeout,
+                    end,
+                }
+            }
+            None => Step::Local {
+                end: self.next_start(from),
+            },
+        }
+    }
+}
+
+impl Registration {
+    // Where the range applies, `Some(None)` without one, and `None` for a
+    // range that covers nothing
+    fn range_position(&self) -> Option<Option<(usize, u32)>> {
+        let Some((range_subid, upper_bound)) = self.range else {
+            return Some(None);
+        };
+        let position = (range_subid as usize).checked_sub(1)?;
+        let &lower_bound = self.subtree.get(position)?;
+        (lower_bound <= upper_bound).then_some(Some((position, upper_bound)))
+    }
+
+    // The values each sub-identifier of a region may take
+    fn bounds(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let range = self.range_position().flatten();
+        self.subtree
+            .iter()
+            .enumerate()
+            .map(move |(i, &subid)| match range {
+                Some((position, upper_bound)) if i == position => (subid, upper_bound),
+                _ => (subid, subid),
+            })
+    }
+
+    // The region of this registration that `oid` lies in
+    fn region_of<'a>(&self, oid: &'a [u32]) -> Option<&'a [u32]> {
+        let region = oid.get(..self.subtree.len())?;
+        region
+            .iter()
+            .zip(self.bounds())
+            .all(|(subid, (low, high))| (low..=high).contains(subid))
+            .then_some(region)
+    }
+
+    // Whether the two registrations share a region
+    fn overlaps(&self, other: &Registration) -> bool {
+        self.subtree.len() == other.subtree.len()
+            && self
+                .bounds()
+                .zip(other.bounds())
+                .all(|((low, high), (other_low, other_high))| {
+                    low <= other_high && other_low <= high
+                })
+    }
+
+    // The first region that starts after `from`. Regions grow with the
+    // ranged sub-identifier, so the first one is found by bisection.
+    fn next_region(&self, from: &[u32]) -> Option<Vec<u32>> {
+        let Some((position, upper_bound)) = self.range_position()? else {
+            return (self.subtree.as_slice() > from).then(|| self.subtree.clone());
+        };
+        let region = |subid| {
+            let mut region = self.subtree.clone();
+            region[position] = subid;
+            region
+        };
+        if region(upper_bound).as_slice() <= from {
+            return None;
+        }
+        let (mut low, mut high) = (self.subtree[position], upper_bound);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if region(middle).as_slice() > from {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        Some(region(low))
+    }
+}
+
+// The agent's MIB combined with the subtrees registered by subagents
+pub(crate) struct MasterMib<'a> {
+    master: &'a AgentxMaster,
+    local: &'a Mib,
+}
+
+impl MasterMib<'_> {
+    fn step(&self, from: &[u32]) -> Step {
+        self.master.state.registry.lock().unwrap().step(from)
+    }
+
+    // Up to `count` consecutive varbinds after `oid`, visiting the regions
+    // in order and moving on when one runs out
+    async fn next_values(&self, oid: &[u32], count: usize) -> Result<Vec<Varbind>, ErrorStatus> {
+        let mut found: Vec<Varbind> = Vec::new();
+        let mut from = oid.to_vec();
+        // Whether `from` itself may be returned, as at the start of a region
+        let mut include = false;
+
+        while found.len() < count {
+            let wanted = count - found.len();
+            let end = match self.step(&from) {
+                Step::Subagent {
+                    session_id,
+                    timeout,
+                    end,
+                } => {
+                    let range = SearchRange {
+                        start: from.clone(),
+                        include,
+                        end: end.clone().unwrap_or_default(),
+                    };
+                    let payload = if wanted == 1 {
+                        Payload::GetNext(vec![range])
+                    } else {
+                        Payload::GetBulk {
+                            non_repeaters: 0,
+                            max_repetitions: wanted.min(u16::MAX as usize) as u16,
+                            ranges: vec![range],
+                        }
+                    };
+                    let transaction_id = self.master.state.next_id();
+                    let reply =
+                        self.master
+                            .request(session_id, transaction_id, payload, timeout)?;
+                    if reply.error != 0 {
+                        return Err(status(reply.error));
+                    }
+
+                    // Keep the answers that move forward inside the range
+                    let mut answered = 0;
+                    for varbind in reply.varbinds.into_iter().take(wanted) {
+                        let after = match found.last() {
+                            Some(last) if answered > 0 => varbind.oid > last.oid,
+                            _ => varbind.oid > from || (include && varbind.oid == from),
+                        };
+                        if !after
+                            || end.as_ref().is_some_and(|end| varbind.oid >= *end)
+                            || matches!(varbind.value, SnmpValue::EndOfMibView)
+                        {
+                            break;
+                        }
+                        found.push(varbind);
+                        answered += 1;
+                    }
+                    if answered == wanted {
+                        break;
+                    }
+                    end
+                }
+                Step::Local { end } => {
+                    if include {
+                        let value = MibAccess::get(self.local, &from).await?;
+                        if !matches!(value, SnmpValue::NoSuchObject | SnmpValue::NoSuchInstance) {
+                            found.push(Varbind {
+                                oid: from.clone(),
+                                value,
+                            });
+                        }
+                    }
+                    let wanted = count - found.len();
+                    if wanted > 0 {
+                        let next = self.local.get_bulk(&from, wanted).await?;
+                        found.extend(next.into_iter().take_while(|varbind| {
+                            end.as_ref().is_none_or(|end| varbind.oid < *end)
+                        }));
+                    }
+                    end
+                }
+            };
+
+            match end {
+                Some(end) => {
+                    from = end;
+                    include = true;
+                }
+                None => break,
+            }
+        }
+        Ok(found)
+    }
+}
+
+impl MibAccess for MasterMib<'_> {
+    async fn get(&self, oid: &[u32]) -> Result<SnmpValue, ErrorStatus> {
+        let owner = self
+            .master
+            .state
+            .registry
+            .lock()
+            .unwrap()
+            .owner(oid)
+            .map(|(r, _)| (r.session_id, r.timeout));
+        let Some((session_id, timeout)) = owner else {
+            return MibAccess::get(self.local, oid).await;
+        };
+
+        let range = SearchRange {
+            start: oid.to_vec(),
+            include: false,
+            end: Vec::new(),
+        };
+        let transaction_id = self.master.state.next_id();
+        let reply = self.master.request(
+            session_id,
+            transaction_id,
+            Payload::Get(vec![range]),
+            timeout,
+        )?;
+        if reply.error != 0 {
+            return Err(status(reply.error));
+        }
+        reply
+            .varbinds
+            .into_iter()
+            .next()
+            .map(|varbind| varbind.value)
+            .ok_or(ErrorStatus::GenErr)
+    }
+
+    async fn get_next(&self, oid: &[u32]) -> Result<Option<Varbind>, ErrorStatus> {
+        Ok(self.next_values(oid, 1).await?.into_iter().next())
+    }
+
+    async fn get_bulk(&self, oid: &[u32], count: usize) -> Result<Vec<Varbind>, ErrorStatus> {
+        self.next_values(oid, count.max(1)).await
+    }
+
+    async fn set(&self, varbind: &Varbind) -> Result<(), ErrorStatus> {
+        self.set_all(std::slice::from_ref(varbind))
+            .await
+            .map_err(|(status, _)| status)
+    }
+
+    async fn set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        // Group the varbinds by the session owning them, the rest are local
+        let mut sessions: Vec<(u32, u8, Vec<usize>)> = Vec::new();
+        let mut local = Vec::new();
+        {
+            let registry = self.master.state.registry.lock().unwrap();
+            for (i, varbind) in varbinds.iter().enumerate() {
+                match registry.owner(&varbind.oid) {
+                    Some((registration, _)) => {
+                        match sessions
+                            .iter_mut()
+                            .find(|(id, _, _)| *id == registration.session_id)
+                        {
+                            Some((_, _, indices)) => indices.push(i),
+                            None => sessions.push((
+                                registration.session_id,
+                                registration.timeout,
+                                vec![i],
+                            )),
+                        }
+                    }
+                    None => local.push(i),
+                }
+            }
+        }
+
+        // Check the local varbinds before any subagent commits
+        let batch: Vec<Varbind> = local.iter().map(|&i| varbinds[i].clone()).collect();
+        MibAccess::test_set_all(self.local, &batch)
+            .await
+            .map_err(|(status, i)| (status, local[i]))?;
+        if sessions.is_empty() {
+            return MibAccess::set_all(self.local, &batch)
+                .await
+                .map_err(|(status, i)| (status, local[i]));
+        }
+
+        let transaction_id = self.master.state.next_id();
+        self.master
+            .set_subagents(transaction_id, varbinds, &sessions)?;
+        let result = MibAccess::set_all(self.local, &batch).await;
+        if let Err((status, i)) = result {
+            let undone = self.master.undo_subagents(transaction_id, &sessions);
+            let status = if status == ErrorStatus::UndoFailed {
+                status
+            } else {
+                undone
+            };
+            self.master.cleanup(transaction_id, &sessions);
+            return Err((status, local[i]));
+        }
+        self.master.cleanup(transaction_id, &sessions);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(subtree: &[u32], priority: u8, range: Option<(u8, u32)>) -> Registration {
+        Registration {
+            session_id: 1,
+            subtree: subtree.to_vec(),
+            priority,
+            range,
+            timeout: 0,
+        }
+    }
+
+    #[test]
+    fn range_registration_is_not_expanded() {
+        let mut registry = Registry::default();
+        let rows = registration(&[1, 3, 6, 1, 99, 5, 7], 127, Some((7, u32::MAX)));
+        assert_eq!(registry.register(rows), 0);
+
+        let (_, region) = registry.owner(&[1, 3, 6, 1, 99, 5, 123_456, 1]).unwrap();
+        assert_eq!(region, [1, 3, 6, 1, 99, 5, 123_456]);
+        assert!(registry.owner(&[1, 3, 6, 1, 99, 5, 6, 1]).is_none());
+
+        assert_eq!(
+            registry.next_start(&[1, 3, 6, 1, 99, 5]),
+            Some(vec![1, 3, 6, 1, 99, 5, 7])
+        );
+        assert_eq!(
+            registry.next_start(&[1, 3, 6, 1, 99, 5, 1000, 2]),
+            Some(vec![1, 3, 6, 1, 99, 5, 1001])
+        );
+        assert_eq!(registry.next_start(&[1, 3, 6, 1, 99, 5, u32::MAX]), None);
+    }
+
+    #[test]
+    fn overlapping_ranges_are_duplicates() {
+        let mut registry = Registry::default();
+        let rows = registration(&[1, 3, 6, 1, 99, 5, 7], 127, Some((7, 100)));
+        assert_eq!(registry.register(rows), 0);
+
+        let overlapping = registration(&[1, 3, 6, 1, 99, 5, 100], 127, Some((7, 200)));
+        assert_eq!(
+            registry.register(overlapping),
+            pdu::ERROR_DUPLICATE_REGISTRATION
+        );
+        let after = registration(&[1, 3, 6, 1, 99, 5, 101], 127, Some((7, 200)));
+        assert_eq!(registry.register(after), 0);
+        let other_priority = registration(&[1, 3, 6, 1, 99, 5, 50], 1, None);
+        assert_eq!(registry.register(other_priority), 0);
+
+        let empty = registration(&[1, 3, 6, 1, 99, 6, 9], 127, Some((7, 8)));
+        assert_eq!(registry.register(empty), pdu::ERROR_PARSE_ERROR);
+        let outside = registration(&[1, 3, 6, 1, 99, 6], 127, Some((9, 8)));
+        assert_eq!(registry.register(outside), pdu::ERROR_PARSE_ERROR);
+    }
+
+    // Requests through an agent attached to a master, from subagents
+    // connected over a Unix socket
+    #[cfg(unix)]
+    mod end_to_end {
+        use super::*;
+        use crate::agent::{AgentCore, MibHandler};
+        use crate::agentx::subagent::{self, Subagent, SubagentHandler};
+        use crate::client;
+        use crate::snmp::{self, PduType, SnmpPdu};
+        use std::collections::BTreeMap;
+        use std::os::unix::net::UnixStream;
+        use std::path::PathBuf;
+        use std::sync::mpsc::Receiver;
+
+        const PREFIX: [u32; 7] = [1, 3, 6, 1, 4, 1, 99];
+
+        fn oid(suffix: &[u32]) -> Vec<u32> {
+            [&PREFIX[..], suffix].concat()
+        }
+
+        // Read-only objects served by a subagent
+        struct Values(BTreeMap<Vec<u32>, i32>);
+
+        impl SubagentHandler for Values {
+            fn get(&self, oid: &[u32]) -> Result<SnmpValue, ErrorStatus> {
+                Ok(self
+                    .0
+                    .get(oid)
+                    .map_or(SnmpValue::NoSuchInstance, |&v| SnmpValue::Integer(v)))
+            }
+
+            fn get_next(&self, oid: &[u32]) -> Result<Option<Varbind>, ErrorStatus> {
+                Ok(self
+                    .0
+                    .iter()
+                    .find(|(next, _)| next.as_slice() > oid)
+                    .map(|(oid, &v)| Varbind {
+                        oid: oid.clone(),
+                        value: SnmpValue::Integer(v),
+                    }))
+            }
+        }
+
+        // A local object that fails to store 13, counting what it stores
+        #[derive(Default)]
+        struct Local {
+            stored: AtomicU32,
+        }
+
+        impl MibHandler for Local {
+            fn get(&self, _oid: &[u32]) -> Option<SnmpValue> {
+                Some(SnmpValue::Integer(0))
+            }
+
+            fn get_next(&self, _oid: &[u32]) -> Option<Varbind> {
+                None
+            }
+
+            fn test_set(&self, _oid: &[u32], value: &SnmpValue) -> Result<(), ErrorStatus> {
+                match value {
+                    SnmpValue::Integer(-1) => Err(ErrorStatus::WrongValue),
+                    _ => Ok(()),
+                }
+            }
+
+            fn set(&self, _oid: &[u32], value: &SnmpValue) -> Result<(), ErrorStatus> {
+                if matches!(value, SnmpValue::Integer(13)) {
+                    return Err(ErrorStatus::ResourceUnavailable);
+                }
+                self.stored.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+
+        fn start(name: &str, master: AgentxMaster) -> (AgentCore, PathBuf) {
+            let path = std::env::temp_dir().join(format!("master-{}-{}", name, std::process::id()));
+            master.listen(path.to_str().unwrap()).unwrap();
+            (
+                AgentCore::new(vec!["public".to_string()]).with_agentx(master),
+                path,
+            )
+        }
+
+        fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !done() {
+                assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        fn registrations(master: &AgentxMaster) -> Vec<Vec<u32>> {
+            let registry = master.state.registry.lock().unwrap();
+            registry
+                .registrations
+                .iter()
+                .map(|r| r.subtree.clone())
+                .collect()
+        }
+
+        fn request(
+            core: &AgentCore,
+            pdu_type: PduType,
+            varbinds: &[Varbind],
+            bulk: Option<(i32, i32)>,
+        ) -> SnmpPdu {
+            let request = client::encode_request(
+                snmp::SNMP_VERSION_2C,
+                "public",
+                1,
+                pdu_type,
+                varbinds,
+                bulk,
+            );
+            let response = core.handle_datagram(&request).unwrap();
+            snmp::decode_snmp_message(&response).unwrap().pdu
+        }
+
+        fn get(core: &AgentCore, oid: &[u32]) -> SnmpPdu {
+            request(
+                core,
+                PduType::GET_REQUEST,
+                &client::null_varbinds(&[oid]),
+                None,
+            )
+        }
+
+        fn set(core: &AgentCore, varbinds: &[(Vec<u32>, i32)]) -> SnmpPdu {
+            let varbinds: Vec<Varbind> = varbinds
+                .iter()
+                .map(|(oid, value)| Varbind {
+                    oid: oid.clone(),
+                    value: SnmpValue::Integer(*value),
+                })
+                .collect();
+            request(core, PduType::SET_REQUEST, &varbinds, None)
+        }
+
+        // The OIDs and integer values of a response
+        fn integers(response: &SnmpPdu) -> Vec<(Vec<u32>, Option<i32>)> {
+            response
+                .varbinds
+                .iter()
+                .map(|v| match v.value {
+                    SnmpValue::Integer(i) => (v.oid.clone(), Some(i)),
+                    _ => (v.oid.clone(), None),
+                })
+                .collect()
+        }
+
+        #[test]
+        fn walks_merge_local_and_subagent_regions() {
+            let master = AgentxMaster::new();
+            let (core, path) = start("walk", master.clone());
+            for i in [1, 3, 5] {
+                core.register_oid(oid(&[i, 0]), SnmpValue::Integer(i as i32))
+                    .unwrap();
+            }
+            // One subagent serves 99.2 and 99.4, another the more
+            // specific 99.2.2
+            let values = |entries: &[(&[u32], i32)]| {
+                Values(entries.iter().map(|&(s, v)| (oid(s), v)).collect())
+            };
+            Subagent::new(values(&[
+                (&[2, 1], 21),
+                (&[2, 2], 22),
+                (&[2, 3], 23),
+                (&[4, 1], 41),
+            ]))
+            .with_address(path.to_str().unwrap())
+            .with_registration(subagent::Registration::new(oid(&[2])))
+            .with_registration(subagent::Registration::new(oid(&[4])))
+            .run_in_thread();
+            Subagent::new(values(&[(&[2, 2], 220)]))
+                .with_address(path.to_str().unwrap())
+                .with_registration(subagent::Registration::new(oid(&[2, 2])))
+                .run_in_thread();
+            wait_for("the registrations", || registrations(&master).len() == 3);
+
+            let walk = vec![
+                (oid(&[1, 0]), Some(1)),
+                (oid(&[2, 1]), Some(21)),
+                (oid(&[2, 2]), Some(220)),
+                (oid(&[2, 3]), Some(23)),
+                (oid(&[3, 0]), Some(3)),
+                (oid(&[4, 1]), Some(41)),
+                (oid(&[5, 0]), Some(5)),
+            ];
+
+            let mut from = PREFIX.to_vec();
+            for expected in &walk {
+                let response = request(
+                    &core,
+                    PduType::GET_NEXT_REQUEST,
+                    &client::null_varbinds(&[&from]),
+                    None,
+                );
+                assert_eq!(&integers(&response)[0], expected);
+                from = expected.0.clone();
+            }
+
+            let bulk = |max_repetitions| {
+                request(
+                    &core,
+                    PduType::GET_BULK_REQUEST,
+                    &client::null_varbinds(&[&PREFIX]),
+                    Some((0, max_repetitions)),
+                )
+            };
+            let response = bulk(10);
+            assert_eq!(integers(&response)[..walk.len()], walk);
+            assert!(matches!(
+                response.varbinds[walk.len()].value,
+                SnmpValue::EndOfMibView
+            ));
+            assert_eq!(integers(&bulk(3)), walk[..3]);
+
+            assert_eq!(integers(&get(&core, &oid(&[2, 2])))[0].1, Some(220));
+            assert_eq!(integers(&get(&core, &oid(&[3, 0])))[0].1, Some(3));
+            assert!(matches!(
+                get(&core, &oid(&[2, 9])).varbinds[0].value,
+                SnmpValue::NoSuchInstance
+            ));
+            let _ = std::fs::remove_file(&path);
+        }
+
+        // A subagent speaking raw PDUs over its own connection
+        struct Raw {
+            stream: UnixStream,
+            session_id: u32,
+        }
+
+        impl Raw {
+            // Open a session and register `subtree`
+            fn connect(path: &PathBuf, subtree: Vec<u32>) -> Self {
+                let mut raw = Self {
+                    stream: UnixStream::connect(path).unwrap(),
+                    session_id: 0,
+                };
+                raw.session_id = raw.call(Payload::Open {
+                    timeout: 0,
+                    id: Vec::new(),
+                    description: b"raw".to_vec(),
+                });
+                raw.call(Payload::Register {
+                    timeout: 0,
+                    priority: 127,
+                    subtree,
+                    range: None,
+                });
+                raw
+            }
+
+            // Send a PDU and return the session ID of the response
+            fn call(&mut self, payload: Payload) -> u32 {
+                let pdu = Pdu::new(self.session_id, 0, 0, payload);
+                self.stream.write_all(&pdu.encode()).unwrap();
+                let response = self.receive().unwrap();
+                assert!(
+                    matches!(response.payload, Payload::Response { error: 0, .. }),
+                    "{:?}",
+                    response.payload
+                );
+                response.session_id
+            }
+
+            fn receive(&mut self) -> Option<Pdu> {
+                let frame = pdu::read_frame(&mut self.stream).ok()??;
+                Some(Pdu::decode(&frame).unwrap())
+            }
+
+            fn reply(&mut self, request: &Pdu, error: u16) {
+                let response = Pdu::response(&request.header(), 0, error, 1, Vec::new());
+                self.stream.write_all(&response.encode()).unwrap();
+            }
+
+            // Take part in set transactions, reporting each phase and its
+            // transaction: -1 fails the TestSet, 13 the CommitSet
+            fn serve_sets(mut self) -> Receiver<(&'static str, u32)> {
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || {
+                    let mut commit_fails = false;
+                    while let Some(request) = self.receive() {
+                        let value =
+                            |v: &Varbind, i| matches!(v.value, SnmpValue::Integer(x) if x == i);
+                        let (phase, error) = match &request.payload {
+                            Payload::TestSet(varbinds) => {
+                                commit_fails = varbinds.iter().any(|v| value(v, 13));
+                                let wrong = varbinds.iter().any(|v| value(v, -1));
+                                ("TestSet", if wrong { 10 } else { 0 })
+                            }
+                            Payload::CommitSet if commit_fails => ("CommitSet", 14),
+                            Payload::CommitSet => ("CommitSet", 0),
+                            Payload::UndoSet => ("UndoSet", 0),
+                            Payload::CleanupSet => ("CleanupSet", 0),
+                            other => panic!("unexpected {:?}", other),
+                        };
+                        if phase != "CleanupSet" {
+                            self.reply(&request, error);
+                        }
+                        tx.send((phase, request.transaction_id)).unwrap();
+                    }
+                });
+                rx
+            }
+        }
+
+        // The phases of one transaction, up to its CleanupSet
+        fn phases(rx: &Receiver<(&'static str, u32)>) -> Vec<&'static str> {
+            let mut phases: Vec<(&str, u32)> = Vec::new();
+            while phases
+                .last()
+                .is_none_or(|&(phase, _)| phase != "CleanupSet")
+            {
+                phases.push(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+            }
+            assert!(phases.iter().all(|&(_, id)| id == phases[0].1));
+            phases.into_iter().map(|(phase, _)| phase).collect()
+        }
+
+        #[test]
+        fn set_runs_the_phases_with_the_subagents() {
+            let (core, path) = start("set", AgentxMaster::new());
+            let local = Arc::new(Local::default());
+            core.register_handler(oid(&[3]), local.clone()).unwrap();
+            let rx = Raw::connect(&path, oid(&[2])).serve_sets();
+            let _ = std::fs::remove_file(&path);
+            let remote = oid(&[2, 1]);
+            let local_oid = oid(&[3, 0]);
+
+            let response = set(&core, &[(remote.clone(), 1), (local_oid.clone(), 1)]);
+            assert_eq!(response.error_status, ErrorStatus::NoError);
+            assert_eq!(phases(&rx), ["TestSet", "CommitSet", "CleanupSet"]);
+            assert_eq!(local.stored.load(Ordering::Relaxed), 1);
+
+            // The subagent's check fails
+            let response = set(&core, &[(local_oid.clone(), 1), (remote.clone(), -1)]);
+            assert_eq!(response.error_status, ErrorStatus::WrongValue);
+            assert_eq!(response.error_index, 2);
+            assert_eq!(phases(&rx), ["TestSet", "CleanupSet"]);
+
+            // The local check fails before the subagent hears of it
+            let response = set(&core, &[(remote.clone(), 1), (local_oid.clone(), -1)]);
+            assert_eq!(response.error_status, ErrorStatus::WrongValue);
+            assert_eq!(response.error_index, 2);
+            assert!(rx.try_recv().is_err());
+
+            // The local commit fails after the subagent's
+            let response = set(&core, &[(remote.clone(), 1), (local_oid.clone(), 13)]);
+            assert_eq!(response.error_status, ErrorStatus::CommitFailed);
+            assert_eq!(response.error_index, 2);
+            assert_eq!(
+                phases(&rx),
+                ["TestSet", "CommitSet", "UndoSet", "CleanupSet"]
+            );
+
+            // The subagent's commit fails, and the local object is left alone
+            let response = set(&core, &[(local_oid, 1), (remote, 13)]);
+            assert_eq!(response.error_status, ErrorStatus::CommitFailed);
+            assert_eq!(response.error_index, 2);
+            assert_eq!(
+                phases(&rx),
+                ["TestSet", "CommitSet", "UndoSet", "CleanupSet"]
+            );
+            assert_eq!(local.stored.load(Ordering::Relaxed), 1);
+        }
+
+        #[test]
+        fn closing_a_connection_drops_its_sessions() {
+            let master = AgentxMaster::new().with_timeout(Duration::from_secs(60));
+            let (core, path) = start("disconnect", master.clone());
+            let mut leaving = Raw::connect(&path, oid(&[2]));
+            leaving.call(Payload::AddAgentCaps {
+                id: oid(&[2]),
+                description: b"leaving".to_vec(),
+            });
+            let _staying = Raw::connect(&path, oid(&[4]));
+            let _ = std::fs::remove_file(&path);
+            assert_eq!(master.agent_capabilities().len(), 1);
+
+            // The subagent goes away in the middle of a request, which
+            // fails at once instead of waiting for the timeout
+            let gone = thread::spawn(move || {
+                let request = leaving.receive().unwrap();
+                assert!(matches!(request.payload, Payload::Get(_)));
+            });
+            let started = Instant::now();
+            let response = get(&core, &oid(&[2, 1]));
+            assert_eq!(response.error_status, ErrorStatus::GenErr);
+            assert!(started.elapsed() < Duration::from_secs(30));
+            gone.join().unwrap();
+
+            wait_for("the session to close", || registrations(&master).len() == 1);
+            assert_eq!(registrations(&master), [oid(&[4])]);
+            assert!(master.agent_capabilities().is_empty());
+            assert!(matches!(
+                get(&core, &oid(&[2, 1])).varbinds[0].value,
+                SnmpValue::NoSuchObject
+            ));
+        }
+    }
+}
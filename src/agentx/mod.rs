@@ -0,0 +1,66 @@
+//! AgentX (RFC 2741): lets subagents in other processes serve parts of the
+//! agent's MIB.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+pub mod master;
+pub(crate) mod pdu;
+pub mod subagent;
+
+pub use master::AgentxMaster;
+pub use subagent::{Registration, Subagent, SubagentHandler};
+
+/// The TCP port masters listen on by default.
+pub const AGENTX_PORT: u16 = 705;
+
+/// The Unix socket masters listen on by default.
+pub const AGENTX_SOCKET: &str = "/var/agentx/master";
+
+// Where a master accepts subagents, written as in net-snmp's agentXSocket:
+// `tcp:host[:port]`, or a Unix socket path with an optional `unix:` prefix
+pub(crate) enum Address {
+    Tcp(String),
+    Unix(String),
+}
+
+impl Address {
+    pub(crate) fn parse(address: &str) -> Self {
+        match address.strip_prefix("tcp:") {
+            Some(addr) => {
+                let has_port = addr.parse::<SocketAddr>().is_ok()
+                    || addr
+                        .rsplit_once(':')
+                        .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+                if has_port {
+                    Address::Tcp(addr.to_string())
+                } else {
+                    Address::Tcp(format!("{}:{}", addr, AGENTX_PORT))
+                }
+            }
+            None => Address::Unix(address.strip_prefix("unix:").unwrap_or(address).to_string()),
+        }
+    }
+
+    // Connect to a master, returning the two halves of the stream
+    pub(crate) fn connect(&self) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        match self {
+            Address::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix domain sockets are not available",
+            )),
+        }
+    }
+}
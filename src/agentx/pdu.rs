@@ -0,0 +1,957 @@
+use std::io::{self, ErrorKind, Read};
+
+use anyhow::{Result, anyhow, bail};
+use bytes::{BufMut, BytesMut};
+
+use crate::snmp::{SnmpValue, Varbind};
+
+pub(crate) const AGENTX_VERSION: u8 = 1;
+pub(crate) const HEADER_LEN: usize = 20;
+// Connections announcing a larger payload are dropped
+const MAX_PAYLOAD_LEN: usize = 1 << 20;
+
+// Header flags (RFC 2741 section 6.1)
+pub(crate) const FLAG_NON_DEFAULT_CONTEXT: u8 = 0x08;
+pub(crate) const FLAG_NETWORK_BYTE_ORDER: u8 = 0x10;
+
+// Varbind types (RFC 2741 section 5.4)
+const TYPE_INTEGER: u16 = 2;
+const TYPE_OCTET_STRING: u16 = 4;
+const TYPE_NULL: u16 = 5;
+const TYPE_OBJECT_IDENTIFIER: u16 = 6;
+const TYPE_IP_ADDRESS: u16 = 64;
+const TYPE_COUNTER32: u16 = 65;
+const TYPE_GAUGE32: u16 = 66;
+const TYPE_TIMETICKS: u16 = 67;
+const TYPE_OPAQUE: u16 = 68;
+const TYPE_COUNTER64: u16 = 70;
+const TYPE_NO_SUCH_OBJECT: u16 = 128;
+const TYPE_NO_SUCH_INSTANCE: u16 = 129;
+const TYPE_END_OF_MIB_VIEW: u16 = 130;
+
+// Response errors beyond the SNMP error-status codes 0-18 (RFC 2741 section 6.2.16)
+pub(crate) const ERROR_NOT_OPEN: u16 = 257;
+pub(crate) const ERROR_UNSUPPORTED_CONTEXT: u16 = 262;
+pub(crate) const ERROR_DUPLICATE_REGISTRATION: u16 = 263;
+pub(crate) const ERROR_UNKNOWN_REGISTRATION: u16 = 264;
+pub(crate) const ERROR_UNKNOWN_AGENT_CAPS: u16 = 265;
+pub(crate) const ERROR_PARSE_ERROR: u16 = 266;
+pub(crate) const ERROR_PROCESSING_ERROR: u16 = 268;
+
+// OIDs below 1.3.6.1 are sent with the fifth sub-identifier as a prefix byte
+const INTERNET: [u32; 4] = [1, 3, 6, 1];
+
+/// A range of OIDs searched by a Get, GetNext or GetBulk; an empty `end`
+/// leaves it open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SearchRange {
+    pub start: Vec<u32>,
+    pub include: bool,
+    pub end: Vec<u32>,
+}
+
+/// The PDU-specific part of an AgentX message.
+#[derive(Debug, Clone)]
+pub(crate) enum Payload {
+    Open {
+        timeout: u8,
+        id: Vec<u32>,
+        description: Vec<u8>,
+    },
+    Close {
+        reason: u8,
+    },
+    Register {
+        timeout: u8,
+        priority: u8,
+        subtree: Vec<u32>,
+        // range_subid and upper_bound
+        range: Option<(u8, u32)>,
+    },
+    Unregister {
+        priority: u8,
+        subtree: Vec<u32>,
+        range: Option<(u8, u32)>,
+    },
+    Get(Vec<SearchRange>),
+    GetNext(Vec<SearchRange>),
+    GetBulk {
+        non_repeaters: u16,
+        max_repetitions: u16,
+        ranges: Vec<SearchRange>,
+    },
+    TestSet(Vec<Varbind>),
+    CommitSet,
+    UndoSet,
+    CleanupSet,
+    Notify(Vec<Varbind>),
+    Ping,
+    IndexAllocate(Vec<Varbind>),
+    IndexDeallocate(Vec<Varbind>),
+    AddAgentCaps {
+        id: Vec<u32>,
+        description: Vec<u8>,
+    },
+    RemoveAgentCaps {
+        id: Vec<u32>,
+    },
+    Response {
+        sys_uptime: u32,
+        error: u16,
+        index: u16,
+        varbinds: Vec<Varbind>,
+    },
+}
+
+impl Payload {
+    fn pdu_type(&self) -> u8 {
+        match self {
+            Payload::Open { .. } => 1,
+            Payload::Close { .. } => 2,
+            Payload::Register { .. } => 3,
+            Payload::Unregister { .. } => 4,
+            Payload::Get(_) => 5,
+            Payload::GetNext(_) => 6,
+            Payload::GetBulk { .. } => 7,
+            Payload::TestSet(_) => 8,
+            Payload::CommitSet => 9,
+            Payload::UndoSet => 10,
+            Payload::CleanupSet => 11,
+            Payload::Notify(_) => 12,
+            Payload::Ping => 13,
+            Payload::IndexAllocate(_) => 14,
+            Payload::IndexDeallocate(_) => 15,
+            Payload::AddAgentCaps { .. } => 16,
+            Payload::RemoveAgentCaps { .. } => 17,
+            Payload::Response { .. } => 18,
+        }
+    }
+
+    // PDUs that may carry a context (RFC 2741 section 6.1.1)
+    fn has_context(pdu_type: u8) -> bool {
+        matches!(pdu_type, 3..=8 | 12..=17)
+    }
+}
+
+/// An AgentX message (RFC 2741 section 6).
+#[derive(Debug, Clone)]
+pub(crate) struct Pdu {
+    pub flags: u8,
+    pub session_id: u32,
+    pub transaction_id: u32,
+    pub packet_id: u32,
+    pub context: Option<Vec<u8>>,
+    pub payload: Payload,
+}
+
+/// The header fields of a message, decoded on their own so a message
+/// whose payload does not parse can still be answered.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Header {
+    pub pdu_type: u8,
+    pub session_id: u32,
+    pub transaction_id: u32,
+    pub packet_id: u32,
+}
+
+impl Pdu {
+    pub fn new(session_id: u32, transaction_id: u32, packet_id: u32, payload: Payload) -> Self {
+        Self {
+            flags: 0,
+            session_id,
+            transaction_id,
+            packet_id,
+            context: None,
+            payload,
+        }
+    }
+
+    /// Builds the response to a message with the given header.
+    pub fn response(
+        request: &Header,
+        sys_uptime: u32,
+        error: u16,
+        index: u16,
+        varbinds: Vec<Varbind>,
+    ) -> Self {
+        Self::new(
+            request.session_id,
+            request.transaction_id,
+            request.packet_id,
+            Payload::Response {
+                sys_uptime,
+                error,
+                index,
+                varbinds,
+            },
+        )
+    }
+
+    pub fn header(&self) -> Header {
+        Header {
+            pdu_type: self.payload.pdu_type(),
+            session_id: self.session_id,
+            transaction_id: self.transaction_id,
+            packet_id: self.packet_id,
+        }
+    }
+
+    /// Encodes the message in network byte order.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = BytesMut::new();
+        let pdu_type = self.payload.pdu_type();
+        let mut flags = (self.flags | FLAG_NETWORK_BYTE_ORDER) & !FLAG_NON_DEFAULT_CONTEXT;
+        if let Some(context) = &self.context
+            && Payload::has_context(pdu_type)
+        {
+            flags |= FLAG_NON_DEFAULT_CONTEXT;
+            put_octets(context, &mut payload);
+        }
+
+        match &self.payload {
+            Payload::Open {
+                timeout,
+                id,
+                description,
+            } => {
+                payload.put_u8(*timeout);
+                payload.put_slice(&[0; 3]);
+                put_oid(id, false, &mut payload);
+                put_octets(description, &mut payload);
+            }
+            Payload::Close { reason } => {
+                payload.put_u8(*reason);
+                payload.put_slice(&[0; 3]);
+            }
+            Payload::Register {
+                timeout,
+                priority,
+                subtree,
+                range,
+            } => {
+                put_registration(*timeout, *priority, subtree, *range, &mut payload);
+            }
+            Payload::Unregister {
+                priority,
+                subtree,
+                range,
+            } => {
+                put_registration(0, *priority, subtree, *range, &mut payload);
+            }
+            Payload::Get(ranges) | Payload::GetNext(ranges) => {
+                put_ranges(ranges, &mut payload);
+            }
+            Payload::GetBulk {
+                non_repeaters,
+                max_repetitions,
+                ranges,
+            } => {
+                payload.put_u16(*non_repeaters);
+                payload.put_u16(*max_repetitions);
+                put_ranges(ranges, &mut payload);
+            }
+            Payload::TestSet(varbinds)
+            | Payload::Notify(varbinds)
+            | Payload::IndexAllocate(varbinds)
+            | Payload::IndexDeallocate(varbinds) => {
+                put_varbinds(varbinds, &mut payload);
+            }
+            Payload::CommitSet | Payload::UndoSet | Payload::CleanupSet | Payload::Ping => {}
+            Payload::AddAgentCaps { id, description } => {
+                put_oid(id, false, &mut payload);
+                put_octets(description, &mut payload);
+            }
+            Payload::RemoveAgentCaps { id } => put_oid(id, false, &mut payload),
+            Payload::Response {
+                sys_uptime,
+                error,
+                index,
+                varbinds,
+            } => {
+                payload.put_u32(*sys_uptime);
+                payload.put_u16(*error);
+                payload.put_u16(*index);
+                put_varbinds(varbinds, &mut payload);
+            }
+        }
+
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+        buf.put_u8(AGENTX_VERSION);
+        buf.put_u8(pdu_type);
+        buf.put_u8(flags);
+        buf.put_u8(0);
+        buf.put_u32(self.session_id);
+        buf.put_u32(self.transaction_id);
+        buf.put_u32(self.packet_id);
+        buf.put_u32(payload.len() as u32);
+        buf.put_slice(&payload);
+        buf.to_vec()
+    }
+
+    /// Decodes a whole message as returned by [`read_frame`].
+    pub fn decode(frame: &[u8]) -> Result<Self> {
+        let header = decode_header(frame)?;
+        let flags = frame[2];
+        let mut reader = Reader {
+            data: &frame[HEADER_LEN..],
+            big_endian: flags & FLAG_NETWORK_BYTE_ORDER != 0,
+        };
+
+        let context =
+            if flags & FLAG_NON_DEFAULT_CONTEXT != 0 && Payload::has_context(header.pdu_type) {
+                Some(reader.octets()?)
+            } else {
+                None
+            };
+
+        let payload = match header.pdu_type {
+            1 => {
+                let timeout = reader.u8()?;
+                reader.skip(3)?;
+                let (id, _) = reader.oid()?;
+                let description = reader.octets()?;
+                Payload::Open {
+                    timeout,
+                    id,
+                    description,
+                }
+            }
+            2 => {
+                let reason = reader.u8()?;
+                reader.skip(3)?;
+                Payload::Close { reason }
+            }
+            3 | 4 => {
+                let timeout = reader.u8()?;
+                let priority = reader.u8()?;
+                let range_subid = reader.u8()?;
+                reader.skip(1)?;
+                let (subtree, _) = reader.oid()?;
+                let range = if range_subid != 0 {
+                    Some((range_subid, reader.u32()?))
+                } else {
+                    None
+                };
+                if header.pdu_type == 3 {
+                    Payload::Register {
+                        timeout,
+                        priority,
+                        subtree,
+                        range,
+                    }
+                } else {
+                    Payload::Unregister {
+                        priority,
+                        subtree,
+                        range,
+                    }
+                }
+            }
+            5 => Payload::Get(reader.ranges()?),
+            6 => Payload::GetNext(reader.ranges()?),
+            7 => {
+                let non_repeaters = reader.u16()?;
+                let max_repetitions = reader.u16()?;
+                Payload::GetBulk {
+                    non_repeaters,
+                    max_repetitions,
+                    ranges: reader.ranges()?,
+                }
+            }
+            8 => Payload::TestSet(reader.varbinds()?),
+            9 => Payload::CommitSet,
+            10 => Payload::UndoSet,
+            11 => Payload::CleanupSet,
+            12 => Payload::Notify(reader.varbinds()?),
+            13 => Payload::Ping,
+            14 => Payload::IndexAllocate(reader.varbinds()?),
+            15 => Payload::IndexDeallocate(reader.varbinds()?),
+            16 => {
+                let (id, _) = reader.oid()?;
+                Payload::AddAgentCaps {
+                    id,
+                    description: reader.octets()?,
+                }
+            }
+            17 => Payload::RemoveAgentCaps {
+                id: reader.oid()?.0,
+            },
+            18 => {
+                let sys_uptime = reader.u32()?;
+                let error = reader.u16()?;
+                let index = reader.u16()?;
+                Payload::Response {
+                    sys_uptime,
+                    error,
+                    index,
+                    varbinds: reader.varbinds()?,
+                }
+            }
+            other => bail!("Unknown AgentX PDU type {}", other),
+        };
+
+        Ok(Self {
+            flags,
+            session_id: header.session_id,
+            transaction_id: header.transaction_id,
+            packet_id: header.packet_id,
+            context,
+            payload,
+        })
+    }
+}
+
+/// Decodes the header of a frame returned by [`read_frame`].
+pub(crate) fn decode_header(frame: &[u8]) -> Result<Header> {
+    if frame.len() < HEADER_LEN {
+        bail!("AgentX header too short");
+    }
+    if frame[0] != AGENTX_VERSION {
+        bail!("Unsupported AgentX version {}", frame[0]);
+    }
+    let mut reader = Reader {
+        data: &frame[4..HEADER_LEN],
+        big_endian: frame[2] & FLAG_NETWORK_BYTE_ORDER != 0,
+    };
+    Ok(Header {
+        pdu_type: frame[1],
+        session_id: reader.u32()?,
+        transaction_id: reader.u32()?,
+        packet_id: reader.u32()?,
+    })
+}
+
+/// Reads one message, header and payload, or `None` if the peer closed
+/// the connection between messages.
+pub(crate) fn read_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut frame = vec![0u8; HEADER_LEN];
+    match reader.read_exact(&mut frame[..1]) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    reader.read_exact(&mut frame[1..])?;
+
+    let length = if frame[2] & FLAG_NETWORK_BYTE_ORDER != 0 {
+        u32::from_be_bytes([frame[16], frame[17], frame[18], frame[19]])
+    } else {
+        u32::from_le_bytes([frame[16], frame[17], frame[18], frame[19]])
+    } as usize;
+    if length > MAX_PAYLOAD_LEN || !length.is_multiple_of(4) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Invalid AgentX payload length",
+        ));
+    }
+
+    frame.resize(HEADER_LEN + length, 0);
+    reader.read_exact(&mut frame[HEADER_LEN..])?;
+    Ok(Some(frame))
+}
+
+fn put_oid(oid: &[u32], include: bool, buf: &mut BytesMut) {
+    let (prefix, subids) = match oid {
+        [1, 3, 6, 1, prefix @ 1..=255, rest @ ..] => (*prefix as u8, rest),
+        _ => (0, oid),
+    };
+    buf.put_u8(subids.len() as u8);
+    buf.put_u8(prefix);
+    buf.put_u8(include as u8);
+    buf.put_u8(0);
+    for subid in subids {
+        buf.put_u32(*subid);
+    }
+}
+
+fn put_octets(data: &[u8], buf: &mut BytesMut) {
+    buf.put_u32(data.len() as u32);
+    buf.put_slice(data);
+    buf.put_bytes(0, (4 - data.len() % 4) % 4);
+}
+
+fn put_registration(
+    timeout: u8,
+    priority: u8,
+    subtree: &[u32],
+    range: Option<(u8, u32)>,
+    buf: &mut BytesMut,
+) {
+    buf.put_u8(timeout);
+    buf.put_u8(priority);
+    buf.put_u8(range.map_or(0, |(subid, _)| subid));
+    buf.put_u8(0);
+    put_oid(subtree, false, buf);
+    if let Some((_, upper_bound)) = range {
+        buf.put_u32(upper_bound);
+    }
+}
+
+fn put_ranges(ranges: &[SearchRange], buf: &mut BytesMut) {
+    for range in ranges {
+        put_oid(&range.start, range.include, buf);
+        put_oid(&range.end, false, buf);
+    }
+}
+
+fn put_varbinds(varbinds: &[Varbind], buf: &mut BytesMut) {
+    for varbind in varbinds {
+        let value_type = match &varbind.value {
+            SnmpValue::Integer(_) => TYPE_INTEGER,
+            SnmpValue::OctetString(_) => TYPE_OCTET_STRING,
+            SnmpValue::Null => TYPE_NULL,
+            SnmpValue::ObjectIdentifier(_) => TYPE_OBJECT_IDENTIFIER,
+            SnmpValue::IpAddress(_) => TYPE_IP_ADDRESS,
+            SnmpValue::Counter32(_) => TYPE_COUNTER32,
+            SnmpValue::Gauge32(_) => TYPE_GAUGE32,
+            SnmpValue::TimeTicks(_) => TYPE_TIMETICKS,
+            SnmpValue::Opaque(_) => TYPE_OPAQUE,
+            SnmpValue::Counter64(_) => TYPE_COUNTER64,
+            SnmpValue::NoSuchObject => TYPE_NO_SUCH_OBJECT,
+            SnmpValue::NoSuchInstance => TYPE_NO_SUCH_INSTANCE,
+            SnmpValue::EndOfMibView => TYPE_END_OF_MIB_VIEW,
+        };
+        buf.put_u16(value_type);
+        buf.put_u16(0);
+        put_oid(&varbind.oid, false, buf);
+
+        match &varbind.value {
+            SnmpValue::Integer(value) => buf.put_i32(*value),
+            SnmpValue::OctetString(data) | SnmpValue::Opaque(data) => put_octets(data, buf),
+            SnmpValue::IpAddress(address) => put_octets(address, buf),
+            SnmpValue::ObjectIdentifier(oid) => put_oid(oid, false, buf),
+            SnmpValue::Counter32(value)
+            | SnmpValue::Gauge32(value)
+            | SnmpValue::TimeTicks(value) => buf.put_u32(*value),
+            SnmpValue::Counter64(value) => buf.put_u64(*value),
+            SnmpValue::Null
+            | SnmpValue::NoSuchObject
+            | SnmpValue::NoSuchInstance
+            | SnmpValue::EndOfMibView => {}
+        }
+    }
+}
+
+// Reads payload fields in the byte order announced by the header
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if self.data.len() < N {
+            bail!("AgentX payload truncated");
+        }
+        let (head, rest) = self.data.split_at(N);
+        self.data = rest;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        if self.data.len() < len {
+            bail!("AgentX payload truncated");
+        }
+        self.data = &self.data[len..];
+        Ok(())
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take()?;
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take()?;
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let bytes = self.take()?;
+        Ok(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    // An OID and its include flag
+    fn oid(&mut self) -> Result<(Vec<u32>, bool)> {
+        let n_subid = self.u8()? as usize;
+        let prefix = self.u8()?;
+        let include = self.u8()? != 0;
+        self.skip(1)?;
+
+        let mut oid = Vec::with_capacity(n_subid + 5);
+        if prefix != 0 {
+            oid.extend_from_slice(&INTERNET);
+            oid.push(prefix as u32);
+        }
+        for _ in 0..n_subid {
+            oid.push(self.u32()?);
+        }
+        Ok((oid, include))
+    }
+
+    fn octets(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        let padded = len
+            .checked_add(3)
+            .ok_or(anyhow!("Invalid octet string length"))?
+            & !3;
+        if self.data.len() < padded {
+            bail!("AgentX payload truncated");
+        }
+        let data = self.data[..len].to_vec();
+        self.data = &self.data[padded..];
+        Ok(data)
+    }
+
+    fn ranges(&mut self) -> Result<Vec<SearchRange>> {
+        let mut ranges = Vec::new();
+        while !self.data.is_empty() {
+            let (start, include) = self.oid()?;
+            let (end, _) = self.oid()?;
+            ranges.push(SearchRange {
+                start,
+                include,
+                end,
+            });
+        }
+        Ok(ranges)
+    }
+
+    fn varbinds(&mut self) -> Result<Vec<Varbind>> {
+        let mut varbinds = Vec::new();
+        while !self.data.is_empty() {
+            let value_type = self.u16()?;
+            self.skip(2)?;
+            let (oid, _) = self.oid()?;
+            let value = match value_type {
+                TYPE_INTEGER => SnmpValue::Integer(self.u32()? as i32),
+                TYPE_OCTET_STRING => SnmpValue::OctetString(self.octets()?),
+                TYPE_NULL => SnmpValue::Null,
+                TYPE_OBJECT_IDENTIFIER => SnmpValue::ObjectIdentifier(self.oid()?.0),
+                TYPE_IP_ADDRESS => {
+                    let address = self.octets()?;
+                    SnmpValue::IpAddress(
+                        address
+                            .try_into()
+                            .map_err(|_| anyhow!("IpAddress must have 4 octets"))?,
+                    )
+                }
+                TYPE_COUNTER32 => SnmpValue::Counter32(self.u32()?),
+                TYPE_GAUGE32 => SnmpValue::Gauge32(self.u32()?),
+                TYPE_TIMETICKS => SnmpValue::TimeTicks(self.u32()?),
+                TYPE_OPAQUE => SnmpValue::Opaque(self.octets()?),
+                TYPE_COUNTER64 => SnmpValue::Counter64(self.u64()?),
+                TYPE_NO_SUCH_OBJECT => SnmpValue::NoSuchObject,
+                TYPE_NO_SUCH_INSTANCE => SnmpValue::NoSuchInstance,
+                TYPE_END_OF_MIB_VIEW => SnmpValue::EndOfMibView,
+                other => bail!("Unknown AgentX varbind type {}", other),
+            };
+            varbinds.push(Varbind { oid, value });
+        }
+        Ok(varbinds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varbind(oid: &[u32], value: SnmpValue) -> Varbind {
+        Varbind {
+            oid: oid.to_vec(),
+            value,
+        }
+    }
+
+    // Every kind of value, under and outside the internet prefix
+    fn varbinds() -> Vec<Varbind> {
+        vec![
+            varbind(&[1, 3, 6, 1, 2, 1, 1, 3, 0], SnmpValue::TimeTicks(4200)),
+            varbind(&[1, 3, 6, 1, 4, 1, 99, 1], SnmpValue::Integer(-7)),
+            varbind(
+                &[1, 3, 6, 1, 4, 1, 99, 2],
+                SnmpValue::OctetString(b"eth0".to_vec()),
+            ),
+            varbind(&[1, 3, 6, 1, 4, 1, 99, 3], SnmpValue::Null),
+            varbind(
+                &[1, 3, 6, 1, 4, 1, 99, 4],
+                SnmpValue::ObjectIdentifier(vec![1, 3, 6, 1, 6, 3, 1]),
+            ),
+            varbind(
+                &[1, 3, 6, 1, 4, 1, 99, 5],
+                SnmpValue::IpAddress([192, 0, 2, 1]),
+            ),
+            varbind(&[1, 3, 6, 1, 4, 1, 99, 6], SnmpValue::Counter32(u32::MAX)),
+            varbind(&[1, 3, 6, 1, 4, 1, 99, 7], SnmpValue::Gauge32(1_000_000)),
+            varbind(
+                &[1, 3, 6, 1, 4, 1, 99, 8],
+                SnmpValue::Opaque(vec![0x9f, 0x78]),
+            ),
+            varbind(&[1, 3, 6, 1, 4, 1, 99, 9], SnmpValue::Counter64(1 << 40)),
+            varbind(&[1, 3, 6, 1, 4, 1, 99, 10], SnmpValue::NoSuchObject),
+            varbind(&[1, 3, 6, 1, 4, 1, 99, 11], SnmpValue::NoSuchInstance),
+            varbind(&[2, 1], SnmpValue::EndOfMibView),
+        ]
+    }
+
+    fn round_trip(pdu: &Pdu) -> Pdu {
+        let encoded = pdu.encode();
+        let frame = read_frame(&mut encoded.as_slice()).unwrap().unwrap();
+        assert_eq!(frame, encoded);
+        let decoded = Pdu::decode(&frame).unwrap();
+        assert_eq!(decoded.encode(), encoded, "{:?}", pdu.payload);
+        decoded
+    }
+
+    #[test]
+    fn every_payload_round_trips() {
+        let range = SearchRange {
+            start: vec![1, 3, 6, 1, 4, 1, 99],
+            include: true,
+            end: vec![1, 3, 6, 1, 4, 1, 100],
+        };
+        let open_range = SearchRange {
+            start: vec![1, 3, 6, 1, 4, 1, 99, 5],
+            include: false,
+            end: Vec::new(),
+        };
+        let payloads = vec![
+            Payload::Open {
+                timeout: 10,
+                id: vec![1, 3, 6, 1, 4, 1, 99],
+                description: b"test subagent".to_vec(),
+            },
+            Payload::Close { reason: 5 },
+            Payload::Register {
+                timeout: 3,
+                priority: 127,
+                subtree: vec![1, 3, 6, 1, 2, 1, 2, 2, 1, 1, 1],
+                range: Some((11, 24)),
+            },
+            Payload::Unregister {
+                priority: 1,
+                subtree: vec![1, 3, 6, 1, 4, 1, 99],
+                range: None,
+            },
+            Payload::Get(vec![range.clone()]),
+            Payload::GetNext(vec![range.clone(), open_range.clone()]),
+            Payload::GetBulk {
+                non_repeaters: 1,
+                max_repetitions: 20,
+                ranges: vec![range, open_range],
+            },
+            Payload::TestSet(varbinds()),
+            Payload::CommitSet,
+            Payload::UndoSet,
+            Payload::CleanupSet,
+            Payload::Notify(varbinds()),
+            Payload::Ping,
+            Payload::IndexAllocate(varbinds()),
+            Payload::IndexDeallocate(varbinds()),
+            Payload::AddAgentCaps {
+                id: vec![1, 3, 6, 1, 2, 1, 31],
+                description: b"IF-MIB".to_vec(),
+            },
+            Payload::RemoveAgentCaps {
+                id: vec![1, 3, 6, 1, 2, 1, 31],
+            },
+            Payload::Response {
+                sys_uptime: 12345,
+                error: ERROR_DUPLICATE_REGISTRATION,
+                index: 2,
+                varbinds: varbinds(),
+            },
+        ];
+
+        for payload in payloads {
+            let pdu = Pdu::new(7, 8, 9, payload);
+            let decoded = round_trip(&pdu);
+            assert_eq!(
+                (
+                    decoded.session_id,
+                    decoded.transaction_id,
+                    decoded.packet_id
+                ),
+                (7, 8, 9)
+            );
+            assert_eq!(decoded.payload.pdu_type(), pdu.payload.pdu_type());
+            assert!(decoded.context.is_none());
+        }
+    }
+
+    #[test]
+    fn decoded_fields_match() {
+        let pdu = round_trip(&Pdu::new(
+            1,
+            0,
+            2,
+            Payload::GetBulk {
+                non_repeaters: 0,
+                max_repetitions: 3,
+                ranges: vec![SearchRange {
+                    start: vec![1, 3, 6, 1, 4, 1, 99],
+                    include: true,
+                    end: Vec::new(),
+                }],
+            },
+        ));
+        let Payload::GetBulk {
+            max_repetitions,
+            ranges,
+            ..
+        } = pdu.payload
+        else {
+            panic!("decoded as {:?}", pdu.payload);
+        };
+        assert_eq!(max_repetitions, 3);
+        assert_eq!(ranges[0].start, [1, 3, 6, 1, 4, 1, 99]);
+        assert!(ranges[0].include);
+        assert!(ranges[0].end.is_empty());
+
+        let pdu = round_trip(&Pdu::new(1, 0, 2, Payload::Notify(varbinds())));
+        let Payload::Notify(decoded) = pdu.payload else {
+            panic!("decoded as {:?}", pdu.payload);
+        };
+        assert_eq!(decoded.len(), varbinds().len());
+        for (decoded, expected) in decoded.iter().zip(varbinds()) {
+            assert_eq!(decoded.oid, expected.oid);
+            assert_eq!(
+                snmp_value_bytes(&decoded.value),
+                snmp_value_bytes(&expected.value)
+            );
+        }
+    }
+
+    // A value as put in a varbind, to compare values without PartialEq
+    fn snmp_value_bytes(value: &SnmpValue) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        put_varbinds(&[varbind(&[], value.clone())], &mut buf);
+        buf.to_vec()
+    }
+
+    #[test]
+    fn context_is_kept_for_the_pdus_that_carry_one() {
+        let mut pdu = Pdu::new(1, 0, 2, Payload::Get(Vec::new()));
+        pdu.context = Some(b"bridge1".to_vec());
+        let decoded = round_trip(&pdu);
+        assert_eq!(
+            decoded.flags & FLAG_NON_DEFAULT_CONTEXT,
+            FLAG_NON_DEFAULT_CONTEXT
+        );
+        assert_eq!(decoded.context.as_deref(), Some(&b"bridge1"[..]));
+
+        // An Open has no context field
+        let mut pdu = Pdu::new(
+            1,
+            0,
+            2,
+            Payload::Open {
+                timeout: 0,
+                id: Vec::new(),
+                description: Vec::new(),
+            },
+        );
+        pdu.context = Some(b"bridge1".to_vec());
+        let decoded = round_trip(&pdu);
+        assert_eq!(decoded.flags & FLAG_NON_DEFAULT_CONTEXT, 0);
+        assert!(decoded.context.is_none());
+    }
+
+    #[test]
+    fn encodes_the_rfc_2741_layout() {
+        let pdu = Pdu::new(
+            0x01020304,
+            5,
+            6,
+            Payload::Open {
+                timeout: 10,
+                id: vec![1, 3, 6, 1, 4, 1, 99],
+                description: b"abcde".to_vec(),
+            },
+        );
+        assert_eq!(
+            pdu.encode(),
+            [
+                1, 1, 0x10, 0, // version, type, network byte order
+                1, 2, 3, 4, 0, 0, 0, 5, 0, 0, 0, 6, // session, transaction, packet
+                0, 0, 0, 28, // payload length
+                10, 0, 0, 0, // timeout
+                2, 4, 0, 0, 0, 0, 0, 1, 0, 0, 0, 99, // 1.3.6.1.4 as prefix 4
+                0, 0, 0, 5, b'a', b'b', b'c', b'd', b'e', 0, 0, 0, // padded
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_little_endian_messages() {
+        let frame = [
+            1, 18, 0, 0, // Response without the network byte order flag
+            7, 0, 0, 0, 8, 0, 0, 0, 9, 0, 0, 0, // session, transaction, packet
+            28, 0, 0, 0, // payload length
+            100, 0, 0, 0, 5, 1, 1, 0, // sysUpTime, error 261, index 1
+            2, 0, 0, 0, // Integer
+            2, 4, 0, 0, 1, 0, 0, 0, 99, 0, 0, 0, // 1.3.6.1.4.1.99
+            42, 0, 0, 0,
+        ];
+        let frame = read_frame(&mut &frame[..]).unwrap().unwrap();
+        let pdu = Pdu::decode(&frame).unwrap();
+        assert_eq!(
+            (pdu.session_id, pdu.transaction_id, pdu.packet_id),
+            (7, 8, 9)
+        );
+        let Payload::Response {
+            sys_uptime,
+            error,
+            index,
+            varbinds,
+        } = pdu.payload
+        else {
+            panic!("decoded as {:?}", pdu.payload);
+        };
+        assert_eq!((sys_uptime, error, index), (100, 261, 1));
+        assert_eq!(varbinds[0].oid, [1, 3, 6, 1, 4, 1, 99]);
+        assert!(matches!(varbinds[0].value, SnmpValue::Integer(42)));
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        assert!(read_frame(&mut &[][..]).unwrap().is_none());
+
+        let mut encoded = Pdu::new(1, 0, 2, Payload::Close { reason: 1 }).encode();
+        // Cut short before the end of the payload
+        assert!(read_frame(&mut &encoded[..encoded.len() - 1]).is_err());
+        // A payload length that is no multiple of 4
+        encoded[19] = 3;
+        assert!(read_frame(&mut encoded.as_slice()).is_err());
+
+        let encoded = Pdu::new(1, 0, 2, Payload::Ping).encode();
+        let mut wrong_version = encoded.clone();
+        wrong_version[0] = 2;
+        assert!(Pdu::decode(&wrong_version).is_err());
+        let mut unknown_type = encoded;
+        unknown_type[1] = 99;
+        assert!(Pdu::decode(&unknown_type).is_err());
+
+        let encoded = Pdu::new(1, 0, 2, Payload::TestSet(varbinds())).encode();
+        let mut truncated = encoded[..encoded.len() - 4].to_vec();
+        let length = (truncated.len() - HEADER_LEN) as u32;
+        truncated[16..20].copy_from_slice(&length.to_be_bytes());
+        assert!(Pdu::decode(&truncated).is_err());
+        assert!(Pdu::decode(&encoded[..HEADER_LEN - 1]).is_err());
+    }
+}
@@ -0,0 +1,849 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+
+use super::pdu::{self, Header, Payload, Pdu, SearchRange};
+use super::{AGENTX_SOCKET, Address};
+use crate::snmp::{ErrorStatus, SnmpValue, Varbind};
+
+// snmpTrapOID.0, the first varbind of a notification
+const SNMP_TRAP_OID: [u32; 11] = [1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0];
+
+/// The objects a [`Subagent`] serves.
+///
+/// The master only asks for OIDs inside the subagent's registrations. An
+/// error fails the whole request with that error status.
+pub trait SubagentHandler: Send + Sync {
+    /// The value of `oid`, or noSuchObject/noSuchInstance when it is absent.
+    fn get(&self, oid: &[u32]) -> Result<SnmpValue, ErrorStatus>;
+
+    /// The first object after `oid` in lexicographical order.
+    fn get_next(&self, oid: &[u32]) -> Result<Option<Varbind>, ErrorStatus>;
+
+    /// Checks that `varbind` can be set, without setting it. Writable
+    /// objects implement both this and [`SubagentHandler::set`].
+    fn test_set(&self, varbind: &Varbind) -> Result<(), ErrorStatus> {
+        let _ = varbind;
+        Err(ErrorStatus::NotWritable)
+    }
+
+    /// Sets a value that passed [`SubagentHandler::test_set`]. If another
+    /// part of the request fails afterwards, the previous values are set
+    /// back through this method.
+    fn set(&self, varbind: &Varbind) -> Result<(), ErrorStatus> {
+        let _ = varbind;
+        Err(ErrorStatus::NotWritable)
+    }
+
+    /// Removes an object that a request created, when another part of it
+    /// fails afterwards. The default fails, and the master reports
+    /// undoFailed.
+    fn remove(&self, oid: &[u32]) -> Result<(), ErrorStatus> {
+        let _ = oid;
+        Err(ErrorStatus::UndoFailed)
+    }
+}
+
+/// A subtree a subagent asks the master to send requests for.
+#[derive(Debug, Clone)]
+pub struct Registration {
+    subtree: Vec<u32>,
+    priority: u8,
+    range: Option<(u8, u32)>,
+    timeout: Duration,
+}
+
+impl Registration {
+    pub fn new(subtree: Vec<u32>) -> Self {
+        Self {
+            subtree,
+            priority: 127,
+            range: None,
+            timeout: Duration::ZERO,
+        }
+    }
+
+    /// Sets the priority, 127 by default. Where registrations of the same
+    /// subtree overlap, the lowest value wins.
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Registers a range of subtrees instead: sub-identifier `range_subid`
+    /// (counting from 1) takes every value from the one in the subtree up
+    /// to `upper_bound`, e.g. one row per interface of a table.
+    pub fn with_range(mut self, range_subid: u8, upper_bound: u32) -> Self {
+        self.range = Some((range_subid, upper_bound));
+        self
+    }
+
+    /// Sets how long the master waits for requests to this subtree, in
+    /// whole seconds; zero leaves it to the session.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// AgentX subagent (RFC 2741): serves subtrees of a master agent's MIB,
+/// such as net-snmp's snmpd or [`super::AgentxMaster`], from this process.
+///
+/// [`Subagent::run`] connects to the master, opens a session, registers
+/// the subtrees and answers the master's requests through the
+/// [`SubagentHandler`]. When the connection is lost, e.g. because the
+/// master restarted, it connects and registers again.
+#[derive(Clone)]
+pub struct Subagent {
+    address: String,
+    id: Vec<u32>,
+    description: String,
+    timeout: Duration,
+    reconnect_interval: Duration,
+    registrations: Vec<Registration>,
+    handler: Arc<dyn SubagentHandler>,
+    state: Arc<SubagentState>,
+}
+
+struct SubagentState {
+    connection: Mutex<Option<Arc<Connection>>>,
+    next_id: AtomicU32,
+}
+
+// An open session; the serving thread hands it the responses to
+// notifications sent from other threads
+struct Connection {
+    session_id: u32,
+    writer: Mutex<Box<dyn Write + Send>>,
+    pending: Mutex<HashMap<u32, Sender<Payload>>>,
+}
+
+// A set in progress: the varbinds tested and, for those committed, the
+// previous values to undo them, none for objects that did not exist
+#[derive(Default)]
+struct Transaction {
+    varbinds: Vec<Varbind>,
+    previous: Vec<(Vec<u32>, Option<SnmpValue>)>,
+}
+
+// A registration the master refused, which stops the subagent
+#[derive(Debug)]
+struct Refused {
+    subtree: Vec<u32>,
+    error: u16,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The master refused to register {:?}: error {}",
+            self.subtree, self.error
+        )
+    }
+}
+
+impl std::error::Error for Refused {}
+
+impl Subagent {
+    /// Creates a subagent for the master listening on [`AGENTX_SOCKET`].
+    pub fn new(handler: impl SubagentHandler + 'static) -> Self {
+        Self {
+            address: AGENTX_SOCKET.to_string(),
+            id: Vec::new(),
+            description: String::new(),
+            timeout: Duration::ZERO,
+            reconnect_interval: Duration::from_secs(5),
+            registrations: Vec::new(),
+            handler: Arc::new(handler),
+            state: Arc::new(SubagentState {
+                connection: Mutex::new(None),
+                next_id: AtomicU32::new(1),
+            }),
+        }
+    }
+
+    /// Sets the master's address, `tcp:host[:port]` or a Unix socket path
+    /// as for [`super::AgentxMaster::listen`].
+    pub fn with_address(mut self, address: &str) -> Self {
+        self.address = address.to_string();
+        self
+    }
+
+    /// Sets the object identifier and description the session is opened
+    /// with.
+    pub fn with_description(mut self, id: Vec<u32>, description: &str) -> Self {
+        self.id = id;
+        self.description = description.to_string();
+        self
+    }
+
+    /// Sets how long the master waits for this subagent, in whole seconds;
+    /// zero leaves it to the master.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how long to wait before connecting again after losing the
+    /// master, 5 seconds by default.
+    pub fn with_reconnect_interval(mut self, interval: Duration) -> Self {
+        self.reconnect_interval = interval;
+        self
+    }
+
+    pub fn with_registration(mut self, registration: Registration) -> Self {
+        self.registrations.push(registration);
+        self
+    }
+
+    /// Sends a notification through the master: `snmpTrapOID.0` set to
+    /// `trap_oid`, followed by `varbinds`.
+    pub fn notify(&self, trap_oid: &[u32], varbinds: &[Varbind]) -> Result<()> {
+        let connection = self
+            .state
+            .connection
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow!("Not connected to the AgentX master"))?;
+
+        let mut notification = vec![Varbind {
+            oid: SNMP_TRAP_OID.to_vec(),
+            value: SnmpValue::ObjectIdentifier(trap_oid.to_vec()),
+        }];
+        notification.extend_from_slice(varbinds);
+
+        let packet_id = self.state.next_id();
+        let (tx, rx) = mpsc::channel();
+        connection.pending.lock().unwrap().insert(packet_id, tx);
+        let pdu = Pdu::new(
+            connection.session_id,
+            0,
+            packet_id,
+            Payload::Notify(notification),
+        );
+        let sent = connection
+            .writer
+            .lock()
+            .unwrap()
+            .write_all(&pdu.encode())
+            .is_ok();
+        let timeout = if self.timeout.is_zero() {
+            Duration::from_secs(5)
+        } else {
+            self.timeout
+        };
+        let response = if sent {
+            rx.recv_timeout(timeout).ok()
+        } else {
+            None
+        };
+        connection.pending.lock().unwrap().remove(&packet_id);
+
+        match response {
+            Some(Payload::Response { error: 0, .. }) => Ok(()),
+            Some(Payload::Response { error, .. }) => {
+                bail!(
+                    "The AgentX master refused the notification: error {}",
+                    error
+                )
+            }
+            _ => bail!("No response from the AgentX master"),
+        }
+    }
+
+    /// Serves the master, connecting again whenever the connection is
+    /// lost. Only returns if the master refuses one of the registrations.
+    pub fn run(&self) -> Result<()> {
+        let address = Address::parse(&self.address);
+        loop {
+            match self.session(&address) {
+                Err(e) if e.is::<Refused>() => return Err(e),
+                Err(e) => println!("AgentX session with {} ended: {}", self.address, e),
+                Ok(()) => {}
+            }
+            *self.state.connection.lock().unwrap() = None;
+            thread::sleep(self.reconnect_interval);
+        }
+    }
+
+    // Run the subagent in a separate thread
+    pub fn run_in_thread(self) -> thread::JoinHandle<Result<()>> {
+        thread::spawn(move || self.run())
+    }
+
+    // Open a session, register and serve requests until the connection ends
+    fn session(&self, address: &Address) -> Result<()> {
+        let (mut reader, mut writer) = address.connect()?;
+
+        let open = Payload::Open {
+            timeout: seconds(self.timeout),
+            id: self.id.clone(),
+            description: self.description.clone().into_bytes(),
+        };
+        let (session_id, error) = self.call(&mut reader, &mut writer, 0, open)?;
+        if error != 0 {
+            bail!("The master refused the session: error {}", error);
+        }
+
+        for registration in &self.registrations {
+            let register = Payload::Register {
+                timeout: seconds(registration.timeout),
+                priority: registration.priority,
+                subtree: registration.subtree.clone(),
+                range: registration.range,
+            };
+            let (_, error) = self.call(&mut reader, &mut writer, session_id, register)?;
+            if error != 0 {
+                return Err(Refused {
+                    subtree: registration.subtree.clone(),
+                    error,
+                }
+                .into());
+            }
+        }
+        println!("AgentX session {} open with {}", session_id, self.address);
+
+        let connection = Arc::new(Connection {
+            session_id,
+            writer: Mutex::new(writer),
+            pending: Mutex::new(HashMap::new()),
+        });
+        *self.state.connection.lock().unwrap() = Some(connection.clone());
+
+        let mut transactions = HashMap::new();
+        loop {
+            let frame = pdu::read_frame(&mut reader)?
+                .ok_or_else(|| anyhow!("Connection closed by the master"))?;
+            let header = pdu::decode_header(&frame)?;
+            let response = match Pdu::decode(&frame) {
+                Ok(Pdu {
+                    payload: payload @ Payload::Response { .. },
+                    packet_id,
+                    ..
+                }) => {
+                    let waiter = connection.pending.lock().unwrap().remove(&packet_id);
+                    if let Some(waiter) = waiter {
+                        let _ = waiter.send(payload);
+                    }
+                    None
+                }
+                Ok(Pdu {
+                    payload: Payload::Close { reason },
+                    ..
+                }) => bail!("Session closed by the master, reason {}", reason),
+                Ok(request) => self.handle(&mut transactions, request),
+                Err(e) => {
+                    println!("Malformed AgentX PDU: {}", e);
+                    Some(Pdu::response(
+                        &header,
+                        0,
+                        pdu::ERROR_PARSE_ERROR,
+                        0,
+                        Vec::new(),
+                    ))
+                }
+            };
+            if let Some(response) = response {
+                connection
+                    .writer
+                    .lock()
+                    .unwrap()
+                    .write_all(&response.encode())?;
+            }
+        }
+    }
+
+    // Send a PDU while the session is being set up and wait for the
+    // response, returning its session ID and error
+    fn call(
+        &self,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+        session_id: u32,
+        payload: Payload,
+    ) -> Result<(u32, u16)> {
+        let packet_id = self.state.next_id();
+        writer.write_all(&Pdu::new(session_id, 0, packet_id, payload).encode())?;
+        loop {
+            let frame = pdu::read_frame(reader)?
+                .ok_or_else(|| anyhow!("Connection closed by the master"))?;
+            let response = Pdu::decode(&frame)?;
+            if let Payload::Response { error, .. } = response.payload
+                && response.packet_id == packet_id
+            {
+                return Ok((response.session_id, error));
+            }
+        }
+    }
+
+    // Answer a request from the master; CleanupSet gets no response
+    fn handle(&self, transactions: &mut HashMap<u32, Transaction>, request: Pdu) -> Option<Pdu> {
+        let header: Header = request.header();
+        let result = match request.payload {
+            Payload::Get(ranges) => ranges
+                .iter()
+                .enumerate()
+                .map(|(i, range)| {
+                    self.handler
+                        .get(&range.start)
+                        .map(|value| Varbind {
+                            oid: range.start.clone(),
+                            value,
+                        })
+                        .map_err(|status| (status, i))
+                })
+                .collect(),
+            Payload::GetNext(ranges) => ranges
+                .iter()
+                .enumerate()
+                .map(|(i, range)| self.next_in_range(range).map_err(|status| (status, i)))
+                .collect(),
+            Payload::GetBulk {
+                non_repeaters,
+                max_repetitions,
+                ranges,
+            } => self.get_bulk(non_repeaters as usize, max_repetitions as usize, ranges),
+            Payload::TestSet(varbinds) => {
+                let result = varbinds.iter().enumerate().try_for_each(|(i, varbind)| {
+                    self.handler.test_set(varbind).map_err(|status| (status, i))
+                });
+                transactions.insert(
+                    request.transaction_id,
+                    Transaction {
+                        varbinds,
+                        previous: Vec::new(),
+                    },
+                );
+                result.map(|()| Vec::new())
+            }
+            Payload::CommitSet => {
+                let transaction = transactions.entry(request.transaction_id).or_default();
+                self.commit(transaction).map(|()| Vec::new())
+            }
+            Payload::UndoSet => {
+                let transaction = transactions.entry(request.transaction_id).or_default();
+                self.undo(transaction).map(|()| Vec::new())
+            }
+            Payload::CleanupSet => {
+                transactions.remove(&request.transaction_id);
+                return None;
+            }
+            _ => {
+                return Some(Pdu::response(
+                    &header,
+                    0,
+                    pdu::ERROR_PROCESSING_ERROR,
+                    0,
+                    Vec::new(),
+                ));
+            }
+        };
+
+        Some(match result {
+            Ok(varbinds) => Pdu::response(&header, 0, 0, 0, varbinds),
+            Err((status, i)) => {
+                Pdu::response(&header, 0, status.code() as u16, i as u16 + 1, Vec::new())
+            }
+        })
+    }
+
+    // The first object in `range`, or endOfMibView named after its start
+    fn next_in_range(&self, range: &SearchRange) -> Result<Varbind, ErrorStatus> {
+        if range.include {
+            let value = self.handler.get(&range.start)?;
+            if !matches!(
+                value,
+                SnmpValue::NoSuchObject | SnmpValue::NoSuchInstance | SnmpValue::EndOfMibView
+            ) {
+                return Ok(Varbind {
+                    oid: range.start.clone(),
+                    value,
+                });
+            }
+        }
+
+        Ok(match self.handler.get_next(&range.start)? {
+            Some(varbind) if range.end.is_empty() || varbind.oid < range.end => varbind,
+            _ => Varbind {
+                oid: range.start.clone(),
+                value: SnmpValue::EndOfMibView,
+            },
+        })
+    }
+
+    // Answer a GetBulk as an SNMP agent would, the repeaters interleaved
+    fn get_bulk(
+        &self,
+        non_repeaters: usize,
+        max_repetitions: usize,
+        ranges: Vec<SearchRange>,
+    ) -> Result<Vec<Varbind>, (ErrorStatus, usize)> {
+        let non_repeaters = non_repeaters.min(ranges.len());
+        let mut varbinds = Vec::new();
+        for (i, range) in ranges[..non_repeaters].iter().enumerate() {
+            varbinds.push(self.next_in_range(range).map_err(|status| (status, i))?);
+        }
+
+        let mut repeaters = ranges[non_repeaters..].to_vec();
+        for _ in 0..max_repetitions {
+            let mut ended = true;
+            for (i, range) in repeaters.iter_mut().enumerate() {
+                let varbind = self
+                    .next_in_range(range)
+                    .map_err(|status| (status, non_repeaters + i))?;
+                if !matches!(varbind.value, SnmpValue::EndOfMibView) {
+                    ended = false;
+                    range.start = varbind.oid.clone();
+                    range.include = false;
+                }
+                varbinds.push(varbind);
+            }
+            if ended {
+                break;
+            }
+        }
+        Ok(varbinds)
+    }
+
+    fn commit(&self, transaction: &mut Transaction) -> Result<(), (ErrorStatus, usize)> {
+        for (i, varbind) in transaction.varbinds.iter().enumerate() {
+            // Without the previous value the set could not be undone
+            let previous = match self.handler.get(&varbind.oid) {
+                Ok(SnmpValue::NoSuchObject | SnmpValue::NoSuchInstance) => None,
+                Ok(value) => Some(value),
+                Err(_) => return Err((ErrorStatus::CommitFailed, i)),
+            };
+            if self.handler.set(varbind).is_err() {
+                return Err((ErrorStatus::CommitFailed, i));
+            }
+            transaction.previous.push((varbind.oid.clone(), previous));
+        }
+        Ok(())
+    }
+
+    fn undo(&self, transaction: &mut Transaction) -> Result<(), (ErrorStatus, usize)> {
+        let mut result = Ok(());
+        for (oid, previous) in transaction.previous.drain(..).rev() {
+            let undone = match previous {
+                Some(value) => self.handler.set(&Varbind { oid, value }),
+                None => self.handler.remove(&oid),
+            };
+            if undone.is_err() {
+                result = Err((ErrorStatus::UndoFailed, 0));
+            }
+        }
+        result
+    }
+}
+
+impl SubagentState {
+    fn next_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+// A timeout as sent in AgentX PDUs, whole seconds up to 255
+fn seconds(timeout: Duration) -> u8 {
+    timeout.as_secs().min(u8::MAX as u64) as u8
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::agent::AgentCore;
+    use crate::agentx::AgentxMaster;
+    use crate::client;
+    use crate::snmp::{self, PduType, SnmpPdu};
+    use std::collections::BTreeMap;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    const SUBTREE: [u32; 8] = [1, 3, 6, 1, 4, 1, 99, 1];
+    // A local object after the subtree
+    const LOCAL: [u32; 9] = [1, 3, 6, 1, 4, 1, 99, 2, 0];
+
+    type Values = Arc<Mutex<BTreeMap<Vec<u32>, SnmpValue>>>;
+
+    // Objects below SUBTREE, any of which may be set or created. -1 fails
+    // the test and 13 passes it but fails to store.
+    struct Store {
+        values: Values,
+    }
+
+    impl SubagentHandler for Store {
+        fn get(&self, oid: &[u32]) -> Result<SnmpValue, ErrorStatus> {
+            let values = self.values.lock().unwrap();
+            Ok(values
+                .get(oid)
+                .cloned()
+                .unwrap_or(SnmpValue::NoSuchInstance))
+        }
+
+        fn get_next(&self, oid: &[u32]) -> Result<Option<Varbind>, ErrorStatus> {
+            let values = self.values.lock().unwrap();
+            Ok(values
+                .iter()
+                .find(|(next, _)| next.as_slice() > oid)
+                .map(|(oid, value)| Varbind {
+                    oid: oid.clone(),
+                    value: value.clone(),
+                }))
+        }
+
+        fn test_set(&self, varbind: &Varbind) -> Result<(), ErrorStatus> {
+            match varbind.value {
+                SnmpValue::Integer(-1) => Err(ErrorStatus::WrongValue),
+                _ => Ok(()),
+            }
+        }
+
+        fn set(&self, varbind: &Varbind) -> Result<(), ErrorStatus> {
+            if matches!(varbind.value, SnmpValue::Integer(13)) {
+                return Err(ErrorStatus::ResourceUnavailable);
+            }
+            let mut values = self.values.lock().unwrap();
+            values.insert(varbind.oid.clone(), varbind.value.clone());
+            Ok(())
+        }
+
+        fn remove(&self, oid: &[u32]) -> Result<(), ErrorStatus> {
+            self.values.lock().unwrap().remove(oid);
+            Ok(())
+        }
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("subagent-{}-{}", name, std::process::id()))
+    }
+
+    fn object(index: u32) -> Vec<u32> {
+        [&SUBTREE[..], &[index]].concat()
+    }
+
+    // A subagent serving objects 1 to 3 of SUBTREE for an agent that
+    // holds LOCAL itself, once the subagent has registered
+    fn start(name: &str, master: AgentxMaster) -> (AgentCore, Subagent, Values) {
+        let path = socket_path(name);
+        master.listen(path.to_str().unwrap()).unwrap();
+        let core = AgentCore::new(vec!["public".to_string()]).with_agentx(master);
+        core.register_oid(LOCAL.to_vec(), SnmpValue::Integer(7))
+            .unwrap();
+
+        let values: Values = Arc::new(Mutex::new(
+            (1..=3)
+                .map(|i| (object(i), SnmpValue::Integer(i as i32)))
+                .collect(),
+        ));
+        let subagent = Subagent::new(Store {
+            values: values.clone(),
+        })
+        .with_address(path.to_str().unwrap())
+        .with_registration(Registration::new(SUBTREE.to_vec()));
+        subagent.clone().run_in_thread();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !matches!(get(&core, &object(1)), SnmpValue::Integer(1)) {
+            assert!(Instant::now() < deadline, "the subagent did not register");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _ = std::fs::remove_file(&path);
+        (core, subagent, values)
+    }
+
+    fn request(
+        core: &AgentCore,
+        pdu_type: PduType,
+        varbinds: &[Varbind],
+        bulk: Option<(i32, i32)>,
+    ) -> SnmpPdu {
+        let request =
+            client::encode_request(snmp::SNMP_VERSION_2C, "public", 1, pdu_type, varbinds, bulk);
+        let response = core.handle_datagram(&request).unwrap();
+        snmp::decode_snmp_message(&response).unwrap().pdu
+    }
+
+    fn get(core: &AgentCore, oid: &[u32]) -> SnmpValue {
+        let response = request(
+            core,
+            PduType::GET_REQUEST,
+            &client::null_varbinds(&[oid]),
+            None,
+        );
+        response.varbinds[0].value.clone()
+    }
+
+    fn set(core: &AgentCore, varbinds: &[(Vec<u32>, i32)]) -> SnmpPdu {
+        let varbinds: Vec<Varbind> = varbinds
+            .iter()
+            .map(|(oid, value)| Varbind {
+                oid: oid.clone(),
+                value: SnmpValue::Integer(*value),
+            })
+            .collect();
+        request(core, PduType::SET_REQUEST, &varbinds, None)
+    }
+
+    fn oids(response: &SnmpPdu) -> Vec<&[u32]> {
+        response.varbinds.iter().map(|v| v.oid.as_slice()).collect()
+    }
+
+    #[test]
+    fn answers_requests_within_the_search_range() {
+        let (core, _, _) = start("get", AgentxMaster::new());
+
+        assert!(matches!(get(&core, &object(2)), SnmpValue::Integer(2)));
+        assert!(matches!(get(&core, &object(9)), SnmpValue::NoSuchInstance));
+
+        let next = |oid: &[u32]| {
+            let response = request(
+                &core,
+                PduType::GET_NEXT_REQUEST,
+                &client::null_varbinds(&[oid]),
+                None,
+            );
+            response.varbinds[0].oid.clone()
+        };
+        assert_eq!(next(&SUBTREE), object(1));
+        assert_eq!(next(&object(1)), object(2));
+        // The subagent's range ends with its subtree
+        assert_eq!(next(&object(3)), LOCAL);
+
+        let response = request(
+            &core,
+            PduType::GET_BULK_REQUEST,
+            &client::null_varbinds(&[&SUBTREE]),
+            Some((0, 10)),
+        );
+        assert_eq!(
+            oids(&response)[..4],
+            [&object(1)[..], &object(2), &object(3), &LOCAL]
+        );
+        assert!(matches!(
+            response.varbinds[4].value,
+            SnmpValue::EndOfMibView
+        ));
+    }
+
+    #[test]
+    fn sets_and_undoes_a_failed_set() {
+        let (core, _, values) = start("set", AgentxMaster::new());
+
+        let response = set(&core, &[(object(1), 5)]);
+        assert_eq!(response.error_status, ErrorStatus::NoError);
+        assert!(matches!(get(&core, &object(1)), SnmpValue::Integer(5)));
+
+        let response = set(&core, &[(object(2), 20), (object(3), -1)]);
+        assert_eq!(response.error_status, ErrorStatus::WrongValue);
+        assert_eq!(response.error_index, 2);
+        assert!(matches!(get(&core, &object(2)), SnmpValue::Integer(2)));
+
+        // The object the request created goes again when it fails
+        let response = set(&core, &[(object(9), 9), (object(2), 20), (object(3), 13)]);
+        assert_eq!(response.error_status, ErrorStatus::CommitFailed);
+        assert_eq!(response.error_index, 3);
+        let values = values.lock().unwrap();
+        assert_eq!(values.len(), 3);
+        assert!(matches!(values[&object(2)], SnmpValue::Integer(2)));
+        assert!(matches!(values[&object(3)], SnmpValue::Integer(3)));
+    }
+
+    #[test]
+    fn notifies_through_the_master() {
+        let unconnected = Subagent::new(Store {
+            values: Values::default(),
+        });
+        assert!(
+            unconnected
+                .notify(&[1, 3, 6, 1, 4, 1, 99, 0, 1], &[])
+                .is_err()
+        );
+
+        let (tx, rx) = mpsc::channel();
+        let master = AgentxMaster::new().with_notification_handler(move |varbinds| {
+            tx.send(varbinds.to_vec()).unwrap();
+        });
+        let (_, subagent, _) = start("notify", master);
+
+        let varbind = Varbind {
+            oid: object(1),
+            value: SnmpValue::Integer(1),
+        };
+        subagent
+            .notify(&[1, 3, 6, 1, 4, 1, 99, 0, 1], &[varbind])
+            .unwrap();
+        let notification = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(notification.len(), 2);
+        assert_eq!(notification[0].oid, SNMP_TRAP_OID);
+        assert!(matches!(
+            &notification[0].value,
+            SnmpValue::ObjectIdentifier(oid) if oid[..] == [1, 3, 6, 1, 4, 1, 99, 0, 1]
+        ));
+        assert_eq!(notification[1].oid, object(1));
+    }
+
+    // A master that answers the subagent's next PDU with `error`,
+    // returning that PDU
+    fn answer(stream: &mut UnixStream, error: u16) -> Payload {
+        let frame = pdu::read_frame(stream).unwrap().unwrap();
+        let request = Pdu::decode(&frame).unwrap();
+        let response = Pdu::response(&request.header(), 0, error, 0, Vec::new());
+        stream.write_all(&response.encode()).unwrap();
+        request.payload
+    }
+
+    #[test]
+    fn registers_again_after_the_master_closes() {
+        let path = socket_path("reconnect");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        Subagent::new(Store {
+            values: Values::default(),
+        })
+        .with_address(path.to_str().unwrap())
+        .with_reconnect_interval(Duration::from_millis(10))
+        .with_registration(Registration::new(SUBTREE.to_vec()))
+        .run_in_thread();
+
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert!(matches!(answer(&mut stream, 0), Payload::Open { .. }));
+            assert!(matches!(
+                answer(&mut stream, 0),
+                Payload::Register { subtree, .. } if subtree == SUBTREE
+            ));
+            // Dropping the stream closes the connection
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn stops_when_a_registration_is_refused() {
+        let path = socket_path("refused");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let subagent = Subagent::new(Store {
+            values: Values::default(),
+        })
+        .with_address(path.to_str().unwrap())
+        .with_reconnect_interval(Duration::from_millis(10))
+        .with_registration(Registration::new(SUBTREE.to_vec()))
+        .run_in_thread();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        answer(&mut stream, 0);
+        answer(&mut stream, pdu::ERROR_DUPLICATE_REGISTRATION);
+        let error = subagent.join().unwrap().unwrap_err();
+        assert!(error.is::<Refused>(), "{}", error);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,10 +1,8 @@
 use crate::asn1::encode;
-use anyhow::{Context, Result, anyhow};
-use bytes::{Buf, Bytes, BytesMut};
+use anyhow::{Result, anyhow};
+use bytes::{Buf, Bytes};
 use std::error::Error;
 use std::fmt;
-use std::io::Read;
-use std::ops::Index;
 
 #[derive(Debug)]
 pub enum Asn1Error {
@@ -80,6 +78,7 @@ pub fn decode_sequence(buf: &mut Bytes) -> Result<Bytes> {
         && tag != encode::GET_NEXT_REQUEST_TAG
         && tag != encode::GET_RESPONSE_TAG
         && tag != encode::SET_REQUEST_TAG
+        && tag != encode::GET_BULK_REQUEST_TAG
     {
         return Err(anyhow!("Expected SEQUENCE tag, got {}", tag));
     }
@@ -127,10 +126,45 @@ pub fn decode_integer(buf: &mut Bytes) -> Result<i32> {
     Ok(value)
 }
 
+/// Decodes an unsigned integer (Counter32, Gauge32, TimeTicks, Counter64) with the given tag
+pub fn decode_unsigned(buf: &mut Bytes, expected_tag: u8) -> Result<u64> {
+    let tag = decode_tag(buf)?;
+    if tag != expected_tag {
+        return Err(anyhow!("Expected tag {}, got {}", expected_tag, tag));
+    }
+
+    let length = decode_length(buf)?;
+
+    if length == 0 || length > 9 {
+        return Err(anyhow!("Invalid unsigned integer length: {} bytes", length));
+    }
+
+    if buf.remaining() < length {
+        return Err(anyhow!("Buffer underflow when decoding unsigned integer content"));
+    }
+
+    let mut value: u64 = 0;
+    for i in 0..length {
+        let byte = buf.get_u8();
+        // A 9th byte is only allowed as the leading zero of a 64-bit value
+        if length == 9 && i == 0 && byte != 0 {
+            return Err(anyhow!("Unsigned integer does not fit in 64 bits"));
+        }
+        value = (value << 8) | byte as u64;
+    }
+
+    Ok(value)
+}
+
 pub fn decode_octet_string(buf: &mut Bytes) -> Result<Vec<u8>> {
+    decode_tagged_octets(buf, encode::OCTET_STRING_TAG)
+}
+
+/// Decodes raw content octets (OCTET STRING, IpAddress, Opaque) with the given tag
+pub fn decode_tagged_octets(buf: &mut Bytes, expected_tag: u8) -> Result<Vec<u8>> {
     let tag = decode_tag(buf)?;
-    if tag != encode::OCTET_STRING_TAG {
-        return Err(anyhow!("Expected OCTET STRING tag, got {}", tag));
+    if tag != expected_tag {
+        return Err(anyhow!("Expected tag {}, got {}", expected_tag, tag));
     }
 
     let length = decode_length(buf)?;
@@ -161,6 +195,24 @@ pub fn decode_null(buf: &mut Bytes) -> Result<()> {
     Ok(())
 }
 
+/// Decodes one of the SNMPv2 exception values (noSuchObject, noSuchInstance, endOfMibView)
+pub fn decode_exception(buf: &mut Bytes) -> Result<u8> {
+    let tag = decode_tag(buf)?;
+    if tag != encode::NO_SUCH_OBJECT_TAG
+        && tag != encode::NO_SUCH_INSTANCE_TAG
+        && tag != encode::END_OF_MIB_VIEW_TAG
+    {
+        return Err(anyhow!("Expected exception tag, got {}", tag));
+    }
+
+    let length = decode_length(buf)?;
+    if length != 0 {
+        return Err(anyhow!("Exception value should have zero length, got {}", length));
+    }
+
+    Ok(tag)
+}
+
 // Decode an OBJECT IDENTIFIER
 pub fn decode_oid(buf: &mut Bytes) -> Result<Vec<u32>> {
     let tag = decode_tag(buf)?;
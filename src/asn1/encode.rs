@@ -6,13 +6,25 @@ pub const OCTET_STRING_TAG: u8 = 0x04;
 pub const NULL_TAG: u8 = 0x05;
 pub const OBJECT_IDENTIFIER_TAG: u8 = 0x06;
 pub const SEQUENCE_TAG: u8 = 0x33;
+// SMIv2 application types (RFC 2578)
+pub const IP_ADDRESS_TAG: u8 = 0x40;
+pub const COUNTER32_TAG: u8 = 0x41;
+pub const GAUGE32_TAG: u8 = 0x42;
+pub const TIMETICKS_TAG: u8 = 0x43;
+pub const OPAQUE_TAG: u8 = 0x44;
+pub const COUNTER64_TAG: u8 = 0x46;
 pub const GET_REQUEST_TAG: u8 = 0xA0;
 pub const GET_RESPONSE_TAG: u8 = 0xA2;
 pub const GET_NEXT_REQUEST_TAG: u8 = 0xA1;
 pub const SET_REQUEST_TAG: u8 = 0xA3;
+pub const GET_BULK_REQUEST_TAG: u8 = 0xA5;
+// SNMPv2 exception values (context-specific, primitive)
+pub const NO_SUCH_OBJECT_TAG: u8 = 0x80;
+pub const NO_SUCH_INSTANCE_TAG: u8 = 0x81;
+pub const END_OF_MIB_VIEW_TAG: u8 = 0x82;
 // use Definite Form
 fn encode_length(len: usize, buf: &mut BytesMut) {
-    if len <= 128 {
+    if len < 128 {
         //short form - one byte
         buf.put_u8(len as u8);
     } else {
@@ -39,7 +51,7 @@ pub fn encode_integer(value: i32, buf: &mut BytesMut) {
     let mut temp = value;
     let mut len = 1;
 
-    while (temp > 127) || (temp < -128) {
+    while !(-128..=127).contains(&temp) {
         temp >>= 8;
         len += 1;
     }
@@ -52,8 +64,32 @@ pub fn encode_integer(value: i32, buf: &mut BytesMut) {
     }
 }
 
+/// Encodes an unsigned integer (Counter32, Gauge32, TimeTicks, Counter64) with the given tag
+pub fn encode_unsigned(tag: u8, value: u64, buf: &mut BytesMut) {
+    buf.put_u8(tag);
+
+    let bytes = value.to_be_bytes();
+    let mut start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len() - 1);
+
+    // Keep a leading zero byte so the value is not read back as negative
+    let pad = bytes[start] & 0x80 != 0;
+    encode_length(bytes.len() - start + pad as usize, buf);
+    if pad {
+        buf.put_u8(0x00);
+    }
+    while start < bytes.len() {
+        buf.put_u8(bytes[start]);
+        start += 1;
+    }
+}
+
 pub fn encode_octet_string(data: &[u8], buf: &mut BytesMut) {
-    buf.put_u8(OCTET_STRING_TAG);
+    encode_tagged_octets(OCTET_STRING_TAG, data, buf);
+}
+
+/// Encodes raw content octets (OCTET STRING, IpAddress, Opaque) with the given tag
+pub fn encode_tagged_octets(tag: u8, data: &[u8], buf: &mut BytesMut) {
+    buf.put_u8(tag);
     encode_length(data.len(), buf);
     buf.put_slice(data);
 }
@@ -63,6 +99,12 @@ pub fn encode_null(buf: &mut BytesMut) {
     buf.put_u8(0x00);
 }
 
+/// Encodes one of the SNMPv2 exception values, which like NULL carry no content.
+pub fn encode_exception(tag: u8, buf: &mut BytesMut) {
+    buf.put_u8(tag);
+    buf.put_u8(0x00);
+}
+
 /// Encodes content as an ASN.1 sequence with the given tag.
 /// 
 /// This function writes a tag byte, encodes the length of the content,
@@ -112,7 +154,6 @@ pub fn encode_oid(oid: &[u32], buf: &mut BytesMut) {
             }
         }
     }
-    //TODO:check if always correct
-    encode_length(oid.len() - 1, buf);
+    encode_length(oid_buf.len(), buf);
     buf.put_slice(&oid_buf);
 }
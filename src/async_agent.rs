@@ -0,0 +1,289 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::net::UdpSocket;
+use tokio::runtime::Handle;
+
+use crate::agent::{AgentCore, Handler};
+use crate::agentx::AgentxMaster;
+use crate::community::Community;
+#[cfg(feature = "if-mib")]
+use crate::if_mib::IfMib;
+use crate::mib::ObjectType;
+use crate::snmp::{ErrorStatus, SnmpValue, Varbind};
+use crate::system::SystemGroup;
+use crate::table::Table;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Serves a subtree of the MIB whose values are produced asynchronously,
+/// e.g. by querying a database or another service.
+///
+/// The async counterpart of [`crate::agent::MibHandler`], with the same
+/// methods and defaults: a handler registered at a prefix answers for
+/// every OID under it, shadowing values registered with
+/// [`AsyncSnmpAgent::register_oid`].
+pub trait AsyncMibHandler: Send + Sync {
+    /// Returns the value of `oid`, or `None` if there is no such instance.
+    fn get<'a>(&'a self, oid: &'a [u32]) -> BoxFuture<'a, Option<SnmpValue>>;
+
+    /// Returns the first varbind of the handler's subtree that follows
+    /// `oid` in lexicographical order. `oid` may lie before the subtree.
+    fn get_next<'a>(&'a self, oid: &'a [u32]) -> BoxFuture<'a, Option<Varbind>>;
+
+    /// Checks a value before any varbind of the SetRequest is stored; the
+    /// default accepts everything and leaves errors to
+    /// [`AsyncMibHandler::set`].
+    fn test_set<'a>(
+        &'a self,
+        oid: &'a [u32],
+        value: &'a SnmpValue,
+    ) -> BoxFuture<'a, Result<(), ErrorStatus>> {
+        let _ = (oid, value);
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Stores a value; the default rejects every write with notWritable.
+    fn set<'a>(
+        &'a self,
+        oid: &'a [u32],
+        value: &'a SnmpValue,
+    ) -> BoxFuture<'a, Result<(), ErrorStatus>> {
+        let _ = (oid, value);
+        Box::pin(async { Err(ErrorStatus::NotWritable) })
+    }
+
+    /// See [`crate::agent::MibHandler::test_set_all`].
+    fn test_set_all<'a>(
+        &'a self,
+        varbinds: &'a [Varbind],
+    ) -> BoxFuture<'a, Result<(), (ErrorStatus, usize)>> {
+        Box::pin(async move {
+            for (i, varbind) in varbinds.iter().enumerate() {
+                self.test_set(&varbind.oid, &varbind.value)
+                    .await
+                    .map_err(|status| (status, i))?;
+            }
+            Ok(())
+        })
+    }
+
+    /// See [`crate::agent::MibHandler::set_all`].
+    fn set_all<'a>(
+        &'a self,
+        varbinds: &'a [Varbind],
+    ) -> BoxFuture<'a, Result<(), (ErrorStatus, usize)>> {
+        Box::pin(async move {
+            for (i, varbind) in varbinds.iter().enumerate() {
+                self.set(&varbind.oid, &varbind.value)
+                    .await
+                    .map_err(|status| (status, i))?;
+            }
+            Ok(())
+        })
+    }
+
+    /// See [`crate::agent::MibHandler::previous_values`].
+    fn previous_values<'a>(
+        &'a self,
+        varbinds: &'a [Varbind],
+    ) -> BoxFuture<'a, Vec<Option<SnmpValue>>> {
+        Box::pin(async move {
+            let mut previous = Vec::with_capacity(varbinds.len());
+            for varbind in varbinds {
+                previous.push(self.get(&varbind.oid).await);
+            }
+            previous
+        })
+    }
+
+    /// See [`crate::agent::MibHandler::undo_set_all`].
+    fn undo_set_all<'a>(
+        &'a self,
+        varbinds: &'a [Varbind],
+        previous: &'a [Option<SnmpValue>],
+    ) -> BoxFuture<'a, Result<(), ErrorStatus>> {
+        Box::pin(async move {
+            let mut result = Ok(());
+            for (varbind, value) in varbinds.iter().zip(previous).rev() {
+                let restored = match value {
+                    Some(value) => self.set(&varbind.oid, value).await,
+                    None => Err(ErrorStatus::UndoFailed),
+                };
+                if restored.is_err() {
+                    result = Err(ErrorStatus::UndoFailed);
+                }
+            }
+            result
+        })
+    }
+}
+
+/// SNMP agent serving an [`AgentCore`] on tokio.
+///
+/// Each datagram is processed in its own task, so a slow handler does not
+/// hold up other requests. Everything but the socket loop is shared with
+/// the synchronous [`crate::agent::SnmpAgent`].
+pub struct AsyncSnmpAgent {
+    socket: Arc<UdpSocket>,
+    core: AgentCore,
+}
+
+impl AsyncSnmpAgent {
+    pub async fn new(addr: &str, communities: Vec<String>) -> Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .context("Failed to bind UDP socket")?;
+
+        Ok(Self {
+            socket: Arc::new(socket),
+            core: AgentCore::new(communities),
+        })
+    }
+
+    /// See [`AgentCore::with_max_message_size`].
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.core = self.core.with_max_message_size(max_message_size);
+        self
+    }
+
+    /// See [`AgentCore::with_agentx`]. Requests for subagents are
+    /// processed on tokio's blocking threads.
+    pub fn with_agentx(mut self, master: AgentxMaster) -> Self {
+        self.core = self.core.with_agentx(master);
+        self
+    }
+
+    /// See [`AgentCore::with_community`].
+    pub fn with_community(mut self, community: Community) -> Self {
+        self.core = self.core.with_community(community);
+        self
+    }
+
+    pub fn register_oid(&self, oid: Vec<u32>, value: SnmpValue) -> Result<()> {
+        self.core.register_oid(oid, value)
+    }
+
+    /// See [`AgentCore::register_object`].
+    pub fn register_object(
+        &self,
+        oid: Vec<u32>,
+        value: SnmpValue,
+        object: ObjectType,
+    ) -> Result<()> {
+        self.core.register_object(oid, value, object)
+    }
+
+    /// Hands the subtree under `prefix` to `handler`. When prefixes nest,
+    /// the longest one matching an OID wins.
+    pub fn register_handler(
+        &self,
+        prefix: Vec<u32>,
+        handler: Arc<dyn AsyncMibHandler>,
+    ) -> Result<()> {
+        self.core.add_handler(prefix, Handler::Async(handler))
+    }
+
+    /// See [`AgentCore::register_scalar`].
+    pub fn register_scalar(
+        &self,
+        oid: Vec<u32>,
+        value: impl Fn() -> SnmpValue + Send + Sync + 'static,
+    ) -> Result<()> {
+        self.core.register_scalar(oid, value)
+    }
+
+    /// See [`AgentCore::register_table`].
+    pub fn register_table(&self, table: Table) -> Result<()> {
+        self.core.register_table(table)
+    }
+
+    /// See [`AgentCore::register_system`].
+    pub fn register_system(&self, system: SystemGroup) -> Result<()> {
+        self.core.register_system(system)
+    }
+
+    /// See [`AgentCore::register_capabilities`].
+    pub fn register_capabilities(&self, id: Vec<u32>, description: &str) {
+        self.core.register_capabilities(id, description)
+    }
+
+    /// See [`AgentCore::register_if_mib`].
+    #[cfg(feature = "if-mib")]
+    pub fn register_if_mib(&self, if_mib: IfMib) -> Result<()> {
+        self.core.register_if_mib(if_mib)
+    }
+
+    /// See [`AgentCore::register_oids`].
+    pub fn register_oids(&self, entries: impl IntoIterator<Item = (Vec<u32>, SnmpValue)>) {
+        self.core.register_oids(entries)
+    }
+
+    /// See [`AgentCore::unregister_subtree`].
+    pub fn unregister_subtree(&self, prefix: &[u32]) -> usize {
+        self.core.unregister_subtree(prefix)
+    }
+
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr> {
+        self.socket
+            .local_addr()
+            .context("Failed to get local address")
+    }
+
+    // Run the SNMP agent
+    pub async fn run(&self) -> Result<()> {
+        println!("SNMP agent running on {}", self.local_addr()?);
+
+        let max_message_size = self.core.max_message_size();
+        let core = Arc::new(self.core.clone());
+        // One spare byte so that an oversized datagram shows up as too long
+        // instead of being silently truncated
+        let mut buf = vec![0u8; max_message_size + 1];
+
+        loop {
+            let (size, src_addr) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    println!("Error receiving data: {}", e);
+                    break;
+                }
+            };
+            if size > max_message_size {
+                println!(
+                    "Dropping message from {}: exceeds {} bytes",
+                    src_addr, max_message_size
+                );
+                continue;
+            }
+
+            let data = buf[..size].to_vec();
+            let socket = self.socket.clone();
+            let core = core.clone();
+            tokio::spawn(async move {
+                let source = Some(src_addr.ip());
+                let response = if core.is_agentx_master() {
+                    // Subagents are asked over blocking sockets, which must
+                    // not hold up the runtime's workers
+                    let runtime = Handle::current();
+                    tokio::task::spawn_blocking(move || {
+                        runtime.block_on(core.process(&data, source, None))
+                    })
+                    .await
+                    .ok()
+                    .flatten()
+                } else {
+                    core.process(&data, source, None).await
+                };
+                if let Some(response_buf) = response
+                    && let Err(e) = socket.send_to(&response_buf, src_addr).await
+                {
+                    println!("Failed to send SNMP response: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
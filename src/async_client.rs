@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::net::UdpSocket;
+use tokio::sync::{Notify, Semaphore, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::client::{self, RequestOptions, Response, ToTarget};
+use crate::session::{ClientSession, Transmit};
+use crate::snmp::{PduType, SnmpError, SnmpValue, Varbind};
+use crate::transport::TransportAddr;
+
+pub type AsyncResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Asynchronous SNMP client that multiplexes any number of concurrent
+/// requests over one UDP socket per address family.
+///
+/// A background task does the I/O of a [`ClientSession`], the same
+/// protocol state the blocking [`client::SnmpClient`] drives, and hands
+/// each finished request to the task waiting for it. Clones share the
+/// sockets, the session and the concurrency limit.
+///
+/// Host names in targets are resolved with the blocking resolver; use IP
+/// addresses when polling at scale.
+#[derive(Clone)]
+pub struct AsyncSnmpClient {
+    shared: Arc<Shared>,
+    options: RequestOptions,
+}
+
+struct Shared {
+    io: Arc<Io>,
+    driver: JoinHandle<()>,
+    // Replaced as a whole when the limit changes; requests already waiting
+    // keep the slots of the old one
+    in_flight: Mutex<Arc<Semaphore>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.driver.abort();
+    }
+}
+
+// What the requests and the driver task share
+struct Io {
+    socket_v4: UdpSocket,
+    socket_v6: Option<UdpSocket>,
+    state: Mutex<State>,
+    // Wakes the driver when a request has been started
+    wake: Notify,
+}
+
+struct State {
+    session: ClientSession,
+    waiters: HashMap<i32, oneshot::Sender<Result<Response, SnmpError>>>,
+}
+
+// Abandons a request when its future completes or is dropped
+struct PendingGuard {
+    io: Arc<Io>,
+    request_id: i32,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let mut state = self.io.state.lock().unwrap();
+        state.session.cancel(self.request_id);
+        state.waiters.remove(&self.request_id);
+    }
+}
+
+impl AsyncSnmpClient {
+    /// Binds the client sockets and starts the driver task; must be
+    /// called from within a tokio runtime.
+    pub async fn new() -> AsyncResult<Self> {
+        let socket_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        // IPv6 may be unavailable on the host, IPv4 targets still work then
+        let socket_v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+
+        let io = Arc::new(Io {
+            socket_v4,
+            socket_v6,
+            state: Mutex::new(State {
+                session: ClientSession::new(),
+                waiters: HashMap::new(),
+            }),
+            wake: Notify::new(),
+        });
+        let driver = tokio::spawn(drive(io.clone()));
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                io,
+                driver,
+                in_flight: Mutex::new(Arc::new(Semaphore::new(Semaphore::MAX_PERMITS))),
+            }),
+            options: RequestOptions::default(),
+        })
+    }
+
+    /// Sets the default timeout and retry settings used by every request.
+    pub fn with_options(mut self, options: RequestOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Selects the protocol version, [`crate::snmp::SNMP_VERSION_1`] or
+    /// [`crate::snmp::SNMP_VERSION_2C`], for this client and its clones.
+    pub fn with_version(self, version: u8) -> Self {
+        self.update_session(|session| session.with_version(version));
+        self
+    }
+
+    /// Sets the largest request the client will send and response it will
+    /// accept, for this client and its clones. A larger response fails the
+    /// request with [`SnmpError::MessageTooLarge`].
+    pub fn with_max_message_size(self, max_message_size: usize) -> Self {
+        self.update_session(|session| session.with_max_message_size(max_message_size));
+        self
+    }
+
+    /// Limits how many requests this client and its clones together may
+    /// have outstanding at once; further requests wait for a slot before
+    /// being sent.
+    pub fn with_max_in_flight(self, max_in_flight: usize) -> Self {
+        *self.shared.in_flight.lock().unwrap() = Arc::new(Semaphore::new(max_in_flight));
+        self
+    }
+
+    fn update_session(&self, update: impl FnOnce(ClientSession) -> ClientSession) {
+        let mut state = self.shared.io.state.lock().unwrap();
+        let session = std::mem::take(&mut state.session);
+        state.session = update(session);
+    }
+
+    pub async fn get(
+        &self,
+        target: impl ToTarget,
+        community: &str,
+        oids: &[&[u32]],
+    ) -> AsyncResult<Response> {
+        self.get_with_options(target, community, oids, &self.options)
+            .await
+    }
+
+    /// Like [`AsyncSnmpClient::get`], overriding the client's timeout and retry settings.
+    pub async fn get_with_options(
+        &self,
+        target: impl ToTarget,
+        community: &str,
+        oids: &[&[u32]],
+        options: &RequestOptions,
+    ) -> AsyncResult<Response> {
+        let target_addr = target.to_target_addr()?;
+        self.request(
+            target_addr,
+            community,
+            PduType::GET_REQUEST,
+            &client::null_varbinds(oids),
+            None,
+            options,
+        )
+        .await
+    }
+
+    pub async fn get_next(
+        &self,
+        target: impl ToTarget,
+        community: &str,
+        oids: &[&[u32]],
+    ) -> AsyncResult<Response> {
+        let target_addr = target.to_target_addr()?;
+        self.request(
+            target_addr,
+            community,
+            PduType::GET_NEXT_REQUEST,
+            &client::null_varbinds(oids),
+            None,
+            &self.options,
+        )
+        .await
+    }
+
+    /// Sends a GetBulk request (SNMPv2c only), see [`client::SnmpClient::get_bulk`].
+    pub async fn get_bulk(
+        &self,
+        target: impl ToTarget,
+        community: &str,
+        non_repeaters: i32,
+        max_repetitions: i32,
+        oids: &[&[u32]],
+    ) -> AsyncResult<Response> {
+        let target_addr = target.to_target_addr()?;
+        self.request(
+            target_addr,
+            community,
+            PduType::GET_BULK_REQUEST,
+            &client::null_varbinds(oids),
+            Some((non_repeaters, max_repetitions)),
+            &self.options,
+        )
+        .await
+    }
+
+    pub async fn set(
+        &self,
+        target: impl ToTarget,
+        community: &str,
+        varbinds: &[(&[u32], SnmpValue)],
+    ) -> AsyncResult<Response> {
+        let target_addr = target.to_target_addr()?;
+        let varbinds: Vec<Varbind> = varbinds
+            .iter()
+            .map(|(oid, value)| Varbind {
+                oid: oid.to_vec(),
+                value: value.clone(),
+            })
+            .collect();
+        self.request(
+            target_addr,
+            community,
+            PduType::SET_REQUEST,
+            &varbinds,
+            None,
+            &self.options,
+        )
+        .await
+    }
+
+    // Start a request in the session and wait for the driver task to hand
+    // over its outcome
+    async fn request(
+        &self,
+        target_addr: SocketAddr,
+        community: &str,
+        pdu_type: PduType,
+        varbinds: &[Varbind],
+        bulk: Option<(i32, i32)>,
+        options: &RequestOptions,
+    ) -> AsyncResult<Response> {
+        let in_flight = self.shared.in_flight.lock().unwrap().clone();
+        let _permit = in_flight.acquire().await?;
+        let io = &self.shared.io;
+        if target_addr.is_ipv6() && io.socket_v6.is_none() {
+            return Err("IPv6 is not available".into());
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let request_id = {
+            let mut state = io.state.lock().unwrap();
+            let request_id = state.session.start_request(
+                Instant::now(),
+                TransportAddr::Udp(target_addr),
+                community,
+                pdu_type,
+                varbinds,
+                bulk,
+                options,
+            )?;
+            state.waiters.insert(request_id, tx);
+            request_id
+        };
+        let _guard = PendingGuard {
+            io: io.clone(),
+            request_id,
+        };
+        io.wake.notify_one();
+
+        match rx.await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err("Client driver task stopped".into()),
+        }
+    }
+}
+
+// Do the session's I/O for every request: send what it queues, feed it
+// the datagrams received and its timeouts, and hand out finished requests
+async fn drive(io: Arc<Io>) {
+    let mut buf_v4 = Vec::new();
+    let mut buf_v6 = Vec::new();
+
+    loop {
+        let (transmits, deadline, max_message_size) = {
+            let mut state = io.state.lock().unwrap();
+            let State { session, waiters } = &mut *state;
+            while let Some((request_id, result)) = session.poll_response() {
+                if let Some(waiter) = waiters.remove(&request_id) {
+                    let _ = waiter.send(result);
+                }
+            }
+            let transmits: Vec<Transmit> = std::iter::from_fn(|| session.poll_transmit()).collect();
+            (
+                transmits,
+                session.poll_timeout(),
+                session.max_message_size(),
+            )
+        };
+
+        for transmit in transmits {
+            let TransportAddr::Udp(addr) = transmit.destination else {
+                continue;
+            };
+            // A failed send is retried on timeout like a lost datagram
+            if let Err(e) = io.socket_for(addr).send_to(&transmit.payload, addr).await {
+                println!("Error sending to {}: {}", addr, e);
+            }
+        }
+
+        // One spare byte to tell a datagram that was cut short by the
+        // buffer apart from one that fits exactly
+        buf_v4.resize(max_message_size + 1, 0);
+        buf_v6.resize(max_message_size + 1, 0);
+
+        let received = tokio::select! {
+            received = io.socket_v4.recv_from(&mut buf_v4) => {
+                received.map(|(len, addr)| Some((&buf_v4[..len], addr)))
+            }
+            received = recv(io.socket_v6.as_ref(), &mut buf_v6) => {
+                received.map(|(len, addr)| Some((&buf_v6[..len], addr)))
+            }
+            _ = sleep_until(deadline) => {
+                io.state.lock().unwrap().session.handle_timeout(Instant::now());
+                Ok(None)
+            }
+            _ = io.wake.notified() => Ok(None),
+        };
+
+        match received {
+            Ok(Some((data, addr))) => io
+                .state
+                .lock()
+                .unwrap()
+                .session
+                .handle_datagram(&TransportAddr::Udp(addr), data),
+            Ok(None) => {}
+            // ICMP errors from earlier sends surface here on some systems
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused
+                ) => {}
+            Err(e) => {
+                println!("Error receiving data: {}", e);
+                break;
+            }
+        }
+    }
+
+    // Fail the requests still waiting
+    io.state.lock().unwrap().waiters.clear();
+}
+
+impl Io {
+    fn socket_for(&self, addr: SocketAddr) -> &UdpSocket {
+        match &self.socket_v6 {
+            Some(socket) if addr.is_ipv6() => socket,
+            _ => &self.socket_v4,
+        }
+    }
+}
+
+// Receive on `socket`, never completing without one
+async fn recv(socket: Option<&UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Sleep until `deadline`, forever without one
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
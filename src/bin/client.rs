@@ -1,5 +1,4 @@
-use bytes::{BufMut, BytesMut};
-use snmp_t::{client::SnmpClient, snmp::{self, SnmpValue}};
+use snmp_t::{client::SnmpClient, snmp::SnmpValue};
 use anyhow::Result;
 fn main() -> Result<()>{
     let mut client = SnmpClient::new();
@@ -8,7 +7,7 @@ fn main() -> Result<()>{
     let system_description_oid = &[1, 3, 6, 1, 2, 1, 1, 1, 0];
 
     // Target device (replace with your SNMP agent's IP)
-    let target = "127.0.0.1";
+    let target = "127.0.0.1:16100";
 
     // Community string (replace with your community string)
     let community = "public";
@@ -17,13 +16,10 @@ fn main() -> Result<()>{
         "Sending SNMP GET request to {} for system description...",
         target
     );
-    let mut bytes = BytesMut::new();
 
     match client.get(target, community, &[system_description_oid]) {
         Ok(response) => {
-            // println!("Received response: {:?}", response);
-            let decoded_response = snmp::decode_snmp_message(&response)?;
-            decoded_response.pdu.varbinds.iter().for_each(|varbind| {
+            response.varbinds.iter().for_each(|varbind| {
                 println!("OID: {:?}, Value: {:?}", varbind.oid, format_snmp_value(&varbind.value));
             });
             Ok(())
@@ -41,7 +37,7 @@ fn format_snmp_value(value: &SnmpValue) -> String {
         SnmpValue::Integer(val) => format!("{} (Integer)", val),
         SnmpValue::OctetString(val) => {
             // Try to display as string if it's printable ASCII
-            if val.iter().all(|&b| b >= 32 && b <= 126) {
+            if val.iter().all(|&b| (32..=126).contains(&b)) {
                 format!("\"{}\" (OctetString)", String::from_utf8_lossy(val))
             } else {
                 format!("0x{} (OctetString)", 
@@ -49,6 +45,24 @@ fn format_snmp_value(value: &SnmpValue) -> String {
             }
         },
         SnmpValue::Null => "NULL".to_string(),
-        SnmpValue::ObjectIdentifier(val) => "TODO".to_string(),
+        SnmpValue::ObjectIdentifier(val) => format!(
+            "{} (ObjectIdentifier)",
+            val.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(".")
+        ),
+        SnmpValue::IpAddress(val) => format!(
+            "{}.{}.{}.{} (IpAddress)",
+            val[0], val[1], val[2], val[3]
+        ),
+        SnmpValue::Counter32(val) => format!("{} (Counter32)", val),
+        SnmpValue::Gauge32(val) => format!("{} (Gauge32)", val),
+        SnmpValue::TimeTicks(val) => format!("{} (TimeTicks)", val),
+        SnmpValue::Opaque(val) => format!(
+            "0x{} (Opaque)",
+            val.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+        ),
+        SnmpValue::Counter64(val) => format!("{} (Counter64)", val),
+        SnmpValue::NoSuchObject => "noSuchObject".to_string(),
+        SnmpValue::NoSuchInstance => "noSuchInstance".to_string(),
+        SnmpValue::EndOfMibView => "endOfMibView".to_string(),
     }
 }
\ No newline at end of file
@@ -1,8 +1,8 @@
 use std::thread;
 use std::time::Duration;
 use anyhow::Result;
-use snmp_t::snmp::SnmpValue;
 use snmp_t::agent::SnmpAgent;
+use snmp_t::system::SystemGroup;
 fn main() -> Result<()> {
     println!("Starting SNMP v1 Agent");
     
@@ -10,28 +10,16 @@ fn main() -> Result<()> {
     // Use a higher port like 16100 if you don't have privileges
     let agent = SnmpAgent::new("0.0.0.0:16100", vec!["public".to_string()])?;
     
-    // Register some OIDs
-    
-    // System description (1.3.6.1.2.1.1.1.0)
-    agent.register_oid(
-        vec![1, 3, 6, 1, 2, 1, 1, 1, 0],
-        SnmpValue::OctetString("Rust SNMP Agent v1.0".as_bytes().to_vec())
-    )?;
-    
-    // System uptime (1.3.6.1.2.1.1.3.0)
-    agent.register_oid(
-        vec![1, 3, 6, 1, 2, 1, 1, 3, 0],
-        SnmpValue::Integer(0) // Will be updated
-    )?;
-    
-    // System contact (1.3.6.1.2.1.1.4.0)
-    agent.register_oid(
-        vec![1, 3, 6, 1, 2, 1, 1, 4, 0],
-        SnmpValue::OctetString("admin@example.com".as_bytes().to_vec())
+    // Register the system group (1.3.6.1.2.1.1): sysUpTime counts from
+    // the agent's start and sysName defaults to the host name. 32473 is
+    // the enterprise number reserved for documentation (RFC 5612).
+    agent.register_system(
+        SystemGroup::new("Rust SNMP Agent v1.0", vec![1, 3, 6, 1, 4, 1, 32473])
+            .with_contact("admin@example.com")
     )?;
     
     // Start the agent in a separate thread
-    let agent_thread = agent.run_in_thread();
+    let _agent_thread = agent.run_in_thread();
     
     println!("SNMP agent started. Press Ctrl+C to stop.");
     
@@ -1,61 +1,1067 @@
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::error::Error;
-use std::fmt::format;
-use std::net::SocketAddr;
-use std::net::UdpSocket;
-use std::time::Duration;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind};
+use std::net::ToSocketAddrs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use bytes::BytesMut;
 
-use crate::snmp;
+use crate::index::{self, IndexType};
+use crate::session::ClientSession;
+use crate::snmp::{self, ErrorStatus, PduType, SnmpError, SnmpValue, Varbind};
+use crate::table::Row;
+#[cfg(feature = "tls")]
+use crate::tls::{DtlsTransport, TlsConfig, TlsTransport};
+use crate::transport::{self, Transport, TransportAddr, TransportKind};
+
+/// A decoded, error-free response to a client request.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub request_id: i32,
+    pub varbinds: Vec<Varbind>,
+}
+
+/// Address family a target is restricted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressFamily {
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+/// An agent address in net-snmp notation: `[transport:]host[:port]`.
+///
+/// The transport is `udp` or `tcp` (IPv4 only), `udp6` or `tcp6` (IPv6
+/// only); without it UDP to the first address the host resolves to is
+/// used. IPv6 literals with a port are written in brackets, e.g.
+/// `udp6:[fe80::1]:161`. The port defaults to [`snmp::SNMP_PORT`].
+///
+/// Unix domain sockets are written `unix:/path` (stream) and
+/// `unixdgram:/path` (datagram); `host` then holds the path and `port`
+/// is unused.
+///
+/// `tlstcp` and `dtlsudp` select TLS and DTLS (RFC 6353), whose port
+/// defaults to [`snmp::SNMP_TLS_PORT`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    pub family: AddressFamily,
+    pub transport: TransportKind,
+}
+
+impl Target {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            family: AddressFamily::Any,
+            transport: TransportKind::Udp,
+        }
+    }
+
+    /// Resolves the host name, keeping only addresses of the target's family.
+    pub fn resolve(&self) -> io::Result<SocketAddr> {
+        if matches!(
+            self.transport,
+            TransportKind::Unix | TransportKind::UnixDgram
+        ) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not an IP target", self),
+            ));
+        }
+
+        (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .find(|addr| match self.family {
+                AddressFamily::Any => true,
+                AddressFamily::Ipv4 => addr.is_ipv4(),
+                AddressFamily::Ipv6 => addr.is_ipv6(),
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("No suitable address found for {}", self),
+                )
+            })
+    }
+}
+
+impl FromStr for Target {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(ErrorKind::InvalidInput, format!("Invalid target: {}", s));
+
+        let (transport, family, rest) = match s.split_once(':') {
+            Some(("udp", rest)) => (TransportKind::Udp, AddressFamily::Ipv4, rest),
+            Some(("udp6", rest)) => (TransportKind::Udp, AddressFamily::Ipv6, rest),
+            Some(("tcp", rest)) => (TransportKind::Tcp, AddressFamily::Ipv4, rest),
+            Some(("tcp6", rest)) => (TransportKind::Tcp, AddressFamily::Ipv6, rest),
+            Some(("tlstcp", rest)) => (TransportKind::Tls, AddressFamily::Any, rest),
+            Some(("dtlsudp", rest)) => (TransportKind::Dtls, AddressFamily::Any, rest),
+            Some(("unix", path)) | Some(("unixdgram", path)) => {
+                if path.is_empty() {
+                    return Err(invalid());
+                }
+                let transport = if s.starts_with("unix:") {
+                    TransportKind::Unix
+                } else {
+                    TransportKind::UnixDgram
+                };
+                return Ok(Self {
+                    host: path.to_string(),
+                    port: 0,
+                    family: AddressFamily::Any,
+                    transport,
+                });
+            }
+            _ => (TransportKind::Udp, AddressFamily::Any, s),
+        };
+
+        let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+            // [v6-address] or [v6-address]:port
+            let (host, after) = bracketed.split_once(']').ok_or_else(invalid)?;
+            let port = match after {
+                "" => None,
+                _ => Some(after.strip_prefix(':').ok_or_else(invalid)?),
+            };
+            (host, port)
+        } else if rest.matches(':').count() > 1 {
+            // Bare IPv6 literal, which cannot carry a port
+            (rest, None)
+        } else {
+            match rest.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let port = match (port, transport) {
+            (Some(port), _) => port.parse().map_err(|_| invalid())?,
+            (None, TransportKind::Tls | TransportKind::Dtls) => snmp::SNMP_TLS_PORT,
+            (None, _) => snmp::SNMP_PORT,
+        };
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            family,
+            transport,
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.transport, self.family) {
+            (TransportKind::Unix, _) => return write!(f, "unix:{}", self.host),
+            (TransportKind::UnixDgram, _) => return write!(f, "unixdgram:{}", self.host),
+            (TransportKind::Udp, AddressFamily::Any) => {}
+            (TransportKind::Udp, AddressFamily::Ipv4) => write!(f, "udp:")?,
+            (TransportKind::Udp, AddressFamily::Ipv6) => write!(f, "udp6:")?,
+            (TransportKind::Tcp, AddressFamily::Ipv6) => write!(f, "tcp6:")?,
+            (TransportKind::Tcp, _) => write!(f, "tcp:")?,
+            (TransportKind::Tls, _) => write!(f, "tlstcp:")?,
+            (TransportKind::Dtls, _) => write!(f, "dtlsudp:")?,
+        }
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Anything that names an agent: a target string (see [`Target`]), a
+/// `Target`, one of the standard socket address types (UDP), a
+/// [`TransportAddr`], or any other [`ToSocketAddrs`] wrapped in
+/// [`SocketAddrs`].
+pub trait ToTarget {
+    /// The UDP address of the agent; fails for other transports.
+    fn to_target_addr(&self) -> io::Result<SocketAddr>;
+
+    /// The agent's address on whichever transport the target names.
+    fn to_transport_addr(&self) -> io::Result<TransportAddr> {
+        self.to_target_addr().map(TransportAddr::Udp)
+    }
+}
+
+impl ToTarget for str {
+    fn to_target_addr(&self) -> io::Result<SocketAddr> {
+        self.parse::<Target>()?.to_target_addr()
+    }
+
+    fn to_transport_addr(&self) -> io::Result<TransportAddr> {
+        self.parse::<Target>()?.to_transport_addr()
+    }
+}
+
+impl ToTarget for String {
+    fn to_target_addr(&self) -> io::Result<SocketAddr> {
+        self.as_str().to_target_addr()
+    }
+
+    fn to_transport_addr(&self) -> io::Result<TransportAddr> {
+        self.as_str().to_transport_addr()
+    }
+}
+
+impl ToTarget for Target {
+    fn to_target_addr(&self) -> io::Result<SocketAddr> {
+        match self.to_transport_addr()? {
+            TransportAddr::Udp(addr) => Ok(addr),
+            _ => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("{} is not a UDP target", self),
+            )),
+        }
+    }
+
+    fn to_transport_addr(&self) -> io::Result<TransportAddr> {
+        Ok(match self.transport {
+            TransportKind::Udp => TransportAddr::Udp(self.resolve()?),
+            TransportKind::Tcp => TransportAddr::Tcp(self.resolve()?),
+            TransportKind::Tls => TransportAddr::Tls(self.resolve()?),
+            TransportKind::Dtls => TransportAddr::Dtls(self.resolve()?),
+            #[cfg(unix)]
+            TransportKind::Unix => TransportAddr::Unix(self.host.clone().into()),
+            #[cfg(unix)]
+            TransportKind::UnixDgram => TransportAddr::UnixDgram(self.host.clone().into()),
+            #[cfg(not(unix))]
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "Unix domain sockets are not available",
+                ));
+            }
+        })
+    }
+}
+
+impl ToTarget for TransportAddr {
+    fn to_target_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            TransportAddr::Udp(addr) => Ok(*addr),
+            _ => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("{} is not a UDP target", self),
+            )),
+        }
+    }
+
+    fn to_transport_addr(&self) -> io::Result<TransportAddr> {
+        Ok(self.clone())
+    }
+}
+
+impl<T: ToTarget + ?Sized> ToTarget for &T {
+    fn to_target_addr(&self) -> io::Result<SocketAddr> {
+        (**self).to_target_addr()
+    }
+
+    fn to_transport_addr(&self) -> io::Result<TransportAddr> {
+        (**self).to_transport_addr()
+    }
+}
+
+/// Names an agent by anything implementing [`ToSocketAddrs`] (UDP), for
+/// address types [`ToTarget`] has no impl of its own for.
+///
+/// The first address the value resolves to is used.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketAddrs<A>(pub A);
+
+impl<A: ToSocketAddrs> ToTarget for SocketAddrs<A> {
+    fn to_target_addr(&self) -> io::Result<SocketAddr> {
+        self.0
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "Target resolved to no address"))
+    }
+}
+
+macro_rules! impl_to_target_for_socket_addrs {
+    ($($ty:ty),*) => {
+        $(
+            impl ToTarget for $ty {
+                fn to_target_addr(&self) -> io::Result<SocketAddr> {
+                    SocketAddrs(self).to_target_addr()
+                }
+            }
+        )*
+    };
+}
+
+impl_to_target_for_socket_addrs!(
+    SocketAddr,
+    SocketAddrV4,
+    SocketAddrV6,
+    (IpAddr, u16),
+    (Ipv4Addr, u16),
+    (Ipv6Addr, u16),
+    (&str, u16),
+    (String, u16)
+);
+
+/// Timeout and retransmission settings for a request.
+///
+/// The first attempt waits `timeout`; every retransmission multiplies the
+/// wait by `backoff` and adds up to 10% of random jitter.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestOptions {
+    pub timeout: Duration,
+    pub retries: u32,
+    pub backoff: f64,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            retries: 3,
+            backoff: 2.0,
+        }
+    }
+}
+
+impl RequestOptions {
+    // How long to wait for an answer to the given (0-based) attempt
+    pub(crate) fn attempt_timeout(&self, attempt: u32) -> Duration {
+        let base = self
+            .timeout
+            .mul_f64(self.backoff.max(1.0).powi(attempt as i32));
+
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(attempt);
+        let jitter = (hasher.finish() % 1000) as f64 / 10_000.0;
+
+        base + base.mul_f64(jitter)
+    }
+}
+
 pub struct SnmpClient {
-    socket: UdpSocket,
-    timeout: Duration,
-    request_id: i32,
+    // One transport per kind (and IP family for UDP), created on first use
+    transports: HashMap<(TransportKind, AddressFamily), Box<dyn Transport>>,
+    options: RequestOptions,
+    session: ClientSession,
+    max_repetitions: i32,
+    #[cfg(feature = "tls")]
+    tls_config: Option<TlsConfig>,
 }
 
 impl SnmpClient {
     pub fn new() -> Self {
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-        let timeout = Duration::from_secs(5);
-
         Self {
-            socket,
-            timeout,
-            request_id: 1,
+            transports: HashMap::new(),
+            options: RequestOptions::default(),
+            session: ClientSession::new(),
+            max_repetitions: 10,
+            #[cfg(feature = "tls")]
+            tls_config: None,
         }
     }
+
+    /// Selects the protocol version, [`snmp::SNMP_VERSION_1`] or [`snmp::SNMP_VERSION_2C`].
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.session = self.session.with_version(version);
+        self
+    }
+
+    /// Sets how many successors [`SnmpClient::walk`] asks for per GetBulk request.
+    pub fn with_max_repetitions(mut self, max_repetitions: i32) -> Self {
+        self.max_repetitions = max_repetitions;
+        self
+    }
+
+    /// Sets the default timeout and retry settings used by every request.
+    pub fn with_options(mut self, options: RequestOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = timeout;
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.options.retries = retries;
+        self
+    }
+
+    /// Sets the largest request the client will send and response it will accept.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.session = self.session.with_max_message_size(max_message_size);
+        self
+    }
+
+    /// Sets the certificate and trust settings for `tlstcp:` and
+    /// `dtlsudp:` targets.
+    #[cfg(feature = "tls")]
+    pub fn with_tls_config(mut self, config: TlsConfig) -> Self {
+        self.tls_config = Some(config);
+        self
+    }
+
     pub fn get(
         &mut self,
-        target: &str,
+        target: impl ToTarget,
         community: &str,
         oids: &[&[u32]],
-    ) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut buf = BytesMut::new();
-        let mut varbind_list_buf = BytesMut::new();
-        let mut pdu_buf = BytesMut::new();
-
-        snmp::build_varbind_list(oids, &mut varbind_list_buf);
-        snmp::build_pdu(
-            self.request_id,
-            0,
+    ) -> Result<Response, Box<dyn Error>> {
+        let options = self.options;
+        self.get_with_options(target, community, oids, &options)
+    }
+
+    /// Like [`SnmpClient::get`], overriding the client's timeout and retry settings.
+    pub fn get_with_options(
+        &mut self,
+        target: impl ToTarget,
+        community: &str,
+        oids: &[&[u32]],
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn Error>> {
+        let target_addr = target.to_transport_addr()?;
+        self.request(
+            &target_addr,
+            community,
+            PduType::GET_REQUEST,
+            &null_varbinds(oids),
+            None,
+            options,
+        )
+    }
+
+    /// Fetches the lexicographic successor of each OID.
+    pub fn get_next(
+        &mut self,
+        target: impl ToTarget,
+        community: &str,
+        oids: &[&[u32]],
+    ) -> Result<Response, Box<dyn Error>> {
+        let options = self.options;
+        self.get_next_with_options(target, community, oids, &options)
+    }
+
+    /// Like [`SnmpClient::get_next`], overriding the client's timeout and retry settings.
+    pub fn get_next_with_options(
+        &mut self,
+        target: impl ToTarget,
+        community: &str,
+        oids: &[&[u32]],
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn Error>> {
+        let target_addr = target.to_transport_addr()?;
+        self.request(
+            &target_addr,
+            community,
+            PduType::GET_NEXT_REQUEST,
+            &null_varbinds(oids),
+            None,
+            options,
+        )
+    }
+
+    /// Writes the given values and returns the agent's response.
+    ///
+    /// A rejected SET comes back as [`SnmpError::AgentError`] naming the
+    /// varbind the agent pointed at.
+    pub fn set(
+        &mut self,
+        target: impl ToTarget,
+        community: &str,
+        varbinds: &[(&[u32], SnmpValue)],
+    ) -> Result<Response, Box<dyn Error>> {
+        let options = self.options;
+        self.set_with_options(target, community, varbinds, &options)
+    }
+
+    /// Like [`SnmpClient::set`], overriding the client's timeout and retry settings.
+    pub fn set_with_options(
+        &mut self,
+        target: impl ToTarget,
+        community: &str,
+        varbinds: &[(&[u32], SnmpValue)],
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn Error>> {
+        let target_addr = target.to_transport_addr()?;
+        let varbinds: Vec<Varbind> = varbinds
+            .iter()
+            .map(|(oid, value)| Varbind {
+                oid: oid.to_vec(),
+                value: value.clone(),
+            })
+            .collect();
+        self.request(
+            &target_addr,
+            community,
+            PduType::SET_REQUEST,
+            &varbinds,
+            None,
+            options,
+        )
+    }
+
+    /// Sends a GetBulk request (SNMPv2c only): one successor for each of the
+    /// first `non_repeaters` OIDs, then up to `max_repetitions` successors
+    /// for each of the remaining ones.
+    pub fn get_bulk(
+        &mut self,
+        target: impl ToTarget,
+        community: &str,
+        non_repeaters: i32,
+        max_repetitions: i32,
+        oids: &[&[u32]],
+    ) -> Result<Response, Box<dyn Error>> {
+        let options = self.options;
+        self.get_bulk_with_options(
+            target,
+            community,
+            non_repeaters,
+            max_repetitions,
+            oids,
+            &options,
+        )
+    }
+
+    /// Like [`SnmpClient::get_bulk`], overriding the client's timeout and retry settings.
+    pub fn get_bulk_with_options(
+        &mut self,
+        target: impl ToTarget,
+        community: &str,
+        non_repeaters: i32,
+        max_repetitions: i32,
+        oids: &[&[u32]],
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn Error>> {
+        let target_addr = target.to_transport_addr()?;
+        self.request(
+            &target_addr,
+            community,
+            PduType::GET_BULK_REQUEST,
+            &null_varbinds(oids),
+            Some((non_repeaters, max_repetitions)),
+            options,
+        )
+    }
+
+    /// Iterates over every varbind below `root`, using GetNext for SNMPv1
+    /// and GetBulk for SNMPv2c.
+    ///
+    /// The walk ends at the end of the subtree or the MIB view. An agent
+    /// that answers with an OID not greater than the previous one ends it
+    /// with [`SnmpError::OidNotIncreasing`].
+    pub fn walk(
+        &mut self,
+        target: impl ToTarget,
+        community: &str,
+        root: &[u32],
+    ) -> Result<Walk<'_>, Box<dyn Error>> {
+        let options = self.options;
+        self.walk_with_options(target, community, root, &options)
+    }
+
+    /// Like [`SnmpClient::walk`], overriding the client's timeout and retry
+    /// settings for each of its requests.
+    pub fn walk_with_options(
+        &mut self,
+        target: impl ToTarget,
+        community: &str,
+        root: &[u32],
+        options: &RequestOptions,
+    ) -> Result<Walk<'_>, Box<dyn Error>> {
+        let target_addr = target.to_transport_addr()?;
+
+        Ok(Walk {
+            client: self,
+            options: *options,
+            target_addr,
+            community: community.to_string(),
+            root: root.to_vec(),
+            last: root.to_vec(),
+            pending: VecDeque::new(),
+            error: None,
+            done: false,
+        })
+    }
+
+    /// Retrieves the given columns of a conceptual table, e.g. ifTable
+    /// (1.3.6.1.2.1.2.2) indexed by `[IndexType::Integer]`, with columns 2
+    /// (ifDescr) and 5 (ifSpeed).
+    ///
+    /// The columns are walked side by side, one varbind per column in every
+    /// request. The rows come in index order, their index values decoded
+    /// according to `index`; a row that lacks some column simply has no
+    /// cell for it.
+    pub fn get_table(
+        &mut self,
+        target: impl ToTarget,
+        community: &str,
+        table_oid: &[u32],
+        index: &[IndexType],
+        columns: &[u32],
+    ) -> Result<Vec<Row>, Box<dyn Error>> {
+        let options = self.options;
+        self.get_table_with_options(target, community, table_oid, index, columns, &options)
+    }
+
+    /// Like [`SnmpClient::get_table`], overriding the client's timeout and
+    /// retry settings for each of its requests.
+    pub fn get_table_with_options(
+        &mut self,
+        target: impl ToTarget,
+        community: &str,
+        table_oid: &[u32],
+        index: &[IndexType],
+        columns: &[u32],
+        options: &RequestOptions,
+    ) -> Result<Vec<Row>, Box<dyn Error>> {
+        let target_addr = target.to_transport_addr()?;
+        // The cells keyed by the OID suffix of their row, which sorts the
+        // rows in index order
+        let mut table: BTreeMap<Vec<u32>, BTreeMap<u32, SnmpValue>> = BTreeMap::new();
+
+        // (column, column OID, last OID seen in that column)
+        let mut cursors: Vec<(u32, Vec<u32>, Vec<u32>)> = columns
+            .iter()
+            .map(|&column| {
+                let mut prefix = table_oid.to_vec();
+                prefix.extend([1, column]);
+                (column, prefix.clone(), prefix)
+            })
+            .collect();
+
+        while !cursors.is_empty() {
+            let oids: Vec<&[u32]> = cursors.iter().map(|(_, _, last)| last.as_slice()).collect();
+            let bulk = if self.session.version() == snmp::SNMP_VERSION_1 {
+                None
+            } else {
+                Some((0, self.max_repetitions))
+            };
+            let pdu_type = if bulk.is_some() {
+                PduType::GET_BULK_REQUEST
+            } else {
+                PduType::GET_NEXT_REQUEST
+            };
+
+            let response = match self.request(
+                &target_addr,
+                community,
+                pdu_type,
+                &null_varbinds(&oids),
+                bulk,
+                options,
+            ) {
+                Ok(response) => response,
+                Err(e) => match e.downcast_ref::<SnmpError>() {
+                    // An SNMPv1 agent ran past the end of the MIB on one
+                    // column: drop that column and carry on with the rest
+                    Some(SnmpError::AgentError {
+                        status: ErrorStatus::NoSuchName,
+                        index,
+                        ..
+                    }) if *index >= 1 && (*index as usize) <= cursors.len() => {
+                        cursors.remove(*index as usize - 1);
+                        continue;
+                    }
+                    _ => return Err(e),
+                },
+            };
+
+            if response.varbinds.is_empty() {
+                break;
+            }
+
+            // GetBulk answers repeat the requested columns in order
+            let mut finished = vec![false; cursors.len()];
+            for (i, varbind) in response.varbinds.into_iter().enumerate() {
+                let slot = i % cursors.len();
+                let (column, prefix, last) = &mut cursors[slot];
+                if finished[slot] {
+                    continue;
+                }
+
+                if matches!(
+                    varbind.value,
+                    SnmpValue::EndOfMibView | SnmpValue::NoSuchObject | SnmpValue::NoSuchInstance
+                ) || !varbind.oid.starts_with(prefix)
+                {
+                    finished[slot] = true;
+                    continue;
+                }
+                if varbind.oid <= *last {
+                    return Err(Box::new(SnmpError::OidNotIncreasing(varbind.oid)));
+                }
+
+                *last = varbind.oid.clone();
+                let suffix = varbind.oid[prefix.len()..].to_vec();
+                table
+                    .entry(suffix)
+                    .or_default()
+                    .insert(*column, varbind.value);
+            }
+
+            let mut finished = finished.into_iter();
+            cursors.retain(|_| !finished.next().unwrap());
+        }
+
+        table
+            .into_iter()
+            .map(|(suffix, cells)| {
+                let index = index::decode_index(index, &suffix)
+                    .map_err(|e| format!("Row {:?}: {}", suffix, e))?;
+                Ok(Row { index, cells })
+            })
+            .collect()
+    }
+
+    // The transport for `target_addr`, created on first use. A stream
+    // transport gives up on connecting after `connect_timeout`.
+    fn transport_for(
+        &mut self,
+        target_addr: &TransportAddr,
+        connect_timeout: Duration,
+    ) -> io::Result<&dyn Transport> {
+        let transport = match self.transports.entry(transport::client_key(target_addr)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                #[cfg(feature = "tls")]
+                let transport: Box<dyn Transport> = match (target_addr, &self.tls_config) {
+                    (TransportAddr::Tls(_), Some(config)) => Box::new(TlsTransport::new(config)?),
+                    (TransportAddr::Dtls(_), Some(config)) => Box::new(DtlsTransport::new(config)?),
+                    _ => transport::client_transport(target_addr, connect_timeout)?,
+                };
+                #[cfg(not(feature = "tls"))]
+                let transport = transport::client_transport(target_addr, connect_timeout)?;
+                entry.insert(transport)
+            }
+        };
+        Ok(&**transport)
+    }
+
+    // Send a request and wait for the matching response, letting the
+    // session retransmit it on timeout
+    fn request(
+        &mut self,
+        target_addr: &TransportAddr,
+        community: &str,
+        pdu_type: PduType,
+        varbinds: &[Varbind],
+        bulk: Option<(i32, i32)>,
+        options: &RequestOptions,
+    ) -> Result<Response, Box<dyn Error>> {
+        let request_id = self.session.start_request(
+            Instant::now(),
+            target_addr.clone(),
+            community,
+            pdu_type,
+            varbinds,
+            bulk,
+            options,
+        )?;
+
+        let result = self.drive(request_id, target_addr, options.timeout);
+        if result.is_err() {
+            self.session.cancel(request_id);
+        }
+        result
+    }
+
+    // Do the session's I/O until the request completes
+    fn drive(
+        &mut self,
+        request_id: i32,
+        target_addr: &TransportAddr,
+        connect_timeout: Duration,
+    ) -> Result<Response, Box<dyn Error>> {
+        // One spare byte to tell a datagram that was cut short by the buffer
+        // apart from one that fits exactly
+        let mut buf = vec![0u8; self.session.max_message_size() + 1];
+
+        loop {
+            while let Some(transmit) = self.session.poll_transmit() {
+                self.transport_for(&transmit.destination, connect_timeout)?
+                    .send_to(&transmit.payload, &transmit.destination)?;
+            }
+            while let Some((id, result)) = self.session.poll_response() {
+                if id == request_id {
+                    return Ok(result?);
+                }
+            }
+
+            let now = Instant::now();
+            let deadline = self.session.poll_timeout().unwrap_or(now);
+            let remaining = deadline.saturating_duration_since(now);
+            if remaining.is_zero() {
+                self.session.handle_timeout(now);
+                continue;
+            }
+
+            let transport = self.transport_for(target_addr, connect_timeout)?;
+            match transport.recv_from(&mut buf, Some(remaining)) {
+                Ok((len, src_addr)) => self.session.handle_datagram(&src_addr, &buf[..len]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    self.session.handle_timeout(Instant::now());
+                }
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+    }
+}
+
+/// Iterator over a subtree, returned by [`SnmpClient::walk`].
+pub struct Walk<'a> {
+    client: &'a mut SnmpClient,
+    options: RequestOptions,
+    target_addr: TransportAddr,
+    community: String,
+    root: Vec<u32>,
+    last: Vec<u32>,
+    pending: VecDeque<Varbind>,
+    error: Option<Box<dyn Error>>,
+    done: bool,
+}
+
+impl Walk<'_> {
+    // Fetch the next batch of varbinds following `last`
+    fn fetch(&mut self) {
+        let client = &mut *self.client;
+        let oids: [&[u32]; 1] = [&self.last];
+        let bulk = if client.session.version() == snmp::SNMP_VERSION_1 {
+            None
+        } else {
+            Some((0, client.max_repetitions))
+        };
+        let pdu_type = if bulk.is_some() {
+            PduType::GET_BULK_REQUEST
+        } else {
+            PduType::GET_NEXT_REQUEST
+        };
+
+        let response = match client.request(
+            &self.target_addr,
+            &self.community,
+            pdu_type,
+            &null_varbinds(&oids),
+            bulk,
+            &self.options,
+        ) {
+            Ok(response) => response,
+            Err(e) => {
+                self.done = true;
+                // SNMPv1 agents report the end of the MIB as noSuchName
+                if !matches!(
+                    e.downcast_ref::<SnmpError>(),
+                    Some(SnmpError::AgentError {
+                        status: ErrorStatus::NoSuchName,
+                        ..
+                    })
+                ) {
+                    self.error = Some(e);
+                }
+                return;
+            }
+        };
+
+        if response.varbinds.is_empty() {
+            self.done = true;
+        }
+
+        for varbind in response.varbinds {
+            if matches!(
+                varbind.value,
+                SnmpValue::EndOfMibView | SnmpValue::NoSuchObject | SnmpValue::NoSuchInstance
+            ) || !varbind.oid.starts_with(&self.root)
+            {
+                self.done = true;
+                break;
+            }
+            if varbind.oid <= self.last {
+                self.done = true;
+                self.error = Some(Box::new(SnmpError::OidNotIncreasing(varbind.oid)));
+                break;
+            }
+
+            self.last = varbind.oid.clone();
+            self.pending.push_back(varbind);
+        }
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = Result<Varbind, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() && self.error.is_none() && !self.done {
+            self.fetch();
+        }
+
+        if let Some(varbind) = self.pending.pop_front() {
+            return Some(Ok(varbind));
+        }
+        self.error.take().map(Err)
+    }
+}
+
+impl Default for SnmpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Varbinds with NULL values, as sent in GET and GETNEXT requests
+pub(crate) fn null_varbinds(oids: &[&[u32]]) -> Vec<Varbind> {
+    oids.iter()
+        .map(|oid| Varbind {
+            oid: oid.to_vec(),
+            value: SnmpValue::Null,
+        })
+        .collect()
+}
+
+// Encode a request message; `bulk` carries non-repeaters and
+// max-repetitions for GetBulk
+pub(crate) fn encode_request(
+    version: u8,
+    community: &str,
+    request_id: i32,
+    pdu_type: PduType,
+    varbinds: &[Varbind],
+    bulk: Option<(i32, i32)>,
+) -> BytesMut {
+    let mut buf = BytesMut::new();
+    let mut varbind_list_buf = BytesMut::new();
+    let mut pdu_buf = BytesMut::new();
+
+    snmp::build_value_varbind_list(varbinds, &mut varbind_list_buf);
+    match bulk {
+        Some((non_repeaters, max_repetitions)) => snmp::build_bulk_pdu(
+            request_id,
+            non_repeaters,
+            max_repetitions,
+            &varbind_list_buf,
+            &mut pdu_buf,
+        ),
+        None => snmp::build_pdu(
+            request_id,
+            ErrorStatus::NoError,
             0,
             &varbind_list_buf,
-            snmp::PduType::GET_REQUEST,
+            pdu_type,
             &mut pdu_buf,
-        );
-        snmp::build_snmp_msg(community, &pdu_buf, &mut buf);
+        ),
+    }
+    snmp::build_snmp_msg(version, community, &pdu_buf, &mut buf);
 
-        self.request_id += 1;
+    buf
+}
 
-        let target_addr: SocketAddr = format!("{}:16100", target).parse()?;
-        self.socket.send_to(&buf, target_addr)?;
+// Turn the PDU matching a request into a Response, or the error the agent reported
+pub(crate) fn into_response(pdu: snmp::SnmpPdu) -> Result<Response, SnmpError> {
+    if !matches!(pdu.pdu_type, PduType::GET_RESPONSE) {
+        return Err(SnmpError::InvalidPdu);
+    }
 
-        let mut response = vec![0u8; 1024];
+    if pdu.error_status != ErrorStatus::NoError {
+        let oid = usize::try_from(pdu.error_index - 1)
+            .ok()
+            .and_then(|i| pdu.varbinds.get(i))
+            .map(|v| v.oid.clone());
+        return Err(SnmpError::AgentError {
+            status: pdu.error_status,
+            index: pdu.error_index,
+            oid,
+        });
+    }
 
-        let (len, _) = self.socket.recv_from(&mut response)?;
+    Ok(Response {
+        request_id: pdu.request_id,
+        varbinds: pdu.varbinds,
+    })
+}
 
-        Ok(response[..len].to_vec())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_targets() {
+        let target: Target = "udp6:[fe80::1]:161".parse().unwrap();
+        assert_eq!(target.host, "fe80::1");
+        assert_eq!(target.port, 161);
+        assert_eq!(target.family, AddressFamily::Ipv6);
+        assert_eq!(target.transport, TransportKind::Udp);
+        assert_eq!(target.to_string(), "udp6:[fe80::1]:161");
+
+        let target: Target = "host:1161".parse().unwrap();
+        assert_eq!(target, Target::new("host", 1161));
+
+        let target: Target = "host".parse().unwrap();
+        assert_eq!(target, Target::new("host", snmp::SNMP_PORT));
+
+        let target: Target = "fe80::1".parse().unwrap();
+        assert_eq!(target, Target::new("fe80::1", snmp::SNMP_PORT));
+
+        let target: Target = "tcp:10.0.0.1".parse().unwrap();
+        assert_eq!(target.transport, TransportKind::Tcp);
+        assert_eq!(target.family, AddressFamily::Ipv4);
+        assert_eq!(target.port, snmp::SNMP_PORT);
+
+        let target: Target = "tlstcp:agent.example.com".parse().unwrap();
+        assert_eq!(target.transport, TransportKind::Tls);
+        assert_eq!(target.port, snmp::SNMP_TLS_PORT);
+
+        let target: Target = "unix:/var/run/snmp.sock".parse().unwrap();
+        assert_eq!(target.transport, TransportKind::Unix);
+        assert_eq!(target.host, "/var/run/snmp.sock");
     }
 
-    // pub fn set
+    #[test]
+    fn rejects_invalid_targets() {
+        for target in [
+            "",
+            "udp:",
+            "host:",
+            "host:port",
+            "host:70000",
+            "udp6:[fe80::1",
+            "udp6:[fe80::1]161",
+            "[]:161",
+            "unix:",
+            "unixdgram:",
+        ] {
+            assert!(target.parse::<Target>().is_err(), "{:?}", target);
+        }
+    }
+
+    #[test]
+    fn socket_addrs_name_udp_targets() {
+        let addr: SocketAddr = "127.0.0.1:1161".parse().unwrap();
+        let addrs = [addr];
+        let names: [&dyn ToTarget; 4] = [
+            &SocketAddrs(addr),
+            &SocketAddrs(addrs.as_slice()),
+            &SocketAddrs(("127.0.0.1", 1161)),
+            &addr,
+        ];
+        for name in names {
+            assert_eq!(name.to_target_addr().unwrap(), addr);
+            assert_eq!(name.to_transport_addr().unwrap(), TransportAddr::Udp(addr));
+        }
+        assert!(
+            SocketAddrs(Vec::<SocketAddr>::new().as_slice())
+                .to_target_addr()
+                .is_err()
+        );
+    }
 }
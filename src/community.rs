@@ -0,0 +1,291 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, anyhow};
+
+/// What requests carrying a community may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// GET, GETNEXT and GETBULK; SET is refused with noAccess.
+    ReadOnly,
+    ReadWrite,
+}
+
+/// A range of IP addresses, such as `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > bits {
+            return Err(anyhow!(
+                "Prefix length {} too long for {}",
+                prefix_len,
+                addr
+            ));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Compare IPv4 networks and peers as the IPv4-mapped IPv6 addresses
+        // they may also arrive as, so that either form matches the other
+        let (net, prefix_len) = match self.addr {
+            IpAddr::V4(net) => (net.to_ipv6_mapped(), self.prefix_len + 96),
+            IpAddr::V6(net) => (net, self.prefix_len),
+        };
+        let ip = match ip {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        let shift = 128 - u32::from(prefix_len);
+        prefix_len == 0 || (u128::from(net) >> shift) == (u128::from(ip) >> shift)
+    }
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    /// Parses `addr/prefix_len`, or a single address.
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("Invalid network address: {}", s))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .with_context(|| format!("Invalid prefix length: {}", s))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// A community the agent accepts, with what it grants: a role, and
+/// optionally the networks requests may come from and the subtree of the
+/// MIB they may see.
+///
+/// Like net-snmp's `rocommunity` and `rwcommunity` directives, which
+/// [`Community::from_str`] parses:
+///
+/// ```text
+/// rocommunity public 10.0.0.0/8 .1.3.6.1.2.1
+/// rwcommunity private 127.0.0.1
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Community {
+    pub name: String,
+    pub role: Role,
+    /// Where requests must come from; anywhere when `None`.
+    pub source: Option<Network>,
+    /// The only subtree requests may read or write; the whole MIB when
+    /// `None`.
+    pub view: Option<Vec<u32>>,
+}
+
+impl Community {
+    pub fn new(name: &str, role: Role) -> Self {
+        Self {
+            name: name.to_string(),
+            role,
+            source: None,
+            view: None,
+        }
+    }
+
+    pub fn read_only(name: &str) -> Self {
+        Self::new(name, Role::ReadOnly)
+    }
+
+    pub fn read_write(name: &str) -> Self {
+        Self::new(name, Role::ReadWrite)
+    }
+
+    /// Only accepts requests from `source`.
+    pub fn with_source(mut self, source: Network) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Restricts requests to the subtree under `view`.
+    pub fn with_view(mut self, view: Vec<u32>) -> Self {
+        self.view = Some(view);
+        self
+    }
+
+    // Whether a request naming `name` and coming from `source` gets this
+    // community. A community limited to a network never matches a request
+    // whose source has no IP address.
+    pub(crate) fn matches(&self, name: &str, source: Option<IpAddr>) -> bool {
+        self.name == name
+            && self
+                .source
+                .is_none_or(|network| source.is_some_and(|ip| network.contains(ip)))
+    }
+}
+
+/// A community named by a plain string has full access, as before roles
+/// existed.
+impl From<String> for Community {
+    fn from(name: String) -> Self {
+        Self::read_write(&name)
+    }
+}
+
+impl From<&str> for Community {
+    fn from(name: &str) -> Self {
+        Self::read_write(name)
+    }
+}
+
+impl FromStr for Community {
+    type Err = anyhow::Error;
+
+    /// Parses a net-snmp `rocommunity` or `rwcommunity` line: the
+    /// directive, the community, then optionally the source network
+    /// (`default` for any) and the OID of the view, e.g.
+    /// `rocommunity public 10.0.0.0/8 .1.3.6.1.2.1`.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        let role = match words.next() {
+            Some("rocommunity") => Role::ReadOnly,
+            Some("rwcommunity") => Role::ReadWrite,
+            _ => return Err(anyhow!("Expected rocommunity or rwcommunity: {}", s)),
+        };
+        let name = words
+            .next()
+            .ok_or_else(|| anyhow!("Missing community name: {}", s))?;
+        let mut community = Self::new(name, role);
+
+        if let Some(source) = words.next()
+            && source != "default"
+        {
+            community.source = Some(source.parse()?);
+        }
+        if let Some(view) = words.next() {
+            let oid = view
+                .trim_start_matches('.')
+                .split('.')
+                .map(|arc| arc.parse::<u32>())
+                .collect::<Result<Vec<u32>, _>>()
+                .with_context(|| format!("Invalid view OID: {}", view))?;
+            community.view = Some(oid);
+        }
+        if words.next().is_some() {
+            return Err(anyhow!("Unexpected words after the view: {}", s));
+        }
+        Ok(community)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(s: &str) -> Network {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefix_edges() {
+        let any = network("0.0.0.0/0");
+        assert!(any.contains(ip("10.1.2.3")));
+        assert!(any.contains(ip("255.255.255.255")));
+        assert!(any.contains(ip("::ffff:192.0.2.1")));
+        assert!(!any.contains(ip("2001:db8::1")));
+        assert!(network("::/0").contains(ip("2001:db8::1")));
+        assert!(network("::/0").contains(ip("10.1.2.3")));
+
+        let host = network("192.0.2.1/32");
+        assert_eq!(host, network("192.0.2.1"));
+        assert!(host.contains(ip("192.0.2.1")));
+        assert!(!host.contains(ip("192.0.2.0")));
+        assert!(!host.contains(ip("192.0.2.129")));
+        assert!(network("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!network("2001:db8::1/128").contains(ip("2001:db8::2")));
+
+        let net = network("10.0.0.0/9");
+        assert!(net.contains(ip("10.127.255.255")));
+        assert!(!net.contains(ip("10.128.0.0")));
+
+        assert!("0.0.0.0/33".parse::<Network>().is_err());
+        assert!("::/129".parse::<Network>().is_err());
+        assert!("10.0.0.0/x".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_networks() {
+        let net = network("10.0.0.0/8");
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("::ffff:11.1.2.3")));
+        // Not a mapped address, despite the same low bits
+        assert!(!net.contains(ip("::10.1.2.3")));
+
+        let mapped = network("::ffff:10.0.0.0/104");
+        assert!(mapped.contains(ip("10.1.2.3")));
+        assert!(mapped.contains(ip("::ffff:10.1.2.3")));
+        assert!(!mapped.contains(ip("11.1.2.3")));
+    }
+
+    #[test]
+    fn parses_community_lines() {
+        let public: Community = "rocommunity public".parse().unwrap();
+        assert_eq!(public, Community::read_only("public"));
+
+        let scoped: Community = "rocommunity  public 10.0.0.0/8 .1.3.6.1.2.1"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            scoped,
+            Community::read_only("public")
+                .with_source(network("10.0.0.0/8"))
+                .with_view(vec![1, 3, 6, 1, 2, 1])
+        );
+
+        let private: Community = "rwcommunity private default 1.3.6.1.4.1".parse().unwrap();
+        assert_eq!(private.role, Role::ReadWrite);
+        assert_eq!(private.source, None);
+        assert_eq!(private.view, Some(vec![1, 3, 6, 1, 4, 1]));
+
+        for line in [
+            "",
+            "community public",
+            "rocommunity",
+            "rocommunity public 10.0.0.0/40",
+            "rocommunity public default .1.3.x",
+            "rocommunity public default .1.3 extra",
+        ] {
+            assert!(line.parse::<Community>().is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn source_restricted_community_needs_a_source() {
+        let community = Community::read_only("public").with_source(network("10.0.0.0/8"));
+        assert!(community.matches("public", Some(ip("10.0.0.1"))));
+        assert!(!community.matches("public", Some(ip("192.0.2.1"))));
+        assert!(!community.matches("public", None));
+        assert!(!community.matches("private", Some(ip("10.0.0.1"))));
+        assert!(Community::read_only("public").matches("public", None));
+    }
+}
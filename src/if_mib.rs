@@ -0,0 +1,393 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::agent::AgentCore;
+use crate::index::IndexType;
+use crate::mib::{Access, ValueType};
+use crate::snmp::SnmpValue;
+use crate::table::{Column, Row, Table};
+
+const IF_NUMBER: [u32; 9] = [1, 3, 6, 1, 2, 1, 2, 1, 0];
+const IF_TABLE: [u32; 8] = [1, 3, 6, 1, 2, 1, 2, 2];
+const IF_X_TABLE: [u32; 9] = [1, 3, 6, 1, 2, 1, 31, 1, 1];
+const IF_MIB: [u32; 7] = [1, 3, 6, 1, 2, 1, 31];
+
+// Interface flags from <linux/if.h>
+const IFF_UP: u32 = 0x1;
+const IFF_PROMISC: u32 = 0x100;
+
+/// The interface statistics of IF-MIB (RFC 2863): ifNumber, ifTable and
+/// ifXTable, read from Linux's `/proc/net/dev` and `/sys/class/net`.
+///
+/// Register it with [`crate::agent::SnmpAgent::register_if_mib`]. The
+/// files are read again once the cache timeout has passed, so a walk sees
+/// consistent counters.
+pub struct IfMib {
+    root: PathBuf,
+    cache_timeout: Duration,
+}
+
+impl IfMib {
+    /// Reads the files under `/`.
+    pub fn new() -> Self {
+        Self {
+            root: PathBuf::from("/"),
+            cache_timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// Reads `proc/net/dev` and `sys/class/net` under `root` instead, e.g.
+    /// a directory of fixtures.
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// Sets how long the interfaces read are served before the files are
+    /// read again; zero reads them on every request.
+    pub fn with_cache_timeout(mut self, timeout: Duration) -> Self {
+        self.cache_timeout = timeout;
+        self
+    }
+
+    /// Reads the current interfaces, ordered by ifIndex. Interfaces
+    /// without an ifindex in sysfs are numbered after the highest one.
+    pub fn interfaces(&self) -> Result<Vec<Interface>> {
+        let path = self.root.join("proc/net/dev");
+        let dev = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let mut interfaces: Vec<Interface> = dev
+            .lines()
+            .skip(2)
+            .filter_map(parse_dev_line)
+            .map(|(name, stats)| {
+                let sys = self.root.join("sys/class/net").join(&name);
+                Interface::read(&sys, name, stats)
+            })
+            .collect();
+        // Numbering them by position could repeat a real ifindex
+        let mut last = interfaces.iter().map(|i| i.index).max().unwrap_or(0);
+        for interface in interfaces.iter_mut().filter(|i| i.index == 0) {
+            last += 1;
+            interface.index = last;
+        }
+        interfaces.sort_by_key(|interface| interface.index);
+        Ok(interfaces)
+    }
+}
+
+impl Default for IfMib {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The counters of an interface, as `/proc/net/dev` lists them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub rx_multicast: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+    pub tx_dropped: u64,
+}
+
+/// One network interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub index: u32,
+    pub name: String,
+    /// IANAifType, e.g. 6 for ethernetCsmacd.
+    pub if_type: i32,
+    pub mtu: i32,
+    /// In Mb/s, `None` when the driver does not report it.
+    pub speed: Option<u64>,
+    pub phys_address: Vec<u8>,
+    pub alias: String,
+    pub flags: u32,
+    /// ifOperStatus: up(1), down(2), testing(3), unknown(4), dormant(5),
+    /// notPresent(6) or lowerLayerDown(7).
+    pub oper_status: i32,
+    pub stats: Stats,
+}
+
+impl Interface {
+    // The attributes of the interface `name` from its directory `sys`,
+    // with defaults for those missing; the index is 0 without an ifindex
+    fn read(sys: &Path, name: String, stats: Stats) -> Self {
+        let attribute = |file: &str| {
+            fs::read_to_string(sys.join(file))
+                .map(|value| value.trim().to_string())
+                .ok()
+        };
+        let number = |file: &str| attribute(file).and_then(|value| value.parse::<i64>().ok());
+
+        let flags = attribute("flags")
+            .and_then(|flags| u32::from_str_radix(flags.trim_start_matches("0x"), 16).ok())
+            .unwrap_or(0);
+        let oper_status = match attribute("operstate").as_deref() {
+            Some("up") => 1,
+            Some("down") => 2,
+            Some("testing") => 3,
+            Some("dormant") => 5,
+            Some("notpresent") => 6,
+            Some("lowerlayerdown") => 7,
+            _ => 4,
+        };
+
+        Self {
+            index: number("ifindex").map_or(0, |index| index as u32),
+            if_type: number("type").map_or(1, if_type),
+            mtu: number("mtu").unwrap_or(0) as i32,
+            speed: number("speed")
+                .filter(|&speed| speed > 0)
+                .map(|speed| speed as u64),
+            phys_address: attribute("address")
+                .map(|address| parse_mac(&address))
+                .unwrap_or_default(),
+            alias: attribute("ifalias").unwrap_or_default(),
+            flags,
+            oper_status,
+            name,
+            stats,
+        }
+    }
+
+    fn row(&self, columns: &[(u32, SnmpValue)]) -> Row {
+        columns.iter().fold(
+            Row::new(vec![SnmpValue::Integer(self.index as i32)]),
+            |row, (column, value)| row.with_cell(*column, value.clone()),
+        )
+    }
+
+    // The cells of the interface's ifEntry
+    fn if_entry(&self) -> Row {
+        let stats = &self.stats;
+        let speed = self.speed.map_or(0, |speed| {
+            speed.saturating_mul(1_000_000).min(u32::MAX as u64) as u32
+        });
+        // ifAdminStatus: up(1) or down(2)
+        let admin_status = if self.flags & IFF_UP != 0 { 1 } else { 2 };
+        self.row(&[
+            (1, SnmpValue::Integer(self.index as i32)),
+            (2, SnmpValue::OctetString(self.name.clone().into_bytes())),
+            (3, SnmpValue::Integer(self.if_type)),
+            (4, SnmpValue::Integer(self.mtu)),
+            (5, SnmpValue::Gauge32(speed)),
+            (6, SnmpValue::OctetString(self.phys_address.clone())),
+            (7, SnmpValue::Integer(admin_status)),
+            (8, SnmpValue::Integer(self.oper_status)),
+            (9, SnmpValue::TimeTicks(0)),
+            (10, counter32(stats.rx_bytes)),
+            (
+                11,
+                counter32(stats.rx_packets.saturating_sub(stats.rx_multicast)),
+            ),
+            (13, counter32(stats.rx_dropped)),
+            (14, counter32(stats.rx_errors)),
+            (15, counter32(0)),
+            (16, counter32(stats.tx_bytes)),
+            (17, counter32(stats.tx_packets)),
+            (19, counter32(stats.tx_dropped)),
+            (20, counter32(stats.tx_errors)),
+        ])
+    }
+
+    // The cells of the interface's ifXEntry
+    fn if_x_entry(&self) -> Row {
+        let stats = &self.stats;
+        // TruthValue: true(1) or false(2)
+        let promiscuous = if self.flags & IFF_PROMISC != 0 { 1 } else { 2 };
+        self.row(&[
+            (1, SnmpValue::OctetString(self.name.clone().into_bytes())),
+            (2, counter32(stats.rx_multicast)),
+            (6, SnmpValue::Counter64(stats.rx_bytes)),
+            (
+                7,
+                SnmpValue::Counter64(stats.rx_packets.saturating_sub(stats.rx_multicast)),
+            ),
+            (8, SnmpValue::Counter64(stats.rx_multicast)),
+            (10, SnmpValue::Counter64(stats.tx_bytes)),
+            (11, SnmpValue::Counter64(stats.tx_packets)),
+            (
+                15,
+                SnmpValue::Gauge32(self.speed.unwrap_or(0).min(u32::MAX as u64) as u32),
+            ),
+            (16, SnmpValue::Integer(promiscuous)),
+            (18, SnmpValue::OctetString(self.alias.clone().into_bytes())),
+            (19, SnmpValue::TimeTicks(0)),
+        ])
+    }
+}
+
+// The IANAifType of an ARPHRD_* link type
+fn if_type(arphrd: i64) -> i32 {
+    match arphrd {
+        // ethernetCsmacd
+        1 => 6,
+        // ppp
+        512 => 23,
+        // tunnel, for IPIP, SIT and GRE
+        768 | 776 | 778 => 131,
+        // softwareLoopback
+        772 => 24,
+        // ieee80211
+        801 => 71,
+        // other
+        _ => 1,
+    }
+}
+
+// A MAC address such as 02:fc:00:00:00:01; all zeroes, as on loopback,
+// means the interface has none
+fn parse_mac(address: &str) -> Vec<u8> {
+    let bytes: Vec<u8> = address
+        .split(':')
+        .filter_map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect();
+    if bytes.iter().all(|&byte| byte == 0) {
+        return Vec::new();
+    }
+    bytes
+}
+
+// A line of /proc/net/dev: the interface name, then eight receive and
+// eight transmit counters
+fn parse_dev_line(line: &str) -> Option<(String, Stats)> {
+    let (name, counters) = line.split_once(':')?;
+    let counters: Vec<u64> = counters
+        .split_whitespace()
+        .map(|counter| counter.parse().ok())
+        .collect::<Option<_>>()?;
+    if counters.len() < 16 {
+        return None;
+    }
+    let stats = Stats {
+        rx_bytes: counters[0],
+        rx_packets: counters[1],
+        rx_errors: counters[2],
+        rx_dropped: counters[3],
+        rx_multicast: counters[7],
+        tx_bytes: counters[8],
+        tx_packets: counters[9],
+        tx_errors: counters[10],
+        tx_dropped: counters[11],
+    };
+    Some((name.trim().to_string(), stats))
+}
+
+// Counter32 wraps at 2^32
+fn counter32(value: u64) -> SnmpValue {
+    SnmpValue::Counter32(value as u32)
+}
+
+// The interfaces last read, shared by the objects of the module
+struct Cache {
+    if_mib: IfMib,
+    read: Mutex<Option<(Instant, Vec<Interface>)>>,
+}
+
+impl Cache {
+    fn interfaces(&self) -> Vec<Interface> {
+        let mut read = self.read.lock().unwrap();
+        if let Some((at, interfaces)) = read.as_ref()
+            && at.elapsed() < self.if_mib.cache_timeout
+        {
+            return interfaces.clone();
+        }
+        let interfaces = self.if_mib.interfaces().unwrap_or_else(|e| {
+            println!("Error reading interfaces: {:#}", e);
+            Vec::new()
+        });
+        *read = Some((Instant::now(), interfaces.clone()));
+        interfaces
+    }
+}
+
+// Register ifNumber, ifTable and ifXTable with `core`, and IF-MIB in
+// sysORTable
+pub(crate) fn register(core: &AgentCore, if_mib: IfMib) -> Result<()> {
+    let cache = Arc::new(Cache {
+        if_mib,
+        read: Mutex::new(None),
+    });
+
+    let number = cache.clone();
+    core.register_scalar(IF_NUMBER.to_vec(), move || {
+        SnmpValue::Integer(number.interfaces().len() as i32)
+    })?;
+
+    let entries = cache.clone();
+    let mut if_table = Table::new(IF_TABLE.to_vec(), vec![IndexType::Integer], move || {
+        entries
+            .interfaces()
+            .iter()
+            .map(Interface::if_entry)
+            .collect()
+    })
+    .with_cache_timeout(cache.if_mib.cache_timeout);
+    for (id, value_type) in [
+        (1, ValueType::Integer),
+        (2, ValueType::OctetString),
+        (3, ValueType::Integer),
+        (4, ValueType::Integer),
+        (5, ValueType::Gauge32),
+        (6, ValueType::OctetString),
+        (7, ValueType::Integer),
+        (8, ValueType::Integer),
+        (9, ValueType::TimeTicks),
+        (10, ValueType::Counter32),
+        (11, ValueType::Counter32),
+        (13, ValueType::Counter32),
+        (14, ValueType::Counter32),
+        (15, ValueType::Counter32),
+        (16, ValueType::Counter32),
+        (17, ValueType::Counter32),
+        (19, ValueType::Counter32),
+        (20, ValueType::Counter32),
+    ] {
+        if_table = if_table.with_column(Column::new(id, value_type, Access::ReadOnly));
+    }
+    core.register_table(if_table)?;
+
+    let entries = cache.clone();
+    let mut if_x_table = Table::new(IF_X_TABLE.to_vec(), vec![IndexType::Integer], move || {
+        entries
+            .interfaces()
+            .iter()
+            .map(Interface::if_x_entry)
+            .collect()
+    })
+    .with_cache_timeout(cache.if_mib.cache_timeout);
+    for (id, value_type) in [
+        (1, ValueType::OctetString),
+        (2, ValueType::Counter32),
+        (6, ValueType::Counter64),
+        (7, ValueType::Counter64),
+        (8, ValueType::Counter64),
+        (10, ValueType::Counter64),
+        (11, ValueType::Counter64),
+        (15, ValueType::Gauge32),
+        (16, ValueType::Integer),
+        (18, ValueType::OctetString),
+        (19, ValueType::TimeTicks),
+    ] {
+        if_x_table = if_x_table.with_column(Column::new(id, value_type, Access::ReadOnly));
+    }
+    core.register_table(if_x_table)?;
+
+    core.register_capabilities(
+        IF_MIB.to_vec(),
+        "The MIB module to describe generic objects for network interface sub-layers",
+    );
+    Ok(())
+}
@@ -0,0 +1,309 @@
+use crate::snmp::SnmpValue;
+use anyhow::{Result, anyhow};
+
+/// Syntax of one component of a table's INDEX clause (RFC 2578 section 7.7).
+///
+/// For example ipNetToMediaTable is indexed by
+/// `[IndexType::Integer, IndexType::IpAddress]` (ifIndex, then the address).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexType {
+    /// INTEGER or Integer32, one non-negative arc
+    Integer,
+    /// Unsigned32 or Gauge32, one arc
+    Unsigned32,
+    /// Variable-length OCTET STRING, prefixed with its length
+    OctetString,
+    /// Fixed-size OCTET STRING (e.g. SIZE(6)), no length prefix
+    FixedOctetString(usize),
+    /// IMPLIED OCTET STRING, no length prefix; only allowed last
+    ImpliedOctetString,
+    /// IpAddress, four arcs
+    IpAddress,
+    /// OBJECT IDENTIFIER, prefixed with its length
+    ObjectIdentifier,
+    /// IMPLIED OBJECT IDENTIFIER, no length prefix; only allowed last
+    ImpliedObjectIdentifier,
+}
+
+impl IndexType {
+    fn is_implied(self) -> bool {
+        matches!(
+            self,
+            IndexType::ImpliedOctetString | IndexType::ImpliedObjectIdentifier
+        )
+    }
+}
+
+// IMPLIED components swallow the rest of the OID, so they have to come last
+fn check_types(types: &[IndexType]) -> Result<()> {
+    match types.iter().position(|t| t.is_implied()) {
+        Some(i) if i + 1 != types.len() => Err(anyhow!("IMPLIED index component must be last")),
+        _ => Ok(()),
+    }
+}
+
+/// Encodes index values into the OID suffix identifying a table row.
+pub fn encode_index(types: &[IndexType], values: &[SnmpValue]) -> Result<Vec<u32>> {
+    check_types(types)?;
+    if types.len() != values.len() {
+        return Err(anyhow!(
+            "Index has {} components, got {} values",
+            types.len(),
+            values.len()
+        ));
+    }
+
+    let mut suffix = Vec::new();
+    for (index_type, value) in types.iter().zip(values) {
+        match (index_type, value) {
+            (IndexType::Integer, SnmpValue::Integer(val)) => {
+                let arc =
+                    u32::try_from(*val).map_err(|_| anyhow!("Negative INTEGER index: {}", val))?;
+                suffix.push(arc);
+            }
+            (IndexType::Unsigned32, SnmpValue::Gauge32(val)) => suffix.push(*val),
+            (IndexType::OctetString, SnmpValue::OctetString(val)) => {
+                suffix.push(val.len() as u32);
+                suffix.extend(val.iter().map(|&b| b as u32));
+            }
+            (IndexType::FixedOctetString(size), SnmpValue::OctetString(val)) => {
+                if val.len() != *size {
+                    return Err(anyhow!(
+                        "Fixed-size index expects {} octets, got {}",
+                        size,
+                        val.len()
+                    ));
+                }
+                suffix.extend(val.iter().map(|&b| b as u32));
+            }
+            (IndexType::ImpliedOctetString, SnmpValue::OctetString(val)) => {
+                suffix.extend(val.iter().map(|&b| b as u32));
+            }
+            (IndexType::IpAddress, SnmpValue::IpAddress(val)) => {
+                suffix.extend(val.iter().map(|&b| b as u32));
+            }
+            (IndexType::ObjectIdentifier, SnmpValue::ObjectIdentifier(val)) => {
+                suffix.push(val.len() as u32);
+                suffix.extend_from_slice(val);
+            }
+            (IndexType::ImpliedObjectIdentifier, SnmpValue::ObjectIdentifier(val)) => {
+                suffix.extend_from_slice(val);
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Value {:?} does not match index type {:?}",
+                    value,
+                    index_type
+                ));
+            }
+        }
+    }
+
+    Ok(suffix)
+}
+
+/// Decodes the OID suffix of a table row back into its index values.
+///
+/// The whole suffix must be consumed, trailing arcs are an error.
+pub fn decode_index(types: &[IndexType], suffix: &[u32]) -> Result<Vec<SnmpValue>> {
+    check_types(types)?;
+
+    let mut rest = suffix;
+    let mut values = Vec::with_capacity(types.len());
+    for index_type in types {
+        let value = match index_type {
+            IndexType::Integer => {
+                let arc = take_arcs(&mut rest, 1)?[0];
+                let val = i32::try_from(arc)
+                    .map_err(|_| anyhow!("INTEGER index out of range: {}", arc))?;
+                SnmpValue::Integer(val)
+            }
+            IndexType::Unsigned32 => SnmpValue::Gauge32(take_arcs(&mut rest, 1)?[0]),
+            IndexType::OctetString => {
+                let len = take_arcs(&mut rest, 1)?[0] as usize;
+                SnmpValue::OctetString(arcs_to_octets(take_arcs(&mut rest, len)?)?)
+            }
+            IndexType::FixedOctetString(size) => {
+                SnmpValue::OctetString(arcs_to_octets(take_arcs(&mut rest, *size)?)?)
+            }
+            IndexType::ImpliedOctetString => {
+                let len = rest.len();
+                SnmpValue::OctetString(arcs_to_octets(take_arcs(&mut rest, len)?)?)
+            }
+            IndexType::IpAddress => {
+                let octets = arcs_to_octets(take_arcs(&mut rest, 4)?)?;
+                SnmpValue::IpAddress([octets[0], octets[1], octets[2], octets[3]])
+            }
+            IndexType::ObjectIdentifier => {
+                let len = take_arcs(&mut rest, 1)?[0] as usize;
+                SnmpValue::ObjectIdentifier(take_arcs(&mut rest, len)?.to_vec())
+            }
+            IndexType::ImpliedObjectIdentifier => {
+                let len = rest.len();
+                SnmpValue::ObjectIdentifier(take_arcs(&mut rest, len)?.to_vec())
+            }
+        };
+        values.push(value);
+    }
+
+    if !rest.is_empty() {
+        return Err(anyhow!("{} unexpected trailing index arcs", rest.len()));
+    }
+
+    Ok(values)
+}
+
+// Split `count` arcs off the front of `rest`
+fn take_arcs<'a>(rest: &mut &'a [u32], count: usize) -> Result<&'a [u32]> {
+    if rest.len() < count {
+        return Err(anyhow!(
+            "Index truncated: needed {} arcs, {} left",
+            count,
+            rest.len()
+        ));
+    }
+    let (taken, remaining) = rest.split_at(count);
+    *rest = remaining;
+    Ok(taken)
+}
+
+fn arcs_to_octets(arcs: &[u32]) -> Result<Vec<u8>> {
+    arcs.iter()
+        .map(|&arc| u8::try_from(arc).map_err(|_| anyhow!("Index arc {} is not an octet", arc)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encode `values`, expecting `suffix`, and decode it back
+    fn round_trip(types: &[IndexType], values: &[SnmpValue], suffix: &[u32]) -> Vec<SnmpValue> {
+        assert_eq!(encode_index(types, values).unwrap(), suffix);
+        let decoded = decode_index(types, suffix).unwrap();
+        assert_eq!(encode_index(types, &decoded).unwrap(), suffix);
+        decoded
+    }
+
+    #[test]
+    fn strings_round_trip() {
+        let name = || SnmpValue::OctetString(b"eth0".to_vec());
+        let decoded = round_trip(
+            &[IndexType::OctetString],
+            &[name()],
+            &[4, 101, 116, 104, 48],
+        );
+        assert!(matches!(&decoded[..], [SnmpValue::OctetString(s)] if s == b"eth0"));
+
+        round_trip(
+            &[IndexType::ImpliedOctetString],
+            &[name()],
+            &[101, 116, 104, 48],
+        );
+        round_trip(
+            &[IndexType::OctetString],
+            &[SnmpValue::OctetString(Vec::new())],
+            &[0],
+        );
+
+        let mac = SnmpValue::OctetString(vec![0, 0x1b, 0x21, 0xff, 0, 1]);
+        let decoded = round_trip(
+            &[IndexType::FixedOctetString(6), IndexType::Integer],
+            &[mac, SnmpValue::Integer(7)],
+            &[0, 27, 33, 255, 0, 1, 7],
+        );
+        assert!(matches!(decoded[1], SnmpValue::Integer(7)));
+    }
+
+    #[test]
+    fn numbers_and_addresses_round_trip() {
+        // ipNetToMediaTable: ifIndex, then the address
+        let decoded = round_trip(
+            &[IndexType::Integer, IndexType::IpAddress],
+            &[
+                SnmpValue::Integer(2),
+                SnmpValue::IpAddress([192, 168, 1, 254]),
+            ],
+            &[2, 192, 168, 1, 254],
+        );
+        assert!(matches!(
+            decoded[..],
+            [
+                SnmpValue::Integer(2),
+                SnmpValue::IpAddress([192, 168, 1, 254])
+            ]
+        ));
+
+        round_trip(
+            &[IndexType::Unsigned32, IndexType::Integer],
+            &[SnmpValue::Gauge32(u32::MAX), SnmpValue::Integer(i32::MAX)],
+            &[u32::MAX, i32::MAX as u32],
+        );
+    }
+
+    #[test]
+    fn oids_round_trip() {
+        let oid = || SnmpValue::ObjectIdentifier(vec![1, 3, 6, 1, 6, 3, 1]);
+        let decoded = round_trip(
+            &[IndexType::ObjectIdentifier, IndexType::Integer],
+            &[oid(), SnmpValue::Integer(1)],
+            &[7, 1, 3, 6, 1, 6, 3, 1, 1],
+        );
+        assert!(matches!(&decoded[0], SnmpValue::ObjectIdentifier(o) if o.len() == 7));
+
+        round_trip(
+            &[IndexType::Integer, IndexType::ImpliedObjectIdentifier],
+            &[SnmpValue::Integer(1), oid()],
+            &[1, 1, 3, 6, 1, 6, 3, 1],
+        );
+    }
+
+    #[test]
+    fn malformed_suffixes_are_errors() {
+        let string = [IndexType::OctetString];
+        for (types, suffix) in [
+            (&[IndexType::Integer][..], &[][..]),
+            (&[IndexType::Integer], &[1, 2]),
+            (&[IndexType::Integer], &[u32::MAX]),
+            (&string, &[]),
+            (&string, &[4, 101, 116]),
+            (&string, &[u32::MAX, 1]),
+            (&string, &[1, 256]),
+            (&[IndexType::FixedOctetString(6)], &[0, 1, 2, 3, 4]),
+            (&[IndexType::IpAddress], &[10, 0, 0]),
+            (&[IndexType::IpAddress], &[10, 0, 0, 300]),
+            (&[IndexType::ObjectIdentifier], &[3, 1, 3]),
+            (&[IndexType::ImpliedOctetString], &[1000]),
+            (
+                &[IndexType::ImpliedOctetString, IndexType::Integer],
+                &[101, 1],
+            ),
+        ] {
+            assert!(
+                decode_index(types, suffix).is_err(),
+                "{:?} decoded as {:?}",
+                suffix,
+                types
+            );
+        }
+    }
+
+    #[test]
+    fn mismatched_values_are_errors() {
+        for (types, values) in [
+            (&[IndexType::Integer][..], vec![SnmpValue::Integer(-1)]),
+            (&[IndexType::Integer], vec![SnmpValue::Gauge32(1)]),
+            (&[IndexType::Integer], vec![]),
+            (
+                &[IndexType::FixedOctetString(6)],
+                vec![SnmpValue::OctetString(vec![0; 5])],
+            ),
+            (
+                &[IndexType::ImpliedObjectIdentifier, IndexType::Integer],
+                vec![SnmpValue::ObjectIdentifier(vec![1]), SnmpValue::Integer(1)],
+            ),
+        ] {
+            assert!(encode_index(types, &values).is_err(), "{:?}", values);
+        }
+    }
+}
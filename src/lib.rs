@@ -1,4 +1,20 @@
 pub mod agent;
+pub mod agentx;
 pub mod asn1;
 pub mod snmp;
-pub mod client;
\ No newline at end of file
+pub mod client;
+pub mod community;
+pub mod index;
+pub mod mib;
+pub mod session;
+pub mod system;
+pub mod table;
+pub mod transport;
+#[cfg(feature = "tokio")]
+pub mod async_client;
+#[cfg(feature = "tokio")]
+pub mod async_agent;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "if-mib")]
+pub mod if_mib;
\ No newline at end of file
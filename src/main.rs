@@ -1,7 +1,3 @@
-mod agent;
-mod asn1;
-mod client;
-mod snmp;
 fn main() {
     // let mut client = client::SnmpClient::new();
 
@@ -0,0 +1,485 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+
+use anyhow::{Result, anyhow};
+
+use crate::snmp::{ErrorStatus, SnmpValue, Varbind};
+
+/// MAX-ACCESS of an object (RFC 2578 section 7.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    NotAccessible,
+    AccessibleForNotify,
+    ReadOnly,
+    ReadWrite,
+    ReadCreate,
+}
+
+impl Access {
+    /// Whether GET and GETNEXT may return the object.
+    pub fn is_readable(self) -> bool {
+        matches!(
+            self,
+            Access::ReadOnly | Access::ReadWrite | Access::ReadCreate
+        )
+    }
+
+    /// Whether SET may change the object.
+    pub fn is_writable(self) -> bool {
+        matches!(self, Access::ReadWrite | Access::ReadCreate)
+    }
+}
+
+/// The base type of an object's SYNTAX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    Integer,
+    OctetString,
+    ObjectIdentifier,
+    IpAddress,
+    Counter32,
+    Gauge32,
+    TimeTicks,
+    Opaque,
+    Counter64,
+}
+
+impl ValueType {
+    /// The type of `value`, `None` for NULL and the exception values.
+    pub fn of(value: &SnmpValue) -> Option<Self> {
+        Some(match value {
+            SnmpValue::Integer(_) => ValueType::Integer,
+            SnmpValue::OctetString(_) => ValueType::OctetString,
+            SnmpValue::ObjectIdentifier(_) => ValueType::ObjectIdentifier,
+            SnmpValue::IpAddress(_) => ValueType::IpAddress,
+            SnmpValue::Counter32(_) => ValueType::Counter32,
+            SnmpValue::Gauge32(_) => ValueType::Gauge32,
+            SnmpValue::TimeTicks(_) => ValueType::TimeTicks,
+            SnmpValue::Opaque(_) => ValueType::Opaque,
+            SnmpValue::Counter64(_) => ValueType::Counter64,
+            SnmpValue::Null
+            | SnmpValue::NoSuchObject
+            | SnmpValue::NoSuchInstance
+            | SnmpValue::EndOfMibView => return None,
+        })
+    }
+}
+
+/// STATUS of an object (RFC 2578 section 7.5). It documents the
+/// definition only: the agent serves objects of any status alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Current,
+    Deprecated,
+    Obsolete,
+}
+
+/// SYNTAX of an object: its base type, refined by the sizes, ranges or
+/// enumerated values it allows (RFC 2578 section 9).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Syntax {
+    pub value_type: ValueType,
+    /// Allowed lengths of a string, e.g. `(0, 255)` for DisplayString.
+    /// Any length when empty.
+    pub sizes: Vec<(usize, usize)>,
+    /// Allowed values of a number. Any value when empty.
+    pub ranges: Vec<(i64, i64)>,
+    /// The named values of an enumerated INTEGER. Any value when empty.
+    pub enumeration: Vec<(i64, String)>,
+}
+
+impl Syntax {
+    pub fn new(value_type: ValueType) -> Self {
+        Self {
+            value_type,
+            sizes: Vec::new(),
+            ranges: Vec::new(),
+            enumeration: Vec::new(),
+        }
+    }
+
+    /// Allows strings of `min` to `max` octets; call it again to allow
+    /// several ranges, or with `min == max` for a fixed size.
+    pub fn with_size(mut self, min: usize, max: usize) -> Self {
+        self.sizes.push((min, max));
+        self
+    }
+
+    /// Allows numbers from `min` to `max`; call it again to allow
+    /// several ranges.
+    pub fn with_range(mut self, min: i64, max: i64) -> Self {
+        self.ranges.push((min, max));
+        self
+    }
+
+    /// Adds the named value `value` of an enumeration.
+    pub fn with_enum(mut self, value: i64, name: &str) -> Self {
+        self.enumeration.push((value, name.to_string()));
+        self
+    }
+
+    /// Checks that `value` fits the syntax, failing with wrongType,
+    /// wrongLength or wrongValue as a SetRequest would (RFC 3416 section
+    /// 4.2.5).
+    pub fn check(&self, value: &SnmpValue) -> Result<(), ErrorStatus> {
+        if ValueType::of(value) != Some(self.value_type) {
+            return Err(ErrorStatus::WrongType);
+        }
+        if let SnmpValue::OctetString(bytes) | SnmpValue::Opaque(bytes) = value
+            && !self.sizes.is_empty()
+            && !self
+                .sizes
+                .iter()
+                .any(|&(min, max)| (min..=max).contains(&bytes.len()))
+        {
+            return Err(ErrorStatus::WrongLength);
+        }
+        if let Some(number) = number(value) {
+            let in_range = self.ranges.is_empty()
+                || self
+                    .ranges
+                    .iter()
+                    .any(|&(min, max)| (min as i128..=max as i128).contains(&number));
+            let named = self.enumeration.is_empty()
+                || self.enumeration.iter().any(|(n, _)| *n as i128 == number);
+            if !in_range || !named {
+                return Err(ErrorStatus::WrongValue);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<ValueType> for Syntax {
+    fn from(value_type: ValueType) -> Self {
+        Self::new(value_type)
+    }
+}
+
+/// The OBJECT-TYPE definition of a registered object (RFC 2578 section
+/// 7), which the agent enforces on requests for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectType {
+    pub syntax: Syntax,
+    pub access: Access,
+    pub status: Status,
+}
+
+impl ObjectType {
+    pub fn new(syntax: impl Into<Syntax>, access: Access) -> Self {
+        Self {
+            syntax: syntax.into(),
+            access,
+            status: Status::Current,
+        }
+    }
+
+    pub fn with_status(mut self, status: Status) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+// The value of a numeric type, wide enough for all of them
+fn number(value: &SnmpValue) -> Option<i128> {
+    Some(match value {
+        SnmpValue::Integer(n) => *n as i128,
+        SnmpValue::Counter32(n) | SnmpValue::Gauge32(n) | SnmpValue::TimeTicks(n) => *n as i128,
+        SnmpValue::Counter64(n) => *n as i128,
+        _ => return None,
+    })
+}
+
+/// The values of an agent's MIB, ordered by OID.
+///
+/// Exact lookups, successor lookups and inserts take O(log n), and
+/// iterating over a subtree only visits the entries inside it, so walking
+/// a large MIB costs O(log n) per step instead of a scan of every OID.
+#[derive(Debug, Clone, Default)]
+pub struct MibStore {
+    entries: BTreeMap<Vec<u32>, SnmpValue>,
+    // The definitions of objects registered with one
+    objects: BTreeMap<Vec<u32>, ObjectType>,
+}
+
+impl MibStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, oid: &[u32]) -> Option<&SnmpValue> {
+        self.entries.get(oid)
+    }
+
+    /// Stores `value` at `oid`, returning the value it replaces.
+    pub fn insert(&mut self, oid: Vec<u32>, value: SnmpValue) -> Option<SnmpValue> {
+        self.entries.insert(oid, value)
+    }
+
+    /// Stores `value` at `oid` along with the object's definition, which
+    /// stays in place when the value is replaced.
+    pub fn insert_object(&mut self, oid: Vec<u32>, value: SnmpValue, object: ObjectType) {
+        self.objects.insert(oid.clone(), object);
+        self.entries.insert(oid, value);
+    }
+
+    /// The definition of the object at `oid`, if it was registered with one.
+    pub fn object_type(&self, oid: &[u32]) -> Option<&ObjectType> {
+        self.objects.get(oid)
+    }
+
+    /// Whether GET and GETNEXT may return the value at `oid`. Objects
+    /// without a definition are.
+    pub fn is_readable(&self, oid: &[u32]) -> bool {
+        self.objects
+            .get(oid)
+            .is_none_or(|object| object.access.is_readable())
+    }
+
+    pub fn remove(&mut self, oid: &[u32]) -> Option<SnmpValue> {
+        self.objects.remove(oid);
+        self.entries.remove(oid)
+    }
+
+    /// Removes every entry under `prefix`, returning how many there were.
+    pub fn remove_subtree(&mut self, prefix: &[u32]) -> usize {
+        let oids: Vec<Vec<u32>> = self.subtree(prefix).map(|(oid, _)| oid.to_vec()).collect();
+        for oid in &oids {
+            self.objects.remove(oid.as_slice());
+            self.entries.remove(oid.as_slice());
+        }
+        oids.len()
+    }
+
+    /// The first readable entry after `oid` in lexicographical order.
+    pub fn next(&self, oid: &[u32]) -> Option<Varbind> {
+        self.range((Bound::Excluded(oid), Bound::Unbounded))
+            .find(|(oid, _)| self.is_readable(oid))
+            .map(|(oid, value)| Varbind {
+                oid: oid.to_vec(),
+                value: value.clone(),
+            })
+    }
+
+    /// The entries whose OIDs fall in `range`, in order.
+    pub fn range<R: RangeBounds<[u32]>>(
+        &self,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = (&[u32], &SnmpValue)> {
+        self.entries
+            .range::<[u32], R>(range)
+            .map(|(oid, value)| (oid.as_slice(), value))
+    }
+
+    /// The entries under `prefix`, in order.
+    pub fn subtree(&self, prefix: &[u32]) -> impl DoubleEndedIterator<Item = (&[u32], &SnmpValue)> {
+        let end = subtree_end(prefix);
+        let end = match &end {
+            Some(end) => Bound::Excluded(end.as_slice()),
+            None => Bound::Unbounded,
+        };
+        self.entries
+            .range::<[u32], _>((Bound::Included(prefix), end))
+            .map(|(oid, value)| (oid.as_slice(), value))
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&[u32], &SnmpValue)> {
+        self.entries
+            .iter()
+            .map(|(oid, value)| (oid.as_slice(), value))
+    }
+}
+
+impl Extend<(Vec<u32>, SnmpValue)> for MibStore {
+    fn extend<I: IntoIterator<Item = (Vec<u32>, SnmpValue)>>(&mut self, entries: I) {
+        self.entries.extend(entries);
+    }
+}
+
+impl FromIterator<(Vec<u32>, SnmpValue)> for MibStore {
+    fn from_iter<I: IntoIterator<Item = (Vec<u32>, SnmpValue)>>(entries: I) -> Self {
+        Self {
+            entries: entries.into_iter().collect(),
+            objects: BTreeMap::new(),
+        }
+    }
+}
+
+// The first entry after `oid` that lies outside all of the `shadowed`
+// subtrees, jumping over each shadowed subtree as a whole
+pub(crate) fn next_unshadowed(
+    store: &MibStore,
+    shadowed: &[&[u32]],
+    oid: &[u32],
+) -> Option<Varbind> {
+    let mut from = Bound::Excluded(oid.to_vec());
+    loop {
+        let (next_oid, value) = store
+            .range((from.as_ref().map(Vec::as_slice), Bound::Unbounded))
+            .find(|(oid, _)| store.is_readable(oid))?;
+        // The outermost shadowed subtree holding it
+        let prefix = shadowed
+            .iter()
+            .filter(|prefix| next_oid.starts_with(prefix))
+            .min_by_key(|prefix| prefix.len());
+        match prefix {
+            None => {
+                return Some(Varbind {
+                    oid: next_oid.to_vec(),
+                    value: value.clone(),
+                });
+            }
+            Some(prefix) => from = Bound::Included(subtree_end(prefix)?),
+        }
+    }
+}
+
+// The first OID after every OID under `subtree`, `None` if there is none
+pub(crate) fn subtree_end(subtree: &[u32]) -> Option<Vec<u32>> {
+    let mut end = subtree.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u32::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// Check a SetRequest varbind against the static value it would replace:
+// only existing objects can be written, within their definition or else
+// with the type of their current value
+pub(crate) fn check_set(store: &MibStore, varbind: &Varbind) -> Result<(), ErrorStatus> {
+    let current = store.get(&varbind.oid).ok_or(ErrorStatus::NoCreation)?;
+    if let Some(object) = store.object_type(&varbind.oid) {
+        return match object.access {
+            access if access.is_writable() => object.syntax.check(&varbind.value),
+            access if access.is_readable() => Err(ErrorStatus::NotWritable),
+            _ => Err(ErrorStatus::NoAccess),
+        };
+    }
+    match ValueType::of(&varbind.value) {
+        Some(value_type) if Some(value_type) == ValueType::of(current) => Ok(()),
+        _ => Err(ErrorStatus::WrongType),
+    }
+}
+
+// Check the initial value of an object being registered
+pub(crate) fn check_value(oid: &[u32], value: &SnmpValue, object: &ObjectType) -> Result<()> {
+    object
+        .syntax
+        .check(value)
+        .map_err(|status| anyhow!("Value of {:?} does not fit its syntax: {}", oid, status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(oids: &[&[u32]]) -> MibStore {
+        oids.iter()
+            .enumerate()
+            .map(|(i, oid)| (oid.to_vec(), SnmpValue::Integer(i as i32)))
+            .collect()
+    }
+
+    fn next_oid(store: &MibStore, oid: &[u32]) -> Option<Vec<u32>> {
+        store.next(oid).map(|varbind| varbind.oid)
+    }
+
+    #[test]
+    fn prefix_sorts_before_longer_oids() {
+        let mib = store(&[
+            &[1, 3, 6, 2],
+            &[1, 3, 6, 1, 0],
+            &[1, 3, 6],
+            &[1, 3, 6, 1],
+            &[1, 3, 10],
+        ]);
+        let order: Vec<&[u32]> = mib.iter().map(|(oid, _)| oid).collect();
+        assert_eq!(
+            order,
+            [
+                &[1, 3, 6][..],
+                &[1, 3, 6, 1],
+                &[1, 3, 6, 1, 0],
+                &[1, 3, 6, 2],
+                &[1, 3, 10],
+            ]
+        );
+
+        assert_eq!(next_oid(&mib, &[1, 3]), Some(vec![1, 3, 6]));
+        assert_eq!(next_oid(&mib, &[1, 3, 6]), Some(vec![1, 3, 6, 1]));
+        assert_eq!(next_oid(&mib, &[1, 3, 6, 1, 0, 5]), Some(vec![1, 3, 6, 2]));
+        // 10 follows 6 numerically, not as text
+        assert_eq!(next_oid(&mib, &[1, 3, 7]), Some(vec![1, 3, 10]));
+
+        let subtree: Vec<&[u32]> = mib.subtree(&[1, 3, 6, 1]).map(|(oid, _)| oid).collect();
+        assert_eq!(subtree, [&[1, 3, 6, 1][..], &[1, 3, 6, 1, 0]]);
+    }
+
+    #[test]
+    fn next_skips_unreadable_objects() {
+        let mut mib = store(&[&[1, 1], &[1, 3]]);
+        let hidden = ObjectType::new(ValueType::Integer, Access::NotAccessible);
+        mib.insert_object(vec![1, 2], SnmpValue::Integer(0), hidden);
+        assert_eq!(next_oid(&mib, &[1, 1]), Some(vec![1, 3]));
+    }
+
+    #[test]
+    fn next_past_the_last_object() {
+        let mib = store(&[&[1, 3, 6, 1], &[1, 3, 6, 2]]);
+        assert_eq!(next_oid(&mib, &[1, 3, 6, 2]), None);
+        assert_eq!(next_oid(&mib, &[1, 3, 6, 2, 0]), None);
+        assert_eq!(next_oid(&mib, &[2]), None);
+        assert!(next_unshadowed(&mib, &[], &[1, 3, 6, 2]).is_none());
+        assert!(next_oid(&MibStore::new(), &[]).is_none());
+    }
+
+    #[test]
+    fn handler_subtree_shadows_static_values() {
+        let mib = store(&[
+            &[1, 1],
+            &[1, 2],
+            &[1, 2, 1],
+            &[1, 2, 1, 5],
+            &[1, 2, 7],
+            &[1, 3],
+        ]);
+        let next = |shadowed: &[&[u32]], oid: &[u32]| {
+            next_unshadowed(&mib, shadowed, oid).map(|varbind| varbind.oid)
+        };
+
+        assert_eq!(next(&[&[1, 2]], &[1, 1]), Some(vec![1, 3]));
+        // From inside a shadowed subtree, and with a nested one
+        assert_eq!(next(&[&[1, 2]], &[1, 2, 1]), Some(vec![1, 3]));
+        assert_eq!(next(&[&[1, 2, 1], &[1, 2]], &[1]), Some(vec![1, 1]));
+        assert_eq!(next(&[&[1, 2, 1]], &[1, 1]), Some(vec![1, 2]));
+        assert_eq!(next(&[&[1, 2, 1]], &[1, 2]), Some(vec![1, 2, 7]));
+        // A shadowed subtree at the end of the MIB
+        assert_eq!(next(&[&[1, 3]], &[1, 2, 7]), None);
+    }
+
+    #[test]
+    fn subtree_end_carries_over_max() {
+        assert_eq!(subtree_end(&[1, 3, 6]), Some(vec![1, 3, 7]));
+        assert_eq!(subtree_end(&[1, u32::MAX]), Some(vec![2]));
+        assert_eq!(subtree_end(&[u32::MAX, u32::MAX]), None);
+        assert_eq!(subtree_end(&[]), None);
+
+        let mib = store(&[&[1, u32::MAX, 4], &[2]]);
+        let shadowed: &[&[u32]] = &[&[1, u32::MAX]];
+        assert_eq!(
+            next_unshadowed(&mib, shadowed, &[1]).map(|varbind| varbind.oid),
+            Some(vec![2])
+        );
+    }
+}
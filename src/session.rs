@@ -0,0 +1,552 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+use bytes::BytesMut;
+
+use crate::client::{self, RequestOptions, Response};
+use crate::snmp::{self, PduType, SnmpError, SnmpValue, Varbind};
+use crate::transport::TransportAddr;
+
+/// A datagram the session wants sent.
+#[derive(Debug, Clone)]
+pub struct Transmit {
+    pub destination: TransportAddr,
+    pub payload: BytesMut,
+}
+
+/// Client protocol state without any I/O.
+///
+/// Requests are started with the methods named after the PDU types, each
+/// returning the request ID that identifies it. The caller then drives
+/// the session:
+///
+/// - sends every datagram returned by [`ClientSession::poll_transmit`],
+/// - passes received datagrams to [`ClientSession::handle_datagram`],
+/// - calls [`ClientSession::handle_timeout`] once the instant returned by
+///   [`ClientSession::poll_timeout`] has passed,
+/// - collects finished requests from [`ClientSession::poll_response`].
+///
+/// Retransmissions reuse the request ID, so a late answer to an earlier
+/// attempt still completes the request.
+pub struct ClientSession {
+    version: u8,
+    max_message_size: usize,
+    request_id: i32,
+    pending: HashMap<i32, PendingRequest>,
+    transmits: VecDeque<Transmit>,
+    completed: VecDeque<(i32, Result<Response, SnmpError>)>,
+}
+
+struct PendingRequest {
+    destination: TransportAddr,
+    payload: BytesMut,
+    options: RequestOptions,
+    attempt: u32,
+    deadline: Instant,
+}
+
+impl ClientSession {
+    pub fn new() -> Self {
+        Self {
+            version: snmp::SNMP_VERSION_1,
+            max_message_size: snmp::DEFAULT_MAX_MESSAGE_SIZE,
+            request_id: 1,
+            pending: HashMap::new(),
+            transmits: VecDeque::new(),
+            completed: VecDeque::new(),
+        }
+    }
+
+    /// Selects the protocol version, [`snmp::SNMP_VERSION_1`] or [`snmp::SNMP_VERSION_2C`].
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Sets the largest request the session will send and response it will accept.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    pub fn get(
+        &mut self,
+        now: Instant,
+        destination: TransportAddr,
+        community: &str,
+        oids: &[&[u32]],
+        options: &RequestOptions,
+    ) -> Result<i32, SnmpError> {
+        self.start_request(
+            now,
+            destination,
+            community,
+            PduType::GET_REQUEST,
+            &client::null_varbinds(oids),
+            None,
+            options,
+        )
+    }
+
+    pub fn get_next(
+        &mut self,
+        now: Instant,
+        destination: TransportAddr,
+        community: &str,
+        oids: &[&[u32]],
+        options: &RequestOptions,
+    ) -> Result<i32, SnmpError> {
+        self.start_request(
+            now,
+            destination,
+            community,
+            PduType::GET_NEXT_REQUEST,
+            &client::null_varbinds(oids),
+            None,
+            options,
+        )
+    }
+
+    /// Starts a GetBulk request (SNMPv2c only), see [`client::SnmpClient::get_bulk`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_bulk(
+        &mut self,
+        now: Instant,
+        destination: TransportAddr,
+        community: &str,
+        non_repeaters: i32,
+        max_repetitions: i32,
+        oids: &[&[u32]],
+        options: &RequestOptions,
+    ) -> Result<i32, SnmpError> {
+        self.start_request(
+            now,
+            destination,
+            community,
+            PduType::GET_BULK_REQUEST,
+            &client::null_varbinds(oids),
+            Some((non_repeaters, max_repetitions)),
+            options,
+        )
+    }
+
+    pub fn set(
+        &mut self,
+        now: Instant,
+        destination: TransportAddr,
+        community: &str,
+        varbinds: &[(&[u32], SnmpValue)],
+        options: &RequestOptions,
+    ) -> Result<i32, SnmpError> {
+        let varbinds: Vec<Varbind> = varbinds
+            .iter()
+            .map(|(oid, value)| Varbind {
+                oid: oid.to_vec(),
+                value: value.clone(),
+            })
+            .collect();
+        self.start_request(
+            now,
+            destination,
+            community,
+            PduType::SET_REQUEST,
+            &varbinds,
+            None,
+            options,
+        )
+    }
+
+    // Encode a request and queue its first transmission; `bulk` carries
+    // non-repeaters and max-repetitions for GetBulk
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn start_request(
+        &mut self,
+        now: Instant,
+        destination: TransportAddr,
+        community: &str,
+        pdu_type: PduType,
+        varbinds: &[Varbind],
+        bulk: Option<(i32, i32)>,
+        options: &RequestOptions,
+    ) -> Result<i32, SnmpError> {
+        if bulk.is_some() && self.version == snmp::SNMP_VERSION_1 {
+            return Err(SnmpError::UnsupportedOperation);
+        }
+
+        // Skip IDs still in use, which can happen after the counter wraps around
+        let mut request_id = self.request_id;
+        while self.pending.contains_key(&request_id) {
+            request_id = request_id.wrapping_add(1);
+        }
+        self.request_id = request_id.wrapping_add(1);
+
+        let payload = client::encode_request(
+            self.version,
+            community,
+            request_id,
+            pdu_type,
+            varbinds,
+            bulk,
+        );
+        if payload.len() > self.max_message_size {
+            return Err(SnmpError::MessageTooLarge(self.max_message_size));
+        }
+
+        self.transmits.push_back(Transmit {
+            destination: destination.clone(),
+            payload: payload.clone(),
+        });
+        self.pending.insert(
+            request_id,
+            PendingRequest {
+                destination,
+                payload,
+                options: *options,
+                attempt: 0,
+                deadline: now + options.attempt_timeout(0),
+            },
+        );
+
+        Ok(request_id)
+    }
+
+    /// Abandons a request; a response arriving for it later is ignored.
+    pub fn cancel(&mut self, request_id: i32) {
+        self.pending.remove(&request_id);
+    }
+
+    /// Returns the next datagram to send.
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    /// Returns the next finished request with its outcome.
+    pub fn poll_response(&mut self) -> Option<(i32, Result<Response, SnmpError>)> {
+        self.completed.pop_front()
+    }
+
+    /// Returns when [`ClientSession::handle_timeout`] is due next, or
+    /// `None` if no request is outstanding.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.pending.values().map(|p| p.deadline).min()
+    }
+
+    /// Processes a datagram received from `source`.
+    ///
+    /// Anything that is not an answer from the agent a request was sent
+    /// to is ignored. The whole datagram must be passed in: one larger than
+    /// the maximum message size fails the requests outstanding at `source`.
+    pub fn handle_datagram(&mut self, source: &TransportAddr, data: &[u8]) {
+        if data.len() > self.max_message_size {
+            let failed: Vec<i32> = self
+                .pending
+                .iter()
+                .filter(|(_, p)| p.destination == *source)
+                .map(|(&request_id, _)| request_id)
+                .collect();
+            for request_id in failed {
+                self.pending.remove(&request_id);
+                self.completed.push_back((
+                    request_id,
+                    Err(SnmpError::MessageTooLarge(self.max_message_size)),
+                ));
+            }
+            return;
+        }
+
+        let Ok(message) = snmp::decode_snmp_message(data) else {
+            return;
+        };
+        let request_id = message.pdu.request_id;
+        if self
+            .pending
+            .get(&request_id)
+            .is_none_or(|p| p.destination != *source)
+        {
+            return;
+        }
+
+        self.pending.remove(&request_id);
+        self.completed
+            .push_back((request_id, client::into_response(message.pdu)));
+    }
+
+    /// Retransmits requests whose attempt timed out and fails those that
+    /// have run out of retries.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        let mut expired = Vec::new();
+        for (&request_id, pending) in self.pending.iter_mut() {
+            if pending.deadline > now {
+                continue;
+            }
+            if pending.attempt >= pending.options.retries {
+                expired.push(request_id);
+                continue;
+            }
+
+            pending.attempt += 1;
+            pending.deadline = now + pending.options.attempt_timeout(pending.attempt);
+            self.transmits.push_back(Transmit {
+                destination: pending.destination.clone(),
+                payload: pending.payload.clone(),
+            });
+        }
+
+        for request_id in expired {
+            let pending = self.pending.remove(&request_id).unwrap();
+            self.completed.push_back((
+                request_id,
+                Err(SnmpError::Timeout {
+                    attempts: pending.options.retries + 1,
+                }),
+            ));
+        }
+    }
+}
+
+impl Default for ClientSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::agent::AgentCore;
+
+    const SYS_CONTACT: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 4, 0];
+
+    fn agent_addr() -> TransportAddr {
+        TransportAddr::Udp("127.0.0.1:161".parse().unwrap())
+    }
+
+    fn agent() -> AgentCore {
+        let agent = AgentCore::new(vec!["public".to_string()]);
+        agent
+            .register_oid(
+                SYS_CONTACT.to_vec(),
+                SnmpValue::OctetString(b"ops".to_vec()),
+            )
+            .unwrap();
+        agent
+    }
+
+    fn options() -> RequestOptions {
+        RequestOptions {
+            timeout: Duration::from_millis(100),
+            retries: 2,
+            backoff: 2.0,
+        }
+    }
+
+    fn session_with_request(now: Instant) -> (ClientSession, i32, Transmit) {
+        let mut session = ClientSession::new().with_version(snmp::SNMP_VERSION_2C);
+        let request_id = session
+            .get(now, agent_addr(), "public", &[&SYS_CONTACT], &options())
+            .unwrap();
+        let transmit = session.poll_transmit().unwrap();
+        (session, request_id, transmit)
+    }
+
+    #[test]
+    fn sends_a_request_and_completes_it_with_the_answer() {
+        let now = Instant::now();
+        let (mut session, request_id, transmit) = session_with_request(now);
+        assert_eq!(transmit.destination, agent_addr());
+        assert!(session.poll_transmit().is_none());
+        assert!(session.poll_response().is_none());
+
+        let request = snmp::decode_snmp_message(&transmit.payload).unwrap();
+        assert_eq!(request.pdu.request_id, request_id);
+        assert_eq!(request.pdu.varbinds[0].oid, SYS_CONTACT);
+
+        let answer = agent().handle_datagram(&transmit.payload).unwrap();
+        session.handle_datagram(&agent_addr(), &answer);
+        let (id, response) = session.poll_response().unwrap();
+        assert_eq!(id, request_id);
+        assert!(matches!(
+            &response.unwrap().varbinds[0].value,
+            SnmpValue::OctetString(contact) if contact == b"ops"
+        ));
+        assert!(session.poll_timeout().is_none());
+        assert!(session.poll_response().is_none());
+    }
+
+    #[test]
+    fn retransmits_with_the_same_request_id_until_it_gives_up() {
+        let start = Instant::now();
+        let (mut session, request_id, first) = session_with_request(start);
+
+        // The first attempt waits 100ms plus up to 10% of jitter
+        let deadline = session.poll_timeout().unwrap();
+        assert!(deadline >= start + Duration::from_millis(100));
+        assert!(deadline <= start + Duration::from_millis(110));
+
+        // Nothing happens before the deadline
+        session.handle_timeout(deadline - Duration::from_millis(1));
+        assert!(session.poll_transmit().is_none());
+        assert_eq!(session.poll_timeout(), Some(deadline));
+
+        session.handle_timeout(deadline);
+        let second = session.poll_transmit().unwrap();
+        assert_eq!(second.destination, agent_addr());
+        assert_eq!(second.payload, first.payload);
+        assert_eq!(
+            snmp::decode_snmp_message(&second.payload)
+                .unwrap()
+                .pdu
+                .request_id,
+            request_id
+        );
+
+        // The second attempt waits twice as long
+        let next = session.poll_timeout().unwrap();
+        assert!(next >= deadline + Duration::from_millis(200));
+        assert!(next <= deadline + Duration::from_millis(220));
+
+        session.handle_timeout(next);
+        assert!(session.poll_transmit().is_some());
+        assert!(session.poll_response().is_none());
+
+        session.handle_timeout(session.poll_timeout().unwrap());
+        assert!(session.poll_transmit().is_none());
+        let (id, response) = session.poll_response().unwrap();
+        assert_eq!(id, request_id);
+        assert!(matches!(response, Err(SnmpError::Timeout { attempts: 3 })));
+        assert!(session.poll_timeout().is_none());
+    }
+
+    #[test]
+    fn a_late_answer_to_an_earlier_attempt_completes_the_request() {
+        let now = Instant::now();
+        let (mut session, request_id, first) = session_with_request(now);
+        session.handle_timeout(session.poll_timeout().unwrap());
+        assert!(session.poll_transmit().is_some());
+
+        let answer = agent().handle_datagram(&first.payload).unwrap();
+        session.handle_datagram(&agent_addr(), &answer);
+        assert_eq!(session.poll_response().unwrap().0, request_id);
+        assert!(session.poll_timeout().is_none());
+    }
+
+    #[test]
+    fn poll_timeout_returns_the_earliest_deadline() {
+        let now = Instant::now();
+        let mut session = ClientSession::new();
+        assert!(session.poll_timeout().is_none());
+
+        let slow = RequestOptions {
+            timeout: Duration::from_secs(5),
+            ..options()
+        };
+        session
+            .get(now, agent_addr(), "public", &[&SYS_CONTACT], &slow)
+            .unwrap();
+        let later = session.poll_timeout().unwrap();
+        assert!(later >= now + Duration::from_secs(5));
+
+        let fast = session
+            .get(now, agent_addr(), "public", &[&SYS_CONTACT], &options())
+            .unwrap();
+        let earlier = session.poll_timeout().unwrap();
+        assert!(earlier < later);
+        assert!(earlier <= now + Duration::from_millis(110));
+
+        session.cancel(fast);
+        assert_eq!(session.poll_timeout(), Some(later));
+    }
+
+    #[test]
+    fn ignores_answers_from_another_source_or_with_another_id() {
+        let now = Instant::now();
+        let (mut session, request_id, transmit) = session_with_request(now);
+        let answer = agent().handle_datagram(&transmit.payload).unwrap();
+
+        // The right answer, from the wrong address
+        let elsewhere = TransportAddr::Udp("127.0.0.1:1161".parse().unwrap());
+        session.handle_datagram(&elsewhere, &answer);
+        assert!(session.poll_response().is_none());
+
+        // An answer to a request that was never sent
+        let stale = client::encode_request(
+            snmp::SNMP_VERSION_2C,
+            "public",
+            request_id + 1,
+            PduType::GET_REQUEST,
+            &client::null_varbinds(&[&SYS_CONTACT]),
+            None,
+        );
+        let stale = agent().handle_datagram(&stale).unwrap();
+        session.handle_datagram(&agent_addr(), &stale);
+        assert!(session.poll_response().is_none());
+
+        // Garbage
+        session.handle_datagram(&agent_addr(), &[0x30, 0x03, 0x02]);
+        assert!(session.poll_response().is_none());
+
+        session.handle_datagram(&agent_addr(), &answer);
+        assert_eq!(session.poll_response().unwrap().0, request_id);
+    }
+
+    #[test]
+    fn a_cancelled_request_ignores_its_answer() {
+        let now = Instant::now();
+        let (mut session, request_id, transmit) = session_with_request(now);
+        session.cancel(request_id);
+        assert!(session.poll_timeout().is_none());
+
+        let answer = agent().handle_datagram(&transmit.payload).unwrap();
+        session.handle_datagram(&agent_addr(), &answer);
+        assert!(session.poll_response().is_none());
+
+        session.handle_timeout(now + Duration::from_secs(1));
+        assert!(session.poll_transmit().is_none());
+        assert!(session.poll_response().is_none());
+    }
+
+    #[test]
+    fn reports_agent_errors_and_rejects_bulk_in_v1() {
+        let now = Instant::now();
+        let mut session = ClientSession::new();
+        let request_id = session
+            .set(
+                now,
+                agent_addr(),
+                "public",
+                &[(&SYS_CONTACT, SnmpValue::Integer(1))],
+                &options(),
+            )
+            .unwrap();
+        let transmit = session.poll_transmit().unwrap();
+        let answer = agent().handle_datagram(&transmit.payload).unwrap();
+        session.handle_datagram(&agent_addr(), &answer);
+        let (id, response) = session.poll_response().unwrap();
+        assert_eq!(id, request_id);
+        assert!(matches!(response, Err(SnmpError::AgentError { .. })));
+
+        assert!(matches!(
+            session.get_bulk(
+                now,
+                agent_addr(),
+                "public",
+                0,
+                10,
+                &[&SYS_CONTACT],
+                &options()
+            ),
+            Err(SnmpError::UnsupportedOperation)
+        ));
+        assert!(session.poll_transmit().is_none());
+    }
+}
@@ -1,18 +1,28 @@
 use crate::asn1::{decode, encode};
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Result, anyhow};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use std::collections::HashMap;
 use std::fmt;
-use std::net::SocketAddr;
 
 pub const SNMP_VERSION_1: u8 = 0x00;
+pub const SNMP_VERSION_2C: u8 = 0x01;
 
+/// Well-known UDP port of SNMP agents.
+pub const SNMP_PORT: u16 = 161;
+
+/// Well-known port of SNMP agents over TLS and DTLS (RFC 6353).
+pub const SNMP_TLS_PORT: u16 = 10161;
+
+/// Default maximum size of an SNMP message, in bytes.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4096;
+
+#[allow(non_camel_case_types)]
 #[derive(Debug)]
 pub enum PduType {
     GET_REQUEST,
     GET_RESPONSE,
     GET_NEXT_REQUEST,
     SET_REQUEST,
+    GET_BULK_REQUEST,
 }
 
 #[derive(Debug)]
@@ -23,6 +33,19 @@ pub enum SnmpError {
     UnsupportedOperation,
     NoSuchObject,
     GenError,
+    MessageTooLarge(usize),
+    // The agent answered with a non-zero error-status; index is the 1-based
+    // error-index and oid the varbind it points at, if any
+    AgentError {
+        status: ErrorStatus,
+        index: i32,
+        oid: Option<Vec<u32>>,
+    },
+    Timeout {
+        attempts: u32,
+    },
+    // A GetNext/GetBulk answer that does not move past the requested OID
+    OidNotIncreasing(Vec<u32>),
 }
 
 impl fmt::Display for SnmpError {
@@ -34,12 +57,144 @@ impl fmt::Display for SnmpError {
             SnmpError::UnsupportedOperation => write!(f, "Unsupported operation"),
             SnmpError::NoSuchObject => write!(f, "No such object"),
             SnmpError::GenError => write!(f, "General error"),
+            SnmpError::MessageTooLarge(max) => {
+                write!(f, "Message exceeds the maximum size of {} bytes", max)
+            }
+            SnmpError::AgentError { status, index, oid } => match oid {
+                Some(oid) => write!(
+                    f,
+                    "Agent returned {} for varbind {} ({:?})",
+                    status, index, oid
+                ),
+                None => write!(f, "Agent returned {} (error index {})", status, index),
+            },
+            SnmpError::Timeout { attempts } => {
+                write!(f, "Request timed out after {} attempts", attempts)
+            }
+            SnmpError::OidNotIncreasing(oid) => {
+                write!(f, "Agent returned non-increasing OID {:?}", oid)
+            }
         }
     }
 }
 
 impl std::error::Error for SnmpError {}
 
+/// The error-status field of a PDU.
+///
+/// Codes 0-5 are defined by RFC 1157, the remaining ones were added by
+/// RFC 3416 and may only be sent to SNMPv2c managers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorStatus {
+    NoError = 0,
+    TooBig = 1,
+    NoSuchName = 2,
+    BadValue = 3,
+    ReadOnly = 4,
+    GenErr = 5,
+    NoAccess = 6,
+    WrongType = 7,
+    WrongLength = 8,
+    WrongEncoding = 9,
+    WrongValue = 10,
+    NoCreation = 11,
+    InconsistentValue = 12,
+    ResourceUnavailable = 13,
+    CommitFailed = 14,
+    UndoFailed = 15,
+    AuthorizationError = 16,
+    NotWritable = 17,
+    InconsistentName = 18,
+}
+
+impl ErrorStatus {
+    pub fn from_code(code: i32) -> Option<Self> {
+        let status = match code {
+            0 => ErrorStatus::NoError,
+            1 => ErrorStatus::TooBig,
+            2 => ErrorStatus::NoSuchName,
+            3 => ErrorStatus::BadValue,
+            4 => ErrorStatus::ReadOnly,
+            5 => ErrorStatus::GenErr,
+            6 => ErrorStatus::NoAccess,
+            7 => ErrorStatus::WrongType,
+            8 => ErrorStatus::WrongLength,
+            9 => ErrorStatus::WrongEncoding,
+            10 => ErrorStatus::WrongValue,
+            11 => ErrorStatus::NoCreation,
+            12 => ErrorStatus::InconsistentValue,
+            13 => ErrorStatus::ResourceUnavailable,
+            14 => ErrorStatus::CommitFailed,
+            15 => ErrorStatus::UndoFailed,
+            16 => ErrorStatus::AuthorizationError,
+            17 => ErrorStatus::NotWritable,
+            18 => ErrorStatus::InconsistentName,
+            _ => return None,
+        };
+        Some(status)
+    }
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// Maps an SNMPv2 error-status onto the closest SNMPv1 one (RFC 3584 section 4.4).
+    pub fn to_v1(self) -> Self {
+        match self {
+            ErrorStatus::WrongValue
+            | ErrorStatus::WrongEncoding
+            | ErrorStatus::WrongType
+            | ErrorStatus::WrongLength
+            | ErrorStatus::InconsistentValue => ErrorStatus::BadValue,
+            ErrorStatus::NoAccess
+            | ErrorStatus::NotWritable
+            | ErrorStatus::NoCreation
+            | ErrorStatus::InconsistentName
+            | ErrorStatus::AuthorizationError => ErrorStatus::NoSuchName,
+            ErrorStatus::ResourceUnavailable
+            | ErrorStatus::CommitFailed
+            | ErrorStatus::UndoFailed => ErrorStatus::GenErr,
+            other => other,
+        }
+    }
+
+    /// Returns the code to put on the wire for a message of the given version.
+    pub fn for_version(self, version: i32) -> Self {
+        if version == SNMP_VERSION_1 as i32 {
+            self.to_v1()
+        } else {
+            self
+        }
+    }
+}
+
+impl fmt::Display for ErrorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorStatus::NoError => "noError",
+            ErrorStatus::TooBig => "tooBig",
+            ErrorStatus::NoSuchName => "noSuchName",
+            ErrorStatus::BadValue => "badValue",
+            ErrorStatus::ReadOnly => "readOnly",
+            ErrorStatus::GenErr => "genErr",
+            ErrorStatus::NoAccess => "noAccess",
+            ErrorStatus::WrongType => "wrongType",
+            ErrorStatus::WrongLength => "wrongLength",
+            ErrorStatus::WrongEncoding => "wrongEncoding",
+            ErrorStatus::WrongValue => "wrongValue",
+            ErrorStatus::NoCreation => "noCreation",
+            ErrorStatus::InconsistentValue => "inconsistentValue",
+            ErrorStatus::ResourceUnavailable => "resourceUnavailable",
+            ErrorStatus::CommitFailed => "commitFailed",
+            ErrorStatus::UndoFailed => "undoFailed",
+            ErrorStatus::AuthorizationError => "authorizationError",
+            ErrorStatus::NotWritable => "notWritable",
+            ErrorStatus::InconsistentName => "inconsistentName",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 impl PduType {
     pub fn to_tag(&self) -> u8 {
         match self {
@@ -47,6 +202,7 @@ impl PduType {
             PduType::GET_RESPONSE => encode::GET_RESPONSE_TAG,
             PduType::GET_NEXT_REQUEST => encode::GET_NEXT_REQUEST_TAG,
             PduType::SET_REQUEST => encode::SET_REQUEST_TAG,
+            PduType::GET_BULK_REQUEST => encode::GET_BULK_REQUEST_TAG,
         }
     }
 }
@@ -72,7 +228,7 @@ pub fn build_varbind_list(oids: &[&[u32]], buf: &mut BytesMut) {
 
 pub fn build_pdu(
     request_id: i32,
-    error_status: i32,
+    error_status: ErrorStatus,
     error_index: i32,
     varbind_list: &[u8],
     pdu_type: PduType,
@@ -82,7 +238,7 @@ pub fn build_pdu(
 
     encode::encode_integer(request_id, &mut pdu_buf);
 
-    encode::encode_integer(error_status, &mut pdu_buf);
+    encode::encode_integer(error_status.code(), &mut pdu_buf);
 
     encode::encode_integer(error_index, &mut pdu_buf);
 
@@ -91,10 +247,32 @@ pub fn build_pdu(
     encode::encode_sequence(&pdu_buf, pdu_type.to_tag(), buf);
 }
 
-pub fn build_snmp_msg(community: &str, pdu: &[u8], buf: &mut BytesMut) {
+/// Encodes a GetBulk PDU, which carries non-repeaters and max-repetitions
+/// where other PDUs have error-status and error-index.
+pub fn build_bulk_pdu(
+    request_id: i32,
+    non_repeaters: i32,
+    max_repetitions: i32,
+    varbind_list: &[u8],
+    buf: &mut BytesMut,
+) {
+    let mut pdu_buf = BytesMut::new();
+
+    encode::encode_integer(request_id, &mut pdu_buf);
+
+    encode::encode_integer(non_repeaters, &mut pdu_buf);
+
+    encode::encode_integer(max_repetitions, &mut pdu_buf);
+
+    pdu_buf.put_slice(varbind_list);
+
+    encode::encode_sequence(&pdu_buf, encode::GET_BULK_REQUEST_TAG, buf);
+}
+
+pub fn build_snmp_msg(version: u8, community: &str, pdu: &[u8], buf: &mut BytesMut) {
     let mut msg_buf = BytesMut::new();
 
-    encode::encode_integer(SNMP_VERSION_1 as i32, &mut msg_buf);
+    encode::encode_integer(version as i32, &mut msg_buf);
 
     encode::encode_octet_string(community.as_bytes(), &mut msg_buf);
 
@@ -109,6 +287,17 @@ pub enum SnmpValue {
     OctetString(Vec<u8>),
     Null,
     ObjectIdentifier(Vec<u32>),
+    IpAddress([u8; 4]),
+    Counter32(u32),
+    Gauge32(u32),
+    TimeTicks(u32),
+    Opaque(Vec<u8>),
+    // Counter64 can only be carried in SNMPv2c messages
+    Counter64(u64),
+    // SNMPv2 exception values (RFC 3416), only valid in v2c responses
+    NoSuchObject,
+    NoSuchInstance,
+    EndOfMibView,
 }
 
 #[derive(Debug, Clone)]
@@ -121,8 +310,12 @@ pub struct Varbind {
 pub struct SnmpPdu {
     pub pdu_type: PduType,
     pub request_id: i32,
-    pub error_status: i32,
+    pub error_status: ErrorStatus,
     pub error_index: i32,
+    // Only meaningful for GetBulk requests, which carry them in place of
+    // error-status and error-index
+    pub non_repeaters: i32,
+    pub max_repetitions: i32,
     pub varbinds: Vec<Varbind>,
 }
 
@@ -138,7 +331,7 @@ pub fn decode_varbind(buf: &mut Bytes) -> Result<Varbind> {
         .map_err(|e| anyhow!("Failed to decode varbind sequence: {}", e))?;
     let oid =
         decode::decode_oid(&mut seq_data).map_err(|e| anyhow!("Failed to decode OID: {}", e))?;
-    let tag = decode::peek_tag(&mut seq_data).map_err(|e| anyhow!("Failed to peek tag: {}", e))?;
+    let tag = decode::peek_tag(&seq_data).map_err(|e| anyhow!("Failed to peek tag: {}", e))?;
     let value = match tag {
         encode::INTEGER_TAG => {
             let val = decode::decode_integer(&mut seq_data)
@@ -160,6 +353,43 @@ pub fn decode_varbind(buf: &mut Bytes) -> Result<Varbind> {
                 .map_err(|e| anyhow!("Failed to decode OID value: {}", e))?;
             SnmpValue::ObjectIdentifier(val)
         }
+        encode::IP_ADDRESS_TAG => {
+            let val = decode::decode_tagged_octets(&mut seq_data, tag)
+                .map_err(|e| anyhow!("Failed to decode IpAddress: {}", e))?;
+            let addr = <[u8; 4]>::try_from(val.as_slice())
+                .map_err(|_| anyhow!("IpAddress must be 4 bytes, got {}", val.len()))?;
+            SnmpValue::IpAddress(addr)
+        }
+        encode::COUNTER32_TAG | encode::GAUGE32_TAG | encode::TIMETICKS_TAG => {
+            let val = decode::decode_unsigned(&mut seq_data, tag)
+                .map_err(|e| anyhow!("Failed to decode unsigned integer: {}", e))?;
+            let val =
+                u32::try_from(val).map_err(|_| anyhow!("32-bit value out of range: {}", val))?;
+            match tag {
+                encode::COUNTER32_TAG => SnmpValue::Counter32(val),
+                encode::GAUGE32_TAG => SnmpValue::Gauge32(val),
+                _ => SnmpValue::TimeTicks(val),
+            }
+        }
+        encode::OPAQUE_TAG => {
+            let val = decode::decode_tagged_octets(&mut seq_data, tag)
+                .map_err(|e| anyhow!("Failed to decode Opaque: {}", e))?;
+            SnmpValue::Opaque(val)
+        }
+        encode::COUNTER64_TAG => {
+            let val = decode::decode_unsigned(&mut seq_data, tag)
+                .map_err(|e| anyhow!("Failed to decode Counter64: {}", e))?;
+            SnmpValue::Counter64(val)
+        }
+        encode::NO_SUCH_OBJECT_TAG | encode::NO_SUCH_INSTANCE_TAG | encode::END_OF_MIB_VIEW_TAG => {
+            decode::decode_exception(&mut seq_data)
+                .map_err(|e| anyhow!("Failed to decode exception value: {}", e))?;
+            match tag {
+                encode::NO_SUCH_OBJECT_TAG => SnmpValue::NoSuchObject,
+                encode::NO_SUCH_INSTANCE_TAG => SnmpValue::NoSuchInstance,
+                _ => SnmpValue::EndOfMibView,
+            }
+        }
         _ => return Err(anyhow!("Invalid varbind value tag: {}", tag)),
     };
 
@@ -186,6 +416,7 @@ pub fn decode_pdu(buf: &mut Bytes) -> Result<SnmpPdu> {
         encode::GET_NEXT_REQUEST_TAG => PduType::GET_NEXT_REQUEST,
         encode::GET_RESPONSE_TAG => PduType::GET_RESPONSE,
         encode::SET_REQUEST_TAG => PduType::SET_REQUEST,
+        encode::GET_BULK_REQUEST_TAG => PduType::GET_BULK_REQUEST,
         _ => return Err(anyhow!("Invalid PDU tag: {}", tag)),
     };
 
@@ -195,11 +426,26 @@ pub fn decode_pdu(buf: &mut Bytes) -> Result<SnmpPdu> {
     let request_id = decode::decode_integer(&mut pdu_data)
         .map_err(|e| anyhow!("Failed to decode request ID: {}", e))?;
 
-    let error_status = decode::decode_integer(&mut pdu_data)
-        .map_err(|e| anyhow!("Failed to decode error status: {}", e))?;
-
-    let error_index = decode::decode_integer(&mut pdu_data)
-        .map_err(|e| anyhow!("Failed to decode error index: {}", e))?;
+    let mut error_status = ErrorStatus::NoError;
+    let mut error_index = 0;
+    let mut non_repeaters = 0;
+    let mut max_repetitions = 0;
+
+    if let PduType::GET_BULK_REQUEST = pdu_type {
+        non_repeaters = decode::decode_integer(&mut pdu_data)
+            .map_err(|e| anyhow!("Failed to decode non-repeaters: {}", e))?;
+
+        max_repetitions = decode::decode_integer(&mut pdu_data)
+            .map_err(|e| anyhow!("Failed to decode max-repetitions: {}", e))?;
+    } else {
+        let status = decode::decode_integer(&mut pdu_data)
+            .map_err(|e| anyhow!("Failed to decode error status: {}", e))?;
+        error_status = ErrorStatus::from_code(status)
+            .ok_or_else(|| anyhow!("Invalid error status: {}", status))?;
+
+        error_index = decode::decode_integer(&mut pdu_data)
+            .map_err(|e| anyhow!("Failed to decode error index: {}", e))?;
+    }
 
     let varbinds = decode_varbind_list(&mut pdu_data)?;
     Ok(SnmpPdu {
@@ -207,6 +453,8 @@ pub fn decode_pdu(buf: &mut Bytes) -> Result<SnmpPdu> {
         request_id,
         error_status,
         error_index,
+        non_repeaters,
+        max_repetitions,
         varbinds,
     })
 }
@@ -219,7 +467,7 @@ pub fn decode_snmp_message(data: &[u8]) -> Result<SnmpMessage> {
     let version = decode::decode_integer(&mut msg_data)
         .map_err(|e| anyhow!("Failed to decode version: {}", e))?;
 
-    if version != SNMP_VERSION_1 as i32 {
+    if version != SNMP_VERSION_1 as i32 && version != SNMP_VERSION_2C as i32 {
         return Err(anyhow!("Invalid SNMP version: {}", version));
     }
 
@@ -227,7 +475,6 @@ pub fn decode_snmp_message(data: &[u8]) -> Result<SnmpMessage> {
         .map_err(|e| anyhow!("Failed to decode community string: {}", e))?;
 
     let pdu = decode_pdu(&mut msg_data)?;
-    
 
     Ok(SnmpMessage {
         version,
@@ -239,7 +486,7 @@ pub fn decode_snmp_message(data: &[u8]) -> Result<SnmpMessage> {
 pub fn build_response_pdu(
     request: &SnmpPdu,
     response_varbinds: Vec<Varbind>,
-    error_status: i32,
+    error_status: ErrorStatus,
     error_index: i32,
     buf: &mut BytesMut,
 ) {
@@ -247,54 +494,97 @@ pub fn build_response_pdu(
 
     encode::encode_integer(request.request_id, &mut pdu_buf);
 
-    encode::encode_integer(error_status, &mut pdu_buf);
+    encode::encode_integer(error_status.code(), &mut pdu_buf);
 
     encode::encode_integer(error_index, &mut pdu_buf);
 
     let mut varbind_list_buf = BytesMut::new();
-    build_response_varbind_list(&response_varbinds, &mut varbind_list_buf);
+    build_value_varbind_list(&response_varbinds, &mut varbind_list_buf);
     pdu_buf.put_slice(&varbind_list_buf);
 
     encode::encode_sequence(&pdu_buf, encode::GET_RESPONSE_TAG, buf);
 }
 
-fn build_response_varbind_list(varbinds: &[Varbind], buf: &mut BytesMut) {
+/// Encodes a list of varbinds together with their values.
+pub fn build_value_varbind_list(varbinds: &[Varbind], buf: &mut BytesMut) {
     let mut varbind_list_buf = BytesMut::new();
 
     for varbind in varbinds {
-        build_response_varbind(varbind, &mut varbind_list_buf);
+        build_value_varbind(varbind, &mut varbind_list_buf);
     }
 
     encode::encode_sequence(&varbind_list_buf, encode::SEQUENCE_TAG, buf);
 }
 
-fn build_response_varbind(varbind: &Varbind, buf: &mut BytesMut) {
+/// Returns the number of bytes `varbind` takes up once encoded.
+pub fn encoded_varbind_len(varbind: &Varbind) -> usize {
+    let mut buf = BytesMut::new();
+    build_value_varbind(varbind, &mut buf);
+    buf.len()
+}
+
+pub fn build_value_varbind(varbind: &Varbind, buf: &mut BytesMut) {
     let mut varbind_buf = BytesMut::new();
 
     encode::encode_oid(&varbind.oid, &mut varbind_buf);
 
-    match &varbind.value {
+    encode_value(&varbind.value, &mut varbind_buf);
+
+    encode::encode_sequence(&varbind_buf, encode::SEQUENCE_TAG, buf);
+}
+
+pub fn encode_value(value: &SnmpValue, buf: &mut BytesMut) {
+    match value {
         SnmpValue::Integer(val) => {
-            encode::encode_integer(*val, &mut varbind_buf);
+            encode::encode_integer(*val, buf);
         }
         SnmpValue::OctetString(val) => {
-            encode::encode_octet_string(val, &mut varbind_buf);
+            encode::encode_octet_string(val, buf);
         }
         SnmpValue::Null => {
-            encode::encode_null(&mut varbind_buf);
+            encode::encode_null(buf);
         }
         SnmpValue::ObjectIdentifier(val) => {
-            encode::encode_oid(val, &mut varbind_buf);
+            encode::encode_oid(val, buf);
+        }
+        SnmpValue::IpAddress(val) => {
+            encode::encode_tagged_octets(encode::IP_ADDRESS_TAG, val, buf);
+        }
+        SnmpValue::Counter32(val) => {
+            encode::encode_unsigned(encode::COUNTER32_TAG, *val as u64, buf);
+        }
+        SnmpValue::Gauge32(val) => {
+            encode::encode_unsigned(encode::GAUGE32_TAG, *val as u64, buf);
+        }
+        SnmpValue::TimeTicks(val) => {
+            encode::encode_unsigned(encode::TIMETICKS_TAG, *val as u64, buf);
+        }
+        SnmpValue::Opaque(val) => {
+            encode::encode_tagged_octets(encode::OPAQUE_TAG, val, buf);
+        }
+        SnmpValue::Counter64(val) => {
+            encode::encode_unsigned(encode::COUNTER64_TAG, *val, buf);
+        }
+        SnmpValue::NoSuchObject => {
+            encode::encode_exception(encode::NO_SUCH_OBJECT_TAG, buf);
+        }
+        SnmpValue::NoSuchInstance => {
+            encode::encode_exception(encode::NO_SUCH_INSTANCE_TAG, buf);
+        }
+        SnmpValue::EndOfMibView => {
+            encode::encode_exception(encode::END_OF_MIB_VIEW_TAG, buf);
         }
     }
-
-    encode::encode_sequence(&varbind_buf, encode::SEQUENCE_TAG, buf);
 }
 
+/// Builds the response to `request`.
+///
+/// SNMPv2-only error codes are downgraded with [`ErrorStatus::to_v1`] when
+/// answering an SNMPv1 request.
 pub fn build_response_message(
     request: &SnmpMessage,
     response_varbinds: Vec<Varbind>,
-    error_status: i32,
+    error_status: ErrorStatus,
     error_index: i32,
     buf: &mut BytesMut,
 ) {
@@ -308,7 +598,7 @@ pub fn build_response_message(
     build_response_pdu(
         &request.pdu,
         response_varbinds,
-        error_status,
+        error_status.for_version(request.version),
         error_index,
         &mut pdu_buf,
     );
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::agent::AgentCore;
+use crate::agentx::AgentxMaster;
+use crate::index::IndexType;
+use crate::mib::{Access, ObjectType, Syntax, ValueType};
+use crate::snmp::SnmpValue;
+use crate::table::{Column, Row, Table};
+
+// The system group of SNMPv2-MIB
+const SYSTEM: [u32; 7] = [1, 3, 6, 1, 2, 1, 1];
+
+/// The scalars of the SNMPv2-MIB system group (RFC 3418), for
+/// [`AgentCore::register_system`].
+///
+/// sysUpTime counts from the agent's creation, sysContact, sysName and
+/// sysLocation are writable, and sysORTable lists what was registered with
+/// [`AgentCore::register_capabilities`].
+#[derive(Debug, Clone)]
+pub struct SystemGroup {
+    pub descr: String,
+    pub object_id: Vec<u32>,
+    pub contact: String,
+    pub name: String,
+    pub location: String,
+    pub services: i32,
+}
+
+impl SystemGroup {
+    /// Creates the group with sysDescr `descr` and sysObjectID
+    /// `object_id`. sysName defaults to the host name and sysServices to
+    /// 72, an application host.
+    pub fn new(descr: &str, object_id: Vec<u32>) -> Self {
+        Self {
+            descr: descr.to_string(),
+            object_id,
+            contact: String::new(),
+            name: host_name(),
+            location: String::new(),
+            services: 72,
+        }
+    }
+
+    pub fn with_contact(mut self, contact: &str) -> Self {
+        self.contact = contact.to_string();
+        self
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn with_location(mut self, location: &str) -> Self {
+        self.location = location.to_string();
+        self
+    }
+
+    pub fn with_services(mut self, services: i32) -> Self {
+        self.services = services;
+        self
+    }
+}
+
+// The agent's start time and its sysORTable entries
+pub(crate) struct SystemState {
+    started: Instant,
+    capabilities: Mutex<Capabilities>,
+}
+
+#[derive(Default)]
+struct Capabilities {
+    // sysORIndex, sysORID, sysORDescr and sysORUpTime
+    entries: Vec<(i32, Vec<u32>, String, u32)>,
+    // The sysORIndex of each subagent entry, by sysORID and when it was
+    // added
+    remote: HashMap<(Vec<u32>, Instant), i32>,
+    last_index: i32,
+    last_change: u32,
+}
+
+impl Capabilities {
+    // A sysORIndex no entry has had, so that the others keep theirs as
+    // entries come and go
+    fn next_index(&mut self) -> i32 {
+        self.last_index += 1;
+        self.last_index
+    }
+}
+
+impl SystemState {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            capabilities: Mutex::new(Capabilities::default()),
+        }
+    }
+
+    // sysUpTime
+    pub(crate) fn uptime(&self) -> u32 {
+        ticks(self.started.elapsed())
+    }
+
+    fn uptime_at(&self, at: Instant) -> u32 {
+        ticks(at.saturating_duration_since(self.started))
+    }
+
+    // Add the sysORTable entry for `id`, or replace it under the same
+    // sysORIndex
+    pub(crate) fn add_capabilities(&self, id: Vec<u32>, description: &str) {
+        let uptime = self.uptime();
+        let mut capabilities = self.capabilities.lock().unwrap();
+        match capabilities.entries.iter_mut().find(|entry| entry.1 == id) {
+            Some(entry) => {
+                entry.2 = description.to_string();
+                entry.3 = uptime;
+            }
+            None => {
+                let index = capabilities.next_index();
+                capabilities
+                    .entries
+                    .push((index, id, description.to_string(), uptime));
+            }
+        }
+        capabilities.last_change = uptime;
+    }
+
+    // The sysORTable rows: local entries, and those of AgentX subagents
+    fn rows(&self, agentx: Option<&AgentxMaster>) -> Vec<Row> {
+        let mut capabilities = self.capabilities.lock().unwrap();
+        let mut entries = capabilities.entries.clone();
+        if let Some(master) = agentx {
+            let added = master.capabilities_added();
+            capabilities
+                .remote
+                .retain(|(id, at), _| added.iter().any(|(i, _, a)| i == id && a == at));
+            for (id, description, at) in added {
+                let index = match capabilities.remote.get(&(id.clone(), at)) {
+                    Some(&index) => index,
+                    None => {
+                        let index = capabilities.next_index();
+                        capabilities.remote.insert((id.clone(), at), index);
+                        index
+                    }
+                };
+                entries.push((index, id, description, self.uptime_at(at)));
+            }
+        }
+        entries
+            .into_iter()
+            .map(|(index, id, description, uptime)| {
+                Row::new(vec![SnmpValue::Integer(index)])
+                    .with_cell(2, SnmpValue::ObjectIdentifier(id))
+                    .with_cell(3, SnmpValue::OctetString(description.into_bytes()))
+                    .with_cell(4, SnmpValue::TimeTicks(uptime))
+            })
+            .collect()
+    }
+
+    // sysORLastChange
+    fn last_change(&self, agentx: Option<&AgentxMaster>) -> u32 {
+        let local = self.capabilities.lock().unwrap().last_change;
+        let remote = agentx
+            .and_then(|master| master.capabilities_changed())
+            .map_or(0, |changed| self.uptime_at(changed));
+        local.max(remote)
+    }
+}
+
+// Register the objects of `group` with `core`
+pub(crate) fn register(
+    core: &AgentCore,
+    state: &Arc<SystemState>,
+    group: SystemGroup,
+    agentx: Arc<RwLock<Option<AgentxMaster>>>,
+) -> Result<()> {
+    let oid = |arcs: &[u32]| [&SYSTEM[..], arcs].concat();
+    let display_string = || Syntax::new(ValueType::OctetString).with_size(0, 255);
+    let string = |s: String| SnmpValue::OctetString(s.into_bytes());
+
+    core.register_object(
+        oid(&[1, 0]),
+        string(group.descr),
+        ObjectType::new(display_string(), Access::ReadOnly),
+    )?;
+    core.register_object(
+        oid(&[2, 0]),
+        SnmpValue::ObjectIdentifier(group.object_id),
+        ObjectType::new(ValueType::ObjectIdentifier, Access::ReadOnly),
+    )?;
+    let uptime = state.clone();
+    core.register_scalar(oid(&[3, 0]), move || SnmpValue::TimeTicks(uptime.uptime()))?;
+    for (arc, value) in [(4, group.contact), (5, group.name), (6, group.location)] {
+        core.register_object(
+            oid(&[arc, 0]),
+            string(value),
+            ObjectType::new(display_string(), Access::ReadWrite),
+        )?;
+    }
+    core.register_object(
+        oid(&[7, 0]),
+        SnmpValue::Integer(group.services),
+        ObjectType::new(
+            Syntax::new(ValueType::Integer).with_range(0, 127),
+            Access::ReadOnly,
+        ),
+    )?;
+
+    // The master is looked up on each request, as it may be attached
+    // after the group is registered
+    let (last_change, master) = (state.clone(), agentx.clone());
+    core.register_scalar(oid(&[8, 0]), move || {
+        let master = master.read().unwrap().clone();
+        SnmpValue::TimeTicks(last_change.last_change(master.as_ref()))
+    })?;
+    let rows = state.clone();
+    let table = Table::new(oid(&[9]), vec![IndexType::Integer], move || {
+        let master = agentx.read().unwrap().clone();
+        rows.rows(master.as_ref())
+    })
+    .with_column(Column::new(
+        2,
+        ValueType::ObjectIdentifier,
+        Access::ReadOnly,
+    ))
+    .with_column(Column::new(3, ValueType::OctetString, Access::ReadOnly))
+    .with_column(Column::new(4, ValueType::TimeTicks, Access::ReadOnly))
+    .with_cache_timeout(Duration::ZERO);
+    core.register_table(table)
+}
+
+// TimeTicks, in hundredths of a second, wrapping like the counter does
+fn ticks(duration: Duration) -> u32 {
+    (duration.as_millis() / 10) as u32
+}
+
+// The host name, sysName's default
+fn host_name() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client;
+    use crate::snmp::{self, PduType};
+
+    // sysORIndex and sysORDescr of each row, in index order
+    fn entries(rows: Vec<Row>) -> Vec<(i32, String)> {
+        let mut entries: Vec<(i32, String)> = rows
+            .into_iter()
+            .map(|row| match (&row.index[..], &row.cells[&3]) {
+                ([SnmpValue::Integer(index)], SnmpValue::OctetString(descr)) => {
+                    (*index, String::from_utf8_lossy(descr).into_owned())
+                }
+                _ => panic!("{:?}", row),
+            })
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn replaced_entry_keeps_its_index() {
+        let state = SystemState::new();
+        state.add_capabilities(vec![1, 3, 6, 1, 6, 3, 1], "SNMPv2-MIB");
+        state.add_capabilities(vec![1, 3, 6, 1, 2, 1, 31], "IF-MIB");
+        state.add_capabilities(vec![1, 3, 6, 1, 6, 3, 1], "SNMPv2-MIB, revised");
+
+        assert_eq!(
+            entries(state.rows(None)),
+            [
+                (1, "SNMPv2-MIB, revised".to_string()),
+                (2, "IF-MIB".to_string())
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lists_subagent_capabilities_of_a_master_attached_later() {
+        use crate::agentx::pdu::{self, Payload, Pdu};
+        use std::os::unix::net::UnixStream;
+
+        let core = AgentCore::new(vec!["public".to_string()]);
+        core.register_system(SystemGroup::new("test", vec![1, 3, 6, 1, 4, 1, 99]))
+            .unwrap();
+        core.register_capabilities(vec![1, 3, 6, 1, 6, 3, 1], "SNMPv2-MIB");
+
+        let path = std::env::temp_dir().join(format!("system-agentx-{}", std::process::id()));
+        let master = AgentxMaster::new();
+        master.listen(path.to_str().unwrap()).unwrap();
+        let core = core.with_agentx(master);
+
+        // A subagent announcing its capabilities
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let mut call = |session_id, payload| {
+            let pdu = Pdu::new(session_id, 0, 0, payload);
+            std::io::Write::write_all(&mut stream, &pdu.encode()).unwrap();
+            let frame = pdu::read_frame(&mut stream).unwrap().unwrap();
+            Pdu::decode(&frame).unwrap().session_id
+        };
+        let session_id = call(
+            0,
+            Payload::Open {
+                timeout: 0,
+                id: Vec::new(),
+                description: Vec::new(),
+            },
+        );
+        call(
+            session_id,
+            Payload::AddAgentCaps {
+                id: vec![1, 3, 6, 1, 2, 1, 31],
+                description: b"IF-MIB".to_vec(),
+            },
+        );
+        let get = |oid: &[u32]| {
+            let request = client::encode_request(
+                snmp::SNMP_VERSION_2C,
+                "public",
+                1,
+                PduType::GET_REQUEST,
+                &client::null_varbinds(&[oid]),
+                None,
+            );
+            let response = core.handle_datagram(&request).unwrap();
+            let message = snmp::decode_snmp_message(&response).unwrap();
+            message.pdu.varbinds[0].value.clone()
+        };
+        let descr = |index| match get(&[&SYSTEM[..], &[9, 1, 3, index]].concat()) {
+            SnmpValue::OctetString(descr) => String::from_utf8(descr).unwrap(),
+            other => panic!("sysORDescr.{} is {:?}", index, other),
+        };
+        assert_eq!(descr(1), "SNMPv2-MIB");
+        assert_eq!(descr(2), "IF-MIB");
+        assert!(matches!(
+            get(&[&SYSTEM[..], &[8, 0]].concat()),
+            SnmpValue::TimeTicks(_)
+        ));
+
+        // The other entries keep their index as entries come and go
+        core.register_capabilities(vec![1, 3, 6, 1, 2, 1, 25], "HOST-RESOURCES-MIB");
+        call(
+            session_id,
+            Payload::RemoveAgentCaps {
+                id: vec![1, 3, 6, 1, 2, 1, 31],
+            },
+        );
+        assert_eq!(descr(1), "SNMPv2-MIB");
+        assert!(matches!(
+            get(&[&SYSTEM[..], &[9, 1, 3, 2]].concat()),
+            SnmpValue::NoSuchInstance
+        ));
+        assert_eq!(descr(3), "HOST-RESOURCES-MIB");
+
+        let _ = fs::remove_file(&path);
+    }
+}
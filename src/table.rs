@@ -0,0 +1,891 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::agent::MibHandler;
+use crate::index::{self, IndexType};
+use crate::mib::{Access, ValueType};
+use crate::snmp::{ErrorStatus, SnmpValue, Varbind};
+
+/// One row of a table: its index values, in the order of the table's
+/// INDEX clause, and its cells keyed by column number.
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub index: Vec<SnmpValue>,
+    pub cells: BTreeMap<u32, SnmpValue>,
+}
+
+impl Row {
+    pub fn new(index: Vec<SnmpValue>) -> Self {
+        Self {
+            index,
+            cells: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_cell(mut self, column: u32, value: SnmpValue) -> Self {
+        self.cells.insert(column, value);
+        self
+    }
+}
+
+/// Supplies the rows of a [`Table`].
+///
+/// Any `Fn() -> Vec<Row>` closure is a read-only source.
+pub trait TableSource: Send + Sync {
+    /// The current rows, in any order. The table reads them again once
+    /// its cache has expired, see [`Table::with_cache_timeout`].
+    fn rows(&self) -> Vec<Row>;
+
+    /// Changes one cell of an existing row; the default rejects every
+    /// write with notWritable.
+    fn set(&self, index: &[SnmpValue], column: u32, value: &SnmpValue) -> Result<(), ErrorStatus> {
+        let _ = (index, column, value);
+        Err(ErrorStatus::NotWritable)
+    }
+
+    /// Adds a row created through its RowStatus column, with the status
+    /// already filled in; the default refuses with noCreation.
+    fn create(&self, row: Row) -> Result<(), ErrorStatus> {
+        let _ = row;
+        Err(ErrorStatus::NoCreation)
+    }
+
+    /// Removes a row destroyed through its RowStatus column; the default
+    /// refuses with notWritable.
+    fn destroy(&self, index: &[SnmpValue]) -> Result<(), ErrorStatus> {
+        let _ = index;
+        Err(ErrorStatus::NotWritable)
+    }
+}
+
+/// The RowStatus textual convention (RFC 2579).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowStatus {
+    Active = 1,
+    NotInService = 2,
+    NotReady = 3,
+    CreateAndGo = 4,
+    CreateAndWait = 5,
+    Destroy = 6,
+}
+
+impl RowStatus {
+    pub fn from_value(value: &SnmpValue) -> Option<Self> {
+        Some(match value {
+            SnmpValue::Integer(1) => RowStatus::Active,
+            SnmpValue::Integer(2) => RowStatus::NotInService,
+            SnmpValue::Integer(3) => RowStatus::NotReady,
+            SnmpValue::Integer(4) => RowStatus::CreateAndGo,
+            SnmpValue::Integer(5) => RowStatus::CreateAndWait,
+            SnmpValue::Integer(6) => RowStatus::Destroy,
+            _ => return None,
+        })
+    }
+
+    pub fn to_value(self) -> SnmpValue {
+        SnmpValue::Integer(self as i32)
+    }
+}
+
+impl<F: Fn() -> Vec<Row> + Send + Sync> TableSource for F {
+    fn rows(&self) -> Vec<Row> {
+        self()
+    }
+}
+
+/// A columnar object of a table.
+#[derive(Debug, Clone, Copy)]
+pub struct Column {
+    pub id: u32,
+    pub value_type: ValueType,
+    pub access: Access,
+    /// Whether a row needs a value in this column before it can become
+    /// active, see [`Table::with_row_status`].
+    pub required: bool,
+}
+
+impl Column {
+    pub fn new(id: u32, value_type: ValueType, access: Access) -> Self {
+        Self {
+            id,
+            value_type,
+            access,
+            required: false,
+        }
+    }
+
+    /// Marks the column as one a new row must fill in before activation.
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+}
+
+/// A conceptual table (RFC 2578 section 7.1.12) served from a
+/// [`TableSource`].
+///
+/// Cell OIDs are `table.1.column.index`, e.g. ifDescr.3 is
+/// 1.3.6.1.2.1.2.2.1.2.3, and GETNEXT walks them column by column, each
+/// column in index order. Register it with
+/// [`crate::agent::SnmpAgent::register_table`].
+pub struct Table {
+    oid: Vec<u32>,
+    index: Vec<IndexType>,
+    columns: Vec<Column>,
+    row_status: Option<u32>,
+    source: Box<dyn TableSource>,
+    cache_timeout: Duration,
+    cache: Mutex<Option<RowCache>>,
+}
+
+// The rows with their encoded indexes, in index order, as last read
+struct RowCache {
+    read: Instant,
+    rows: Arc<Vec<(Vec<u32>, Row)>>,
+}
+
+impl Table {
+    /// Creates a table at `oid` whose rows are identified by `index`.
+    pub fn new(oid: Vec<u32>, index: Vec<IndexType>, source: impl TableSource + 'static) -> Self {
+        Self {
+            oid,
+            index,
+            columns: Vec::new(),
+            row_status: None,
+            source: Box::new(source),
+            cache_timeout: Duration::from_secs(1),
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// Sets how long the rows read from the source serve requests, one
+    /// second by default, so that a walk does not read and sort them for
+    /// every step. Zero reads them for every lookup; a SetRequest always
+    /// reads them afresh.
+    pub fn with_cache_timeout(mut self, timeout: Duration) -> Self {
+        self.cache_timeout = timeout;
+        self
+    }
+
+    pub fn with_column(mut self, column: Column) -> Self {
+        self.columns.retain(|c| c.id != column.id);
+        self.columns.push(column);
+        self.columns.sort_by_key(|c| c.id);
+        self
+    }
+
+    /// Lets managers create and delete rows through the RowStatus column
+    /// `column` (RFC 2579). createAndGo needs every required column in the
+    /// same request; a createAndWait row stays notReady until they are
+    /// all set, then notInService until activated.
+    pub fn with_row_status(mut self, column: u32) -> Self {
+        self.row_status = Some(column);
+        self.with_column(Column::new(column, ValueType::Integer, Access::ReadCreate))
+    }
+
+    pub fn oid(&self) -> &[u32] {
+        &self.oid
+    }
+
+    // The column and index suffix of a cell OID
+    fn cell<'a>(&self, oid: &'a [u32]) -> Option<(&Column, &'a [u32])> {
+        let rest = oid.strip_prefix(self.oid.as_slice())?.strip_prefix(&[1])?;
+        let (&id, suffix) = rest.split_first()?;
+        let column = self.columns.iter().find(|c| c.id == id)?;
+        Some((column, suffix))
+    }
+
+    // The rows with their encoded indexes, in index order, from the cache
+    // while it is recent enough
+    fn sorted_rows(&self) -> Arc<Vec<(Vec<u32>, Row)>> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(cached) = cache.as_ref()
+            && cached.read.elapsed() < self.cache_timeout
+        {
+            return cached.rows.clone();
+        }
+
+        let mut rows: Vec<(Vec<u32>, Row)> = self
+            .source
+            .rows()
+            .into_iter()
+            .filter_map(|row| Some((index::encode_index(&self.index, &row.index).ok()?, row)))
+            .collect();
+        rows.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let rows = Arc::new(rows);
+        *cache = Some(RowCache {
+            read: Instant::now(),
+            rows: rows.clone(),
+        });
+        rows
+    }
+
+    // The rows as the source has them now, for a SetRequest
+    fn current_rows(&self) -> Arc<Vec<(Vec<u32>, Row)>> {
+        self.invalidate();
+        self.sorted_rows()
+    }
+
+    fn invalidate(&self) {
+        *self.cache.lock().unwrap() = None;
+    }
+
+    // The row with the encoded index `suffix`
+    fn find_row<'a>(rows: &'a [(Vec<u32>, Row)], suffix: &[u32]) -> Option<&'a Row> {
+        rows.binary_search_by(|(s, _)| s.as_slice().cmp(suffix))
+            .ok()
+            .map(|position| &rows[position].1)
+    }
+
+    fn cell_oid(&self, column: u32, suffix: &[u32]) -> Vec<u32> {
+        let mut oid = self.oid.clone();
+        oid.push(1);
+        oid.push(column);
+        oid.extend_from_slice(suffix);
+        oid
+    }
+}
+
+impl MibHandler for Table {
+    fn get(&self, oid: &[u32]) -> Option<SnmpValue> {
+        let (column, suffix) = self.cell(oid)?;
+        if !column.access.is_readable() {
+            return None;
+        }
+        Self::find_row(&self.sorted_rows(), suffix)?
+            .cells
+            .get(&column.id)
+            .cloned()
+    }
+
+    fn get_next(&self, oid: &[u32]) -> Option<Varbind> {
+        let rows = self.sorted_rows();
+        for column in self.columns.iter().filter(|c| c.access.is_readable()) {
+            // Skip the rows up to `oid` in this column
+            let start = rows
+                .partition_point(|(suffix, _)| self.cell_oid(column.id, suffix).as_slice() <= oid);
+            let next = rows[start..].iter().find_map(|(suffix, row)| {
+                let value = row.cells.get(&column.id)?;
+                Some(Varbind {
+                    oid: self.cell_oid(column.id, suffix),
+                    value: value.clone(),
+                })
+            });
+            if next.is_some() {
+                return next;
+            }
+        }
+        None
+    }
+
+    fn set(&self, oid: &[u32], value: &SnmpValue) -> Result<(), ErrorStatus> {
+        let varbind = Varbind {
+            oid: oid.to_vec(),
+            value: value.clone(),
+        };
+        self.set_all(&[varbind]).map_err(|(status, _)| status)
+    }
+
+    fn test_set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        self.plan(varbinds).map(|_| ())
+    }
+
+    fn set_all(&self, varbinds: &[Varbind]) -> Result<(), (ErrorStatus, usize)> {
+        let (changes, actions): (Vec<RowChange>, Vec<RowAction>) =
+            self.plan(varbinds)?.into_iter().unzip();
+        let mut steps: Vec<(usize, Step)> = Vec::new();
+        for (change, action) in changes.iter().zip(actions) {
+            self.steps(change, action, &mut steps);
+        }
+        // Store in request order, so that when a step fails the varbinds
+        // before it are the ones stored
+        steps.sort_by_key(|(i, _)| *i);
+        let result = steps.into_iter().try_for_each(|(i, step)| {
+            match step {
+                Step::Create(row) => self.source.create(row),
+                Step::Destroy(index) => self.source.destroy(index),
+                Step::Set(index, column, value) => self.source.set(index, column, &value),
+            }
+            .map_err(|status| (status, i))
+        });
+        self.invalidate();
+        result
+    }
+
+    fn previous_values(&self, varbinds: &[Varbind]) -> Vec<Option<SnmpValue>> {
+        let rows = self.current_rows();
+        varbinds
+            .iter()
+            .map(|varbind| {
+                let (column, suffix) = self.cell(&varbind.oid)?;
+                Self::find_row(&rows, suffix)?
+                    .cells
+                    .get(&column.id)
+                    .cloned()
+            })
+            .collect()
+    }
+
+    fn undo_set_all(
+        &self,
+        varbinds: &[Varbind],
+        previous: &[Option<SnmpValue>],
+    ) -> Result<(), ErrorStatus> {
+        let rows = self.current_rows();
+        let mut suffixes: Vec<&[u32]> = Vec::new();
+        for varbind in varbinds {
+            if let Some((_, suffix)) = self.cell(&varbind.oid)
+                && !suffixes.contains(&suffix)
+            {
+                suffixes.push(suffix);
+            }
+        }
+
+        let mut result = Ok(());
+        for suffix in suffixes {
+            let Ok(index) = index::decode_index(&self.index, suffix) else {
+                continue;
+            };
+            // The old values of the row's cells in the request
+            let cells: Vec<(u32, &Option<SnmpValue>)> = varbinds
+                .iter()
+                .zip(previous)
+                .filter_map(|(varbind, value)| {
+                    let (column, s) = self.cell(&varbind.oid)?;
+                    (s == suffix).then_some((column.id, value))
+                })
+                .collect();
+            let existed = cells.iter().any(|(_, value)| value.is_some());
+            let exists = Self::find_row(&rows, suffix).is_some();
+
+            let restored = match (existed, exists) {
+                (false, false) => Ok(()),
+                // Created by the request
+                (false, true) => self.source.destroy(&index),
+                // Destroyed by the request, with cells the request never saw
+                (true, false) => Err(ErrorStatus::UndoFailed),
+                (true, true) => cells
+                    .iter()
+                    .rev()
+                    .try_for_each(|(column, value)| match value {
+                        Some(value) => self.source.set(&index, *column, value),
+                        None => Err(ErrorStatus::UndoFailed),
+                    }),
+            };
+            if restored.is_err() {
+                result = Err(ErrorStatus::UndoFailed);
+            }
+        }
+        result
+    }
+}
+
+// The varbinds of a SetRequest that concern one row
+struct RowChange<'a> {
+    suffix: Vec<u32>,
+    index: Vec<SnmpValue>,
+    // The first varbind, blamed when no better one applies
+    first: usize,
+    status: Option<(RowStatus, usize)>,
+    cells: Vec<(u32, &'a SnmpValue, usize)>,
+}
+
+// One store of a SetRequest
+enum Step<'a> {
+    Create(Row),
+    Destroy(&'a [SnmpValue]),
+    Set(&'a [SnmpValue], u32, SnmpValue),
+}
+
+// What a SetRequest does to one row
+enum RowAction {
+    Nothing,
+    Create(Row, usize),
+    Destroy(usize),
+    // Store the cells, and the new status if it changes
+    Update(Option<(RowStatus, usize)>),
+}
+
+impl Table {
+    // Whether a row with `cells`, plus the ones being set, has every
+    // required column
+    fn is_complete(&self, cells: &BTreeMap<u32, SnmpValue>, change: &RowChange) -> bool {
+        self.columns
+            .iter()
+            .filter(|c| c.required && Some(c.id) != self.row_status)
+            .all(|c| cells.contains_key(&c.id) || change.cells.iter().any(|(id, _, _)| *id == c.id))
+    }
+
+    // Check every varbind of a SetRequest and work out what it does to
+    // each row, without changing anything
+    fn plan<'a>(
+        &self,
+        varbinds: &'a [Varbind],
+    ) -> Result<Vec<(RowChange<'a>, RowAction)>, (ErrorStatus, usize)> {
+        // Group the varbinds by row
+        let mut changes: Vec<RowChange> = Vec::new();
+        for (i, varbind) in varbinds.iter().enumerate() {
+            let (column, suffix) = self
+                .cell(&varbind.oid)
+                .ok_or((ErrorStatus::NoCreation, i))?;
+            if !column.access.is_writable() {
+                return Err((ErrorStatus::NotWritable, i));
+            }
+            if ValueType::of(&varbind.value) != Some(column.value_type) {
+                return Err((ErrorStatus::WrongType, i));
+            }
+
+            let change = match changes.iter_mut().position(|c| c.suffix == suffix) {
+                Some(position) => &mut changes[position],
+                None => {
+                    let index = index::decode_index(&self.index, suffix)
+                        .map_err(|_| (ErrorStatus::NoCreation, i))?;
+                    changes.push(RowChange {
+                        suffix: suffix.to_vec(),
+                        index,
+                        first: i,
+                        status: None,
+                        cells: Vec::new(),
+                    });
+                    changes.last_mut().unwrap()
+                }
+            };
+            if Some(column.id) == self.row_status {
+                // notReady is only ever reported, never requested
+                match RowStatus::from_value(&varbind.value) {
+                    Some(status) if status != RowStatus::NotReady => {
+                        change.status = Some((status, i))
+                    }
+                    _ => return Err((ErrorStatus::WrongValue, i)),
+                }
+            } else {
+                change.cells.push((column.id, &varbind.value, i));
+            }
+        }
+
+        let rows = self.current_rows();
+        changes
+            .into_iter()
+            .map(|change| {
+                let existing = Self::find_row(&rows, &change.suffix);
+                let action = self.row_action(&change, existing)?;
+                Ok((change, action))
+            })
+            .collect()
+    }
+
+    // Run the RowStatus state machine for one row
+    fn row_action(
+        &self,
+        change: &RowChange,
+        existing: Option<&Row>,
+    ) -> Result<RowAction, (ErrorStatus, usize)> {
+        let Some(row) = existing else {
+            let Some((status, i)) = change.status else {
+                return Err((ErrorStatus::NoCreation, change.first));
+            };
+            let complete = self.is_complete(&BTreeMap::new(), change);
+            let status = match status {
+                // Destroying a row that does not exist is a no-op
+                RowStatus::Destroy => return Ok(RowAction::Nothing),
+                RowStatus::CreateAndGo if complete => RowStatus::Active,
+                RowStatus::CreateAndWait if complete => RowStatus::NotInService,
+                RowStatus::CreateAndWait => RowStatus::NotReady,
+                _ => return Err((ErrorStatus::InconsistentValue, i)),
+            };
+
+            let mut row = Row::new(change.index.clone());
+            for (column, value, _) in &change.cells {
+                row.cells.insert(*column, (*value).clone());
+            }
+            if let Some(column) = self.row_status {
+                row.cells.insert(column, status.to_value());
+            }
+            return Ok(RowAction::Create(row, i));
+        };
+
+        let current = self
+            .row_status
+            .and_then(|column| row.cells.get(&column))
+            .and_then(RowStatus::from_value);
+        let complete = self.is_complete(&row.cells, change);
+        let status = match change.status {
+            Some((RowStatus::Destroy, i)) => return Ok(RowAction::Destroy(i)),
+            Some((RowStatus::Active, _)) if complete => Some(RowStatus::Active),
+            Some((RowStatus::NotInService, _)) if complete => Some(RowStatus::NotInService),
+            Some((_, i)) => return Err((ErrorStatus::InconsistentValue, i)),
+            // Filling in the last required column readies the row
+            None if current == Some(RowStatus::NotReady) && complete => {
+                Some(RowStatus::NotInService)
+            }
+            None => None,
+        };
+        // A status that changes by itself is stored with the last cell
+        let i = match (change.status, change.cells.last()) {
+            (Some((_, i)), _) | (None, Some(&(_, _, i))) => i,
+            (None, None) => change.first,
+        };
+        Ok(RowAction::Update(
+            status
+                .filter(|&status| current != Some(status))
+                .map(|status| (status, i)),
+        ))
+    }
+
+    // Add the stores of the change planned for one row to `steps`, each
+    // at the index of the varbind it answers. A new row is created with
+    // all its cells at its RowStatus varbind; undo_set_all restores rows
+    // as a whole, so any of its varbinds undoes it.
+    fn steps<'a>(
+        &self,
+        change: &'a RowChange,
+        action: RowAction,
+        steps: &mut Vec<(usize, Step<'a>)>,
+    ) {
+        match action {
+            RowAction::Nothing => {}
+            RowAction::Create(row, i) => steps.push((i, Step::Create(row))),
+            RowAction::Destroy(i) => steps.push((i, Step::Destroy(&change.index))),
+            RowAction::Update(status) => {
+                for (column, value, i) in &change.cells {
+                    steps.push((*i, Step::Set(&change.index, *column, (*value).clone())));
+                }
+                if let (Some(column), Some((status, i))) = (self.row_status, status) {
+                    steps.push((i, Step::Set(&change.index, column, status.to_value())));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::AgentCore;
+    use crate::client;
+    use crate::snmp::{self, PduType};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TABLE: [u32; 3] = [1, 3, 99];
+
+    // Rows 1 to 100 in reverse order, counting the reads
+    #[derive(Default)]
+    struct Counted {
+        reads: AtomicUsize,
+    }
+
+    impl TableSource for Arc<Counted> {
+        fn rows(&self) -> Vec<Row> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            (1..=100)
+                .rev()
+                .map(|i| {
+                    Row::new(vec![SnmpValue::Integer(i)])
+                        .with_cell(2, SnmpValue::Integer(i * 10))
+                        .with_cell(3, SnmpValue::Integer(-i))
+                })
+                .collect()
+        }
+    }
+
+    fn counted_table(source: &Arc<Counted>) -> Table {
+        Table::new(TABLE.to_vec(), vec![IndexType::Integer], source.clone())
+            .with_column(Column::new(2, ValueType::Integer, Access::ReadOnly))
+            .with_column(Column::new(3, ValueType::Integer, Access::ReadOnly))
+    }
+
+    fn walk(table: &Table) -> Vec<Varbind> {
+        let mut varbinds: Vec<Varbind> = Vec::new();
+        while let Some(next) = table.get_next(varbinds.last().map_or(&TABLE[..], |v| &v.oid)) {
+            varbinds.push(next);
+        }
+        varbinds
+    }
+
+    #[test]
+    fn walk_reads_the_rows_once() {
+        let source = Arc::new(Counted::default());
+        let table = counted_table(&source);
+
+        let varbinds = walk(&table);
+        assert_eq!(varbinds.len(), 200);
+        assert_eq!(varbinds[0].oid, [1, 3, 99, 1, 2, 1]);
+        assert_eq!(varbinds[99].oid, [1, 3, 99, 1, 2, 100]);
+        assert_eq!(varbinds[100].oid, [1, 3, 99, 1, 3, 1]);
+        assert!(matches!(
+            table.get(&[1, 3, 99, 1, 3, 42]),
+            Some(SnmpValue::Integer(-42))
+        ));
+        assert_eq!(source.reads.load(Ordering::Relaxed), 1);
+
+        let uncached = counted_table(&source).with_cache_timeout(Duration::ZERO);
+        source.reads.store(0, Ordering::Relaxed);
+        assert_eq!(walk(&uncached).len(), 200);
+        assert_eq!(source.reads.load(Ordering::Relaxed), 201);
+    }
+
+    // A writable table whose source refuses to change row 2
+    #[derive(Default)]
+    struct Provisioned {
+        rows: Mutex<Vec<Row>>,
+    }
+
+    impl Provisioned {
+        fn position(&self, index: &[SnmpValue]) -> Option<usize> {
+            let rows = self.rows.lock().unwrap();
+            let &[SnmpValue::Integer(i)] = index else {
+                return None;
+            };
+            rows.iter()
+                .position(|row| matches!(row.index[..], [SnmpValue::Integer(j)] if j == i))
+        }
+
+        fn cell(&self, row: i32, column: u32) -> Option<SnmpValue> {
+            let position = self.position(&[SnmpValue::Integer(row)])?;
+            self.rows.lock().unwrap()[position]
+                .cells
+                .get(&column)
+                .cloned()
+        }
+    }
+
+    impl TableSource for Arc<Provisioned> {
+        fn rows(&self) -> Vec<Row> {
+            self.rows.lock().unwrap().clone()
+        }
+
+        fn set(
+            &self,
+            index: &[SnmpValue],
+            column: u32,
+            value: &SnmpValue,
+        ) -> Result<(), ErrorStatus> {
+            if matches!(index, [SnmpValue::Integer(2)]) {
+                return Err(ErrorStatus::ResourceUnavailable);
+            }
+            let position = self.position(index).ok_or(ErrorStatus::NoCreation)?;
+            self.rows.lock().unwrap()[position]
+                .cells
+                .insert(column, value.clone());
+            Ok(())
+        }
+
+        fn create(&self, row: Row) -> Result<(), ErrorStatus> {
+            self.rows.lock().unwrap().push(row);
+            Ok(())
+        }
+
+        fn destroy(&self, index: &[SnmpValue]) -> Result<(), ErrorStatus> {
+            let position = self.position(index).ok_or(ErrorStatus::UndoFailed)?;
+            self.rows.lock().unwrap().remove(position);
+            Ok(())
+        }
+    }
+
+    const NAME: u32 = 2;
+    const COMMENT: u32 = 3;
+    const STATUS: u32 = 4;
+
+    // Rows 1 and 2, active, with a required name and an optional comment
+    fn provisioned_table() -> (Arc<Provisioned>, Table) {
+        let source = Arc::new(Provisioned::default());
+        for i in 1..=2 {
+            source
+                .create(
+                    Row::new(vec![SnmpValue::Integer(i)])
+                        .with_cell(NAME, SnmpValue::Integer(i * 10))
+                        .with_cell(STATUS, RowStatus::Active.to_value()),
+                )
+                .unwrap();
+        }
+        let table = Table::new(TABLE.to_vec(), vec![IndexType::Integer], source.clone())
+            .with_column(Column::new(NAME, ValueType::Integer, Access::ReadCreate).required())
+            .with_column(Column::new(COMMENT, ValueType::Integer, Access::ReadCreate))
+            .with_row_status(STATUS)
+            .with_cache_timeout(Duration::ZERO);
+        (source, table)
+    }
+
+    fn set(column: u32, row: u32, value: SnmpValue) -> Varbind {
+        Varbind {
+            oid: [&TABLE[..], &[1, column, row]].concat(),
+            value,
+        }
+    }
+
+    fn status(row: u32, status: RowStatus) -> Varbind {
+        set(STATUS, row, status.to_value())
+    }
+
+    #[test]
+    fn create_and_wait_row_activates_once_complete() {
+        let (source, table) = provisioned_table();
+
+        table
+            .set_all(&[status(5, RowStatus::CreateAndWait)])
+            .unwrap();
+        assert!(matches!(
+            source.cell(5, STATUS),
+            Some(SnmpValue::Integer(3))
+        ));
+
+        // The name is still missing
+        assert!(matches!(
+            table.test_set_all(&[
+                set(COMMENT, 5, SnmpValue::Integer(7)),
+                status(5, RowStatus::Active),
+            ]),
+            Err((ErrorStatus::InconsistentValue, 1))
+        ));
+        assert!(matches!(
+            table.set_all(&[status(5, RowStatus::Active)]),
+            Err((ErrorStatus::InconsistentValue, 0))
+        ));
+        assert!(matches!(
+            source.cell(5, STATUS),
+            Some(SnmpValue::Integer(3))
+        ));
+        assert!(source.cell(5, COMMENT).is_none());
+
+        // Filling it in readies the row
+        table
+            .set_all(&[set(NAME, 5, SnmpValue::Integer(50))])
+            .unwrap();
+        assert!(matches!(
+            source.cell(5, STATUS),
+            Some(SnmpValue::Integer(2))
+        ));
+
+        table.set_all(&[status(5, RowStatus::Active)]).unwrap();
+        assert!(matches!(
+            source.cell(5, STATUS),
+            Some(SnmpValue::Integer(1))
+        ));
+        assert!(matches!(
+            table.get(&[1, 3, 99, 1, STATUS, 5]),
+            Some(SnmpValue::Integer(1))
+        ));
+    }
+
+    #[test]
+    fn create_and_go_needs_every_required_column() {
+        let (source, table) = provisioned_table();
+
+        assert!(matches!(
+            table.set_all(&[status(5, RowStatus::CreateAndGo)]),
+            Err((ErrorStatus::InconsistentValue, 0))
+        ));
+        assert!(source.position(&[SnmpValue::Integer(5)]).is_none());
+
+        // Activating a row that does not exist creates nothing either
+        assert!(matches!(
+            table.set_all(&[
+                set(NAME, 5, SnmpValue::Integer(50)),
+                status(5, RowStatus::Active),
+            ]),
+            Err((ErrorStatus::InconsistentValue, 1))
+        ));
+        assert!(matches!(
+            table.set_all(&[set(NAME, 5, SnmpValue::Integer(50))]),
+            Err((ErrorStatus::NoCreation, 0))
+        ));
+        assert!(source.position(&[SnmpValue::Integer(5)]).is_none());
+    }
+
+    #[test]
+    fn destroying_a_missing_row_does_nothing() {
+        let (source, table) = provisioned_table();
+
+        table.set_all(&[status(5, RowStatus::Destroy)]).unwrap();
+        assert_eq!(source.rows().len(), 2);
+
+        table.set_all(&[status(1, RowStatus::Destroy)]).unwrap();
+        assert!(source.position(&[SnmpValue::Integer(1)]).is_none());
+        assert!(table.get(&[1, 3, 99, 1, NAME, 1]).is_none());
+
+        // notReady can only be reported
+        assert!(matches!(
+            table.test_set_all(&[status(2, RowStatus::NotReady)]),
+            Err((ErrorStatus::WrongValue, 0))
+        ));
+    }
+
+    #[test]
+    fn undo_reverts_the_rows_a_failed_batch_changed() {
+        let (source, table) = provisioned_table();
+        let varbinds = [
+            set(NAME, 1, SnmpValue::Integer(11)),
+            set(NAME, 5, SnmpValue::Integer(50)),
+            status(5, RowStatus::CreateAndGo),
+            // Refused by the source, after rows 1 and 5 were stored
+            set(COMMENT, 2, SnmpValue::Integer(7)),
+        ];
+        let previous: Vec<Option<SnmpValue>> = varbinds.iter().map(|v| table.get(&v.oid)).collect();
+
+        table.test_set_all(&varbinds).unwrap();
+        assert!(matches!(
+            table.set_all(&varbinds),
+            Err((ErrorStatus::ResourceUnavailable, 3))
+        ));
+        assert!(matches!(source.cell(1, NAME), Some(SnmpValue::Integer(11))));
+        assert!(source.position(&[SnmpValue::Integer(5)]).is_some());
+
+        // Row 2 was never changed, and is left alone
+        table.undo_set_all(&varbinds[..3], &previous[..3]).unwrap();
+        assert!(matches!(source.cell(1, NAME), Some(SnmpValue::Integer(10))));
+        assert!(source.position(&[SnmpValue::Integer(5)]).is_none());
+        assert_eq!(source.rows().len(), 2);
+
+        // A destroyed row cannot be brought back
+        let destroy = [status(1, RowStatus::Destroy)];
+        let previous = [table.get(&destroy[0].oid)];
+        table.set_all(&destroy).unwrap();
+        assert!(matches!(
+            table.undo_set_all(&destroy, &previous),
+            Err(ErrorStatus::UndoFailed)
+        ));
+    }
+
+    #[test]
+    fn failed_set_of_interleaved_rows_is_undone_from_current_values() {
+        let (source, table) = provisioned_table();
+        let core = AgentCore::new(vec!["public".to_string()]);
+        core.register_table(table.with_cache_timeout(Duration::from_secs(60)))
+            .unwrap();
+        let request = |pdu_type, varbinds: &[Varbind]| {
+            let request = client::encode_request(
+                snmp::SNMP_VERSION_2C,
+                "public",
+                1,
+                pdu_type,
+                varbinds,
+                None,
+            );
+            let response = core.handle_datagram(&request).unwrap();
+            snmp::decode_snmp_message(&response).unwrap().pdu
+        };
+
+        // Cache the rows, then change row 1 behind the table's back
+        let name = set(NAME, 1, SnmpValue::Null);
+        request(PduType::GET_REQUEST, std::slice::from_ref(&name));
+        source
+            .set(&[SnmpValue::Integer(1)], NAME, &SnmpValue::Integer(12))
+            .unwrap();
+
+        // Row 1 is stored around row 2, whose source refuses the change
+        let response = request(
+            PduType::SET_REQUEST,
+            &[
+                set(NAME, 1, SnmpValue::Integer(11)),
+                set(COMMENT, 2, SnmpValue::Integer(7)),
+                set(COMMENT, 1, SnmpValue::Integer(8)),
+            ],
+        );
+        assert_eq!(response.error_status, ErrorStatus::CommitFailed);
+        assert_eq!(response.error_index, 2);
+        assert!(matches!(source.cell(1, NAME), Some(SnmpValue::Integer(12))));
+        assert!(source.cell(1, COMMENT).is_none());
+        assert!(source.cell(2, COMMENT).is_none());
+    }
+}
@@ -0,0 +1,1227 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+    UdpSocket,
+};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::ssl::{
+    HandshakeError, MidHandshakeSslStream, Ssl, SslContext, SslMethod, SslOptions, SslRef,
+    SslStream, SslVerifyMode, SslVersion,
+};
+use openssl::x509::{GeneralNameRef, X509, X509Ref, X509VerifyResult};
+use openssl::{memcmp, rand};
+
+use crate::transport::{self, Incoming, Transport, TransportAddr};
+
+/// Longest tmSecurityName a certificate may map to (SnmpAdminString
+/// of RFC 6353 is limited to 32 octets).
+pub const MAX_SECURITY_NAME_LEN: usize = 32;
+
+// How long a session waits for input before looking at its deadlines
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// DTLS has no connection to close, idle sessions are dropped after this
+const DTLS_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const DTLS_MTU: u32 = 1400;
+// Sessions a transport runs at once; further peers are turned away
+const MAX_SESSIONS: usize = 256;
+// DTLS handshakes waiting for the manager to return its cookie. They cost
+// no thread, and the oldest is dropped to make room for a new one.
+const MAX_PENDING_HANDSHAKES: usize = 1024;
+
+/// Hash algorithm of a certificate fingerprint, numbered as in the TLS
+/// HashAlgorithm registry used by SnmpTLSFingerprint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    Md5 = 1,
+    Sha1 = 2,
+    Sha224 = 3,
+    Sha256 = 4,
+    Sha384 = 5,
+    Sha512 = 6,
+}
+
+impl HashAlgorithm {
+    pub fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            1 => HashAlgorithm::Md5,
+            2 => HashAlgorithm::Sha1,
+            3 => HashAlgorithm::Sha224,
+            4 => HashAlgorithm::Sha256,
+            5 => HashAlgorithm::Sha384,
+            6 => HashAlgorithm::Sha512,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "MD5",
+            HashAlgorithm::Sha1 => "SHA1",
+            HashAlgorithm::Sha224 => "SHA224",
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Sha384 => "SHA384",
+            HashAlgorithm::Sha512 => "SHA512",
+        }
+    }
+
+    fn digest(self) -> MessageDigest {
+        match self {
+            HashAlgorithm::Md5 => MessageDigest::md5(),
+            HashAlgorithm::Sha1 => MessageDigest::sha1(),
+            HashAlgorithm::Sha224 => MessageDigest::sha224(),
+            HashAlgorithm::Sha256 => MessageDigest::sha256(),
+            HashAlgorithm::Sha384 => MessageDigest::sha384(),
+            HashAlgorithm::Sha512 => MessageDigest::sha512(),
+        }
+    }
+}
+
+/// The fingerprint of a certificate (SnmpTLSFingerprint).
+///
+/// Written as the algorithm name followed by the digest in hex, e.g.
+/// `SHA256:3C:A1:...`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    pub algorithm: HashAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+impl Fingerprint {
+    pub fn of(cert: &X509Ref, algorithm: HashAlgorithm) -> Result<Self> {
+        let digest = cert
+            .digest(algorithm.digest())
+            .context("Failed to hash certificate")?;
+        Ok(Self {
+            algorithm,
+            digest: digest.to_vec(),
+        })
+    }
+
+    /// Decodes the SnmpTLSFingerprint octet string: the algorithm number
+    /// followed by the digest.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (&code, digest) = bytes.split_first().ok_or(anyhow!("Empty fingerprint"))?;
+        let algorithm = HashAlgorithm::from_code(code)
+            .ok_or_else(|| anyhow!("Unknown fingerprint hash algorithm {}", code))?;
+        if digest.len() != algorithm.digest().size() {
+            bail!("Fingerprint length does not match {}", algorithm.name());
+        }
+        Ok(Self {
+            algorithm,
+            digest: digest.to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.algorithm as u8];
+        bytes.extend_from_slice(&self.digest);
+        bytes
+    }
+
+    pub fn matches(&self, cert: &X509Ref) -> bool {
+        cert.digest(self.algorithm.digest())
+            .is_ok_and(|digest| *digest == *self.digest)
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.algorithm.name())?;
+        for byte in &self.digest {
+            write!(f, ":{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, hex) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid fingerprint: {}", s))?;
+        let algorithm = (1..=6)
+            .filter_map(HashAlgorithm::from_code)
+            .find(|a| a.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("Unknown fingerprint hash algorithm {}", name))?;
+        let digest = hex
+            .split(':')
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .with_context(|| format!("Invalid fingerprint: {}", s))?;
+
+        let mut bytes = vec![algorithm as u8];
+        bytes.extend(digest);
+        Fingerprint::from_bytes(&bytes)
+    }
+}
+
+/// How a tmSecurityName is derived from a certificate
+/// (snmpTlstmCertToTSNMapType).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertMapType {
+    /// Use the given name
+    Specified(String),
+    /// The first rfc822Name subjectAltName, with the host part lowercased
+    SanRfc822Name,
+    /// The first dNSName subjectAltName, lowercased
+    SanDnsName,
+    /// The first iPAddress subjectAltName; IPv4 as dotted decimal, IPv6
+    /// as 32 lowercase hex digits
+    SanIpAddress,
+    /// The first subjectAltName of any of the three kinds above
+    SanAny,
+    /// The certificate's CommonName
+    CommonName,
+}
+
+#[derive(Debug, Clone)]
+struct CertToTsnRow {
+    fingerprint: Fingerprint,
+    map_type: CertMapType,
+}
+
+/// Maps certificates to tmSecurityNames like the snmpTlstmCertToTSNTable.
+///
+/// Rows are tried in order of their ID. A row applies when its
+/// fingerprint matches the peer's certificate or one of the CA
+/// certificates of its verified chain; the name is then derived from the
+/// peer's certificate. A row that yields no usable name is skipped.
+#[derive(Debug, Clone, Default)]
+pub struct CertToTsnTable {
+    rows: BTreeMap<u32, CertToTsnRow>,
+}
+
+impl CertToTsnTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a row, replacing any row with the same ID.
+    pub fn with_row(mut self, id: u32, fingerprint: Fingerprint, map_type: CertMapType) -> Self {
+        self.add(id, fingerprint, map_type);
+        self
+    }
+
+    /// Adds a row, replacing any row with the same ID.
+    pub fn add(&mut self, id: u32, fingerprint: Fingerprint, map_type: CertMapType) {
+        self.rows.insert(
+            id,
+            CertToTsnRow {
+                fingerprint,
+                map_type,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.rows.remove(&id);
+    }
+
+    /// Derives the tmSecurityName for a peer from its certificate chain,
+    /// the peer's own certificate first.
+    pub fn map(&self, chain: &[X509]) -> Option<String> {
+        let leaf = chain.first()?;
+        self.rows
+            .values()
+            .filter(|row| chain.iter().any(|cert| row.fingerprint.matches(cert)))
+            .find_map(|row| derive_name(&row.map_type, leaf))
+    }
+
+    fn fingerprints(&self) -> impl Iterator<Item = &Fingerprint> {
+        self.rows.values().map(|row| &row.fingerprint)
+    }
+}
+
+fn derive_name(map_type: &CertMapType, cert: &X509Ref) -> Option<String> {
+    let name = match map_type {
+        CertMapType::Specified(name) => Some(name.clone()),
+        CertMapType::SanRfc822Name => first_san(cert, |san| san.email().map(rfc822_name)),
+        CertMapType::SanDnsName => first_san(cert, |san| san.dnsname().map(str::to_lowercase)),
+        CertMapType::SanIpAddress => first_san(cert, |san| san.ipaddress().and_then(ip_name)),
+        CertMapType::SanAny => first_san(cert, |san| {
+            san.email()
+                .map(rfc822_name)
+                .or_else(|| san.dnsname().map(str::to_lowercase))
+                .or_else(|| san.ipaddress().and_then(ip_name))
+        }),
+        CertMapType::CommonName => cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().to_string().ok()),
+    }?;
+    (!name.is_empty() && name.len() <= MAX_SECURITY_NAME_LEN).then_some(name)
+}
+
+fn first_san(cert: &X509Ref, name: impl Fn(&GeneralNameRef) -> Option<String>) -> Option<String> {
+    cert.subject_alt_names()?.iter().find_map(name)
+}
+
+fn rfc822_name(address: &str) -> String {
+    match address.rsplit_once('@') {
+        Some((local, host)) => format!("{}@{}", local, host.to_lowercase()),
+        None => address.to_string(),
+    }
+}
+
+fn ip_name(address: &[u8]) -> Option<String> {
+    match address.len() {
+        4 => Some(Ipv4Addr::new(address[0], address[1], address[2], address[3]).to_string()),
+        16 => Some(address.iter().map(|b| format!("{:02x}", b)).collect()),
+        _ => None,
+    }
+}
+
+/// Certificates and trust settings of a TLS or DTLS endpoint.
+///
+/// A peer is accepted if its certificate chains to a trusted
+/// certificate, or if its own certificate matches a pinned fingerprint or
+/// a row of the certificate to tmSecurityName table. CA certificates only
+/// count once the chain through them verifies.
+/// Agents also derive each manager's tmSecurityName from that table and
+/// close connections from managers that map to no name.
+///
+/// Unless its certificate is pinned, an agent must also be the one the
+/// manager meant to reach (RFC 6353 section 5.3.1): its certificate has to
+/// name the server name set with [`TlsConfig::with_server_name`] or, by
+/// default, the IP address connected to.
+#[derive(Clone)]
+pub struct TlsConfig {
+    certificate: X509,
+    private_key: PKey<Private>,
+    chain: Vec<X509>,
+    trusted: Vec<X509>,
+    fingerprints: Vec<Fingerprint>,
+    cert_to_tsn: CertToTsnTable,
+    server_name: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn new(certificate: X509, private_key: PKey<Private>) -> Self {
+        Self {
+            certificate,
+            private_key,
+            chain: Vec::new(),
+            trusted: Vec::new(),
+            fingerprints: Vec::new(),
+            cert_to_tsn: CertToTsnTable::new(),
+            server_name: None,
+        }
+    }
+
+    /// Loads the endpoint's certificate, optionally followed by its CA
+    /// certificates, and private key from PEM.
+    pub fn from_pem(certificates: &[u8], private_key: &[u8]) -> Result<Self> {
+        let mut certificates = X509::stack_from_pem(certificates)
+            .context("Failed to parse certificates")?
+            .into_iter();
+        let certificate = certificates.next().ok_or(anyhow!("No certificate found"))?;
+        let private_key =
+            PKey::private_key_from_pem(private_key).context("Failed to parse private key")?;
+
+        let mut config = Self::new(certificate, private_key);
+        config.chain.extend(certificates);
+        Ok(config)
+    }
+
+    /// Sends a CA certificate along with the endpoint's own.
+    pub fn with_chain_certificate(mut self, certificate: X509) -> Self {
+        self.chain.push(certificate);
+        self
+    }
+
+    /// Trusts peers whose certificate chains to `certificate`.
+    pub fn with_trusted_certificate(mut self, certificate: X509) -> Self {
+        self.trusted.push(certificate);
+        self
+    }
+
+    /// Trusts peers presenting a certificate with this fingerprint, the
+    /// way a manager pins an agent's certificate.
+    pub fn with_peer_fingerprint(mut self, fingerprint: Fingerprint) -> Self {
+        self.fingerprints.push(fingerprint);
+        self
+    }
+
+    pub fn with_cert_to_tsn(mut self, table: CertToTsnTable) -> Self {
+        self.cert_to_tsn = table;
+        self
+    }
+
+    /// The name agents must present a certificate for: a host name,
+    /// matched against dNSName subjectAltNames or the CommonName, or an
+    /// IP address, matched against iPAddress subjectAltNames.
+    pub fn with_server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    fn context(&self, datagram: bool) -> io::Result<SslContext> {
+        let mut builder = if datagram {
+            SslContext::builder(SslMethod::dtls())
+        } else {
+            SslContext::builder(SslMethod::tls())
+        }
+        .map_err(io::Error::other)?;
+
+        builder
+            .set_certificate(&self.certificate)
+            .map_err(io::Error::other)?;
+        builder
+            .set_private_key(&self.private_key)
+            .map_err(io::Error::other)?;
+        builder.check_private_key().map_err(io::Error::other)?;
+        for certificate in &self.chain {
+            builder
+                .add_extra_chain_cert(certificate.clone())
+                .map_err(io::Error::other)?;
+        }
+        for certificate in &self.trusted {
+            builder
+                .cert_store_mut()
+                .add_cert(certificate.clone())
+                .map_err(io::Error::other)?;
+        }
+
+        if datagram {
+            builder
+                .set_min_proto_version(Some(SslVersion::DTLS1_2))
+                .map_err(io::Error::other)?;
+            // Managers must prove they receive at their address before the
+            // agent keeps any state for them (RFC 6347 section 4.2.1)
+            builder.set_options(SslOptions::NO_QUERY_MTU | SslOptions::COOKIE_EXCHANGE);
+            let mut secret = [0u8; 32];
+            rand::rand_bytes(&mut secret).map_err(io::Error::other)?;
+            let key = PKey::hmac(&secret).map_err(io::Error::other)?;
+            let verify_key = key.clone();
+            builder.set_cookie_generate_cb(move |ssl, buf| {
+                let cookie = cookie(&key, ssl)?;
+                buf[..cookie.len()].copy_from_slice(&cookie);
+                Ok(cookie.len())
+            });
+            builder.set_cookie_verify_cb(move |ssl, cookie| {
+                let valid = self::cookie(&verify_key, ssl)
+                    .is_ok_and(|expected| memcmp::eq(&expected, cookie));
+                if valid
+                    && let Ok(index) = dtls_peer_index()
+                    && let Some(peer) = ssl.ex_data_mut(index)
+                {
+                    peer.cookie_verified = true;
+                }
+                valid
+            });
+        } else {
+            builder
+                .set_min_proto_version(Some(SslVersion::TLS1_2))
+                .map_err(io::Error::other)?;
+        }
+
+        let pinned: Vec<Fingerprint> = self
+            .fingerprints
+            .iter()
+            .chain(self.cert_to_tsn.fingerprints())
+            .cloned()
+            .collect();
+        builder.set_verify_callback(
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            move |preverified, store| {
+                // A chain that fails to verify is only accepted for a pinned
+                // leaf: the CA certificates in it prove nothing
+                preverified
+                    || store
+                        .chain()
+                        .and_then(|chain| chain.get(0))
+                        .is_some_and(|leaf| pinned.iter().any(|f| f.matches(leaf)))
+            },
+        );
+
+        Ok(builder.build())
+    }
+}
+
+// The manager of a DTLS handshake an agent accepts: where it is, for its
+// cookie, and whether it has returned a valid one
+struct DtlsPeer {
+    addr: SocketAddr,
+    cookie_verified: bool,
+}
+
+fn dtls_peer_index() -> Result<Index<Ssl, DtlsPeer>, ErrorStack> {
+    static INDEX: OnceLock<Index<Ssl, DtlsPeer>> = OnceLock::new();
+    if let Some(index) = INDEX.get() {
+        return Ok(*index);
+    }
+    let index = Ssl::new_ex_index()?;
+    Ok(*INDEX.get_or_init(|| index))
+}
+
+// A DTLS cookie: an HMAC of the manager's address, which only a manager
+// receiving at that address can return
+fn cookie(key: &PKey<Private>, ssl: &SslRef) -> Result<Vec<u8>, ErrorStack> {
+    let peer = ssl
+        .ex_data(dtls_peer_index()?)
+        .ok_or_else(ErrorStack::get)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    signer.update(peer.addr.to_string().as_bytes())?;
+    signer.sign_to_vec()
+}
+
+fn cookie_verified(ssl: &SslRef) -> bool {
+    dtls_peer_index()
+        .ok()
+        .and_then(|index| ssl.ex_data(index))
+        .is_some_and(|peer| peer.cookie_verified)
+}
+
+/// SNMP over TLS over TCP (RFC 6353).
+///
+/// A client transport connects to each agent on first use and keeps the
+/// connection open; a listener accepts any number of managers and
+/// reports the tmSecurityName of each through
+/// [`Transport::security_name`].
+pub struct TlsTransport {
+    endpoint: Endpoint,
+}
+
+impl TlsTransport {
+    /// Creates a client transport.
+    pub fn new(config: &TlsConfig) -> io::Result<Self> {
+        Ok(Self {
+            endpoint: Endpoint::new(config, false, None)?,
+        })
+    }
+
+    /// Listens for managers on `addr`.
+    pub fn listen(addr: impl ToSocketAddrs, config: &TlsConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let endpoint = Endpoint::new(config, false, Some(TransportAddr::Tls(local_addr)))?;
+
+        let shared = endpoint.shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let _ = accept_stream(stream, &shared);
+            }
+        });
+
+        Ok(Self { endpoint })
+    }
+}
+
+impl Transport for TlsTransport {
+    fn send_to(&self, data: &[u8], peer: &TransportAddr) -> io::Result<()> {
+        match peer {
+            TransportAddr::Tls(_) => self.endpoint.send(data, peer),
+            _ => Err(transport::wrong_peer(peer)),
+        }
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, TransportAddr)> {
+        transport::receive(&self.endpoint.incoming, buf, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<TransportAddr> {
+        self.endpoint.local_addr()
+    }
+
+    fn security_name(&self, peer: &TransportAddr) -> Option<String> {
+        self.endpoint.shared.security_name(peer)
+    }
+}
+
+/// SNMP over DTLS over UDP (RFC 6353).
+///
+/// A client transport opens a session with each agent on first use; a
+/// bound transport accepts sessions from any number of managers and
+/// reports the tmSecurityName of each through
+/// [`Transport::security_name`].
+pub struct DtlsTransport {
+    endpoint: Endpoint,
+}
+
+impl DtlsTransport {
+    /// Creates a client transport.
+    pub fn new(config: &TlsConfig) -> io::Result<Self> {
+        Ok(Self {
+            endpoint: Endpoint::new(config, true, None)?,
+        })
+    }
+
+    /// Accepts sessions from managers on `addr`.
+    pub fn bind(addr: impl ToSocketAddrs, config: &TlsConfig) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr)?);
+        let local_addr = socket.local_addr()?;
+        let endpoint = Endpoint::new(config, true, Some(TransportAddr::Dtls(local_addr)))?;
+
+        let shared = endpoint.shared.clone();
+        thread::spawn(move || accept_datagrams(socket, &shared));
+
+        Ok(Self { endpoint })
+    }
+}
+
+impl Transport for DtlsTransport {
+    fn send_to(&self, data: &[u8], peer: &TransportAddr) -> io::Result<()> {
+        match peer {
+            TransportAddr::Dtls(_) => self.endpoint.send(data, peer),
+            _ => Err(transport::wrong_peer(peer)),
+        }
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, TransportAddr)> {
+        transport::receive(&self.endpoint.incoming, buf, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<TransportAddr> {
+        self.endpoint.local_addr()
+    }
+
+    fn security_name(&self, peer: &TransportAddr) -> Option<String> {
+        self.endpoint.shared.security_name(peer)
+    }
+}
+
+// What a session thread is told: data from the peer, a message to send
+// to the peer, or that the peer has gone away
+enum Event {
+    Data(Vec<u8>),
+    Send(Vec<u8>),
+    Closed,
+}
+
+// A session in progress. Its thread owns the TLS state and is reached
+// through `events`.
+struct Session {
+    id: u64,
+    events: Sender<Event>,
+    security_name: Option<String>,
+}
+
+// The part of a TLS or DTLS transport shared with its threads
+struct Shared {
+    context: SslContext,
+    cert_to_tsn: CertToTsnTable,
+    server_name: Option<String>,
+    datagram: bool,
+    sessions: Mutex<HashMap<TransportAddr, Session>>,
+    incoming: Sender<Incoming>,
+    next_id: AtomicU64,
+}
+
+impl Shared {
+    // Add a session, unless MAX_SESSIONS others are running
+    fn register(&self, peer: TransportAddr, events: Sender<Event>) -> Option<u64> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= MAX_SESSIONS && !sessions.contains_key(&peer) {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        sessions.insert(
+            peer,
+            Session {
+                id,
+                events,
+                security_name: None,
+            },
+        );
+        Some(id)
+    }
+
+    // Remove a finished session, unless a newer one has replaced it
+    fn unregister(&self, peer: &TransportAddr, id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(peer).is_some_and(|s| s.id == id) {
+            sessions.remove(peer);
+        }
+    }
+
+    fn set_security_name(&self, peer: &TransportAddr, id: u64, name: String) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(peer)
+            && session.id == id
+        {
+            session.security_name = Some(name);
+        }
+    }
+
+    fn security_name(&self, peer: &TransportAddr) -> Option<String> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(peer)
+            .and_then(|s| s.security_name.clone())
+    }
+
+    // Queue a message on the session with `peer`, if it is still running
+    fn queue(&self, data: &[u8], peer: &TransportAddr) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(peer)
+            .is_some_and(|s| s.events.send(Event::Send(data.to_vec())).is_ok())
+    }
+}
+
+struct Endpoint {
+    shared: Arc<Shared>,
+    incoming: Mutex<Receiver<Incoming>>,
+    local_addr: Option<TransportAddr>,
+}
+
+impl Endpoint {
+    fn new(
+        config: &TlsConfig,
+        datagram: bool,
+        local_addr: Option<TransportAddr>,
+    ) -> io::Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        Ok(Self {
+            shared: Arc::new(Shared {
+                context: config.context(datagram)?,
+                cert_to_tsn: config.cert_to_tsn.clone(),
+                server_name: config.server_name.clone(),
+                datagram,
+                sessions: Mutex::new(HashMap::new()),
+                incoming: tx,
+                next_id: AtomicU64::new(1),
+            }),
+            incoming: Mutex::new(rx),
+            local_addr,
+        })
+    }
+
+    // Listeners only answer over existing sessions, clients open a
+    // session on first use or when the previous one has ended
+    fn send(&self, data: &[u8], peer: &TransportAddr) -> io::Result<()> {
+        if self.shared.queue(data, peer) {
+            return Ok(());
+        }
+        if self.local_addr.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                format!("No session with {}", peer),
+            ));
+        }
+
+        connect(&self.shared, peer)?;
+        if self.shared.queue(data, peer) {
+            Ok(())
+        } else {
+            Err(io::Error::from(ErrorKind::BrokenPipe))
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<TransportAddr> {
+        self.local_addr
+            .clone()
+            .ok_or_else(|| transport::unsupported("Client transport has no local address"))
+    }
+}
+
+// Where a session's TLS records go
+enum Output {
+    Stream(TcpStream),
+    Datagram(Arc<UdpSocket>, SocketAddr),
+}
+
+// The I/O under a TLS session. Reads return what the forwarding thread
+// received from the peer, one datagram at a time for DTLS. A message
+// queued for sending interrupts a read with `WouldBlock` so the session
+// thread can write it. A pipe that does not block, as for a handshake
+// waiting for its cookie, returns `WouldBlock` as soon as it runs dry.
+struct Pipe {
+    events: Receiver<Event>,
+    input: Vec<u8>,
+    outgoing: Vec<Vec<u8>>,
+    output: Output,
+    closed: Arc<AtomicBool>,
+    blocking: bool,
+}
+
+impl Pipe {
+    fn new(events: Receiver<Event>, output: Output) -> Self {
+        Self {
+            events,
+            input: Vec::new(),
+            outgoing: Vec::new(),
+            output,
+            closed: Arc::new(AtomicBool::new(false)),
+            blocking: true,
+        }
+    }
+
+    fn next_event(&self) -> Result<Event, RecvTimeoutError> {
+        if self.blocking {
+            return self.events.recv_timeout(POLL_INTERVAL);
+        }
+        self.events.try_recv().map_err(|e| match e {
+            TryRecvError::Empty => RecvTimeoutError::Timeout,
+            TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+        })
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.input.is_empty() {
+            match self.next_event() {
+                Ok(Event::Data(data)) => self.input = data,
+                Ok(Event::Send(message)) => {
+                    self.outgoing.push(message);
+                    return Err(io::Error::from(ErrorKind::WouldBlock));
+                }
+                Ok(Event::Closed) | Err(RecvTimeoutError::Disconnected) => return Ok(0),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::from(ErrorKind::WouldBlock));
+                }
+            }
+        }
+
+        let len = self.input.len().min(buf.len());
+        buf[..len].copy_from_slice(&self.input[..len]);
+        match self.output {
+            // The rest of a datagram that does not fit is lost
+            Output::Datagram(..) => self.input.clear(),
+            Output::Stream(_) => {
+                self.input.drain(..len);
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.output {
+            Output::Stream(stream) => stream.write(buf),
+            Output::Datagram(socket, peer) => socket.send_to(buf, *peer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            Output::Stream(stream) => stream.flush(),
+            Output::Datagram(..) => Ok(()),
+        }
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Output::Stream(stream) = &self.output {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn new_ssl(shared: &Shared) -> io::Result<Ssl> {
+    let mut ssl = Ssl::new(&shared.context).map_err(io::Error::other)?;
+    if shared.datagram {
+        ssl.set_mtu(DTLS_MTU).map_err(io::Error::other)?;
+    }
+    Ok(ssl)
+}
+
+// Run the handshake, waiting for the peer's records as they arrive. A
+// client passes the address of the agent, whose certificate must name it
+// or the configured server name.
+fn handshake(shared: &Shared, pipe: Pipe, agent: Option<IpAddr>) -> io::Result<SslStream<Pipe>> {
+    let mut ssl = new_ssl(shared)?;
+    let Some(agent) = agent else {
+        return finish_handshake(ssl.accept(pipe));
+    };
+
+    let param = ssl.param_mut();
+    match shared.server_name.as_deref() {
+        Some(name) => match name.parse::<IpAddr>() {
+            Ok(ip) => param.set_ip(ip),
+            Err(_) => param.set_host(name),
+        },
+        None => param.set_ip(agent),
+    }
+    .map_err(io::Error::other)?;
+    finish_handshake(ssl.connect(pipe))
+}
+
+// A handshake as it stands after its latest step
+type Handshake = Result<SslStream<Pipe>, HandshakeError<Pipe>>;
+
+// Continue a handshake until it completes, fails or times out
+fn finish_handshake(mut result: Handshake) -> io::Result<SslStream<Pipe>> {
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    loop {
+        match result {
+            Ok(stream) => {
+                // Accepting a pinned certificate despite a verify error can
+                // leave that error queued, where it would fail the first read
+                let _ = ErrorStack::get();
+                return Ok(stream);
+            }
+            Err(HandshakeError::WouldBlock(_)) if Instant::now() >= deadline => {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "TLS handshake timed out",
+                ));
+            }
+            Err(HandshakeError::WouldBlock(stream)) => result = stream.handshake(),
+            Err(HandshakeError::SetupFailure(e)) => return Err(io::Error::other(e)),
+            Err(HandshakeError::Failure(stream)) => {
+                let verify_result = stream.ssl().verify_result();
+                return Err(io::Error::new(
+                    ErrorKind::ConnectionAborted,
+                    format!(
+                        "TLS handshake failed: {} ({})",
+                        stream.into_error(),
+                        verify_result
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+// Open a client session with an agent. The handshake runs before
+// returning so that failures reach the caller.
+fn connect(shared: &Arc<Shared>, peer: &TransportAddr) -> io::Result<()> {
+    let (events, rx) = mpsc::channel();
+    let peer_ip = peer.ip().ok_or_else(|| transport::wrong_peer(peer))?;
+    let pipe = match peer {
+        TransportAddr::Tls(addr) => {
+            let stream = TcpStream::connect_timeout(addr, HANDSHAKE_TIMEOUT)?;
+            let reader = stream.try_clone()?;
+            let events = events.clone();
+            thread::spawn(move || forward_stream(reader, events));
+            Pipe::new(rx, Output::Stream(stream))
+        }
+        TransportAddr::Dtls(addr) => {
+            let socket = if addr.is_ipv4() {
+                UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+            } else {
+                UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?
+            };
+            socket.set_read_timeout(Some(POLL_INTERVAL))?;
+            let socket = Arc::new(socket);
+            let pipe = Pipe::new(rx, Output::Datagram(socket.clone(), *addr));
+            let closed = pipe.closed.clone();
+            let events = events.clone();
+            let addr = *addr;
+            thread::spawn(move || forward_datagrams(socket, addr, events, closed));
+            pipe
+        }
+        _ => return Err(transport::wrong_peer(peer)),
+    };
+
+    let stream = handshake(shared, pipe, Some(peer_ip))?;
+    let id = shared
+        .register(peer.clone(), events)
+        .ok_or_else(|| io::Error::other("Too many sessions"))?;
+    let shared = shared.clone();
+    let peer = peer.clone();
+    thread::spawn(move || {
+        serve(stream, &peer, &shared);
+        shared.unregister(&peer, id);
+    });
+    Ok(())
+}
+
+fn accept_stream(stream: TcpStream, shared: &Arc<Shared>) -> io::Result<()> {
+    let peer = TransportAddr::Tls(stream.peer_addr()?);
+    let reader = stream.try_clone()?;
+    let (events, rx) = mpsc::channel();
+    let Some(id) = shared.register(peer.clone(), events.clone()) else {
+        println!("Refusing TLS session with {}: too many sessions", peer);
+        return Ok(());
+    };
+    thread::spawn(move || forward_stream(reader, events));
+
+    let pipe = Pipe::new(rx, Output::Stream(stream));
+    let shared = shared.clone();
+    thread::spawn(move || accept_session(handshake(&shared, pipe, None), &peer, id, &shared));
+    Ok(())
+}
+
+// A DTLS handshake waiting for the manager to return its cookie
+struct PendingHandshake {
+    handshake: MidHandshakeSslStream<Pipe>,
+    events: Sender<Event>,
+    started: Instant,
+}
+
+// Hand each datagram to the session with its sender. Unknown senders are
+// first answered with a cookie, and only get a session of their own once
+// they return it.
+fn accept_datagrams(socket: Arc<UdpSocket>, shared: &Arc<Shared>) {
+    let mut buf = vec![0u8; 65536];
+    let mut pending: HashMap<SocketAddr, PendingHandshake> = HashMap::new();
+    loop {
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            // ICMP errors from earlier sends surface here on some systems
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused
+                ) =>
+            {
+                continue;
+            }
+            Err(_) => break,
+        };
+        let peer = TransportAddr::Dtls(src);
+        let data = buf[..len].to_vec();
+
+        if let Some(session) = shared.sessions.lock().unwrap().get(&peer)
+            && session.events.send(Event::Data(data.clone())).is_ok()
+        {
+            continue;
+        }
+
+        let (events, started, result) = match pending.remove(&src) {
+            Some(handshake) => {
+                let _ = handshake.events.send(Event::Data(data));
+                let result = handshake.handshake.handshake();
+                (handshake.events, handshake.started, result)
+            }
+            None => match start_accept(shared, &socket, src, data) {
+                Ok(started) => started,
+                Err(e) => {
+                    println!("DTLS handshake with {} failed: {}", peer, e);
+                    continue;
+                }
+            },
+        };
+        match result {
+            Err(HandshakeError::WouldBlock(handshake)) if !cookie_verified(handshake.ssl()) => {
+                if pending.len() >= MAX_PENDING_HANDSHAKES {
+                    pending.retain(|_, p| p.started.elapsed() < HANDSHAKE_TIMEOUT);
+                }
+                if pending.len() >= MAX_PENDING_HANDSHAKES
+                    && let Some(oldest) = pending
+                        .iter()
+                        .min_by_key(|(_, p)| p.started)
+                        .map(|(addr, _)| *addr)
+                {
+                    pending.remove(&oldest);
+                }
+                pending.insert(
+                    src,
+                    PendingHandshake {
+                        handshake,
+                        events,
+                        started,
+                    },
+                );
+            }
+            result => promote(shared, peer, events, result),
+        }
+    }
+}
+
+// Start accepting a handshake with the first datagram of `src`, without
+// blocking on what follows
+fn start_accept(
+    shared: &Shared,
+    socket: &Arc<UdpSocket>,
+    src: SocketAddr,
+    data: Vec<u8>,
+) -> io::Result<(Sender<Event>, Instant, Handshake)> {
+    let (events, rx) = mpsc::channel();
+    let _ = events.send(Event::Data(data));
+    let mut pipe = Pipe::new(rx, Output::Datagram(socket.clone(), src));
+    pipe.blocking = false;
+
+    let mut ssl = new_ssl(shared)?;
+    let index = dtls_peer_index().map_err(io::Error::other)?;
+    ssl.set_ex_data(
+        index,
+        DtlsPeer {
+            addr: src,
+            cookie_verified: false,
+        },
+    );
+    Ok((events, Instant::now(), ssl.accept(pipe)))
+}
+
+// Give a manager that returned its cookie a session of its own
+fn promote(shared: &Arc<Shared>, peer: TransportAddr, events: Sender<Event>, result: Handshake) {
+    let Some(id) = shared.register(peer.clone(), events) else {
+        println!("Refusing DTLS session with {}: too many sessions", peer);
+        return;
+    };
+    let result = match result {
+        Ok(mut stream) => {
+            stream.get_mut().blocking = true;
+            Ok(stream)
+        }
+        Err(HandshakeError::WouldBlock(mut handshake)) => {
+            handshake.get_mut().blocking = true;
+            Err(HandshakeError::WouldBlock(handshake))
+        }
+        failed => failed,
+    };
+    let shared = shared.clone();
+    thread::spawn(move || accept_session(finish_handshake(result), &peer, id, &shared));
+}
+
+// Run an agent's side of a session: the manager must present a
+// certificate that maps to a tmSecurityName
+fn accept_session(
+    handshake: io::Result<SslStream<Pipe>>,
+    peer: &TransportAddr,
+    id: u64,
+    shared: &Shared,
+) {
+    match handshake {
+        Ok(mut stream) => match shared.cert_to_tsn.map(&peer_chain(stream.ssl())) {
+            Some(name) => {
+                shared.set_security_name(peer, id, name);
+                serve(stream, peer, shared);
+            }
+            None => {
+                println!(
+                    "Closing session with {}: certificate maps to no security name",
+                    peer
+                );
+                let _ = stream.shutdown();
+            }
+        },
+        Err(e) => println!("TLS handshake with {} failed: {}", peer, e),
+    }
+    shared.unregister(peer, id);
+}
+
+// The peer's certificate followed by the CA certificates it chains to.
+// Only a verified chain counts: a peer accepted for its pinned
+// fingerprint is mapped from its own certificate alone, never from the
+// unverified certificates it sent along.
+fn peer_chain(ssl: &SslRef) -> Vec<X509> {
+    if ssl.verify_result() == X509VerifyResult::OK
+        && let Some(chain) = ssl.verified_chain()
+    {
+        return chain.iter().map(|cert| cert.to_owned()).collect();
+    }
+    ssl.peer_certificate().into_iter().collect()
+}
+
+// Run an established session: write the messages queued for the peer and
+// forward every message from the peer to the transport, until either
+// side closes it
+fn serve(mut stream: SslStream<Pipe>, peer: &TransportAddr, shared: &Shared) {
+    let mut buf = vec![0u8; 65536];
+    let mut pending = Vec::new();
+    let mut last_activity = Instant::now();
+
+    loop {
+        for message in std::mem::take(&mut stream.get_mut().outgoing) {
+            if stream.write_all(&message).is_err() {
+                return;
+            }
+            last_activity = Instant::now();
+        }
+
+        let len = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if shared.datagram && last_activity.elapsed() > DTLS_IDLE_TIMEOUT {
+                    break;
+                }
+                continue;
+            }
+            Err(_) => return,
+        };
+        last_activity = Instant::now();
+
+        // Each DTLS record carries one message, over TLS messages are
+        // framed by their BER length like over TCP
+        let mut messages = Vec::new();
+        if shared.datagram {
+            messages.push(buf[..len].to_vec());
+        } else {
+            pending.extend_from_slice(&buf[..len]);
+            loop {
+                match transport::split_message(&mut pending) {
+                    Ok(Some(message)) => messages.push(message),
+                    Ok(None) => break,
+                    Err(_) => return,
+                }
+            }
+        }
+        for message in messages {
+            if shared.incoming.send((message, peer.clone())).is_err() {
+                return;
+            }
+        }
+    }
+
+    let _ = stream.shutdown();
+}
+
+// Forward what arrives on a TCP connection to its session
+fn forward_stream(mut stream: TcpStream, events: Sender<Event>) {
+    let mut buf = vec![0u8; 16384];
+    while let Ok(len) = stream.read(&mut buf) {
+        if len == 0 || events.send(Event::Data(buf[..len].to_vec())).is_err() {
+            break;
+        }
+    }
+    let _ = events.send(Event::Closed);
+}
+
+// Forward the agent's datagrams to a client session until it ends
+fn forward_datagrams(
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    events: Sender<Event>,
+    closed: Arc<AtomicBool>,
+) {
+    let mut buf = vec![0u8; 65536];
+    while !closed.load(Ordering::Relaxed) {
+        match socket.recv_from(&mut buf) {
+            Ok((len, src)) if src == peer => {
+                if events.send(Event::Data(buf[..len].to_vec())).is_err() {
+                    break;
+                }
+            }
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock
+                        | ErrorKind::TimedOut
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionRefused
+                ) => {}
+            Err(_) => break,
+        }
+    }
+}
@@ -0,0 +1,882 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+    UdpSocket,
+};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::client::{AddressFamily, Target};
+
+// Stream transports drop a connection announcing a message larger than this
+const MAX_STREAM_MESSAGE_SIZE: usize = 1 << 20;
+
+// Stream transports refuse connections beyond this many
+const MAX_STREAM_CONNECTIONS: usize = 256;
+
+// A connection whose peer does not take a message within this long is
+// dropped
+const STREAM_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long client stream transports wait for a connection by default
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The kind of transport a target or listener uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportKind {
+    Udp,
+    /// TCP with RFC 3430 framing
+    Tcp,
+    /// Unix domain stream socket
+    Unix,
+    /// Unix domain datagram socket
+    UnixDgram,
+    /// TLS over TCP (RFC 6353), needs the `tls` feature
+    Tls,
+    /// DTLS over UDP (RFC 6353), needs the `tls` feature
+    Dtls,
+}
+
+/// The address of a peer on some transport.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TransportAddr {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    Tls(SocketAddr),
+    Dtls(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    #[cfg(unix)]
+    UnixDgram(PathBuf),
+    /// A connection accepted by a stream listener whose peer has no
+    /// address of its own, e.g. an unnamed Unix socket
+    Connection(u64),
+}
+
+impl TransportAddr {
+    /// The peer's IP address, for transports that have one.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            TransportAddr::Udp(addr)
+            | TransportAddr::Tcp(addr)
+            | TransportAddr::Tls(addr)
+            | TransportAddr::Dtls(addr) => Some(addr.ip()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TransportAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportAddr::Udp(SocketAddr::V4(addr)) => write!(f, "udp:{}", addr),
+            TransportAddr::Udp(SocketAddr::V6(addr)) => write!(f, "udp6:{}", addr),
+            TransportAddr::Tcp(SocketAddr::V4(addr)) => write!(f, "tcp:{}", addr),
+            TransportAddr::Tcp(SocketAddr::V6(addr)) => write!(f, "tcp6:{}", addr),
+            TransportAddr::Tls(addr) => write!(f, "tlstcp:{}", addr),
+            TransportAddr::Dtls(addr) => write!(f, "dtlsudp:{}", addr),
+            #[cfg(unix)]
+            TransportAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            TransportAddr::UnixDgram(path) => write!(f, "unixdgram:{}", path.display()),
+            TransportAddr::Connection(id) => write!(f, "connection #{}", id),
+        }
+    }
+}
+
+/// A way of exchanging whole SNMP messages with peers.
+///
+/// Stream transports carry one BER-encoded message after another, as
+/// RFC 3430 specifies for TCP, and keep connections open for reuse.
+///
+/// A receive that times out fails with [`ErrorKind::WouldBlock`] or
+/// [`ErrorKind::TimedOut`]. A message longer than the buffer is cut
+/// short, like a UDP datagram.
+pub trait Transport: Send + Sync {
+    fn send_to(&self, data: &[u8], peer: &TransportAddr) -> io::Result<()>;
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, TransportAddr)>;
+
+    fn local_addr(&self) -> io::Result<TransportAddr>;
+
+    /// The authenticated identity of a peer, for transports that provide
+    /// one (the tmSecurityName of RFC 6353).
+    fn security_name(&self, _peer: &TransportAddr) -> Option<String> {
+        None
+    }
+}
+
+/// Opens a listener for an agent on the target's transport and address.
+pub fn listen(target: &Target) -> io::Result<Box<dyn Transport>> {
+    Ok(match target.transport {
+        TransportKind::Udp => Box::new(UdpTransport::bind(target.resolve()?)?),
+        TransportKind::Tcp => Box::new(TcpTransport::listen(target.resolve()?)?),
+        #[cfg(unix)]
+        TransportKind::Unix => Box::new(UnixStreamTransport::listen(&target.host)?),
+        #[cfg(unix)]
+        TransportKind::UnixDgram => Box::new(UnixDatagramTransport::bind(&target.host)?),
+        TransportKind::Tls | TransportKind::Dtls => {
+            return Err(unsupported(
+                "TLS listeners need a TLS configuration, see the tls module",
+            ));
+        }
+        #[cfg(not(unix))]
+        _ => return Err(unsupported("Unix domain sockets are not available")),
+    })
+}
+
+// Client transports are shared by every peer of the same kind, UDP needs
+// one socket per IP family
+pub(crate) fn client_key(peer: &TransportAddr) -> (TransportKind, AddressFamily) {
+    match peer {
+        TransportAddr::Udp(addr) if addr.is_ipv6() => (TransportKind::Udp, AddressFamily::Ipv6),
+        TransportAddr::Udp(_) => (TransportKind::Udp, AddressFamily::Ipv4),
+        TransportAddr::Tcp(_) | TransportAddr::Connection(_) => {
+            (TransportKind::Tcp, AddressFamily::Any)
+        }
+        TransportAddr::Tls(_) => (TransportKind::Tls, AddressFamily::Any),
+        TransportAddr::Dtls(_) => (TransportKind::Dtls, AddressFamily::Any),
+        #[cfg(unix)]
+        TransportAddr::Unix(_) => (TransportKind::Unix, AddressFamily::Any),
+        #[cfg(unix)]
+        TransportAddr::UnixDgram(_) => (TransportKind::UnixDgram, AddressFamily::Any),
+    }
+}
+
+// Create a transport for a client talking to `peer`, which gives up on
+// connecting to it after `connect_timeout`
+pub(crate) fn client_transport(
+    peer: &TransportAddr,
+    connect_timeout: Duration,
+) -> io::Result<Box<dyn Transport>> {
+    Ok(match peer {
+        TransportAddr::Udp(addr) if addr.is_ipv6() => {
+            Box::new(UdpTransport::bind((Ipv6Addr::UNSPECIFIED, 0))?)
+        }
+        TransportAddr::Udp(_) => Box::new(UdpTransport::bind((Ipv4Addr::UNSPECIFIED, 0))?),
+        TransportAddr::Tcp(_) => {
+            Box::new(TcpTransport::new().with_connect_timeout(connect_timeout))
+        }
+        #[cfg(unix)]
+        TransportAddr::Unix(_) => {
+            Box::new(UnixStreamTransport::new().with_connect_timeout(connect_timeout))
+        }
+        #[cfg(unix)]
+        TransportAddr::UnixDgram(_) => Box::new(UnixDatagramTransport::bind_temporary()?),
+        TransportAddr::Tls(_) | TransportAddr::Dtls(_) => {
+            return Err(unsupported("TLS targets need a TLS configuration"));
+        }
+        TransportAddr::Connection(_) => {
+            return Err(unsupported("Cannot connect to an accepted connection"));
+        }
+    })
+}
+
+pub(crate) fn unsupported(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::Unsupported, message.to_string())
+}
+
+pub(crate) fn wrong_peer(peer: &TransportAddr) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("Peer {} does not belong to this transport", peer),
+    )
+}
+
+// Socket read timeouts reject a zero duration, report it as timed out
+fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout.is_some_and(|t| t.is_zero()) {
+        return Err(io::Error::from(ErrorKind::TimedOut));
+    }
+    Ok(())
+}
+
+/// UDP over IPv4 or IPv6, depending on the bound address.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_to(&self, data: &[u8], peer: &TransportAddr) -> io::Result<()> {
+        let TransportAddr::Udp(addr) = peer else {
+            return Err(wrong_peer(peer));
+        };
+        self.socket.send_to(data, addr)?;
+        Ok(())
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, TransportAddr)> {
+        check_timeout(timeout)?;
+        self.socket.set_read_timeout(timeout)?;
+        let (len, addr) = self.socket.recv_from(buf)?;
+        Ok((len, TransportAddr::Udp(addr)))
+    }
+
+    fn local_addr(&self) -> io::Result<TransportAddr> {
+        self.socket.local_addr().map(TransportAddr::Udp)
+    }
+}
+
+/// TCP with RFC 3430 framing.
+///
+/// A client transport connects to each agent on first use and keeps the
+/// connection open; a listener accepts any number of managers.
+pub struct TcpTransport {
+    streams: StreamSet<TcpStream>,
+    local_addr: Option<SocketAddr>,
+    connect_timeout: Duration,
+}
+
+impl TcpTransport {
+    /// Creates a client transport.
+    pub fn new() -> Self {
+        Self {
+            streams: StreamSet::new(),
+            local_addr: None,
+            connect_timeout: CONNECT_TIMEOUT,
+        }
+    }
+
+    /// Sets how long a client transport waits for an agent to accept a
+    /// connection, 5 seconds by default.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Listens for managers on `addr`.
+    pub fn listen(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let streams = StreamSet::new();
+
+        let connections = streams.connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                if let Ok(peer) = stream.peer_addr() {
+                    let _ = connections.add(stream, TransportAddr::Tcp(peer));
+                }
+            }
+        });
+
+        Ok(Self {
+            streams,
+            local_addr: Some(local_addr),
+            connect_timeout: CONNECT_TIMEOUT,
+        })
+    }
+}
+
+impl Default for TcpTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send_to(&self, data: &[u8], peer: &TransportAddr) -> io::Result<()> {
+        match peer {
+            TransportAddr::Tcp(addr) if self.local_addr.is_none() => {
+                self.streams.send_or_connect(data, peer, || {
+                    TcpStream::connect_timeout(addr, self.connect_timeout)
+                })
+            }
+            TransportAddr::Tcp(_) => self.streams.send(data, peer),
+            _ => Err(wrong_peer(peer)),
+        }
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, TransportAddr)> {
+        self.streams.recv(buf, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<TransportAddr> {
+        self.local_addr
+            .map(TransportAddr::Tcp)
+            .ok_or_else(|| unsupported("Client transport has no local address"))
+    }
+}
+
+/// Unix domain stream sockets, framed like TCP.
+#[cfg(unix)]
+pub struct UnixStreamTransport {
+    streams: StreamSet<UnixStream>,
+    path: Option<PathBuf>,
+    connect_timeout: Duration,
+}
+
+#[cfg(unix)]
+impl UnixStreamTransport {
+    /// Creates a client transport.
+    pub fn new() -> Self {
+        Self {
+            streams: StreamSet::new(),
+            path: None,
+            connect_timeout: CONNECT_TIMEOUT,
+        }
+    }
+
+    /// Sets how long a client transport waits for an agent to accept a
+    /// connection, 5 seconds by default.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Listens for managers on `path`, which is removed again on drop.
+    pub fn listen(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        // A socket left behind by an earlier agent would make bind fail
+        if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+            let _ = std::fs::remove_file(&path);
+        }
+        let listener = UnixListener::bind(&path)?;
+        let streams = StreamSet::new();
+
+        let connections = streams.connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let peer = TransportAddr::Connection(connections.next_id());
+                let _ = connections.add(stream, peer);
+            }
+        });
+
+        Ok(Self {
+            streams,
+            path: Some(path),
+            connect_timeout: CONNECT_TIMEOUT,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Default for UnixStreamTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStreamTransport {
+    fn send_to(&self, data: &[u8], peer: &TransportAddr) -> io::Result<()> {
+        match peer {
+            TransportAddr::Unix(path) if self.path.is_none() => {
+                self.streams.send_or_connect(data, peer, || {
+                    connect_unix(path.clone(), self.connect_timeout)
+                })
+            }
+            TransportAddr::Connection(_) if self.path.is_some() => self.streams.send(data, peer),
+            _ => Err(wrong_peer(peer)),
+        }
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, TransportAddr)> {
+        self.streams.recv(buf, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<TransportAddr> {
+        self.path
+            .clone()
+            .map(TransportAddr::Unix)
+            .ok_or_else(|| unsupported("Client transport has no local address"))
+    }
+}
+
+// Connect to a Unix socket, which blocks while the listener's backlog is
+// full. The standard library has no timeout for it, so the connection is
+// made on a thread of its own that is left behind on timeout.
+#[cfg(unix)]
+fn connect_unix(path: PathBuf, timeout: Duration) -> io::Result<UnixStream> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(UnixStream::connect(path));
+    });
+    rx.recv_timeout(timeout)
+        .map_err(|_| io::Error::new(ErrorKind::TimedOut, "Connection timed out"))?
+}
+
+#[cfg(unix)]
+impl Drop for UnixStreamTransport {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Unix domain datagram sockets.
+///
+/// Replies can only be sent to bound sockets, so a client transport binds
+/// a temporary path of its own; it is removed again on drop, as is the
+/// path of a listener.
+#[cfg(unix)]
+pub struct UnixDatagramTransport {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixDatagramTransport {
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        Ok(Self {
+            socket: UnixDatagram::bind(&path)?,
+            path,
+        })
+    }
+
+    /// Binds a fresh path in the temporary directory.
+    pub fn bind_temporary() -> io::Result<Self> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "snmp-{}-{}.sock",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        Self::bind(path)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixDatagramTransport {
+    fn send_to(&self, data: &[u8], peer: &TransportAddr) -> io::Result<()> {
+        let TransportAddr::UnixDgram(path) = peer else {
+            return Err(wrong_peer(peer));
+        };
+        self.socket.send_to(data, path)?;
+        Ok(())
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, TransportAddr)> {
+        check_timeout(timeout)?;
+        self.socket.set_read_timeout(timeout)?;
+        loop {
+            let (len, addr) = self.socket.recv_from(buf)?;
+            // An unbound sender cannot be answered
+            if let Some(path) = addr.as_pathname() {
+                return Ok((len, TransportAddr::UnixDgram(path.to_path_buf())));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<TransportAddr> {
+        Ok(TransportAddr::UnixDgram(self.path.clone()))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixDatagramTransport {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// A byte stream that can be split into a reading and a writing half
+trait Stream: Read + Write + Send + Sized + 'static {
+    fn duplicate(&self) -> io::Result<Self>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    // Close both halves, ending the reader thread
+    fn close(&self);
+}
+
+impl Stream for TcpStream {
+    fn duplicate(&self) -> io::Result<Self> {
+        self.try_clone()
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn duplicate(&self) -> io::Result<Self> {
+        self.try_clone()
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+// The write half of a connection, tagged with an ID so a reader thread
+// only removes its own connection
+struct Writer<S> {
+    id: u64,
+    stream: Mutex<S>,
+}
+
+pub(crate) type Incoming = (Vec<u8>, TransportAddr);
+
+// The open connections of a stream transport. Every connection has a
+// reader thread that forwards whole messages to the transport.
+struct Connections<S> {
+    streams: Arc<Mutex<HashMap<TransportAddr, Arc<Writer<S>>>>>,
+    incoming: Sender<Incoming>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl<S> Clone for Connections<S> {
+    fn clone(&self) -> Self {
+        Self {
+            streams: self.streams.clone(),
+            incoming: self.incoming.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+impl<S: Stream> Connections<S> {
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn add(&self, stream: S, peer: TransportAddr) -> io::Result<()> {
+        let mut reader = stream.duplicate()?;
+        stream.set_write_timeout(Some(STREAM_WRITE_TIMEOUT))?;
+        let id = self.next_id();
+        {
+            let mut streams = self.streams.lock().unwrap();
+            if streams.len() >= MAX_STREAM_CONNECTIONS && !streams.contains_key(&peer) {
+                stream.close();
+                return Err(io::Error::new(
+                    ErrorKind::ConnectionRefused,
+                    "Too many connections",
+                ));
+            }
+            let writer = Writer {
+                id,
+                stream: Mutex::new(stream),
+            };
+            streams.insert(peer.clone(), Arc::new(writer));
+        }
+
+        let connections = self.clone();
+        thread::spawn(move || {
+            while let Ok(Some(message)) = read_message(&mut reader) {
+                if connections.incoming.send((message, peer.clone())).is_err() {
+                    break;
+                }
+            }
+            connections.remove(&peer, id);
+        });
+
+        Ok(())
+    }
+
+    // Forget the connection to `peer` if it is still the one with `id`
+    fn remove(&self, peer: &TransportAddr, id: u64) {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.get(peer).is_some_and(|writer| writer.id == id) {
+            streams.remove(peer);
+        }
+    }
+}
+
+// The shared part of the stream transports
+struct StreamSet<S> {
+    connections: Connections<S>,
+    incoming: Mutex<Receiver<Incoming>>,
+}
+
+impl<S: Stream> StreamSet<S> {
+    fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self {
+            connections: Connections {
+                streams: Arc::new(Mutex::new(HashMap::new())),
+                incoming: tx,
+                next_id: Arc::new(AtomicU64::new(1)),
+            },
+            incoming: Mutex::new(rx),
+        }
+    }
+
+    // Send over an existing connection, without holding up the others
+    // while the peer takes the message; a connection that fails is closed
+    fn send(&self, data: &[u8], peer: &TransportAddr) -> io::Result<()> {
+        let writer = self
+            .connections
+            .streams
+            .lock()
+            .unwrap()
+            .get(peer)
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::NotConnected,
+                    format!("No connection to {}", peer),
+                )
+            })?;
+
+        let mut stream = writer.stream.lock().unwrap();
+        let result = stream.write_all(data);
+        if result.is_err() {
+            stream.close();
+            self.connections.remove(peer, writer.id);
+        }
+        result
+    }
+
+    // Send over the connection to `peer`, opening it if there is none or
+    // the one kept open has been closed by the peer
+    fn send_or_connect(
+        &self,
+        data: &[u8],
+        peer: &TransportAddr,
+        connect: impl FnOnce() -> io::Result<S>,
+    ) -> io::Result<()> {
+        if self.send(data, peer).is_ok() {
+            return Ok(());
+        }
+        self.connections.streams.lock().unwrap().remove(peer);
+        self.connections.add(connect()?, peer.clone())?;
+        self.send(data, peer)
+    }
+
+    fn recv(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, TransportAddr)> {
+        receive(&self.incoming, buf, timeout)
+    }
+}
+
+// Wait for the next message forwarded by a connection thread
+pub(crate) fn receive(
+    incoming: &Mutex<Receiver<Incoming>>,
+    buf: &mut [u8],
+    timeout: Option<Duration>,
+) -> io::Result<(usize, TransportAddr)> {
+    let incoming = incoming.lock().unwrap();
+    let (message, peer) = match timeout {
+        Some(timeout) => incoming.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => io::Error::from(ErrorKind::TimedOut),
+            RecvTimeoutError::Disconnected => io::Error::from(ErrorKind::BrokenPipe),
+        })?,
+        None => incoming
+            .recv()
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?,
+    };
+
+    let len = message.len().min(buf.len());
+    buf[..len].copy_from_slice(&message[..len]);
+    Ok((len, peer))
+}
+
+// Read one BER-encoded message, or `None` if the peer closed the
+// connection between messages
+fn read_message(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut header = vec![0u8; 2];
+    match reader.read_exact(&mut header[..1]) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    reader.read_exact(&mut header[1..2])?;
+
+    let length = if header[1] < 0x80 {
+        header[1] as usize
+    } else {
+        let num_bytes = (header[1] & 0x7F) as usize;
+        if num_bytes == 0 || num_bytes > 4 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid BER length"));
+        }
+        let mut length_bytes = vec![0u8; num_bytes];
+        reader.read_exact(&mut length_bytes)?;
+        header.extend_from_slice(&length_bytes);
+        length_bytes
+            .iter()
+            .fold(0usize, |length, &b| (length << 8) | b as usize)
+    };
+
+    if length > MAX_STREAM_MESSAGE_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Message too large"));
+    }
+
+    let mut message = header;
+    let header_len = message.len();
+    message.resize(header_len + length, 0);
+    reader.read_exact(&mut message[header_len..])?;
+    Ok(Some(message))
+}
+
+// Take the first message off the front of `pending` once all of it has
+// arrived, for readers that cannot block in the middle of a message
+#[cfg(feature = "tls")]
+pub(crate) fn split_message(pending: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    if pending.len() < 2 {
+        return Ok(None);
+    }
+
+    let (header_len, length) = if pending[1] < 0x80 {
+        (2, pending[1] as usize)
+    } else {
+        let num_bytes = (pending[1] & 0x7F) as usize;
+        if num_bytes == 0 || num_bytes > 4 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid BER length"));
+        }
+        if pending.len() < 2 + num_bytes {
+            return Ok(None);
+        }
+        let length = pending[2..2 + num_bytes]
+            .iter()
+            .fold(0usize, |length, &b| (length << 8) | b as usize);
+        (2 + num_bytes, length)
+    };
+
+    if length > MAX_STREAM_MESSAGE_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Message too large"));
+    }
+    if pending.len() < header_len + length {
+        return Ok(None);
+    }
+    Ok(Some(pending.drain(..header_len + length).collect()))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("snmp-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn unix_listener_replaces_a_stale_socket() {
+        let path = socket_path("stale");
+        let _ = std::fs::remove_file(&path);
+        // A socket file nobody listens on any more
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let transport = UnixStreamTransport::listen(&path).unwrap();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(&[0x30, 0x03, 0x02, 0x01, 0x00]).unwrap();
+        let mut buf = [0u8; 16];
+        let (len, _) = transport
+            .recv_from(&mut buf, Some(Duration::from_secs(2)))
+            .unwrap();
+        assert_eq!(len, 5);
+
+        // Anything but a socket is left alone
+        let file = socket_path("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(UnixStreamTransport::listen(&file).is_err());
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn connections_are_capped() {
+        let path = socket_path("cap");
+        let transport = UnixStreamTransport::listen(&path).unwrap();
+        let streams: Vec<UnixStream> = (0..MAX_STREAM_CONNECTIONS + 1)
+            .map(|_| UnixStream::connect(&path).unwrap())
+            .collect();
+
+        // The listener closes the connection beyond the limit
+        let mut last = streams.last().unwrap();
+        last.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(last.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            transport.streams.connections.streams.lock().unwrap().len(),
+            MAX_STREAM_CONNECTIONS
+        );
+    }
+
+    #[test]
+    fn tcp_messages_are_framed_by_their_ber_length() {
+        let transport = TcpTransport::listen((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let TransportAddr::Tcp(addr) = transport.local_addr().unwrap() else {
+            unreachable!();
+        };
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+
+        // A SEQUENCE with a long-form length, and two short ones
+        let long = [&[0x30, 0x81, 0xc8][..], &[0x05; 200]].concat();
+        let first = [0x30, 0x03, 0x02, 0x01, 0x01];
+        let second = [0x30, 0x03, 0x02, 0x01, 0x02];
+
+        // Split inside the length octets and again inside the contents
+        for part in [&long[..2], &long[2..50], &long[50..]] {
+            stream.write_all(part).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        // Both in a single write
+        stream.write_all(&[first, second].concat()).unwrap();
+
+        let mut buf = [0u8; 512];
+        for expected in [&long[..], &first, &second] {
+            let (len, peer) = transport
+                .recv_from(&mut buf, Some(Duration::from_secs(2)))
+                .unwrap();
+            assert_eq!(&buf[..len], expected);
+            assert_eq!(peer, TransportAddr::Tcp(stream.local_addr().unwrap()));
+        }
+        assert!(
+            transport
+                .recv_from(&mut buf, Some(Duration::from_millis(50)))
+                .is_err()
+        );
+    }
+}
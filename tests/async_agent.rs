@@ -0,0 +1,184 @@
+#![cfg(feature = "tokio")]
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use snmp_t::async_agent::{AsyncMibHandler, AsyncSnmpAgent, BoxFuture};
+use snmp_t::client::{RequestOptions, SnmpClient};
+use snmp_t::community::Community;
+use snmp_t::snmp::{self, ErrorStatus, SnmpValue, Varbind};
+
+const PREFIX: [u32; 7] = [1, 3, 6, 1, 4, 1, 99];
+const STATIC: [u32; 8] = [1, 3, 6, 1, 4, 1, 100, 0];
+
+// Values kept behind an await, rejecting negative numbers in the test phase
+#[derive(Default)]
+struct Store {
+    values: Mutex<BTreeMap<Vec<u32>, SnmpValue>>,
+}
+
+impl AsyncMibHandler for Store {
+    fn get<'a>(&'a self, oid: &'a [u32]) -> BoxFuture<'a, Option<SnmpValue>> {
+        Box::pin(async move {
+            tokio::task::yield_now().await;
+            self.values.lock().unwrap().get(oid).cloned()
+        })
+    }
+
+    fn get_next<'a>(&'a self, oid: &'a [u32]) -> BoxFuture<'a, Option<Varbind>> {
+        Box::pin(async move {
+            tokio::task::yield_now().await;
+            let values = self.values.lock().unwrap();
+            let (oid, value) = values
+                .range::<[u32], _>((Bound::Excluded(oid), Bound::Unbounded))
+                .next()?;
+            Some(Varbind {
+                oid: oid.clone(),
+                value: value.clone(),
+            })
+        })
+    }
+
+    fn test_set<'a>(
+        &'a self,
+        _oid: &'a [u32],
+        value: &'a SnmpValue,
+    ) -> BoxFuture<'a, Result<(), ErrorStatus>> {
+        Box::pin(async move {
+            match value {
+                SnmpValue::Integer(n) if *n >= 0 => Ok(()),
+                SnmpValue::Integer(_) => Err(ErrorStatus::WrongValue),
+                _ => Err(ErrorStatus::WrongType),
+            }
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        oid: &'a [u32],
+        value: &'a SnmpValue,
+    ) -> BoxFuture<'a, Result<(), ErrorStatus>> {
+        Box::pin(async move {
+            self.values
+                .lock()
+                .unwrap()
+                .insert(oid.to_vec(), value.clone());
+            Ok(())
+        })
+    }
+}
+
+fn oid(last: u32) -> Vec<u32> {
+    let mut oid = PREFIX.to_vec();
+    oid.extend([last, 0]);
+    oid
+}
+
+// Start an agent on its own runtime, returning its address
+fn start_agent(store: Arc<Store>) -> String {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let agent = runtime.block_on(async {
+        AsyncSnmpAgent::new("127.0.0.1:0", vec!["private".to_string()])
+            .await
+            .unwrap()
+            .with_community(Community::read_only("public"))
+    });
+    agent.register_handler(PREFIX.to_vec(), store).unwrap();
+    agent
+        .register_oid(STATIC.to_vec(), SnmpValue::Integer(7))
+        .unwrap();
+    agent
+        .register_scalar(vec![1, 3, 6, 1, 4, 1, 101, 0], || SnmpValue::Gauge32(42))
+        .unwrap();
+    let target = agent.local_addr().unwrap().to_string();
+    std::thread::spawn(move || runtime.block_on(agent.run()));
+    target
+}
+
+fn client() -> SnmpClient {
+    SnmpClient::new()
+        .with_version(snmp::SNMP_VERSION_2C)
+        .with_options(RequestOptions {
+            timeout: Duration::from_millis(500),
+            retries: 1,
+            backoff: 1.0,
+        })
+}
+
+#[test]
+fn serves_async_and_sync_handlers() {
+    let store = Arc::new(Store::default());
+    store
+        .values
+        .lock()
+        .unwrap()
+        .insert(oid(1), SnmpValue::Integer(1));
+    let target = start_agent(store);
+    let mut client = client();
+
+    let response = client
+        .get_next(target.as_str(), "public", &[&PREFIX])
+        .unwrap();
+    assert_eq!(response.varbinds[0].oid, oid(1));
+    let response = client
+        .get_next(target.as_str(), "public", &[&oid(1)])
+        .unwrap();
+    assert_eq!(response.varbinds[0].oid, STATIC);
+    let response = client
+        .get_next(target.as_str(), "public", &[&STATIC])
+        .unwrap();
+    assert!(matches!(response.varbinds[0].value, SnmpValue::Gauge32(42)));
+}
+
+#[test]
+fn set_is_tested_before_anything_is_stored() {
+    let store = Arc::new(Store::default());
+    let target = start_agent(store.clone());
+    let mut client = client();
+
+    let error = client
+        .set(
+            target.as_str(),
+            "private",
+            &[
+                (&STATIC, SnmpValue::Integer(8)),
+                (&oid(1), SnmpValue::Integer(1)),
+                (&oid(2), SnmpValue::Integer(-1)),
+            ],
+        )
+        .unwrap_err();
+    assert!(error.to_string().contains("wrongValue"), "{}", error);
+    assert!(store.values.lock().unwrap().is_empty());
+    let response = client.get(target.as_str(), "public", &[&STATIC]).unwrap();
+    assert!(matches!(response.varbinds[0].value, SnmpValue::Integer(7)));
+
+    // A read-only community may not write at all
+    let error = client
+        .set(
+            target.as_str(),
+            "public",
+            &[(&oid(1), SnmpValue::Integer(1))],
+        )
+        .unwrap_err();
+    assert!(error.to_string().contains("noAccess"), "{}", error);
+
+    client
+        .set(
+            target.as_str(),
+            "private",
+            &[
+                (&STATIC, SnmpValue::Integer(8)),
+                (&oid(1), SnmpValue::Integer(1)),
+            ],
+        )
+        .unwrap();
+    assert!(matches!(
+        store.values.lock().unwrap()[&oid(1)],
+        SnmpValue::Integer(1)
+    ));
+}
@@ -0,0 +1,172 @@
+#![cfg(feature = "tokio")]
+
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use snmp_t::agent::SnmpAgent;
+use snmp_t::async_client::AsyncSnmpClient;
+use snmp_t::client::RequestOptions;
+use snmp_t::snmp::{self, SnmpError, SnmpValue};
+use snmp_t::transport::{Transport, TransportAddr, UdpTransport};
+
+const OPTIONS: RequestOptions = RequestOptions {
+    timeout: Duration::from_millis(200),
+    retries: 2,
+    backoff: 1.0,
+};
+
+const LARGE: [u32; 8] = [1, 3, 6, 1, 4, 1, 100, 0];
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+// Start an agent serving 100 integers under 1.3.6.1.4.1.99, returning its address
+fn start_agent() -> String {
+    let transport = UdpTransport::bind("127.0.0.1:0").unwrap();
+    let TransportAddr::Udp(addr) = transport.local_addr().unwrap() else {
+        unreachable!();
+    };
+    let agent = SnmpAgent::with_transport(Box::new(transport), vec!["public".to_string()]);
+    agent
+        .register_oid(LARGE.to_vec(), SnmpValue::OctetString(vec![b'x'; 1000]))
+        .unwrap();
+    agent.register_oids(
+        (0..100).map(|i| (vec![1, 3, 6, 1, 4, 1, 99, i], SnmpValue::Integer(i as i32))),
+    );
+    agent.run_in_thread();
+    addr.to_string()
+}
+
+#[test]
+fn concurrent_requests_get_their_own_answers() {
+    let target = start_agent();
+    runtime().block_on(async {
+        let client = AsyncSnmpClient::new()
+            .await
+            .unwrap()
+            .with_version(snmp::SNMP_VERSION_2C)
+            .with_options(OPTIONS);
+
+        let requests = (0..100u32).map(|i| {
+            let client = client.clone();
+            let target = target.clone();
+            tokio::spawn(async move {
+                let oid = [1, 3, 6, 1, 4, 1, 99, i];
+                let response = client
+                    .get(target.as_str(), "public", &[&oid])
+                    .await
+                    .unwrap();
+                (i, response.varbinds[0].value.clone())
+            })
+        });
+        for request in requests.collect::<Vec<_>>() {
+            let (i, value) = request.await.unwrap();
+            assert!(matches!(value, SnmpValue::Integer(n) if n == i as i32));
+        }
+
+        let response = client
+            .get_bulk(target.as_str(), "public", 0, 10, &[&[1, 3, 6, 1, 4, 1, 99]])
+            .await
+            .unwrap();
+        assert_eq!(response.varbinds.len(), 10);
+    });
+}
+
+#[test]
+fn retransmits_then_times_out() {
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let target = silent.local_addr().unwrap().to_string();
+    silent
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+
+    let started = Instant::now();
+    let error = runtime().block_on(async {
+        let client = AsyncSnmpClient::new().await.unwrap().with_options(OPTIONS);
+        client
+            .get(target.as_str(), "public", &[&[1, 3, 6, 1, 2, 1, 1, 1, 0]])
+            .await
+            .unwrap_err()
+    });
+    assert!(started.elapsed() >= Duration::from_millis(600));
+    assert!(matches!(
+        error.downcast_ref::<SnmpError>(),
+        Some(SnmpError::Timeout { attempts: 3 })
+    ));
+
+    let mut buf = [0u8; 1500];
+    let mut sent = Vec::new();
+    while let Ok(len) = silent.recv(&mut buf) {
+        sent.push(buf[..len].to_vec());
+    }
+    // Every attempt carries the same request
+    assert_eq!(sent.len(), 3);
+    assert!(sent.iter().all(|datagram| *datagram == sent[0]));
+}
+
+#[test]
+fn oversized_response_is_reported() {
+    let target = start_agent();
+    let error = runtime().block_on(async {
+        let client = AsyncSnmpClient::new()
+            .await
+            .unwrap()
+            .with_options(OPTIONS)
+            .with_max_message_size(500);
+        client
+            .get(target.as_str(), "public", &[&LARGE])
+            .await
+            .unwrap_err()
+    });
+    assert!(matches!(
+        error.downcast_ref::<SnmpError>(),
+        Some(SnmpError::MessageTooLarge(500))
+    ));
+}
+
+#[test]
+fn clones_share_the_in_flight_limit() {
+    // A silent agent reporting when each request arrives
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let target = silent.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        while silent.recv(&mut buf).is_ok() {
+            if tx.send(Instant::now()).is_err() {
+                break;
+            }
+        }
+    });
+
+    runtime().block_on(async {
+        let client = AsyncSnmpClient::new()
+            .await
+            .unwrap()
+            .with_options(RequestOptions {
+                retries: 0,
+                ..OPTIONS
+            });
+        // The limit is set after cloning and still covers the clone
+        let clone = client.clone();
+        let client = client.with_max_in_flight(1);
+
+        let oids: [&[u32]; 1] = [&[1, 3, 6, 1, 2, 1, 1, 1, 0]];
+        let (first, second) = tokio::join!(
+            client.get(target.as_str(), "public", &oids),
+            clone.get(target.as_str(), "public", &oids),
+        );
+        assert!(first.is_err() && second.is_err());
+    });
+
+    // The second request was only sent once the first had timed out
+    let arrivals: Vec<Instant> = rx.try_iter().collect();
+    assert_eq!(arrivals.len(), 2);
+    assert!(arrivals[1] - arrivals[0] >= Duration::from_millis(150));
+}
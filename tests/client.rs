@@ -0,0 +1,546 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use snmp_t::agent::SnmpAgent;
+use snmp_t::client::{RequestOptions, SnmpClient};
+use snmp_t::index::IndexType;
+use snmp_t::mib::{Access, ObjectType, Syntax, ValueType};
+use snmp_t::snmp::{
+    self, ErrorStatus, PduType, SnmpError, SnmpMessage, SnmpPdu, SnmpValue, Varbind,
+};
+use snmp_t::table::{Column, Row, Table};
+use snmp_t::transport::{Transport, TransportAddr, UdpTransport};
+
+const IP_NET_TO_MEDIA_TABLE: [u32; 8] = [1, 3, 6, 1, 2, 1, 4, 22];
+const OBJECTS: [u32; 8] = [1, 3, 6, 1, 4, 1, 99, 1];
+const SYS_CONTACT: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 4, 0];
+
+fn start_agent(agent: impl FnOnce(&SnmpAgent)) -> String {
+    let transport = UdpTransport::bind("127.0.0.1:0").unwrap();
+    let TransportAddr::Udp(addr) = transport.local_addr().unwrap() else {
+        unreachable!();
+    };
+    let snmp_agent = SnmpAgent::with_transport(Box::new(transport), vec!["public".to_string()]);
+    agent(&snmp_agent);
+    snmp_agent.run_in_thread();
+    addr.to_string()
+}
+
+fn client(version: u8) -> SnmpClient {
+    SnmpClient::new()
+        .with_version(version)
+        .with_options(RequestOptions {
+            timeout: Duration::from_millis(500),
+            retries: 1,
+            backoff: 1.0,
+        })
+}
+
+// ipNetToMediaPhysAddress and ipNetToMediaType, indexed by ifIndex and
+// the neighbour's address
+fn neighbours() -> Table {
+    let neighbour = |if_index, address, mac: u8| {
+        Row::new(vec![
+            SnmpValue::Integer(if_index),
+            SnmpValue::IpAddress(address),
+        ])
+        .with_cell(2, SnmpValue::OctetString(vec![0, 0x1b, 0x21, 0, 0, mac]))
+        .with_cell(4, SnmpValue::Integer(3))
+    };
+    Table::new(
+        IP_NET_TO_MEDIA_TABLE.to_vec(),
+        vec![IndexType::Integer, IndexType::IpAddress],
+        move || {
+            vec![
+                neighbour(3, [10, 0, 0, 1], 1),
+                neighbour(2, [192, 168, 1, 254], 2),
+                neighbour(2, [192, 168, 1, 10], 3),
+            ]
+        },
+    )
+    .with_column(Column::new(2, ValueType::OctetString, Access::ReadOnly))
+    .with_column(Column::new(4, ValueType::Integer, Access::ReadOnly))
+}
+
+#[test]
+fn get_table_decodes_composite_indexes() {
+    let target = start_agent(|agent| agent.register_table(neighbours()).unwrap());
+
+    for version in [snmp::SNMP_VERSION_1, snmp::SNMP_VERSION_2C] {
+        let rows = client(version)
+            .get_table(
+                target.as_str(),
+                "public",
+                &IP_NET_TO_MEDIA_TABLE,
+                &[IndexType::Integer, IndexType::IpAddress],
+                &[2, 4],
+            )
+            .unwrap();
+
+        assert_eq!(rows.len(), 3);
+        assert!(matches!(
+            rows[0].index[..],
+            [
+                SnmpValue::Integer(2),
+                SnmpValue::IpAddress([192, 168, 1, 10])
+            ]
+        ));
+        assert!(matches!(
+            rows[1].index[..],
+            [
+                SnmpValue::Integer(2),
+                SnmpValue::IpAddress([192, 168, 1, 254])
+            ]
+        ));
+        assert!(matches!(
+            rows[2].index[..],
+            [SnmpValue::Integer(3), SnmpValue::IpAddress([10, 0, 0, 1])]
+        ));
+        assert!(matches!(&rows[2].cells[&2], SnmpValue::OctetString(mac) if mac[5] == 1));
+        assert!(matches!(rows[2].cells[&4], SnmpValue::Integer(3)));
+    }
+}
+
+#[test]
+fn get_table_rejects_rows_that_do_not_match_the_index() {
+    let target = start_agent(|agent| agent.register_table(neighbours()).unwrap());
+
+    let result = client(snmp::SNMP_VERSION_2C).get_table(
+        target.as_str(),
+        "public",
+        &IP_NET_TO_MEDIA_TABLE,
+        &[IndexType::Integer],
+        &[2],
+    );
+    assert!(result.is_err());
+}
+
+// An agent that never answers, reporting when each request arrived and
+// its request ID
+fn silent_agent() -> (String, Receiver<(Instant, i32)>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        while let Ok((len, _)) = socket.recv_from(&mut buf) {
+            let message = snmp::decode_snmp_message(&buf[..len]).unwrap();
+            if tx.send((Instant::now(), message.pdu.request_id)).is_err() {
+                break;
+            }
+        }
+    });
+    (addr, rx)
+}
+
+#[test]
+fn silent_agent_gets_retransmissions_until_the_timeout() {
+    let (target, rx) = silent_agent();
+    let options = RequestOptions {
+        timeout: Duration::from_millis(100),
+        retries: 2,
+        backoff: 2.0,
+    };
+
+    // The per-request options replace the client's 500ms and one retry
+    let started = Instant::now();
+    let error = client(snmp::SNMP_VERSION_2C)
+        .set_with_options(
+            target.as_str(),
+            "public",
+            &[(&[1, 3, 6, 1, 2, 1, 1, 4, 0], SnmpValue::Integer(1))],
+            &options,
+        )
+        .unwrap_err();
+    let elapsed = started.elapsed();
+    assert!(
+        matches!(
+            error.downcast_ref::<SnmpError>(),
+            Some(SnmpError::Timeout { attempts: 3 })
+        ),
+        "{}",
+        error
+    );
+    // 100ms, then 200ms, then 400ms, each with up to 10% of jitter
+    assert!(elapsed >= Duration::from_millis(700), "{:?}", elapsed);
+
+    let attempts: Vec<(Instant, i32)> = (0..3)
+        .map(|_| rx.recv_timeout(Duration::from_secs(1)).unwrap())
+        .collect();
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    assert!(attempts.iter().all(|&(_, id)| id == attempts[0].1));
+    let waits: Vec<Duration> = attempts.windows(2).map(|w| w[1].0 - w[0].0).collect();
+    assert!(waits[0] >= Duration::from_millis(90), "{:?}", waits);
+    assert!(waits[1] >= Duration::from_millis(190), "{:?}", waits);
+    assert!(waits[1] > waits[0], "{:?}", waits);
+
+    let error = client(snmp::SNMP_VERSION_2C)
+        .get_next_with_options(
+            target.as_str(),
+            "public",
+            &[&[1, 3, 6, 1]],
+            &RequestOptions {
+                retries: 0,
+                ..options
+            },
+        )
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<SnmpError>(),
+        Some(SnmpError::Timeout { attempts: 1 })
+    ));
+}
+
+// A response to `request` with the given request ID and varbinds
+fn reply(request: &SnmpMessage, request_id: i32, varbinds: Vec<Varbind>) -> Vec<u8> {
+    let request = SnmpMessage {
+        version: request.version,
+        community: request.community.clone(),
+        pdu: SnmpPdu {
+            pdu_type: PduType::GET_RESPONSE,
+            request_id,
+            error_status: ErrorStatus::NoError,
+            error_index: 0,
+            non_repeaters: 0,
+            max_repetitions: 0,
+            varbinds: Vec::new(),
+        },
+    };
+    let mut buf = BytesMut::new();
+    snmp::build_response_message(&request, varbinds, ErrorStatus::NoError, 0, &mut buf);
+    buf.to_vec()
+}
+
+// A response to `request` carrying sysContact
+fn contact_reply(request: &SnmpMessage, request_id: i32, contact: &str) -> Vec<u8> {
+    let varbind = Varbind {
+        oid: SYS_CONTACT.to_vec(),
+        value: SnmpValue::OctetString(contact.as_bytes().to_vec()),
+    };
+    reply(request, request_id, vec![varbind])
+}
+
+#[test]
+fn stale_and_spoofed_replies_are_ignored() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let target = socket.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        let mut receive = || -> (SnmpMessage, SocketAddr) {
+            let (len, client) = socket.recv_from(&mut buf).unwrap();
+            (snmp::decode_snmp_message(&buf[..len]).unwrap(), client)
+        };
+
+        // Leave the first request unanswered until the second arrives
+        let (first, _) = receive();
+        let (second, client) = receive();
+        let id = second.pdu.request_id;
+        let replies = [
+            (
+                &socket,
+                contact_reply(&first, first.pdu.request_id, "stale"),
+            ),
+            (
+                &socket,
+                contact_reply(&second, id.wrapping_add(1000), "wrong id"),
+            ),
+            (&spoofer, contact_reply(&second, id, "spoofed")),
+        ];
+        for (from, reply) in replies {
+            from.send_to(&reply, client).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        socket
+            .send_to(&contact_reply(&second, id, "fresh"), client)
+            .unwrap();
+    });
+
+    let mut client = client(snmp::SNMP_VERSION_2C);
+    let error = client
+        .get_with_options(
+            target.as_str(),
+            "public",
+            &[&SYS_CONTACT],
+            &RequestOptions {
+                timeout: Duration::from_millis(100),
+                retries: 0,
+                backoff: 1.0,
+            },
+        )
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<SnmpError>(),
+        Some(SnmpError::Timeout { attempts: 1 })
+    ));
+
+    let response = client
+        .get(target.as_str(), "public", &[&SYS_CONTACT])
+        .unwrap();
+    assert!(matches!(
+        &response.varbinds[0].value,
+        SnmpValue::OctetString(contact) if contact == b"fresh"
+    ));
+}
+
+fn object(id: u32) -> Vec<u32> {
+    [&OBJECTS[..], &[id]].concat()
+}
+
+// One read-write object of every type, the integer limited to 0..100, and
+// a read-only Counter64
+fn typed_objects(agent: &SnmpAgent) {
+    let objects = [
+        (
+            SnmpValue::Integer(50),
+            Syntax::new(ValueType::Integer).with_range(0, 100),
+        ),
+        (
+            SnmpValue::OctetString(b"edge".to_vec()),
+            ValueType::OctetString.into(),
+        ),
+        (
+            SnmpValue::ObjectIdentifier(vec![1, 3, 6, 1]),
+            ValueType::ObjectIdentifier.into(),
+        ),
+        (
+            SnmpValue::IpAddress([10, 0, 0, 1]),
+            ValueType::IpAddress.into(),
+        ),
+        (SnmpValue::Counter32(1), ValueType::Counter32.into()),
+        (SnmpValue::Gauge32(2), ValueType::Gauge32.into()),
+        (SnmpValue::TimeTicks(3), ValueType::TimeTicks.into()),
+        (
+            SnmpValue::Opaque(vec![0x9f, 0x78, 0x04]),
+            ValueType::Opaque.into(),
+        ),
+    ];
+    for (id, (value, syntax)) in (1..).zip(objects) {
+        agent
+            .register_object(
+                object(id),
+                value,
+                ObjectType::new(syntax, Access::ReadWrite),
+            )
+            .unwrap();
+    }
+    agent
+        .register_object(
+            object(9),
+            SnmpValue::Counter64(1 << 40),
+            ObjectType::new(ValueType::Counter64, Access::ReadOnly),
+        )
+        .unwrap();
+}
+
+#[test]
+fn set_and_get_next_carry_typed_values() {
+    let target = start_agent(typed_objects);
+    let mut client = client(snmp::SNMP_VERSION_2C);
+
+    let ids: Vec<Vec<u32>> = (1..=8).map(object).collect();
+    let values = [
+        SnmpValue::Integer(0),
+        SnmpValue::OctetString(vec![0, 0xff, b'x']),
+        SnmpValue::ObjectIdentifier(vec![1, 3, 6, 1, 4, 1, 99, 4_294_967_295]),
+        SnmpValue::IpAddress([192, 168, 1, 254]),
+        SnmpValue::Counter32(u32::MAX),
+        SnmpValue::Gauge32(1_000_000),
+        SnmpValue::TimeTicks(8_640_000),
+        SnmpValue::Opaque(vec![0x9f, 0x78, 0x04, 0x3f, 0x80, 0, 0]),
+    ];
+    let varbinds: Vec<(&[u32], SnmpValue)> = ids
+        .iter()
+        .map(|id| id.as_slice())
+        .zip(values.iter().cloned())
+        .collect();
+    let response = client.set(target.as_str(), "public", &varbinds).unwrap();
+    assert_eq!(response.varbinds.len(), 8);
+
+    // Each GetNext lands on the following object and sees the new value
+    let oids: Vec<&[u32]> = [&OBJECTS[..]]
+        .into_iter()
+        .chain(ids.iter().map(|id| id.as_slice()))
+        .collect();
+    let response = client.get_next(target.as_str(), "public", &oids).unwrap();
+    let got: Vec<&SnmpValue> = response.varbinds.iter().map(|v| &v.value).collect();
+    assert_eq!(response.varbinds[0].oid, object(1));
+    assert_eq!(response.varbinds[8].oid, object(9));
+    assert!(matches!(got[0], SnmpValue::Integer(0)));
+    assert!(matches!(got[1], SnmpValue::OctetString(s) if s == &[0, 0xff, b'x']));
+    assert!(matches!(got[2], SnmpValue::ObjectIdentifier(oid) if oid[7] == 4_294_967_295));
+    assert!(matches!(got[3], SnmpValue::IpAddress([192, 168, 1, 254])));
+    assert!(matches!(got[4], SnmpValue::Counter32(u32::MAX)));
+    assert!(matches!(got[5], SnmpValue::Gauge32(1_000_000)));
+    assert!(matches!(got[6], SnmpValue::TimeTicks(8_640_000)));
+    assert!(matches!(got[7], SnmpValue::Opaque(o) if o.len() == 7));
+    assert!(matches!(got[8], SnmpValue::Counter64(value) if *value == 1 << 40));
+}
+
+#[test]
+fn agent_errors_reach_the_caller() {
+    let target = start_agent(typed_objects);
+    let agent_error = |error: Box<dyn std::error::Error>| match error.downcast::<SnmpError>() {
+        Ok(error) => match *error {
+            SnmpError::AgentError { status, index, oid } => (status, index, oid),
+            other => panic!("{}", other),
+        },
+        Err(error) => panic!("{}", error),
+    };
+
+    for version in [snmp::SNMP_VERSION_1, snmp::SNMP_VERSION_2C] {
+        let mut client = client(version);
+        let v1 = version == snmp::SNMP_VERSION_1;
+
+        // The second varbind is out of range; the first must not be stored
+        let error = client
+            .set(
+                target.as_str(),
+                "public",
+                &[
+                    (&object(2), SnmpValue::OctetString(b"lost".to_vec())),
+                    (&object(1), SnmpValue::Integer(101)),
+                ],
+            )
+            .unwrap_err();
+        let status = if v1 {
+            ErrorStatus::BadValue
+        } else {
+            ErrorStatus::WrongValue
+        };
+        assert_eq!(agent_error(error), (status, 2, Some(object(1))));
+        let response = client
+            .get(target.as_str(), "public", &[&object(2)])
+            .unwrap();
+        assert!(matches!(
+            &response.varbinds[0].value,
+            SnmpValue::OctetString(s) if s == b"edge"
+        ));
+
+        let error = client
+            .set(
+                target.as_str(),
+                "public",
+                &[(&object(1), SnmpValue::Gauge32(1))],
+            )
+            .unwrap_err();
+        let status = if v1 {
+            ErrorStatus::BadValue
+        } else {
+            ErrorStatus::WrongType
+        };
+        assert_eq!(agent_error(error), (status, 1, Some(object(1))));
+
+        let error = client
+            .set(
+                target.as_str(),
+                "public",
+                &[(&object(9), SnmpValue::Counter64(1))],
+            )
+            .unwrap_err();
+        let status = if v1 {
+            ErrorStatus::NoSuchName
+        } else {
+            ErrorStatus::NotWritable
+        };
+        assert_eq!(agent_error(error).0, status);
+
+        // Off the end of the MIB, SNMPv1 has an error where SNMPv2c has
+        // an exception value
+        let result = client.get_next(target.as_str(), "public", &[&[1, 3, 6, 1, 4, 1, 99, 2]]);
+        if v1 {
+            let (status, index, _) = agent_error(result.unwrap_err());
+            assert_eq!((status, index), (ErrorStatus::NoSuchName, 1));
+        } else {
+            assert!(matches!(
+                result.unwrap().varbinds[0].value,
+                SnmpValue::EndOfMibView
+            ));
+        }
+    }
+}
+
+fn walk(target: &str, version: u8, root: &[u32]) -> Vec<Result<Varbind, String>> {
+    client(version)
+        .with_max_repetitions(3)
+        .walk(target, "public", root)
+        .unwrap()
+        .map(|item| item.map_err(|e| e.to_string()))
+        .collect()
+}
+
+#[test]
+fn walk_stops_at_the_end_of_the_subtree() {
+    let target = start_agent(|agent| {
+        typed_objects(agent);
+        agent
+            .register_oid(vec![1, 3, 6, 1, 4, 1, 99, 2, 1], SnmpValue::Integer(1))
+            .unwrap();
+    });
+
+    for version in [snmp::SNMP_VERSION_1, snmp::SNMP_VERSION_2C] {
+        let oids: Vec<Vec<u32>> = walk(&target, version, &OBJECTS)
+            .into_iter()
+            .map(|item| item.unwrap().oid)
+            .collect();
+        // SNMPv1 cannot see the Counter64
+        let last = if version == snmp::SNMP_VERSION_1 {
+            8
+        } else {
+            9
+        };
+        assert_eq!(oids, (1..=last).map(object).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn walk_stops_at_the_end_of_the_mib() {
+    let target = start_agent(typed_objects);
+
+    // endOfMibView in SNMPv2c, noSuchName in SNMPv1, neither an error
+    for version in [snmp::SNMP_VERSION_1, snmp::SNMP_VERSION_2C] {
+        let items = walk(&target, version, &[1, 3, 6, 1, 4, 1, 99]);
+        assert!(items.iter().all(|item| item.is_ok()), "{:?}", items);
+        let last = if version == snmp::SNMP_VERSION_1 {
+            8
+        } else {
+            9
+        };
+        assert_eq!(items.len(), last);
+    }
+
+    // Nothing at all past the root
+    for version in [snmp::SNMP_VERSION_1, snmp::SNMP_VERSION_2C] {
+        assert!(walk(&target, version, &[1, 3, 6, 1, 4, 1, 100]).is_empty());
+    }
+}
+
+#[test]
+fn walk_fails_on_an_oid_that_does_not_increase() {
+    // An agent that answers every request with the same object
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let target = socket.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        while let Ok((len, client)) = socket.recv_from(&mut buf) {
+            let request = snmp::decode_snmp_message(&buf[..len]).unwrap();
+            let varbind = Varbind {
+                oid: object(1),
+                value: SnmpValue::Integer(1),
+            };
+            let reply = reply(&request, request.pdu.request_id, vec![varbind]);
+            socket.send_to(&reply, client).unwrap();
+        }
+    });
+
+    for version in [snmp::SNMP_VERSION_1, snmp::SNMP_VERSION_2C] {
+        let mut items = walk(&target, version, &OBJECTS).into_iter();
+        assert_eq!(items.next().unwrap().unwrap().oid, object(1));
+        let error = items.next().unwrap().unwrap_err();
+        assert_eq!(error, SnmpError::OidNotIncreasing(object(1)).to_string());
+        assert!(items.next().is_none());
+    }
+}
@@ -0,0 +1,233 @@
+#![cfg(feature = "if-mib")]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use snmp_t::agent::SnmpAgent;
+use snmp_t::client::{RequestOptions, SnmpClient};
+use snmp_t::if_mib::IfMib;
+use snmp_t::index::IndexType;
+use snmp_t::snmp::{self, SnmpValue};
+use snmp_t::transport::{Transport, TransportAddr, UdpTransport};
+
+const IF_NUMBER: [u32; 9] = [1, 3, 6, 1, 2, 1, 2, 1, 0];
+const IF_TABLE: [u32; 8] = [1, 3, 6, 1, 2, 1, 2, 2];
+const IF_X_TABLE: [u32; 9] = [1, 3, 6, 1, 2, 1, 31, 1, 1];
+
+const PROC_NET_DEV: &str = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:    1200      12    0    0    0     0          0         0     1200      12    0    0    0     0       0          0
+  eth0: 5000000000 4000000  3    7    0     0          0       100 70000      600    1    2    0     0       0          0
+  wg0:      10       1    0    0    0     0          0         0       20        2    0    0    0     0       0          0
+";
+
+// A directory standing in for `/`, with the interfaces lo, eth0 and wg0;
+// wg0 has no attributes under sys/class/net
+fn fixture(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("if-mib-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("proc/net")).unwrap();
+    fs::write(root.join("proc/net/dev"), PROC_NET_DEV).unwrap();
+
+    interface(
+        &root,
+        "lo",
+        &[
+            ("ifindex", "1"),
+            ("type", "772"),
+            ("mtu", "65536"),
+            ("address", "00:00:00:00:00:00"),
+            ("operstate", "unknown"),
+            ("flags", "0x9"),
+        ],
+    );
+    interface(
+        &root,
+        "eth0",
+        &[
+            ("ifindex", "2"),
+            ("type", "1"),
+            ("mtu", "1500"),
+            ("speed", "10000"),
+            ("address", "02:fc:00:00:00:01"),
+            ("operstate", "down"),
+            ("flags", "0x1102"),
+            ("ifalias", "uplink"),
+        ],
+    );
+    root
+}
+
+fn interface(root: &Path, name: &str, attributes: &[(&str, &str)]) {
+    let dir = root.join("sys/class/net").join(name);
+    fs::create_dir_all(&dir).unwrap();
+    for (file, value) in attributes {
+        fs::write(dir.join(file), format!("{}\n", value)).unwrap();
+    }
+}
+
+// Start an agent serving IF-MIB from `root`, returning its port
+fn start_agent(root: &Path) -> u16 {
+    let transport = UdpTransport::bind("127.0.0.1:0").unwrap();
+    let TransportAddr::Udp(addr) = transport.local_addr().unwrap() else {
+        unreachable!();
+    };
+    let agent = SnmpAgent::with_transport(Box::new(transport), vec!["public".to_string()]);
+    agent.register_if_mib(IfMib::new().with_root(root)).unwrap();
+    agent.run_in_thread();
+    addr.port()
+}
+
+fn client() -> SnmpClient {
+    SnmpClient::new()
+        .with_version(snmp::SNMP_VERSION_2C)
+        .with_options(RequestOptions {
+            timeout: Duration::from_millis(500),
+            retries: 1,
+            backoff: 1.0,
+        })
+}
+
+fn string(value: &SnmpValue) -> String {
+    match value {
+        SnmpValue::OctetString(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+        other => panic!("Expected an OCTET STRING, got {:?}", other),
+    }
+}
+
+#[test]
+fn reads_interfaces() {
+    let root = fixture("read");
+    let interfaces = IfMib::new().with_root(&root).interfaces().unwrap();
+
+    let names: Vec<&str> = interfaces.iter().map(|i| i.name.as_str()).collect();
+    assert_eq!(names, ["lo", "eth0", "wg0"]);
+
+    let eth0 = &interfaces[1];
+    assert_eq!(eth0.index, 2);
+    assert_eq!(eth0.if_type, 6);
+    assert_eq!(eth0.mtu, 1500);
+    assert_eq!(eth0.speed, Some(10000));
+    assert_eq!(eth0.phys_address, [0x02, 0xfc, 0, 0, 0, 1]);
+    assert_eq!(eth0.oper_status, 2);
+    assert_eq!(eth0.alias, "uplink");
+    assert_eq!(eth0.stats.rx_bytes, 5_000_000_000);
+    assert_eq!(eth0.stats.rx_multicast, 100);
+    assert_eq!(eth0.stats.tx_dropped, 2);
+
+    // Without attributes wg0 is numbered after the highest ifindex
+    let wg0 = &interfaces[2];
+    assert_eq!(wg0.index, 3);
+    assert_eq!(wg0.if_type, 1);
+    assert_eq!(wg0.speed, None);
+    assert!(wg0.phys_address.is_empty());
+    assert_eq!(wg0.oper_status, 4);
+
+    assert!(
+        IfMib::new()
+            .with_root(root.join("missing"))
+            .interfaces()
+            .is_err()
+    );
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn numbers_interfaces_without_ifindex_after_the_others() {
+    let root = fixture("renumber");
+    // wg0's position in /proc/net/dev
+    fs::write(root.join("sys/class/net/eth0/ifindex"), "3\n").unwrap();
+    fs::write(root.join("sys/class/net/lo/ifindex"), "0\n").unwrap();
+
+    let interfaces = IfMib::new().with_root(&root).interfaces().unwrap();
+    let indexes: Vec<(&str, u32)> = interfaces
+        .iter()
+        .map(|i| (i.name.as_str(), i.index))
+        .collect();
+    assert_eq!(indexes, [("eth0", 3), ("lo", 4), ("wg0", 5)]);
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn serves_if_table() {
+    let root = fixture("table");
+    let target = format!("127.0.0.1:{}", start_agent(&root));
+    let mut client = client();
+
+    let response = client
+        .get(target.as_str(), "public", &[&IF_NUMBER])
+        .unwrap();
+    assert!(matches!(response.varbinds[0].value, SnmpValue::Integer(3)));
+
+    let table = client
+        .get_table(
+            target.as_str(),
+            "public",
+            &IF_TABLE,
+            &[IndexType::Integer],
+            &[1, 2, 3, 5, 6, 7, 8, 10, 11],
+        )
+        .unwrap();
+    let indexes: Vec<i32> = table
+        .iter()
+        .map(|row| match row.index[..] {
+            [SnmpValue::Integer(index)] => index,
+            _ => panic!("{:?}", row.index),
+        })
+        .collect();
+    assert_eq!(indexes, [1, 2, 3]);
+
+    let lo = &table[0].cells;
+    assert_eq!(string(&lo[&2]), "lo");
+    assert!(matches!(lo[&3], SnmpValue::Integer(24)));
+    assert!(matches!(&lo[&6], SnmpValue::OctetString(mac) if mac.is_empty()));
+    assert!(matches!(lo[&7], SnmpValue::Integer(1)));
+    assert!(matches!(lo[&8], SnmpValue::Integer(4)));
+
+    let eth0 = &table[1].cells;
+    assert!(matches!(eth0[&1], SnmpValue::Integer(2)));
+    assert!(matches!(eth0[&3], SnmpValue::Integer(6)));
+    // 10 Gb/s does not fit ifSpeed
+    assert!(matches!(eth0[&5], SnmpValue::Gauge32(u32::MAX)));
+    assert!(matches!(eth0[&7], SnmpValue::Integer(2)));
+    assert!(matches!(eth0[&8], SnmpValue::Integer(2)));
+    // ifInOctets wraps at 2^32
+    assert!(matches!(eth0[&10], SnmpValue::Counter32(705_032_704)));
+    assert!(matches!(eth0[&11], SnmpValue::Counter32(3_999_900)));
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn serves_if_x_table() {
+    let root = fixture("x-table");
+    let target = format!("127.0.0.1:{}", start_agent(&root));
+    let mut client = client();
+
+    let table = client
+        .get_table(
+            target.as_str(),
+            "public",
+            &IF_X_TABLE,
+            &[IndexType::Integer],
+            &[1, 6, 8, 10, 15, 16, 18],
+        )
+        .unwrap();
+    let eth0 = &table[1].cells;
+    assert!(matches!(table[1].index[..], [SnmpValue::Integer(2)]));
+    assert_eq!(string(&eth0[&1]), "eth0");
+    assert!(matches!(eth0[&6], SnmpValue::Counter64(5_000_000_000)));
+    assert!(matches!(eth0[&8], SnmpValue::Counter64(100)));
+    assert!(matches!(eth0[&10], SnmpValue::Counter64(70_000)));
+    assert!(matches!(eth0[&15], SnmpValue::Gauge32(10_000)));
+    assert!(matches!(eth0[&16], SnmpValue::Integer(1)));
+    assert_eq!(string(&eth0[&18]), "uplink");
+
+    let wg0 = &table[2].cells;
+    assert!(matches!(wg0[&15], SnmpValue::Gauge32(0)));
+    assert!(matches!(wg0[&16], SnmpValue::Integer(2)));
+
+    fs::remove_dir_all(root).unwrap();
+}
@@ -0,0 +1,415 @@
+#![cfg(feature = "tls")]
+
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509, X509NameBuilder};
+
+use snmp_t::agent::SnmpAgent;
+use snmp_t::client::{RequestOptions, SnmpClient};
+use snmp_t::snmp::SnmpValue;
+use snmp_t::tls::{
+    CertMapType, CertToTsnTable, DtlsTransport, Fingerprint, HashAlgorithm, TlsConfig, TlsTransport,
+};
+use snmp_t::transport::{Transport, TransportAddr};
+
+const SYS_DESCR: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 1, 0];
+const SYS_CONTACT: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 4, 0];
+
+struct Identity {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+impl Identity {
+    fn config(&self) -> TlsConfig {
+        TlsConfig::new(self.cert.clone(), self.key.clone())
+    }
+
+    fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of(&self.cert, HashAlgorithm::Sha256).unwrap()
+    }
+}
+
+fn new_key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+// A certificate for `common_name`, signed by `issuer` or self-signed
+fn certificate(common_name: &str, dns_names: &[&str], issuer: Option<&Identity>) -> Identity {
+    build_certificate(common_name, dns_names, issuer, false)
+}
+
+// A certificate naming `issuer` as its issuer but signed with its own key,
+// as an attacker without the CA's key would make it
+fn forged_certificate(common_name: &str, issuer: &Identity) -> Identity {
+    build_certificate(common_name, &[], Some(issuer), true)
+}
+
+fn build_certificate(
+    common_name: &str,
+    dns_names: &[&str],
+    issuer: Option<&Identity>,
+    forged: bool,
+) -> Identity {
+    let key = new_key();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+        .unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    let serial = BigNum::from_u32(rand_serial()).unwrap();
+    builder
+        .set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder
+        .set_issuer_name(issuer.map_or(&name, |i| i.cert.subject_name()))
+        .unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    if issuer.is_none() {
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+    }
+    if !dns_names.is_empty() {
+        let mut san = SubjectAlternativeName::new();
+        for dns_name in dns_names {
+            san.dns(dns_name);
+        }
+        let san = san
+            .build(&builder.x509v3_context(issuer.map(|i| &*i.cert), None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+    }
+    let signing_key = match issuer {
+        Some(issuer) if !forged => &issuer.key,
+        _ => &key,
+    };
+    builder.sign(signing_key, MessageDigest::sha256()).unwrap();
+
+    Identity {
+        cert: builder.build(),
+        key,
+    }
+}
+
+fn rand_serial() -> u32 {
+    let mut bytes = [0u8; 4];
+    openssl::rand::rand_bytes(&mut bytes).unwrap();
+    u32::from_be_bytes(bytes) >> 1
+}
+
+fn options() -> RequestOptions {
+    RequestOptions {
+        timeout: Duration::from_millis(500),
+        retries: 1,
+        backoff: 1.0,
+    }
+}
+
+// Start an agent over `transport` whose only community is the
+// tmSecurityName "manager"
+fn start_agent(transport: Box<dyn Transport>) {
+    let agent = SnmpAgent::with_transport(transport, vec!["manager".to_string()]);
+    agent
+        .register_oid(
+            SYS_DESCR.to_vec(),
+            SnmpValue::OctetString(b"TLS test agent".to_vec()),
+        )
+        .unwrap();
+    agent
+        .register_oid(SYS_CONTACT.to_vec(), SnmpValue::OctetString(Vec::new()))
+        .unwrap();
+    agent.run_in_thread();
+}
+
+fn agent_config(agent: &Identity, manager: &Identity) -> TlsConfig {
+    agent
+        .config()
+        .with_cert_to_tsn(CertToTsnTable::new().with_row(
+            1,
+            manager.fingerprint(),
+            CertMapType::Specified("manager".to_string()),
+        ))
+}
+
+fn get_and_set(target: &str, agent: &Identity, manager: &Identity) {
+    let mut client = SnmpClient::new()
+        .with_options(options())
+        .with_tls_config(manager.config().with_peer_fingerprint(agent.fingerprint()));
+
+    // The community is ignored, the agent authorizes the security name
+    let response = client.get(target, "ignored", &[&SYS_DESCR]).unwrap();
+    assert!(matches!(
+        &response.varbinds[0].value,
+        SnmpValue::OctetString(v) if v == b"TLS test agent"
+    ));
+
+    client
+        .set(
+            target,
+            "ignored",
+            &[(&SYS_CONTACT, SnmpValue::OctetString(b"ops".to_vec()))],
+        )
+        .unwrap();
+    let response = client.get(target, "ignored", &[&SYS_CONTACT]).unwrap();
+    assert!(matches!(
+        &response.varbinds[0].value,
+        SnmpValue::OctetString(v) if v == b"ops"
+    ));
+}
+
+fn port(addr: TransportAddr) -> u16 {
+    match addr {
+        TransportAddr::Tls(addr) | TransportAddr::Dtls(addr) => addr.port(),
+        addr => panic!("unexpected address {}", addr),
+    }
+}
+
+#[test]
+fn tls_get_and_set() {
+    let agent = certificate("agent", &[], None);
+    let manager = certificate("manager", &[], None);
+    let transport =
+        TlsTransport::listen((Ipv4Addr::LOCALHOST, 0), &agent_config(&agent, &manager)).unwrap();
+    let port = port(transport.local_addr().unwrap());
+    start_agent(Box::new(transport));
+
+    get_and_set(&format!("tlstcp:127.0.0.1:{}", port), &agent, &manager);
+}
+
+#[test]
+fn dtls_get_and_set() {
+    let agent = certificate("agent", &[], None);
+    let manager = certificate("manager", &[], None);
+    let transport =
+        DtlsTransport::bind((Ipv4Addr::LOCALHOST, 0), &agent_config(&agent, &manager)).unwrap();
+    let port = port(transport.local_addr().unwrap());
+    start_agent(Box::new(transport));
+
+    get_and_set(&format!("dtlsudp:127.0.0.1:{}", port), &agent, &manager);
+}
+
+#[test]
+fn unknown_manager_is_rejected() {
+    let agent = certificate("agent", &[], None);
+    let manager = certificate("manager", &[], None);
+    let stranger = certificate("stranger", &[], None);
+    let transport =
+        TlsTransport::listen((Ipv4Addr::LOCALHOST, 0), &agent_config(&agent, &manager)).unwrap();
+    let port = port(transport.local_addr().unwrap());
+    start_agent(Box::new(transport));
+
+    let mut client = SnmpClient::new()
+        .with_options(options())
+        .with_tls_config(stranger.config().with_peer_fingerprint(agent.fingerprint()));
+    let target = format!("tlstcp:127.0.0.1:{}", port);
+    assert!(
+        client
+            .get(target.as_str(), "manager", &[&SYS_DESCR])
+            .is_err()
+    );
+}
+
+#[test]
+fn manager_rejects_unpinned_agent() {
+    let agent = certificate("agent", &[], None);
+    let manager = certificate("manager", &[], None);
+    let impostor = certificate("agent", &[], None);
+    let transport =
+        TlsTransport::listen((Ipv4Addr::LOCALHOST, 0), &agent_config(&impostor, &manager)).unwrap();
+    let port = port(transport.local_addr().unwrap());
+    start_agent(Box::new(transport));
+
+    let mut client = SnmpClient::new()
+        .with_options(options())
+        .with_tls_config(manager.config().with_peer_fingerprint(agent.fingerprint()));
+    let target = format!("tlstcp:127.0.0.1:{}", port);
+    assert!(
+        client
+            .get(target.as_str(), "manager", &[&SYS_DESCR])
+            .is_err()
+    );
+}
+
+#[test]
+fn manager_checks_the_agent_name() {
+    let ca = certificate("Example CA", &[], None);
+    let manager = certificate("manager", &[], None);
+    let start = |agent: &Identity| {
+        let transport =
+            TlsTransport::listen((Ipv4Addr::LOCALHOST, 0), &agent_config(agent, &manager)).unwrap();
+        let port = port(transport.local_addr().unwrap());
+        start_agent(Box::new(transport));
+        format!("tlstcp:127.0.0.1:{}", port)
+    };
+    let get = |target: &str, config: TlsConfig| {
+        SnmpClient::new()
+            .with_options(options())
+            .with_tls_config(config.with_trusted_certificate(ca.cert.clone()))
+            .get(target, "ignored", &[&SYS_DESCR])
+    };
+
+    let agent = certificate("agent", &["agent.example.com"], Some(&ca));
+    let target = start(&agent);
+    let expecting = |name: &str| manager.config().with_server_name(name);
+    assert!(get(&target, expecting("agent.example.com")).is_ok());
+    // Issued by the same CA, but for another agent
+    assert!(get(&target, expecting("other.example.com")).is_err());
+    // Without a server name the certificate must name the address
+    assert!(get(&target, manager.config()).is_err());
+
+    let other = certificate("other", &["other.example.com"], Some(&ca));
+    let target = start(&other);
+    assert!(get(&target, expecting("agent.example.com")).is_err());
+    // Pinning the agent's certificate stands in for the name
+    let pinned = expecting("agent.example.com").with_peer_fingerprint(other.fingerprint());
+    assert!(get(&target, pinned).is_ok());
+}
+
+// An agent that trusts `ca` and maps the managers it issued to their
+// CommonName, returning the TLS target
+fn start_ca_agent(agent: &Identity, ca: &Identity) -> String {
+    let config = agent
+        .config()
+        .with_trusted_certificate(ca.cert.clone())
+        .with_cert_to_tsn(CertToTsnTable::new().with_row(
+            1,
+            ca.fingerprint(),
+            CertMapType::CommonName,
+        ));
+    let transport = TlsTransport::listen((Ipv4Addr::LOCALHOST, 0), &config).unwrap();
+    let port = port(transport.local_addr().unwrap());
+    start_agent(Box::new(transport));
+    format!("tlstcp:127.0.0.1:{}", port)
+}
+
+#[test]
+fn manager_issued_by_trusted_ca() {
+    let agent = certificate("agent", &[], None);
+    let ca = certificate("Example CA", &[], None);
+    let manager = certificate("manager", &[], Some(&ca));
+    let target = start_ca_agent(&agent, &ca);
+
+    let mut client = SnmpClient::new().with_options(options()).with_tls_config(
+        manager
+            .config()
+            .with_chain_certificate(ca.cert.clone())
+            .with_peer_fingerprint(agent.fingerprint()),
+    );
+    assert!(
+        client
+            .get(target.as_str(), "ignored", &[&SYS_DESCR])
+            .is_ok()
+    );
+}
+
+#[test]
+fn forged_chain_is_rejected() {
+    let agent = certificate("agent", &[], None);
+    let ca = certificate("Example CA", &[], None);
+    let forged = forged_certificate("manager", &ca);
+    let config = agent
+        .config()
+        .with_trusted_certificate(ca.cert.clone())
+        .with_cert_to_tsn(CertToTsnTable::new().with_row(
+            1,
+            ca.fingerprint(),
+            CertMapType::CommonName,
+        ));
+    let listener = TlsTransport::listen((Ipv4Addr::LOCALHOST, 0), &config).unwrap();
+
+    // The CA certificate sent along matches the agent's mapping row, but
+    // the leaf's signature does not verify against it
+    let client = TlsTransport::new(
+        &forged
+            .config()
+            .with_chain_certificate(ca.cert.clone())
+            .with_peer_fingerprint(agent.fingerprint()),
+    )
+    .unwrap();
+    // Any BER SEQUENCE would be handed to the agent
+    let message = [0x30, 0x03, 0x02, 0x01, 0x00];
+    let _ = client.send_to(&message, &listener.local_addr().unwrap());
+
+    let mut buf = [0u8; 64];
+    assert!(
+        listener
+            .recv_from(&mut buf, Some(Duration::from_secs(2)))
+            .is_err()
+    );
+}
+
+#[test]
+fn cert_to_tsn_mapping() {
+    let ca = certificate("Example CA", &[], None);
+    let leaf = certificate("Manager-1", &["Ops.Example.COM"], Some(&ca));
+    let chain = [leaf.cert.clone(), ca.cert.clone()];
+
+    let by_leaf = CertToTsnTable::new().with_row(5, leaf.fingerprint(), CertMapType::SanDnsName);
+    assert_eq!(by_leaf.map(&chain).as_deref(), Some("ops.example.com"));
+
+    // A CA fingerprint covers every certificate it issued
+    let by_ca = CertToTsnTable::new().with_row(1, ca.fingerprint(), CertMapType::CommonName);
+    assert_eq!(by_ca.map(&chain).as_deref(), Some("Manager-1"));
+
+    // Rows apply in ID order and a row yielding no name is skipped
+    let table = CertToTsnTable::new()
+        .with_row(3, ca.fingerprint(), CertMapType::CommonName)
+        .with_row(2, leaf.fingerprint(), CertMapType::SanIpAddress)
+        .with_row(1, ca.fingerprint(), CertMapType::SanAny);
+    assert_eq!(table.map(&chain).as_deref(), Some("ops.example.com"));
+    let mut table = table;
+    table.remove(1);
+    assert_eq!(table.map(&chain).as_deref(), Some("Manager-1"));
+
+    let too_long = CertToTsnTable::new().with_row(
+        1,
+        leaf.fingerprint(),
+        CertMapType::Specified("x".repeat(33)),
+    );
+    assert_eq!(too_long.map(&chain), None);
+
+    let stranger = certificate("stranger", &[], None);
+    assert_eq!(table.map(&[stranger.cert]), None);
+}
+
+#[test]
+fn fingerprint_encodings() {
+    let identity = certificate("agent", &[], None);
+    let fingerprint = Fingerprint::of(&identity.cert, HashAlgorithm::Sha1).unwrap();
+
+    let bytes = fingerprint.to_bytes();
+    assert_eq!(bytes[0], 2);
+    assert_eq!(bytes.len(), 21);
+    assert_eq!(Fingerprint::from_bytes(&bytes).unwrap(), fingerprint);
+
+    let text = fingerprint.to_string();
+    assert!(text.starts_with("SHA1:"));
+    assert_eq!(text.parse::<Fingerprint>().unwrap(), fingerprint);
+    assert_eq!(
+        text.to_lowercase().parse::<Fingerprint>().unwrap(),
+        fingerprint
+    );
+
+    assert!(Fingerprint::from_bytes(&[4, 1, 2, 3]).is_err());
+    assert!("SHA999:01".parse::<Fingerprint>().is_err());
+    assert!(fingerprint.matches(&identity.cert));
+}